@@ -1,13 +1,14 @@
 use anda_cognitive_nexus::{CognitiveNexus, ConceptPK};
 use anda_core::{
-    Agent, AgentContext, AgentOutput, BoxError, CompletionRequest, Document, Documents,
-    Message, Principal, Resource, StateFeatures, Tool, ToolSet, Usage, evaluate_tokens,
+    Agent, AgentContext, AgentOutput, BoxError, CompletionFeatures, CompletionRequest, Document,
+    Documents, Message, Principal, Resource, StateFeatures, Tool, ToolErrorPolicy, ToolSet, Usage,
     update_resources,
 };
 use anda_db::{database::AndaDB, index::BTree};
 use anda_engine::{
     ANONYMOUS,
-    context::{AgentCtx, BaseCtx},
+    context::{AgentCtx, BaseCtx, CompletionRunner},
+    engine::Engine,
     extension::fetch::FetchWebResourcesTool,
     memory::{
         Conversation, ConversationRef, ConversationState, ConversationStatus,
@@ -97,6 +98,155 @@ impl Assistant {
         self.memory.clone()
     }
 
+    /// Resumes the conversations interrupted by an engine restart in the background,
+    /// returns the number of resumed conversations. `controller` is the controller of the engine.
+    ///
+    /// The runs are resumed by [`Engine::resume_run`], which checks the access, rate limits
+    /// and credit of their callers and charges the usage, and the conversations are updated
+    /// by [`Agent::on_resumed`]. A conversation fails if its run can not be resumed.
+    pub async fn resume_conversations(
+        &self,
+        engine: &Engine,
+        controller: Principal,
+    ) -> Result<usize, BoxError> {
+        let mut resumed = 0;
+        for (name, state) in engine.list_runs().await? {
+            // 等待人工审批的运行由调用者恢复
+            if name != Self::NAME || state.pending.is_some() {
+                continue;
+            }
+            let Some(id) = state.conversation else {
+                continue;
+            };
+            let Ok(mut conversation) = self.memory.get_conversation(id).await else {
+                continue;
+            };
+            if conversation.status == ConversationStatus::Canceled
+                || conversation.status == ConversationStatus::Failed
+            {
+                continue;
+            }
+
+            log::info!("Resume conversation {id} from run {}", state.id);
+            let assistant = self.clone();
+            let engine = engine.clone();
+            tokio::spawn(async move {
+                if let Err(err) = engine.resume_run(controller, Self::NAME, &state.id).await {
+                    log::error!("Failed to resume conversation {id}: {:?}", err);
+                    conversation.status = ConversationStatus::Failed;
+                    conversation.failed_reason = Some(err.to_string());
+                    conversation.updated_at = unix_ms();
+                    if let Ok(changes) = conversation.to_changes() {
+                        let _ = assistant.memory.update_conversation(id, changes).await;
+                    }
+                }
+            });
+            resumed += 1;
+        }
+
+        Ok(resumed)
+    }
+
+    /// Runs the completion of a conversation in the background,
+    /// and updates the conversation in memory after each step.
+    fn spawn_conversation(
+        &self,
+        ctx: AgentCtx,
+        mut runner: CompletionRunner,
+        mut conversation: Conversation,
+        mut first_round: bool,
+    ) {
+        let assistant = self.clone();
+        tokio::spawn(async move {
+            let id = conversation._id;
+            let mut rt = async || {
+                loop {
+                    match runner.next().await {
+                        Ok(None) => break,
+                        Ok(Some(mut res)) => {
+                            let now_ms = unix_ms();
+                            let artifacts =
+                                assistant.memory.try_add_resources(&res.artifacts).await?;
+
+                            if first_round {
+                                first_round = false;
+                                conversation.messages.clear(); // clear the first pending message.
+                                conversation.append_messages(res.chat_history);
+                            } else {
+                                res.chat_history.drain(0..conversation.messages.len());
+                                conversation.append_messages(res.chat_history);
+                            }
+
+                            conversation.artifacts = artifacts;
+                            conversation.status = if res.pending_approval.is_some() {
+                                // 等待人工审批，由 `on_resumed` 更新恢复后的结果
+                                ConversationStatus::Working
+                            } else if runner.is_done() {
+                                ConversationStatus::Completed
+                            } else if res.failed_reason.is_some() {
+                                ConversationStatus::Failed
+                            } else {
+                                ConversationStatus::Working
+                            };
+                            conversation.usage = res.usage;
+                            conversation.updated_at = now_ms;
+
+                            if let Some(failed_reason) = res.failed_reason {
+                                conversation.failed_reason = Some(failed_reason);
+                            }
+
+                            let old = assistant.memory.get_conversation(conversation._id).await?;
+                            if old.status == ConversationStatus::Canceled
+                                && (conversation.status == ConversationStatus::Submitted
+                                    || conversation.status == ConversationStatus::Working)
+                            {
+                                conversation.status = ConversationStatus::Canceled;
+                            }
+
+                            let _ = assistant
+                                .memory
+                                .update_conversation(id, conversation.to_changes()?)
+                                .await;
+
+                            ctx.base.set_state(ConversationState::from(&conversation));
+
+                            if conversation.status == ConversationStatus::Canceled
+                                || conversation.status == ConversationStatus::Failed
+                            {
+                                runner.discard().await?;
+                                break;
+                            }
+                        }
+                        Err(err) => {
+                            log::error!("Conversation {id} in CompletionRunner error: {:?}", err);
+                            let now_ms = unix_ms();
+                            conversation.failed_reason = Some(err.to_string());
+                            conversation.status = ConversationStatus::Failed;
+                            conversation.updated_at = now_ms;
+                            let _ = assistant
+                                .memory
+                                .update_conversation(id, conversation.to_changes()?)
+                                .await;
+
+                            ctx.base.set_state(ConversationState::from(&conversation));
+                            runner.discard().await?;
+                            break;
+                        }
+                    }
+                }
+
+                Ok::<(), BoxError>(())
+            };
+
+            match rt().await {
+                Ok(_) => {}
+                Err(err) => {
+                    log::error!("Error occurred in conversation {id}: {:?}", err);
+                }
+            }
+        });
+    }
+
     pub async fn to_kip_system_role_instructions(&self) -> Result<String, BoxError> {
         let system = self.memory.describe_system().await?;
 
@@ -159,6 +309,33 @@ impl Agent<AgentCtx> for Assistant {
         "AI assistant powered by the Knowledge Interaction Protocol (KIP)".to_string()
     }
 
+    /// Deletes the interrupted runs of the conversations that are gone, canceled or failed,
+    /// the others are resumed by [`Assistant::resume_conversations`] after the engine is built.
+    async fn init(&self, ctx: AgentCtx) -> Result<(), BoxError> {
+        for state in ctx.list_runs().await? {
+            let Some(id) = state.conversation else {
+                continue;
+            };
+            // 等待人工审批的运行由调用者恢复
+            if state.pending.is_some() {
+                continue;
+            }
+
+            let finished = match self.memory.get_conversation(id).await {
+                Ok(conversation) => {
+                    conversation.status == ConversationStatus::Canceled
+                        || conversation.status == ConversationStatus::Failed
+                }
+                Err(_) => true,
+            };
+            if finished {
+                ctx.delete_run(&state.id).await?;
+            }
+        }
+
+        Ok(())
+    }
+
     /// Returns a list of tool names that this agent depends on
     fn tool_dependencies(&self) -> Vec<String> {
         self.tools.clone()
@@ -168,6 +345,46 @@ impl Agent<AgentCtx> for Assistant {
         vec!["text".to_string()]
     }
 
+    /// Returns tool errors to the model so that it can recover in the conversation.
+    fn tool_error_policy(&self) -> ToolErrorPolicy {
+        ToolErrorPolicy::Feedback {
+            max_consecutive_failures: 3,
+        }
+    }
+
+    /// Updates the conversation with the output of a run resumed by the engine,
+    /// e.g. after its sensitive tool calls are approved.
+    async fn on_resumed(
+        &self,
+        ctx: AgentCtx,
+        output: AgentOutput,
+    ) -> Result<AgentOutput, BoxError> {
+        let Some(id) = output.conversation else {
+            return Ok(output);
+        };
+
+        let mut conversation = self.memory.get_conversation(id).await?;
+        let artifacts = self.memory.try_add_resources(&output.artifacts).await?;
+        conversation.messages.clear();
+        conversation.append_messages(output.chat_history.clone());
+        conversation.artifacts = artifacts;
+        conversation.status = if output.pending_approval.is_some() {
+            ConversationStatus::Working
+        } else if output.failed_reason.is_some() {
+            ConversationStatus::Failed
+        } else {
+            ConversationStatus::Completed
+        };
+        conversation.failed_reason = output.failed_reason.clone();
+        conversation.usage = output.usage.clone();
+        conversation.updated_at = unix_ms();
+        self.memory
+            .update_conversation(id, conversation.to_changes()?)
+            .await?;
+        ctx.base.set_state(ConversationState::from(&conversation));
+        Ok(output)
+    }
+
     /// Main execution method for the agent.
     ///
     /// # Arguments
@@ -215,18 +432,20 @@ impl Agent<AgentCtx> for Assistant {
             .memory
             .list_conversations_by_user(caller, None, Some(7))
             .await?;
-        let max_history_bytes = self.max_input_tokens.saturating_sub(
-            ((evaluate_tokens(&instructions) + evaluate_tokens(&prompt)) as f64 * 1.2) as usize,
-        ) * 3; // Rough estimate of bytes per token
-        let mut writer: Vec<u8> = Vec::with_capacity(256);
-        let _ = serde_json::to_writer(&mut writer, &conversations);
-        let mut history_bytes = writer.len();
-        while history_bytes > max_history_bytes && !conversations.is_empty() {
-            writer.clear();
+        let tokenizer = ctx.tokenizer();
+        let max_history_tokens = self.max_input_tokens.saturating_sub(
+            tokenizer.count_tokens(&instructions) + tokenizer.count_tokens(&prompt),
+        );
+        let mut conversation_tokens: Vec<usize> = conversations
+            .iter()
+            .map(|conv| tokenizer.count_tokens(&serde_json::to_string(conv).unwrap_or_default()))
+            .collect();
+        let mut history_tokens: usize = conversation_tokens.iter().sum();
+        // Drops the oldest conversations until the history fits the budget
+        while history_tokens > max_history_tokens && !conversations.is_empty() {
             let conv = conversations.remove(0);
             cursor = BTree::to_cursor(&conv._id);
-            let _ = serde_json::to_writer(&mut writer, &conv);
-            history_bytes = history_bytes.saturating_sub(writer.len());
+            history_tokens -= conversation_tokens.remove(0);
         }
 
         let mut history_docs: Vec<Document> = Vec::with_capacity(conversations.len() + 2);
@@ -302,105 +521,24 @@ impl Agent<AgentCtx> for Assistant {
             ..Default::default()
         };
 
-        let assistant = self.clone();
-        let mut runner = ctx.completion_iter(
-            CompletionRequest {
-                instructions,
-                prompt,
-                chat_history,
-                documents: Documents::new("resources".to_string(), resource_docs),
-                tools: ctx.tool_definitions(Some(
-                    &self.tools.iter().map(|v| v.as_str()).collect::<Vec<_>>(),
-                )),
-                tool_choice_required: false,
-                ..Default::default()
-            },
-            resources,
-        );
-
-        tokio::spawn(async move {
-            let mut rt = async || {
-                let mut first_round = true;
-                loop {
-                    match runner.next().await {
-                        Ok(None) => break,
-                        Ok(Some(mut res)) => {
-                            let now_ms = unix_ms();
-                            let artifacts =
-                                assistant.memory.try_add_resources(&res.artifacts).await?;
-
-                            if first_round {
-                                first_round = false;
-                                conversation.messages.clear(); // clear the first pending message.
-                                conversation.append_messages(res.chat_history);
-                            } else {
-                                res.chat_history.drain(0..conversation.messages.len());
-                                conversation.append_messages(res.chat_history);
-                            }
-
-                            conversation.artifacts = artifacts;
-                            conversation.status = if runner.is_done() {
-                                ConversationStatus::Completed
-                            } else if res.failed_reason.is_some() {
-                                ConversationStatus::Failed
-                            } else {
-                                ConversationStatus::Working
-                            };
-                            conversation.usage = res.usage;
-                            conversation.updated_at = now_ms;
-
-                            if let Some(failed_reason) = res.failed_reason {
-                                conversation.failed_reason = Some(failed_reason);
-                            }
-
-                            let old = assistant.memory.get_conversation(conversation._id).await?;
-                            if old.status == ConversationStatus::Canceled
-                                && (conversation.status == ConversationStatus::Submitted
-                                    || conversation.status == ConversationStatus::Working)
-                            {
-                                conversation.status = ConversationStatus::Canceled;
-                            }
-
-                            let _ = assistant
-                                .memory
-                                .update_conversation(id, conversation.to_changes()?)
-                                .await;
-
-                            ctx.base.set_state(ConversationState::from(&conversation));
-
-                            if conversation.status == ConversationStatus::Canceled
-                                || conversation.status == ConversationStatus::Failed
-                            {
-                                break;
-                            }
-                        }
-                        Err(err) => {
-                            log::error!("Conversation {id} in CompletionRunner error: {:?}", err);
-                            let now_ms = unix_ms();
-                            conversation.failed_reason = Some(err.to_string());
-                            conversation.status = ConversationStatus::Failed;
-                            conversation.updated_at = now_ms;
-                            let _ = assistant
-                                .memory
-                                .update_conversation(id, conversation.to_changes()?)
-                                .await;
-
-                            ctx.base.set_state(ConversationState::from(&conversation));
-                            break;
-                        }
-                    }
-                }
-
-                Ok::<(), BoxError>(())
-            };
-
-            match rt().await {
-                Ok(_) => {}
-                Err(err) => {
-                    log::error!("Error occurred in conversation {id}: {:?}", err);
-                }
-            }
-        });
+        let runner = ctx
+            .completion_iter(
+                CompletionRequest {
+                    instructions,
+                    prompt,
+                    chat_history,
+                    documents: Documents::new("resources".to_string(), resource_docs),
+                    tools: ctx.tool_definitions(Some(
+                        &self.tools.iter().map(|v| v.as_str()).collect::<Vec<_>>(),
+                    )),
+                    tool_choice_required: false,
+                    ..Default::default()
+                },
+                resources,
+            )
+            .with_checkpoint()
+            .with_conversation(id);
+        self.spawn_conversation(ctx, runner, conversation, true);
 
         Ok(res)
     }
@@ -1,5 +1,5 @@
 use anda_core::{ContentPart, Resource};
-use anda_db_schema::{AndaDBSchema, FieldEntry, FieldType, Schema, SchemaError};
+use anda_db_schema::{AndaDBSchema, FieldEntry, FieldKey, FieldType, Schema, SchemaError};
 use anda_engine::context::EngineCard;
 use candid::Principal;
 use ic_auth_types::Xid;
@@ -1,10 +1,36 @@
 use anda_core::{AgentInput, AgentOutput, BoxError, HttpFeatures, ToolInput, ToolOutput};
-use anda_web3_client::client::{Client as Web3Client, load_identity};
+use anda_web3_client::client::{Client as Web3Client, Identity, load_identity};
 use base64::{Engine, prelude::BASE64_URL_SAFE};
+use candid::Principal;
 use ciborium::value::Value;
 use clap::{Parser, Subcommand};
 use rand::RngCore;
-use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeSet,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+static DAY_MS: u64 = 24 * 3600 * 1000;
+
+/// The user returned by the user management methods of the engine.
+#[derive(Debug, Deserialize, Serialize)]
+struct User {
+    id: Principal,
+    features: BTreeSet<String>,
+    status: i8,
+    subscription_tier: u8,
+    subscription_expiry: u64,
+    credit_balance: u64,
+    credit_expiry: u64,
+    last_access: u64,
+    agent_requests: u64,
+    tool_requests: u64,
+    credit_consumed: u64,
+    #[serde(default)]
+    credit_debt: Option<u64>,
+}
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -71,6 +97,91 @@ pub enum Commands {
         #[arg(short, long)]
         args: String,
     },
+
+    /// Get a user of the engine, requires a manager identity.
+    GetUser {
+        #[arg(short, long, default_value = "http://127.0.0.1:8042/default")]
+        endpoint: String,
+
+        /// Principal ID of the user.
+        #[arg(short, long)]
+        user: String,
+    },
+
+    /// List the users of the engine, requires a manager identity.
+    ListUsers {
+        #[arg(short, long, default_value = "http://127.0.0.1:8042/default")]
+        endpoint: String,
+
+        #[arg(short, long, default_value = "0")]
+        offset: usize,
+
+        #[arg(short, long, default_value = "100")]
+        limit: usize,
+    },
+
+    /// Top up the credit of a user, requires a manager identity.
+    TopupCredit {
+        #[arg(short, long, default_value = "http://127.0.0.1:8042/default")]
+        endpoint: String,
+
+        /// Principal ID of the user.
+        #[arg(short, long)]
+        user: String,
+
+        #[arg(short, long)]
+        credit: u64,
+
+        /// Days until the credit expires, default is 365.
+        #[arg(short, long, default_value = "365")]
+        days: u64,
+    },
+
+    /// Set the subscription tier of a user, requires a manager identity.
+    UpdateSubscription {
+        #[arg(short, long, default_value = "http://127.0.0.1:8042/default")]
+        endpoint: String,
+
+        /// Principal ID of the user.
+        #[arg(short, long)]
+        user: String,
+
+        /// Subscription tier: 0: free, 1: premium, 2: enterprise.
+        #[arg(short, long)]
+        tier: u8,
+
+        /// Days until the subscription expires, default is 30.
+        #[arg(short, long, default_value = "30")]
+        days: u64,
+    },
+
+    /// Set the features of a user, requires a manager identity.
+    UpdateFeatures {
+        #[arg(short, long, default_value = "http://127.0.0.1:8042/default")]
+        endpoint: String,
+
+        /// Principal ID of the user.
+        #[arg(short, long)]
+        user: String,
+
+        /// Comma separated features, empty to clear the features.
+        #[arg(short, long, default_value = "")]
+        features: String,
+    },
+
+    /// Set the status of a user, requires a manager identity.
+    UpdateStatus {
+        #[arg(short, long, default_value = "http://127.0.0.1:8042/default")]
+        endpoint: String,
+
+        /// Principal ID of the user.
+        #[arg(short, long)]
+        user: String,
+
+        /// Status: 0: active, -1: suspended, -2: banned.
+        #[arg(short, long, allow_hyphen_values = true)]
+        status: i8,
+    },
 }
 
 #[tokio::main]
@@ -179,6 +290,88 @@ async fn main() -> Result<(), BoxError> {
             println!("{}", serde_json::to_string_pretty(&res)?);
         }
 
+        Some(Commands::GetUser { endpoint, user }) => {
+            let web3 = build_client(&cli.host, identity).await?;
+            let user = Principal::from_text(user)?;
+            let res: User = web3
+                .https_signed_rpc(endpoint, "get_user", &(user,))
+                .await?;
+            println!("{}", serde_json::to_string_pretty(&res)?);
+        }
+
+        Some(Commands::ListUsers {
+            endpoint,
+            offset,
+            limit,
+        }) => {
+            let web3 = build_client(&cli.host, identity).await?;
+            let res: Vec<User> = web3
+                .https_signed_rpc(endpoint, "list_users", &(offset, limit))
+                .await?;
+            println!("{}", serde_json::to_string_pretty(&res)?);
+        }
+
+        Some(Commands::TopupCredit {
+            endpoint,
+            user,
+            credit,
+            days,
+        }) => {
+            let web3 = build_client(&cli.host, identity).await?;
+            let user = Principal::from_text(user)?;
+            let expiry_ms = unix_ms() + days * DAY_MS;
+            let res: User = web3
+                .https_signed_rpc(endpoint, "topup_credit", &(user, credit, expiry_ms))
+                .await?;
+            println!("{}", serde_json::to_string_pretty(&res)?);
+        }
+
+        Some(Commands::UpdateSubscription {
+            endpoint,
+            user,
+            tier,
+            days,
+        }) => {
+            let web3 = build_client(&cli.host, identity).await?;
+            let user = Principal::from_text(user)?;
+            let expiry_ms = unix_ms() + days * DAY_MS;
+            let res: User = web3
+                .https_signed_rpc(endpoint, "update_subscription", &(user, tier, expiry_ms))
+                .await?;
+            println!("{}", serde_json::to_string_pretty(&res)?);
+        }
+
+        Some(Commands::UpdateFeatures {
+            endpoint,
+            user,
+            features,
+        }) => {
+            let web3 = build_client(&cli.host, identity).await?;
+            let user = Principal::from_text(user)?;
+            let features: BTreeSet<String> = features
+                .split(',')
+                .map(|f| f.trim().to_string())
+                .filter(|f| !f.is_empty())
+                .collect();
+            let res: User = web3
+                .https_signed_rpc(endpoint, "update_features", &(user, features))
+                .await?;
+            println!("{}", serde_json::to_string_pretty(&res)?);
+        }
+
+        Some(Commands::UpdateStatus {
+            endpoint,
+            user,
+            status,
+        }) => {
+            let web3 = build_client(&cli.host, identity).await?;
+            let user = Principal::from_text(user)?;
+            let res: User = web3
+                .https_signed_rpc(endpoint, "update_status", &(user, status))
+                .await?;
+            println!("{}", serde_json::to_string_pretty(&res)?);
+        }
+
         None => {
             println!("no command");
         }
@@ -186,3 +379,19 @@ async fn main() -> Result<(), BoxError> {
 
     Ok(())
 }
+
+async fn build_client(host: &str, identity: Box<dyn Identity>) -> Result<Web3Client, BoxError> {
+    Web3Client::builder()
+        .with_ic_host(host)
+        .with_identity(Arc::new(identity))
+        .with_allow_http(true)
+        .build()
+        .await
+}
+
+fn unix_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
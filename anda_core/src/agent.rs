@@ -39,7 +39,7 @@ use std::{collections::BTreeMap, future::Future, marker::PhantomData, sync::Arc}
 use crate::{
     BoxError, BoxPinFut, Function,
     context::AgentContext,
-    model::{AgentOutput, FunctionDefinition, Resource},
+    model::{AgentOutput, FunctionDefinition, Resource, ToolErrorPolicy},
     select_resources, validate_function_name,
 };
 
@@ -119,6 +119,12 @@ where
         Vec::new()
     }
 
+    /// Returns the policy for handling failed tool/agent calls in the agent's completion runs.
+    /// By default, the run ends on the first failed call.
+    fn tool_error_policy(&self) -> ToolErrorPolicy {
+        ToolErrorPolicy::default()
+    }
+
     /// Executes the agent's main logic with given context and inputs.
     ///
     /// # Arguments
@@ -128,12 +134,25 @@ where
     ///
     /// # Returns
     /// - A future resolving to Result<[`AgentOutput`], BoxError>.
+    ///   Return an [`AndaError`](crate::AndaError) to tell the caller the error code, e.g. invalid input.
     fn run(
         &self,
         ctx: C,
         prompt: String,
         resources: Vec<Resource>,
     ) -> impl Future<Output = Result<AgentOutput, BoxError>> + Send;
+
+    /// Post-processes the output of a completion run of the agent resumed by the engine,
+    /// e.g. after its sensitive tool calls are approved or after an engine restart,
+    /// like [`Agent::run`] does for the runs it starts. The output may be paused for approval again.
+    /// By default, it returns the output as is.
+    fn on_resumed(
+        &self,
+        _ctx: C,
+        output: AgentOutput,
+    ) -> impl Future<Output = Result<AgentOutput, BoxError>> + Send {
+        futures::future::ready(Ok(output))
+    }
 }
 
 /// Dynamic dispatch version of Agent trait for runtime flexibility.
@@ -152,6 +171,8 @@ where
 
     fn supported_resource_tags(&self) -> Vec<String>;
 
+    fn tool_error_policy(&self) -> ToolErrorPolicy;
+
     fn init(&self, ctx: C) -> BoxPinFut<Result<(), BoxError>>;
 
     fn run(
@@ -160,6 +181,8 @@ where
         prompt: String,
         resources: Vec<Resource>,
     ) -> BoxPinFut<Result<AgentOutput, BoxError>>;
+
+    fn on_resumed(&self, ctx: C, output: AgentOutput) -> BoxPinFut<Result<AgentOutput, BoxError>>;
 }
 
 /// Adapter for converting static Agent to dynamic dispatch.
@@ -189,6 +212,10 @@ where
         self.0.supported_resource_tags()
     }
 
+    fn tool_error_policy(&self) -> ToolErrorPolicy {
+        self.0.tool_error_policy()
+    }
+
     fn init(&self, ctx: C) -> BoxPinFut<Result<(), BoxError>> {
         let agent = self.0.clone();
         Box::pin(async move { agent.init(ctx).await })
@@ -203,6 +230,11 @@ where
         let agent = self.0.clone();
         Box::pin(async move { agent.run(ctx, prompt, resources).await })
     }
+
+    fn on_resumed(&self, ctx: C, output: AgentOutput) -> BoxPinFut<Result<AgentOutput, BoxError>> {
+        let agent = self.0.clone();
+        Box::pin(async move { agent.on_resumed(ctx, output).await })
+    }
 }
 
 /// Collection of agents with lookup capabilities.
@@ -53,8 +53,11 @@ use crate::model::*;
 /// It combines core functionality with AI-specific features:
 /// - [`BaseContext`]`: Fundamental operations;
 /// - [`CompletionFeatures`]: LLM completions and function calling;
-/// - [`EmbeddingFeatures`]: Text embeddings.
-pub trait AgentContext: BaseContext + CompletionFeatures + EmbeddingFeatures {
+/// - [`EmbeddingFeatures`]: Text embeddings;
+/// - [`RerankFeatures`]: Document reranking.
+pub trait AgentContext:
+    BaseContext + CompletionFeatures + EmbeddingFeatures + RerankFeatures
+{
     /// Retrieves definitions for available tools.
     ///
     /// # Arguments
@@ -231,6 +234,25 @@ pub trait VectorSearchFeatures: Sized {
         query: &str,
         n: usize,
     ) -> impl std::future::Future<Output = Result<Vec<String>, BoxError>> + Send;
+
+    /// Performs a hybrid (BM25 + vector) search with metadata filters.
+    /// Returns the matched documents, the most relevant first.
+    fn vector_search(
+        &self,
+        query: VectorQuery,
+    ) -> impl Future<Output = Result<Vec<VectorDocument>, BoxError>> + Send;
+
+    /// Embeds and indexes the documents, replacing the documents with the same IDs.
+    fn vector_upsert(
+        &self,
+        docs: Vec<VectorDocument>,
+    ) -> impl Future<Output = Result<(), BoxError>> + Send;
+
+    /// Deletes the documents by IDs, returns the number of deleted documents.
+    fn vector_delete(
+        &self,
+        ids: Vec<String>,
+    ) -> impl Future<Output = Result<usize, BoxError>> + Send;
 }
 
 /// KeysFeatures is one of the context feature sets available when calling Agent or Tool.
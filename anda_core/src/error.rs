@@ -0,0 +1,356 @@
+//! Structured errors for tools, agents and RPC calls.
+//!
+//! Tools and agents return [`BoxError`], they can return an [`AndaError`] to carry an error code
+//! to the caller. The engine converts all errors into [`AndaError`] with [`AndaError::from`],
+//! and [`crate::RPCResponse`] serializes it, so remote clients can tell bad input,
+//! permission denied, insufficient credit, upstream failures and cancellation apart.
+
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::json;
+use std::fmt;
+
+use crate::{BoxError, HttpRPCError, Json, ToolArgsError};
+
+/// The code of an [`AndaError`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The input is invalid, e.g. malformed params or tool arguments.
+    InvalidArgument,
+    /// The caller is not authenticated, e.g. an anonymous caller.
+    Unauthenticated,
+    /// The caller does not have permission.
+    PermissionDenied,
+    /// The engine, agent or tool is not found.
+    NotFound,
+    /// The caller does not have enough credit.
+    InsufficientCredit,
+    /// The caller exceeded the rate limit or quota, see [`AndaError::retry_after_ms`].
+    RateLimited,
+    /// The method is not implemented.
+    Unimplemented,
+    /// An upstream service (model provider, remote engine, ...) failed.
+    Upstream,
+    /// The operation timed out.
+    Timeout,
+    /// The operation was cancelled.
+    Cancelled,
+    /// An internal error.
+    Internal,
+    /// An unknown error, e.g. a plain error message from an older peer.
+    #[serde(other)]
+    Unknown,
+}
+
+impl ErrorCode {
+    /// Returns the code as a snake case string.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::InvalidArgument => "invalid_argument",
+            ErrorCode::Unauthenticated => "unauthenticated",
+            ErrorCode::PermissionDenied => "permission_denied",
+            ErrorCode::NotFound => "not_found",
+            ErrorCode::InsufficientCredit => "insufficient_credit",
+            ErrorCode::RateLimited => "rate_limited",
+            ErrorCode::Unimplemented => "unimplemented",
+            ErrorCode::Upstream => "upstream",
+            ErrorCode::Timeout => "timeout",
+            ErrorCode::Cancelled => "cancelled",
+            ErrorCode::Internal => "internal",
+            ErrorCode::Unknown => "unknown",
+        }
+    }
+
+    /// Returns `true` if errors with the code are retryable by default.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            ErrorCode::RateLimited | ErrorCode::Upstream | ErrorCode::Timeout
+        )
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A structured error with a code, a message, a retryable flag and optional details.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AndaError {
+    /// The error code.
+    pub code: ErrorCode,
+    /// The error message.
+    pub message: String,
+    /// Whether the request can be retried later.
+    pub retryable: bool,
+    /// Optional details, e.g. the schema violations of invalid tool arguments.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<Json>,
+}
+
+impl AndaError {
+    /// Creates an error with the code and message, it is retryable if the code is.
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            retryable: code.is_retryable(),
+            details: None,
+        }
+    }
+
+    pub fn invalid_argument(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::InvalidArgument, message)
+    }
+
+    pub fn unauthenticated(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Unauthenticated, message)
+    }
+
+    pub fn permission_denied(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::PermissionDenied, message)
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::NotFound, message)
+    }
+
+    pub fn insufficient_credit(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::InsufficientCredit, message)
+    }
+
+    /// Creates a rate limited error, the request can be retried after `retry_after_ms` milliseconds.
+    pub fn rate_limited(message: impl Into<String>, retry_after_ms: u64) -> Self {
+        Self::new(ErrorCode::RateLimited, message)
+            .with_details(json!({"retry_after_ms": retry_after_ms}))
+    }
+
+    pub fn unimplemented(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Unimplemented, message)
+    }
+
+    pub fn upstream(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Upstream, message)
+    }
+
+    pub fn timeout(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Timeout, message)
+    }
+
+    pub fn cancelled(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Cancelled, message)
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Internal, message)
+    }
+
+    /// Sets whether the request can be retried.
+    pub fn with_retryable(mut self, retryable: bool) -> Self {
+        self.retryable = retryable;
+        self
+    }
+
+    /// Sets the details of the error.
+    pub fn with_details(mut self, details: Json) -> Self {
+        self.details = Some(details);
+        self
+    }
+
+    /// Returns the milliseconds to wait before retrying from the details, if any.
+    pub fn retry_after_ms(&self) -> Option<u64> {
+        self.details.as_ref()?.get("retry_after_ms")?.as_u64()
+    }
+
+    /// Prefixes the message with the context, keeping the code and details.
+    pub fn context(mut self, context: impl fmt::Display) -> Self {
+        self.message = format!("{}: {}", context, self.message);
+        self
+    }
+}
+
+impl fmt::Display for AndaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for AndaError {}
+
+/// Deserializes both the structured error and a plain error message,
+/// the latter is the error of [`crate::RPCResponse`] from older peers.
+impl<'de> Deserialize<'de> for AndaError {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Message(String),
+            Error {
+                code: ErrorCode,
+                message: String,
+                #[serde(default)]
+                retryable: bool,
+                #[serde(default)]
+                details: Option<Json>,
+            },
+        }
+
+        Ok(match Repr::deserialize(deserializer)? {
+            Repr::Message(message) => AndaError::new(ErrorCode::Unknown, message),
+            Repr::Error {
+                code,
+                message,
+                retryable,
+                details,
+            } => AndaError {
+                code,
+                message,
+                retryable,
+                details,
+            },
+        })
+    }
+}
+
+/// Converts any error into an [`AndaError`].
+///
+/// [`AndaError`] is kept as is, [`ToolArgsError`], [`HttpRPCError`] and [`reqwest::Error`]
+/// are mapped to the matching codes, other errors are internal errors.
+impl From<BoxError> for AndaError {
+    fn from(err: BoxError) -> Self {
+        let err = match err.downcast::<AndaError>() {
+            Ok(err) => return *err,
+            Err(err) => err,
+        };
+        let err = match err.downcast::<ToolArgsError>() {
+            Ok(err) => {
+                return AndaError::invalid_argument(err.to_string())
+                    .with_details(json!({"violations": err.violations}));
+            }
+            Err(err) => err,
+        };
+        let err = match err.downcast::<HttpRPCError>() {
+            Ok(err) => return (*err).into(),
+            Err(err) => err,
+        };
+        match err.downcast::<reqwest::Error>() {
+            Ok(err) if err.is_timeout() => AndaError::timeout(err.to_string()),
+            Ok(err) => AndaError::upstream(err.to_string()),
+            Err(err) => AndaError::internal(err.to_string()),
+        }
+    }
+}
+
+impl From<HttpRPCError> for AndaError {
+    fn from(err: HttpRPCError) -> Self {
+        match err {
+            HttpRPCError::RemoteError { error, .. } => error,
+            HttpRPCError::RequestError { .. } => AndaError::upstream(err.to_string()),
+            HttpRPCError::ResponseError { status, .. } => {
+                let code = match status {
+                    400 => ErrorCode::InvalidArgument,
+                    401 => ErrorCode::Unauthenticated,
+                    403 => ErrorCode::PermissionDenied,
+                    404 => ErrorCode::NotFound,
+                    408 | 504 => ErrorCode::Timeout,
+                    429 => ErrorCode::RateLimited,
+                    _ => ErrorCode::Upstream,
+                };
+                AndaError::new(code, err.to_string())
+                    .with_retryable(status == 408 || status == 429 || status >= 500)
+            }
+            HttpRPCError::ResultError { .. } => AndaError::upstream(err.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ciborium::from_reader;
+    use ic_cose_types::to_cbor_bytes;
+
+    #[test]
+    fn test_anda_error() {
+        let err = AndaError::permission_denied("caller does not have permission");
+        assert!(!err.retryable);
+        assert_eq!(
+            err.to_string(),
+            "permission_denied: caller does not have permission"
+        );
+
+        let data = to_cbor_bytes(&err);
+        let res: AndaError = from_reader(&data[..]).unwrap();
+        assert_eq!(res, err);
+
+        let res: AndaError = serde_json::from_value(json!({
+            "code": "upstream",
+            "message": "model error",
+            "retryable": true,
+            "details": {"status": 503}
+        }))
+        .unwrap();
+        assert_eq!(
+            res,
+            AndaError::upstream("model error").with_details(json!({"status": 503}))
+        );
+
+        // plain messages and unknown codes
+        let res: AndaError = from_reader(&to_cbor_bytes(&"failed")[..]).unwrap();
+        assert_eq!(res, AndaError::new(ErrorCode::Unknown, "failed"));
+        let res: AndaError =
+            serde_json::from_value(json!({"code": "new_code", "message": "failed"})).unwrap();
+        assert_eq!(res.code, ErrorCode::Unknown);
+    }
+
+    #[test]
+    fn test_from_box_error() {
+        let err: BoxError = AndaError::not_found("agent foo not found").into();
+        assert_eq!(
+            AndaError::from(err),
+            AndaError::not_found("agent foo not found")
+        );
+
+        let err: BoxError = ToolArgsError {
+            name: "foo".to_string(),
+            violations: vec![],
+        }
+        .into();
+        assert_eq!(AndaError::from(err).code, ErrorCode::InvalidArgument);
+
+        let err: BoxError = HttpRPCError::ResponseError {
+            endpoint: "https://example.com".to_string(),
+            path: "agent_run".to_string(),
+            status: 503,
+            error: "unavailable".to_string(),
+        }
+        .into();
+        let err = AndaError::from(err);
+        assert_eq!(err.code, ErrorCode::Upstream);
+        assert!(err.retryable);
+
+        let err: BoxError = HttpRPCError::RemoteError {
+            endpoint: "https://example.com".to_string(),
+            path: "agent_run".to_string(),
+            error: AndaError::insufficient_credit("no credit"),
+        }
+        .into();
+        assert_eq!(
+            AndaError::from(err),
+            AndaError::insufficient_credit("no credit")
+        );
+
+        let err = AndaError::rate_limited("too many requests", 1500);
+        assert!(err.retryable);
+        assert_eq!(err.retry_after_ms(), Some(1500));
+        assert_eq!(AndaError::internal("failed").retry_after_ms(), None);
+
+        let err: BoxError = "some error".into();
+        assert_eq!(AndaError::from(err), AndaError::internal("some error"));
+    }
+}
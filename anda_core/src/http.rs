@@ -10,7 +10,8 @@
 //! - [`RPCRequest`]: Represents a generic RPC request with CBOR-encoded parameters;
 //! - [`CanisterRequest`]: Represents a canister-specific request with Candid-encoded parameters;
 //! - [`RPCResponse`]: Represents a response from an RPC call;
-//! - [`HttpRPCError`]: Represents possible errors during RPC operations.
+//! - [`HttpRPCError`]: Represents possible errors during RPC operations, the errors returned
+//!   by the remote service are decoded as [`AndaError`] in [`HttpRPCError::RemoteError`].
 //!
 //! The main functions are:
 //! - [`http_rpc`]: Makes a generic CBOR-encoded RPC call;
@@ -26,6 +27,8 @@ use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use serde_bytes::ByteBuf;
 use std::fmt::Display;
 
+use crate::AndaError;
+
 pub static CONTENT_TYPE_CBOR: &str = "application/cbor";
 pub static CONTENT_TYPE_JSON: &str = "application/json";
 pub static CONTENT_TYPE_TEXT: &str = "text/plain";
@@ -77,8 +80,8 @@ pub struct CanisterRequestRef<'a> {
 
 /// Represents an RPC response that can be either:
 /// - Ok(ByteBuf): CBOR or Candid encoded successful response;
-/// - Err(String): Error message as a string.
-pub type RPCResponse = Result<ByteBuf, String>;
+/// - Err(AndaError): Structured error with code, message, retryable flag and details.
+pub type RPCResponse = Result<ByteBuf, AndaError>;
 
 // #[derive(Debug, Deserialize, Serialize)]
 // pub struct ListPagination {
@@ -123,6 +126,13 @@ pub enum HttpRPCError {
         path: String,
         error: String,
     },
+
+    #[error("http_rpc({endpoint:?}, {path:?}): {error}")]
+    RemoteError {
+        endpoint: String,
+        path: String,
+        error: AndaError,
+    },
 }
 
 /// Makes an HTTP RPC call with CBOR-encoded parameters and returns the decoded response.
@@ -258,9 +268,9 @@ pub async fn cbor_rpc(
         path: path.to_string(),
         error: format!("{e:?}"),
     })?;
-    res.map_err(|e| HttpRPCError::ResultError {
+    res.map_err(|error| HttpRPCError::RemoteError {
         endpoint: endpoint.to_string(),
         path: path.to_string(),
-        error: format!("{e:?}"),
+        error,
     })
 }
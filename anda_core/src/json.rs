@@ -1,4 +1,6 @@
 use schemars::{JsonSchema, Schema, generate::SchemaSettings, transform::RestrictFormats};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 /// Generate JSON schema for a given type T.
 pub fn root_schema_for<T: JsonSchema>() -> Schema {
@@ -19,10 +21,384 @@ pub fn gen_schema_for<T: JsonSchema>() -> serde_json::Value {
     root_schema_for::<T>().to_value()
 }
 
+/// A violation of a JSON Schema, see [`validate_json_schema`].
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SchemaViolation {
+    /// The JSON Pointer to the invalid value, empty for the root value.
+    pub path: String,
+    /// The description of the violation.
+    pub message: String,
+}
+
+impl std::fmt::Display for SchemaViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "/: {}", self.message)
+        } else {
+            write!(f, "{}: {}", self.path, self.message)
+        }
+    }
+}
+
+/// Validates a JSON value against a JSON Schema, returns the violations, empty if valid.
+///
+/// It supports the keywords generated by `schemars`: `type`, `enum`, `const`, `$ref` to
+/// the local definitions, `properties`, `required`, `additionalProperties`, `items`,
+/// `prefixItems`, `allOf`, `anyOf`, `oneOf`, `not`, the numeric, length and size limits,
+/// and the `date-time`, `date`, `time`, `email`, `ipv4`, `ipv6`, `uri` and `uuid` formats.
+/// Other keywords such as `pattern` and unknown formats are ignored.
+pub fn validate_json_schema(schema: &Value, value: &Value) -> Vec<SchemaViolation> {
+    let mut validator = SchemaValidator {
+        root: schema,
+        violations: Vec::new(),
+    };
+    validator.validate(schema, value, &mut String::new(), 0);
+    validator.violations
+}
+
+/// The maximum depth of nested schemas, it stops recursive `$ref`s.
+const MAX_SCHEMA_DEPTH: usize = 64;
+
+struct SchemaValidator<'a> {
+    root: &'a Value,
+    violations: Vec<SchemaViolation>,
+}
+
+impl<'a> SchemaValidator<'a> {
+    fn violation(&mut self, path: &str, message: String) {
+        self.violations.push(SchemaViolation {
+            path: path.to_string(),
+            message,
+        });
+    }
+
+    /// Validates the value with a new validator, returns the violations.
+    fn check(
+        &self,
+        schema: &'a Value,
+        value: &Value,
+        path: &mut String,
+        depth: usize,
+    ) -> Vec<SchemaViolation> {
+        let mut validator = SchemaValidator {
+            root: self.root,
+            violations: Vec::new(),
+        };
+        validator.validate(schema, value, path, depth);
+        validator.violations
+    }
+
+    fn validate(&mut self, schema: &'a Value, value: &Value, path: &mut String, depth: usize) {
+        if depth > MAX_SCHEMA_DEPTH {
+            return;
+        }
+
+        let schema = match schema {
+            Value::Bool(false) => {
+                self.violation(path, "value is not allowed".to_string());
+                return;
+            }
+            Value::Object(schema) => schema,
+            _ => return,
+        };
+
+        if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            match reference
+                .strip_prefix('#')
+                .and_then(|pointer| self.root.pointer(pointer))
+            {
+                Some(sub) => self.validate(sub, value, path, depth + 1),
+                None => self.violation(path, format!("unresolvable $ref {:?}", reference)),
+            }
+        }
+
+        if let Some(ty) = schema.get("type") {
+            let types: Vec<&str> = match ty {
+                Value::String(t) => vec![t.as_str()],
+                Value::Array(ts) => ts.iter().filter_map(Value::as_str).collect(),
+                _ => Vec::new(),
+            };
+            if !types.is_empty() && !types.iter().any(|t| type_matches(t, value)) {
+                // 类型不匹配时不再检查其它约束，避免产生无意义的错误
+                self.violation(
+                    path,
+                    format!("expected {}, got {}", types.join(" or "), type_name(value)),
+                );
+                return;
+            }
+        }
+
+        if let Some(Value::Array(values)) = schema.get("enum")
+            && !values.contains(value)
+        {
+            self.violation(
+                path,
+                format!(
+                    "value must be one of {}",
+                    serde_json::to_string(values).unwrap_or_default()
+                ),
+            );
+        }
+
+        if let Some(expected) = schema.get("const")
+            && expected != value
+        {
+            self.violation(path, format!("value must be {}", expected));
+        }
+
+        match value {
+            Value::Number(n) => {
+                let n = n.as_f64().unwrap_or_default();
+                if let Some(min) = schema.get("minimum").and_then(Value::as_f64)
+                    && n < min
+                {
+                    self.violation(path, format!("value must be >= {}", min));
+                }
+                if let Some(max) = schema.get("maximum").and_then(Value::as_f64)
+                    && n > max
+                {
+                    self.violation(path, format!("value must be <= {}", max));
+                }
+                if let Some(min) = schema.get("exclusiveMinimum").and_then(Value::as_f64)
+                    && n <= min
+                {
+                    self.violation(path, format!("value must be > {}", min));
+                }
+                if let Some(max) = schema.get("exclusiveMaximum").and_then(Value::as_f64)
+                    && n >= max
+                {
+                    self.violation(path, format!("value must be < {}", max));
+                }
+                if let Some(m) = schema.get("multipleOf").and_then(Value::as_f64)
+                    && m > 0.0
+                    && (n / m).fract() != 0.0
+                {
+                    self.violation(path, format!("value must be a multiple of {}", m));
+                }
+            }
+            Value::String(s) => {
+                let len = s.chars().count() as u64;
+                if let Some(min) = schema.get("minLength").and_then(Value::as_u64)
+                    && len < min
+                {
+                    self.violation(path, format!("string length must be >= {}", min));
+                }
+                if let Some(max) = schema.get("maxLength").and_then(Value::as_u64)
+                    && len > max
+                {
+                    self.violation(path, format!("string length must be <= {}", max));
+                }
+                if let Some(format) = schema.get("format").and_then(Value::as_str)
+                    && !format_matches(format, s)
+                {
+                    self.violation(path, format!("string must be a valid {}", format));
+                }
+            }
+            Value::Array(items) => {
+                let len = items.len() as u64;
+                if let Some(min) = schema.get("minItems").and_then(Value::as_u64)
+                    && len < min
+                {
+                    self.violation(path, format!("array must have at least {} items", min));
+                }
+                if let Some(max) = schema.get("maxItems").and_then(Value::as_u64)
+                    && len > max
+                {
+                    self.violation(path, format!("array must have at most {} items", max));
+                }
+                if schema.get("uniqueItems") == Some(&Value::Bool(true))
+                    && items
+                        .iter()
+                        .enumerate()
+                        .any(|(i, item)| items[..i].contains(item))
+                {
+                    self.violation(path, "array items must be unique".to_string());
+                }
+
+                // draft 2020-12 使用 prefixItems，旧版本的 items 可以是数组
+                let (prefix, rest) = match (schema.get("prefixItems"), schema.get("items")) {
+                    (Some(Value::Array(prefix)), rest) => (prefix.as_slice(), rest),
+                    (None, Some(Value::Array(prefix))) => {
+                        (prefix.as_slice(), schema.get("additionalItems"))
+                    }
+                    (_, rest) => (&[][..], rest),
+                };
+                for (i, item) in items.iter().enumerate() {
+                    let sub = match prefix.get(i) {
+                        Some(sub) => sub,
+                        None => match rest {
+                            Some(sub) => sub,
+                            None => break,
+                        },
+                    };
+                    let len = path.len();
+                    path.push_str(&format!("/{}", i));
+                    self.validate(sub, item, path, depth + 1);
+                    path.truncate(len);
+                }
+            }
+            Value::Object(obj) => {
+                let len = obj.len() as u64;
+                if let Some(min) = schema.get("minProperties").and_then(Value::as_u64)
+                    && len < min
+                {
+                    self.violation(
+                        path,
+                        format!("object must have at least {} properties", min),
+                    );
+                }
+                if let Some(max) = schema.get("maxProperties").and_then(Value::as_u64)
+                    && len > max
+                {
+                    self.violation(path, format!("object must have at most {} properties", max));
+                }
+                if let Some(Value::Array(required)) = schema.get("required") {
+                    for name in required.iter().filter_map(Value::as_str) {
+                        if !obj.contains_key(name) {
+                            self.violation(path, format!("missing required property {:?}", name));
+                        }
+                    }
+                }
+
+                let properties = schema.get("properties").and_then(Value::as_object);
+                // patternProperties 不支持，存在时不检查额外属性
+                let additional = schema
+                    .get("additionalProperties")
+                    .filter(|_| !schema.contains_key("patternProperties"));
+                for (name, val) in obj {
+                    let sub = match properties.and_then(|props| props.get(name)) {
+                        Some(sub) => sub,
+                        None => match additional {
+                            Some(Value::Bool(false)) => {
+                                self.violation(path, format!("unexpected property {:?}", name));
+                                continue;
+                            }
+                            Some(sub) => sub,
+                            None => continue,
+                        },
+                    };
+                    let len = path.len();
+                    path.push('/');
+                    path.push_str(&name.replace('~', "~0").replace('/', "~1"));
+                    self.validate(sub, val, path, depth + 1);
+                    path.truncate(len);
+                }
+            }
+            _ => {}
+        }
+
+        if let Some(Value::Array(subs)) = schema.get("allOf") {
+            for sub in subs {
+                self.validate(sub, value, path, depth + 1);
+            }
+        }
+
+        if let Some(Value::Array(subs)) = schema.get("anyOf") {
+            let mut closest: Option<Vec<SchemaViolation>> = None;
+            for sub in subs {
+                let violations = self.check(sub, value, path, depth + 1);
+                if violations.is_empty() {
+                    closest = None;
+                    break;
+                }
+                if closest.as_ref().is_none_or(|c| violations.len() < c.len()) {
+                    closest = Some(violations);
+                }
+            }
+            if let Some(mut closest) = closest {
+                self.violation(path, "value does not match any schema of anyOf".to_string());
+                self.violations.append(&mut closest);
+            }
+        }
+
+        if let Some(Value::Array(subs)) = schema.get("oneOf") {
+            let mut matched = 0;
+            let mut closest: Option<Vec<SchemaViolation>> = None;
+            for sub in subs {
+                let violations = self.check(sub, value, path, depth + 1);
+                if violations.is_empty() {
+                    matched += 1;
+                } else if closest.as_ref().is_none_or(|c| violations.len() < c.len()) {
+                    closest = Some(violations);
+                }
+            }
+            match matched {
+                0 => {
+                    self.violation(path, "value does not match any schema of oneOf".to_string());
+                    self.violations.append(&mut closest.unwrap_or_default());
+                }
+                1 => {}
+                _ => self.violation(
+                    path,
+                    "value matches more than one schema of oneOf".to_string(),
+                ),
+            }
+        }
+
+        if let Some(sub) = schema.get("not")
+            && self.check(sub, value, path, depth + 1).is_empty()
+        {
+            self.violation(path, "value must not match the schema of not".to_string());
+        }
+    }
+}
+
+fn type_matches(ty: &str, value: &Value) -> bool {
+    match ty {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => {
+            value.is_i64() || value.is_u64() || value.as_f64().is_some_and(|n| n.fract() == 0.0)
+        }
+        _ => true,
+    }
+}
+
+fn format_matches(format: &str, s: &str) -> bool {
+    match format {
+        "date-time" => chrono::DateTime::parse_from_rfc3339(s).is_ok(),
+        "date" => chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d").is_ok(),
+        "time" => chrono::DateTime::parse_from_rfc3339(&format!("1970-01-01T{}", s)).is_ok(),
+        "email" => s.split_once('@').is_some_and(|(local, domain)| {
+            !local.is_empty()
+                && !domain.is_empty()
+                && !domain.contains('@')
+                && !s.contains(char::is_whitespace)
+        }),
+        "ipv4" => s.parse::<std::net::Ipv4Addr>().is_ok(),
+        "ipv6" => s.parse::<std::net::Ipv6Addr>().is_ok(),
+        "uri" => reqwest::Url::parse(s).is_ok(),
+        "uuid" => {
+            s.len() == 36
+                && s.char_indices().all(|(i, c)| match i {
+                    8 | 13 | 18 | 23 => c == '-',
+                    _ => c.is_ascii_hexdigit(),
+                })
+        }
+        _ => true,
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(n) if n.is_f64() => "number",
+        Value::Number(_) => "integer",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use serde::{Deserialize, Serialize};
+    use serde_json::json;
 
     #[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
     struct TestStruct {
@@ -40,4 +416,103 @@ mod tests {
             r#"{"title":"TestStruct","type":"object","properties":{"age":{"type":["integer","null"],"maximum":255,"minimum":0},"name":{"type":"string"}},"required":["name"]}"#
         );
     }
+
+    #[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+    #[serde(rename_all = "lowercase")]
+    enum Level {
+        Low,
+        High,
+    }
+
+    #[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+    #[serde(deny_unknown_fields)]
+    struct Task {
+        title: String,
+        level: Level,
+        tags: Vec<String>,
+        owner: Option<TestStruct>,
+    }
+
+    #[test]
+    fn test_validate_json_schema() {
+        let schema = gen_schema_for::<Task>();
+        let task = json!({"title": "test", "level": "low", "tags": ["a"], "owner": {"name": "Anda", "age": 1}});
+        assert!(validate_json_schema(&schema, &task).is_empty());
+        let task = json!({"title": "test", "level": "high", "tags": [], "owner": null});
+        assert!(validate_json_schema(&schema, &task).is_empty());
+
+        let task = json!({"title": 1, "level": "medium", "tags": ["a", 2], "extra": true});
+        let violations: Vec<String> = validate_json_schema(&schema, &task)
+            .iter()
+            .map(|v| v.to_string())
+            .collect();
+        assert_eq!(
+            violations,
+            vec![
+                r#"/: unexpected property "extra""#,
+                r#"/level: value must be one of ["low","high"]"#,
+                "/tags/1: expected string, got integer",
+                "/title: expected string, got integer",
+            ]
+        );
+
+        let task = json!({"title": "test", "level": "low", "tags": [], "owner": {"age": 256}});
+        let violations: Vec<String> = validate_json_schema(&schema, &task)
+            .iter()
+            .map(|v| v.to_string())
+            .collect();
+        assert_eq!(
+            violations,
+            vec![
+                r#"/owner: missing required property "name""#,
+                "/owner/age: value must be <= 255",
+            ]
+        );
+
+        let violations = validate_json_schema(&schema, &json!([]));
+        assert_eq!(violations[0].path, "");
+        assert_eq!(violations[0].message, "expected object, got array");
+
+        let schema = json!({"type": "object", "properties": {
+            "at": {"type": "string", "format": "date-time"},
+            "email": {"type": "string", "format": "email"},
+            "id": {"type": "string", "format": "uuid"},
+            "url": {"type": "string", "format": "uri"},
+            "color": {"type": "string", "format": "color"},
+        }});
+        let val = json!({"at": "2025-01-01T00:00:00Z", "email": "a@b.c", "id": "67e55044-10b1-426f-9247-bb680e5fe0c8", "url": "https://anda.ai", "color": "red"});
+        assert!(validate_json_schema(&schema, &val).is_empty());
+        let val = json!({"at": "2025-01-01", "email": "a b@c", "id": "67e55044", "url": "anda.ai"});
+        let violations: Vec<String> = validate_json_schema(&schema, &val)
+            .iter()
+            .map(|v| v.to_string())
+            .collect();
+        assert_eq!(
+            violations,
+            vec![
+                "/at: string must be a valid date-time",
+                "/email: string must be a valid email",
+                "/id: string must be a valid uuid",
+                "/url: string must be a valid uri",
+            ]
+        );
+
+        let schema = json!({"$defs": {"id": {"type": "integer", "minimum": 1}}, "anyOf": [{"$ref": "#/$defs/id"}, {"type": "string", "minLength": 3}]});
+        assert!(validate_json_schema(&schema, &json!(1)).is_empty());
+        assert!(validate_json_schema(&schema, &json!("abc")).is_empty());
+        let violations = validate_json_schema(&schema, &json!(0));
+        assert_eq!(
+            violations,
+            vec![
+                SchemaViolation {
+                    path: "".to_string(),
+                    message: "value does not match any schema of anyOf".to_string()
+                },
+                SchemaViolation {
+                    path: "".to_string(),
+                    message: "value must be >= 1".to_string()
+                },
+            ]
+        );
+    }
 }
@@ -3,13 +3,17 @@ use std::{future::Future, pin::Pin};
 
 pub mod agent;
 pub mod context;
+pub mod error;
 pub mod http;
 pub mod json;
 pub mod model;
 pub mod tool;
 
+pub use anda_macros::{AndaTool, tool};
+
 pub use agent::*;
 pub use context::*;
+pub use error::*;
 pub use http::*;
 pub use json::*;
 pub use model::*;
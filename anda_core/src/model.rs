@@ -6,23 +6,28 @@
 //! - Function definition and tooling support ([`FunctionDefinition`]).
 //! - Knowledge and document handling ([`Document`], [`Documents`]).
 //! - Completion request and response structures ([`CompletionRequest`], [`Embedding`]).
-//! - Core AI capabilities traits ([`CompletionFeatures`], [`EmbeddingFeatures`]).
+//! - Core AI capabilities traits ([`CompletionFeatures`], [`EmbeddingFeatures`], [`RerankFeatures`]).
+//! - Token counting for completion models ([`Tokenizer`], [`BpeTokenizer`], [`HeuristicTokenizer`]).
 
 use candid::Principal;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::collections::BTreeMap;
 
-use crate::Json;
+use crate::{Json, SchemaViolation};
 pub use ic_auth_types::{ByteArrayB64, ByteBufB64, Xid};
 
 mod completion;
 mod embedding;
+mod rerank;
 mod resource;
+mod tokenizer;
 
 pub use completion::*;
 pub use embedding::*;
+pub use rerank::*;
 pub use resource::*;
+pub use tokenizer::*;
 
 /// Represents a request to an agent for processing.
 #[derive(Debug, Clone, Default, Deserialize, Serialize)]
@@ -89,6 +94,42 @@ pub struct AgentOutput {
     /// The conversation ID.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub conversation: Option<u64>,
+
+    /// The tool calls waiting for human approval.
+    /// If present, the run is paused and can be resumed with an [`ApprovalInput`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pending_approval: Option<PendingApproval>,
+}
+
+/// Represents a paused agent run waiting for human approval of sensitive tool calls.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct PendingApproval {
+    /// The ID of the paused run.
+    pub id: String,
+
+    /// The tool calls that require approval.
+    pub tool_calls: Vec<ToolCall>,
+}
+
+/// Represents a human decision on the tool calls of a paused agent run.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ApprovalInput {
+    /// agent name, use default agent if empty.
+    pub name: String,
+
+    /// The ID of the paused run, from [`PendingApproval`].
+    pub id: String,
+
+    /// Whether the tool calls are approved.
+    pub approved: bool,
+
+    /// The reason of the decision, it will be sent to the model if rejected.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+
+    /// The metadata for the request.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub meta: Option<RequestMeta>,
 }
 
 /// Represents a message send to LLM for completion.
@@ -145,6 +186,7 @@ impl Message {
                     call_id: call_id.clone(),
                     result: None,
                     remote_id: None,
+                    error: None,
                 });
             }
         }
@@ -342,6 +384,52 @@ pub struct ToolCall {
     /// The remote engine id where tool running
     #[serde(skip_serializing_if = "Option::is_none")]
     pub remote_id: Option<Principal>,
+
+    /// The error of the tool call if it failed, the error may be returned to the model
+    /// depending on the [`ToolErrorPolicy`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ToolCallError>,
+}
+
+/// Represents a failed tool or agent call.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ToolCallError {
+    /// The name of the tool or agent.
+    pub name: String,
+
+    /// The error message.
+    pub message: String,
+
+    /// The violations of the parameters schema if the arguments are invalid.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub violations: Vec<SchemaViolation>,
+}
+
+impl ToolCallError {
+    /// Returns the tool output that carries this error back to the model.
+    pub fn to_output(&self) -> Json {
+        json!({ "error": self })
+    }
+}
+
+impl std::fmt::Display for ToolCallError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} call failed: {}", self.name, self.message)
+    }
+}
+
+/// Defines how a completion run handles failed tool or agent calls.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ToolErrorPolicy {
+    /// Ends the run on the first failed call, the error becomes the `failed_reason`.
+    #[default]
+    Abort,
+
+    /// Returns the error to the model as the tool output, so the model can correct
+    /// its arguments or try another way.
+    /// The run ends when the number of consecutive failed calls exceeds `max_consecutive_failures`.
+    Feedback { max_consecutive_failures: usize },
 }
 
 /// Represents a function definition with its metadata.
@@ -379,9 +467,10 @@ impl FunctionDefinition {
     }
 }
 
-/// Returns the number of tokens in the given content in the simplest way.
+/// Returns the estimated number of tokens in the given content by [`HeuristicTokenizer`].
+/// Use the [`Tokenizer`] of the completion model to count tokens exactly.
 pub fn evaluate_tokens(content: &str) -> usize {
-    content.len() / 3
+    HeuristicTokenizer.count_tokens(content)
 }
 
 /// A document with metadata and content.
@@ -410,7 +499,7 @@ impl From<&Resource> for Document {
 }
 
 /// Collection of knowledge documents.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Documents {
     /// The tag of the document collection. Defaults to "documents".
     tag: String,
@@ -443,6 +532,22 @@ impl Documents {
         &self.tag
     }
 
+    /// Keeps the documents of the rerank results in their order,
+    /// the relevance score is added to the metadata as "relevance_score".
+    pub fn reranked(mut self, results: &[RerankResult]) -> Self {
+        let mut docs: Vec<Option<Document>> = self.docs.into_iter().map(Some).collect();
+        self.docs = results
+            .iter()
+            .filter_map(|res| {
+                let mut doc = docs.get_mut(res.index)?.take()?;
+                doc.metadata
+                    .insert("relevance_score".to_string(), res.relevance_score.into());
+                Some(doc)
+            })
+            .collect();
+        self
+    }
+
     /// Converts the document collection to a message.
     pub fn to_message(&self, rfc3339_datetime: &str) -> Option<Message> {
         if self.docs.is_empty() {
@@ -559,6 +664,37 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_documents_reranked() {
+        let documents: Documents = (0..3)
+            .map(|i| Document {
+                metadata: BTreeMap::from([("_id".to_string(), i.into())]),
+                content: format!("Test document {i}.").into(),
+            })
+            .collect::<Vec<_>>()
+            .into();
+        let documents = documents.reranked(&[
+            RerankResult {
+                index: 2,
+                relevance_score: 0.5,
+            },
+            RerankResult {
+                index: 0,
+                relevance_score: 0.25,
+            },
+            // out of range
+            RerankResult {
+                index: 5,
+                relevance_score: 0.1,
+            },
+        ]);
+        assert_eq!(documents.len(), 2);
+        assert_eq!(documents[0].content, "Test document 2.");
+        assert_eq!(documents[0].metadata["relevance_score"], 0.5);
+        assert_eq!(documents[1].content, "Test document 0.");
+        assert_eq!(documents[1].metadata["relevance_score"], 0.25);
+    }
+
     #[test]
     fn test_content_part_text_serde_and_from() {
         let part: ContentPart = "hello".to_string().into();
@@ -1,8 +1,9 @@
-use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
 
 use crate::{
-    AgentOutput, BoxError, ContentPart, Document, Documents, FunctionDefinition, Json, Message,
-    Resource,
+    AgentOutput, BoxError, ContentPart, Document, Documents, FunctionDefinition,
+    HeuristicTokenizer, Json, Message, Resource, Tokenizer, Usage,
 };
 
 /// Provides LLM completion capabilities for agents.
@@ -13,10 +14,48 @@ pub trait CompletionFeatures: Sized {
         req: CompletionRequest,
         resources: Vec<Resource>,
     ) -> impl Future<Output = Result<AgentOutput, BoxError>> + Send;
+
+    /// Returns the tokenizer of the completion model to budget prompts.
+    /// The default implementation returns a [`HeuristicTokenizer`].
+    fn tokenizer(&self) -> Arc<dyn Tokenizer> {
+        Arc::new(HeuristicTokenizer)
+    }
+}
+
+/// An incremental piece of a streaming completion.
+///
+/// Providers emit text, reasoning and tool call deltas as they arrive, and always finish
+/// with an [`CompletionChunk::Output`] carrying the same [`AgentOutput`] that a non-streaming
+/// completion would have returned.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all_fields = "camelCase")]
+pub enum CompletionChunk {
+    /// A piece of the assistant's text output.
+    Text { text: String },
+
+    /// A piece of the model's reasoning (thinking) output.
+    Reasoning { text: String },
+
+    /// A piece of a tool call. The `index` identifies the tool call within the model turn,
+    /// `call_id` and `name` are usually only present in the first delta of a tool call,
+    /// and `args` is a fragment of the JSON encoded arguments.
+    ToolCall {
+        index: usize,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        call_id: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+        #[serde(default)]
+        args: String,
+    },
+
+    /// The accumulated output of a completion step.
+    Output(AgentOutput),
 }
 
 /// Represents a general completion request that can be sent to a completion model provider.
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
 pub struct CompletionRequest {
     /// The system instructions to be sent to the completion model provider, as the "system" role.
     pub instructions: String,
@@ -59,6 +98,75 @@ pub struct CompletionRequest {
 
     /// The stop sequence to be sent to the completion model provider.
     pub stop: Option<Vec<String>>,
+
+    /// The model alias to route the request to, e.g. "fast" or "reasoning".
+    /// It is used by a model router, completion model providers ignore it.
+    pub model: Option<String>,
+
+    /// The budget of the completion run of the request, overriding the default budget of the engine.
+    /// Completion model providers ignore it.
+    pub budget: Option<RunBudget>,
+}
+
+/// Limits of a completion run, the run ends with a `failed_reason` once any limit is exceeded.
+///
+/// The steps and tokens limits are checked before each model call,
+/// the duration limit also interrupts the running step.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct RunBudget {
+    /// Maximum number of model calls.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_steps: Option<usize>,
+
+    /// Maximum accumulated input tokens, including tool and agent calls.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_input_tokens: Option<u64>,
+
+    /// Maximum accumulated output tokens, including tool and agent calls.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_output_tokens: Option<u64>,
+
+    /// Maximum wall-clock duration of the run.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_duration: Option<Duration>,
+
+    /// Gives the model one final turn without tools to summarise when the steps
+    /// or tokens limit is exceeded. It does not apply to the duration limit.
+    #[serde(default)]
+    pub final_summary: bool,
+}
+
+impl RunBudget {
+    /// Returns the reason if the budget is exceeded.
+    pub fn exceeded(&self, steps: usize, usage: &Usage, elapsed: Duration) -> Option<String> {
+        if let Some(max) = self.max_steps
+            && steps >= max
+        {
+            return Some(format!("max steps {}", max));
+        }
+        if let Some(max) = self.max_input_tokens
+            && usage.input_tokens >= max
+        {
+            return Some(format!(
+                "max input tokens {}, used {}",
+                max, usage.input_tokens
+            ));
+        }
+        if let Some(max) = self.max_output_tokens
+            && usage.output_tokens >= max
+        {
+            return Some(format!(
+                "max output tokens {}, used {}",
+                max, usage.output_tokens
+            ));
+        }
+        if let Some(max) = self.max_duration
+            && elapsed >= max
+        {
+            return Some(format!("duration {:?}", elapsed));
+        }
+        None
+    }
 }
 
 impl CompletionRequest {
@@ -1,7 +1,8 @@
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 use super::Usage;
-use crate::BoxError;
+use crate::{BoxError, Json};
 
 /// Represents a text embedding with its original text and vector representation.
 #[derive(Debug, Clone, Default, Deserialize, Serialize)]
@@ -13,6 +14,34 @@ pub struct Embedding {
     pub vec: Vec<f32>,
 }
 
+/// A document indexed for vector search.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct VectorDocument {
+    /// The unique identifier of the document in its namespace.
+    pub id: String,
+
+    /// The text to embed and search.
+    pub text: String,
+
+    /// The metadata of the document, scalar values can be used in filters.
+    #[serde(default)]
+    pub metadata: BTreeMap<String, Json>,
+}
+
+/// A hybrid (BM25 + vector) search query.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct VectorQuery {
+    /// The query text.
+    pub query: String,
+
+    /// The maximum number of documents to return.
+    pub n: usize,
+
+    /// The metadata the documents must match, only scalar values are supported.
+    #[serde(default)]
+    pub filter: BTreeMap<String, Json>,
+}
+
 /// Provides text embedding capabilities for agents.
 pub trait EmbeddingFeatures: Sized {
     /// The number of dimensions in the embedding vector.
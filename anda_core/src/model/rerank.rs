@@ -0,0 +1,26 @@
+use serde::{Deserialize, Serialize};
+
+use super::Usage;
+use crate::BoxError;
+
+/// Represents a reranked document with its index in the input documents and relevance score.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+pub struct RerankResult {
+    /// The index of the document in the input documents.
+    pub index: usize,
+
+    /// The relevance score of the document to the query, higher is more relevant.
+    pub relevance_score: f32,
+}
+
+/// Provides document reranking capabilities for agents.
+pub trait RerankFeatures: Sized {
+    /// Reranks the documents by relevance to the query.
+    /// Returns at most `top_n` results ordered by relevance score, the most relevant first.
+    fn rerank(
+        &self,
+        query: &str,
+        documents: impl IntoIterator<Item = String> + Send,
+        top_n: usize,
+    ) -> impl Future<Output = Result<(Vec<RerankResult>, Usage), BoxError>> + Send;
+}
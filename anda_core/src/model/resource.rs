@@ -1,6 +1,6 @@
+use anda_db_schema::{ByteArrayB64, ByteBufB64};
 use candid::Principal;
 use chrono::prelude::*;
-use ic_auth_types::{ByteArrayB64, ByteBufB64};
 use ic_cose_types::cose::sha3_256;
 use serde::Serialize;
 
@@ -39,7 +39,7 @@ pub struct ResourceRef<'a> {
 
     /// The size of the resource in bytes.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub size: Option<usize>,
+    pub size: Option<u64>,
 
     /// The SHA3-256 hash of the resource.
     #[serde(skip_serializing_if = "Option::is_none")]
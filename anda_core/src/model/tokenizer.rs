@@ -0,0 +1,428 @@
+use base64::{Engine, prelude::BASE64_STANDARD};
+use regex::Regex;
+use std::collections::HashMap;
+
+use crate::{BoxError, CompletionRequest, ContentPart, Message};
+
+/// The pre-tokenization pattern of OpenAI's `cl100k_base` encoding (GPT-4, GPT-3.5, embeddings v3),
+/// the trailing `\s+(?!\S)` alternative is emulated by [`BpeTokenizer`].
+pub const CL100K_BASE_PATTERN: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+";
+
+/// The pre-tokenization pattern of OpenAI's `o200k_base` encoding (GPT-4o, GPT-4.1, o-series),
+/// the trailing `\s+(?!\S)` alternative is emulated by [`BpeTokenizer`].
+pub const O200K_BASE_PATTERN: &str = r"[^\r\n\p{L}\p{N}]?[\p{Lu}\p{Lt}\p{Lm}\p{Lo}\p{M}]*[\p{Ll}\p{Lm}\p{Lo}\p{M}]+(?i:'s|'t|'re|'ve|'m|'ll|'d)?|[^\r\n\p{L}\p{N}]?[\p{Lu}\p{Lt}\p{Lm}\p{Lo}\p{M}]+[\p{Ll}\p{Lm}\p{Lo}\p{M}]*(?i:'s|'t|'re|'ve|'m|'ll|'d)?|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n/]*|\s*[\r\n]+|\s+";
+
+/// The tokens added for each message by the chat format.
+pub const MESSAGE_OVERHEAD_TOKENS: usize = 4;
+
+/// The estimated tokens of an inline or file data part, e.g. an image.
+pub const MEDIA_TOKENS: usize = 765;
+
+/// Counts and truncates tokens for a completion model.
+///
+/// Implementations:
+/// - [`BpeTokenizer`]: exact byte pair encoding, compatible with OpenAI's tiktoken encodings;
+/// - [`HeuristicTokenizer`]: an estimate for models without a known encoding.
+pub trait Tokenizer: Send + Sync {
+    /// Returns the number of tokens in the text.
+    fn count_tokens(&self, text: &str) -> usize;
+
+    /// Returns the longest prefix of the text within `max_tokens` tokens.
+    fn truncate<'a>(&self, text: &'a str, max_tokens: usize) -> &'a str;
+
+    /// Returns the number of tokens of a message, including the chat format overhead.
+    fn count_message_tokens(&self, message: &Message) -> usize {
+        MESSAGE_OVERHEAD_TOKENS
+            + self.count_tokens(&message.role)
+            + message
+                .content
+                .iter()
+                .map(|part| self.count_part_tokens(part))
+                .sum::<usize>()
+    }
+
+    /// Returns the number of tokens of a content part.
+    fn count_part_tokens(&self, part: &ContentPart) -> usize {
+        match part {
+            ContentPart::Text { text } | ContentPart::Reasoning { text } => self.count_tokens(text),
+            ContentPart::FileData { .. } | ContentPart::InlineData { .. } => MEDIA_TOKENS,
+            ContentPart::ToolCall { name, args, .. } => {
+                self.count_tokens(name) + self.count_tokens(&args.to_string())
+            }
+            ContentPart::ToolOutput { name, output, .. } => {
+                self.count_tokens(name) + self.count_tokens(&output.to_string())
+            }
+            ContentPart::Action { name, payload, .. } => {
+                self.count_tokens(name) + self.count_tokens(&payload.to_string())
+            }
+            ContentPart::Any(val) => self.count_tokens(&val.to_string()),
+        }
+    }
+
+    /// Returns the number of input tokens of a completion request,
+    /// including the instructions, raw history, chat history, documents, prompt, content and tools.
+    fn count_request_tokens(&self, req: &CompletionRequest) -> usize {
+        let mut tokens = MESSAGE_OVERHEAD_TOKENS; // the reply is primed with an assistant message
+        if !req.instructions.is_empty() {
+            tokens += MESSAGE_OVERHEAD_TOKENS + self.count_tokens(&req.instructions);
+        }
+        tokens += req
+            .raw_history
+            .iter()
+            .map(|msg| MESSAGE_OVERHEAD_TOKENS + self.count_tokens(&msg.to_string()))
+            .sum::<usize>();
+        tokens += req
+            .chat_history
+            .iter()
+            .map(|msg| self.count_message_tokens(msg))
+            .sum::<usize>();
+        if !req.documents.is_empty() || !req.prompt.is_empty() || !req.content.is_empty() {
+            tokens += MESSAGE_OVERHEAD_TOKENS;
+        }
+        if !req.documents.is_empty() {
+            tokens += self.count_tokens(&req.documents.to_string());
+        }
+        tokens += self.count_tokens(&req.prompt);
+        tokens += req
+            .content
+            .iter()
+            .map(|part| self.count_part_tokens(part))
+            .sum::<usize>();
+        tokens += req
+            .tools
+            .iter()
+            .map(|tool| self.count_tokens(&serde_json::to_string(tool).unwrap_or_default()))
+            .sum::<usize>();
+        tokens
+    }
+}
+
+/// Estimates tokens without an encoding: 4 ASCII bytes per token and 1 token per other character.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeuristicTokenizer;
+
+impl Tokenizer for HeuristicTokenizer {
+    fn count_tokens(&self, text: &str) -> usize {
+        let (ascii, others) = text.chars().fold((0usize, 0usize), |(ascii, others), c| {
+            if c.is_ascii() {
+                (ascii + 1, others)
+            } else {
+                (ascii, others + 1)
+            }
+        });
+        ascii.div_ceil(4) + others
+    }
+
+    fn truncate<'a>(&self, text: &'a str, max_tokens: usize) -> &'a str {
+        // costs in quarter tokens
+        let max = max_tokens * 4;
+        let mut cost = 0;
+        for (i, c) in text.char_indices() {
+            cost += if c.is_ascii() { 1 } else { 4 };
+            if cost > max {
+                return &text[..i];
+            }
+        }
+        text
+    }
+}
+
+/// A byte pair encoding tokenizer, compatible with OpenAI's tiktoken encodings.
+///
+/// The mergeable ranks are loaded from a tiktoken file, e.g. `cl100k_base.tiktoken` or
+/// `o200k_base.tiktoken`, special tokens are not supported and are encoded as plain text.
+///
+/// # Example
+/// ```rust,ignore
+/// let data = std::fs::read_to_string("o200k_base.tiktoken")?;
+/// let tokenizer = BpeTokenizer::o200k_base(&data)?;
+/// assert_eq!(tokenizer.count_tokens("hello world"), 2);
+/// ```
+pub struct BpeTokenizer {
+    encoder: HashMap<Vec<u8>, u32>,
+    decoder: HashMap<u32, Vec<u8>>,
+    pattern: Regex,
+}
+
+impl std::fmt::Debug for BpeTokenizer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BpeTokenizer")
+            .field("vocab_size", &self.encoder.len())
+            .field("pattern", &self.pattern.as_str())
+            .finish()
+    }
+}
+
+impl BpeTokenizer {
+    /// Creates a tokenizer with the mergeable ranks and the pre-tokenization pattern.
+    ///
+    /// The pattern must be supported by the `regex` crate. Like tiktoken's `\s+(?!\S)`,
+    /// a whitespace piece followed by a non-whitespace character leaves its last
+    /// character to the next piece. All single bytes must be in the ranks.
+    pub fn new(encoder: HashMap<Vec<u8>, u32>, pattern: &str) -> Result<Self, BoxError> {
+        let pattern = Regex::new(pattern).map_err(|err| format!("BPE tokenizer error: {}", err))?;
+        for b in 0..=255u8 {
+            if !encoder.contains_key([b].as_slice()) {
+                return Err(format!("BPE tokenizer error: missing byte {}", b).into());
+            }
+        }
+        let decoder = encoder.iter().map(|(k, v)| (*v, k.clone())).collect();
+        Ok(Self {
+            encoder,
+            decoder,
+            pattern,
+        })
+    }
+
+    /// Creates a tokenizer from the content of a tiktoken file,
+    /// each line is a base64 encoded token and its rank.
+    pub fn from_tiktoken(data: &str, pattern: &str) -> Result<Self, BoxError> {
+        let mut encoder = HashMap::new();
+        for line in data.lines().filter(|line| !line.is_empty()) {
+            let (token, rank) = line
+                .split_once(' ')
+                .ok_or_else(|| format!("BPE tokenizer error: invalid line {:?}", line))?;
+            let token = BASE64_STANDARD.decode(token).map_err(|err| {
+                format!("BPE tokenizer error: invalid token {:?}, {}", token, err)
+            })?;
+            let rank: u32 = rank
+                .parse()
+                .map_err(|err| format!("BPE tokenizer error: invalid rank {:?}, {}", rank, err))?;
+            encoder.insert(token, rank);
+        }
+        Self::new(encoder, pattern)
+    }
+
+    /// Creates a `cl100k_base` tokenizer from the content of `cl100k_base.tiktoken`.
+    pub fn cl100k_base(data: &str) -> Result<Self, BoxError> {
+        Self::from_tiktoken(data, CL100K_BASE_PATTERN)
+    }
+
+    /// Creates an `o200k_base` tokenizer from the content of `o200k_base.tiktoken`.
+    pub fn o200k_base(data: &str) -> Result<Self, BoxError> {
+        Self::from_tiktoken(data, O200K_BASE_PATTERN)
+    }
+
+    /// Returns the number of tokens in the vocabulary.
+    pub fn vocab_size(&self) -> usize {
+        self.encoder.len()
+    }
+
+    /// Encodes the text into token ids.
+    pub fn encode(&self, text: &str) -> Vec<u32> {
+        let mut tokens = Vec::new();
+        for (start, end) in self.pieces(text) {
+            let piece = &text.as_bytes()[start..end];
+            let bounds = self.merge(piece);
+            tokens.extend(bounds.windows(2).map(|w| self.encoder[&piece[w[0]..w[1]]]));
+        }
+        tokens
+    }
+
+    /// Decodes the token ids into bytes, unknown ids are skipped.
+    pub fn decode(&self, tokens: &[u32]) -> Vec<u8> {
+        tokens
+            .iter()
+            .filter_map(|id| self.decoder.get(id))
+            .flatten()
+            .copied()
+            .collect()
+    }
+
+    /// Splits the text into pieces by the pattern, returns the byte ranges.
+    fn pieces(&self, text: &str) -> Vec<(usize, usize)> {
+        let mut pieces = Vec::new();
+        let mut start = 0;
+        while start < text.len() {
+            let Some(m) = self.pattern.find_at(text, start) else {
+                // the pattern does not cover the rest, takes it as a piece
+                pieces.push((start, text.len()));
+                break;
+            };
+            let mut end = m.end();
+            let piece = m.as_str();
+            // emulates `\s+(?!\S)`
+            if m.start() == start
+                && piece.chars().all(char::is_whitespace)
+                && let Some(last) = piece.chars().last()
+                && piece.len() > last.len_utf8()
+                && text[end..]
+                    .chars()
+                    .next()
+                    .is_some_and(|c| !c.is_whitespace())
+            {
+                end -= last.len_utf8();
+            }
+            if end <= start {
+                end = text.len();
+            }
+            // the unmatched bytes before the match are included in the piece
+            pieces.push((start, end));
+            start = end;
+        }
+        pieces
+    }
+
+    /// Merges the byte pairs of a piece by rank, returns the token boundaries.
+    fn merge(&self, piece: &[u8]) -> Vec<usize> {
+        if piece.len() <= 1 || self.encoder.contains_key(piece) {
+            return vec![0, piece.len()];
+        }
+
+        let rank_of = |parts: &[(usize, u32)], i: usize| -> u32 {
+            if i + 3 < parts.len() {
+                self.encoder
+                    .get(&piece[parts[i].0..parts[i + 3].0])
+                    .copied()
+                    .unwrap_or(u32::MAX)
+            } else {
+                u32::MAX
+            }
+        };
+
+        // parts[i].1 is the rank of merging parts[i] and parts[i + 1]
+        let mut parts: Vec<(usize, u32)> = Vec::with_capacity(piece.len() + 1);
+        for i in 0..piece.len() - 1 {
+            let rank = self
+                .encoder
+                .get(&piece[i..i + 2])
+                .copied()
+                .unwrap_or(u32::MAX);
+            parts.push((i, rank));
+        }
+        parts.push((piece.len() - 1, u32::MAX));
+        parts.push((piece.len(), u32::MAX));
+
+        loop {
+            let mut min = (u32::MAX, 0);
+            for (i, &(_, rank)) in parts[..parts.len() - 1].iter().enumerate() {
+                if rank < min.0 {
+                    min = (rank, i);
+                }
+            }
+            if min.0 == u32::MAX {
+                break;
+            }
+
+            let i = min.1;
+            if i > 0 {
+                parts[i - 1].1 = rank_of(&parts, i - 1);
+            }
+            parts[i].1 = rank_of(&parts, i);
+            parts.remove(i + 1);
+        }
+
+        parts.into_iter().map(|(start, _)| start).collect()
+    }
+}
+
+impl Tokenizer for BpeTokenizer {
+    fn count_tokens(&self, text: &str) -> usize {
+        self.pieces(text)
+            .into_iter()
+            .map(|(start, end)| self.merge(&text.as_bytes()[start..end]).len() - 1)
+            .sum()
+    }
+
+    fn truncate<'a>(&self, text: &'a str, max_tokens: usize) -> &'a str {
+        let mut count = 0;
+        for (start, end) in self.pieces(text) {
+            let bounds = self.merge(&text.as_bytes()[start..end]);
+            if count + bounds.len() - 1 <= max_tokens {
+                count += bounds.len() - 1;
+                continue;
+            }
+
+            // cuts inside the piece, at a char boundary
+            let mut cut = start + bounds[max_tokens - count];
+            while !text.is_char_boundary(cut) {
+                cut -= 1;
+            }
+            return &text[..cut];
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_tokenizer(pattern: &str) -> BpeTokenizer {
+        let mut encoder: HashMap<Vec<u8>, u32> = (0..=255u8).map(|b| (vec![b], b as u32)).collect();
+        for (i, token) in [
+            "he", "ll", "hell", "hello", " w", "or", " wor", " world", "  ",
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            encoder.insert(token.as_bytes().to_vec(), 256 + i as u32);
+        }
+        BpeTokenizer::new(encoder, pattern).unwrap()
+    }
+
+    #[test]
+    fn test_bpe_tokenizer() {
+        let tokenizer = test_tokenizer(CL100K_BASE_PATTERN);
+        assert_eq!(tokenizer.vocab_size(), 265);
+
+        let text = "hello  world";
+        assert_eq!(tokenizer.pieces(text), vec![(0, 5), (5, 6), (6, 12)]);
+        assert_eq!(tokenizer.encode(text), vec![259, 32, 263]);
+        assert_eq!(tokenizer.count_tokens(text), 3);
+        assert_eq!(tokenizer.decode(&tokenizer.encode(text)), text.as_bytes());
+
+        // "help" => "hel" is not a token, so "he" + "l" + "p"
+        assert_eq!(
+            tokenizer.encode("help"),
+            vec![256, b'l' as u32, b'p' as u32]
+        );
+        assert_eq!(tokenizer.count_tokens("hello 世界"), 1 + 7);
+
+        assert_eq!(tokenizer.truncate(text, 0), "");
+        assert_eq!(tokenizer.truncate(text, 1), "hello");
+        assert_eq!(tokenizer.truncate(text, 3), text);
+        // "世" is 3 bytes, can not be cut in the middle
+        assert_eq!(tokenizer.truncate("hello 世界", 3), "hello ");
+        assert_eq!(tokenizer.truncate("hello 世界", 5), "hello 世");
+
+        let tokenizer = test_tokenizer(O200K_BASE_PATTERN);
+        assert_eq!(
+            tokenizer.pieces("Hello  world\n\n"),
+            vec![(0, 5), (5, 6), (6, 12), (12, 14)]
+        );
+        assert_eq!(tokenizer.count_tokens("hello world"), 2);
+    }
+
+    #[test]
+    fn test_from_tiktoken() {
+        let mut data: Vec<String> = (0..=255u8)
+            .map(|b| format!("{} {}", BASE64_STANDARD.encode([b]), b))
+            .collect();
+        data.push(format!("{} 256", BASE64_STANDARD.encode("ab")));
+        let tokenizer = BpeTokenizer::cl100k_base(&data.join("\n")).unwrap();
+        assert_eq!(tokenizer.encode("abc"), vec![256, b'c' as u32]);
+
+        assert!(BpeTokenizer::cl100k_base("YWI= 256").is_err());
+        assert!(BpeTokenizer::cl100k_base("YWI=").is_err());
+    }
+
+    #[test]
+    fn test_heuristic_tokenizer() {
+        let tokenizer = HeuristicTokenizer;
+        assert_eq!(tokenizer.count_tokens(""), 0);
+        assert_eq!(tokenizer.count_tokens("hello world"), 3);
+        assert_eq!(tokenizer.count_tokens("你好"), 2);
+        assert_eq!(tokenizer.count_tokens("hi, 你好"), 3);
+        assert_eq!(tokenizer.truncate("hello world", 2), "hello wo");
+        assert_eq!(tokenizer.truncate("你好世界", 3), "你好世");
+        assert_eq!(tokenizer.truncate("你好", 3), "你好");
+
+        let req = CompletionRequest {
+            instructions: "You are a helpful assistant.".to_string(),
+            prompt: "hello".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(tokenizer.count_request_tokens(&req), 4 + 4 + 7 + 4 + 2);
+    }
+}
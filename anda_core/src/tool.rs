@@ -26,15 +26,62 @@
 //!
 //! These reference implementations share a common feature: they automatically generate the JSON Schema.
 //! required for LLMs Function Calling.
+//!
+//! ## Macros
+//! The [`macro@crate::tool`] attribute and the [`crate::AndaTool`] derive generate the [`Tool`]
+//! implementation from an async function or a struct with a handler method, see
+//! [`FetchWebResourcesTool`](https://github.com/ldclabs/anda/blob/main/anda_engine/src/extension/fetch.rs).
 
-use serde::{Serialize, de::DeserializeOwned};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use std::{collections::BTreeMap, future::Future, marker::PhantomData, sync::Arc};
 
 use crate::{
-    BoxError, BoxPinFut, Function, Json, Resource, ToolOutput, context::BaseContext,
-    model::FunctionDefinition, select_resources, validate_function_name,
+    BoxError, BoxPinFut, Function, Json, Resource, SchemaViolation, ToolOutput,
+    context::BaseContext, model::FunctionDefinition, select_resources, validate_function_name,
+    validate_json_schema,
 };
 
+/// The error of tool arguments that do not match the parameters schema of the tool.
+///
+/// The completion runner returns the violations to the model with the tool call error,
+/// so the model can correct its arguments.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ToolArgsError {
+    /// The name of the tool.
+    pub name: String,
+    /// The violations of the parameters schema.
+    pub violations: Vec<SchemaViolation>,
+}
+
+impl ToolArgsError {
+    /// Validates the arguments against the parameters schema of the tool definition.
+    pub fn validate(definition: &FunctionDefinition, args: &Json) -> Result<(), Self> {
+        let violations = validate_json_schema(&definition.parameters, args);
+        if violations.is_empty() {
+            return Ok(());
+        }
+        Err(Self {
+            name: definition.name.clone(),
+            violations,
+        })
+    }
+}
+
+impl std::fmt::Display for ToolArgsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "tool {}, invalid args: ", self.name)?;
+        for (i, violation) in self.violations.iter().enumerate() {
+            if i > 0 {
+                write!(f, "; ")?;
+            }
+            write!(f, "{}", violation)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ToolArgsError {}
+
 /// Core trait for implementing tools that can be used by the AI Agent system.
 ///
 /// # Type Parameters
@@ -78,6 +125,14 @@ where
         Vec::new()
     }
 
+    /// Returns whether calls to the tool must be approved by a human before execution.
+    /// The completion runner pauses the run and waits for the approval of such calls.
+    /// It should be true for tools with side effects such as transferring tokens.
+    /// By default, it returns false.
+    fn requires_approval(&self) -> bool {
+        false
+    }
+
     /// Selects resources based on the tool's supported tags.
     /// This method filters the provided resources based on the tags that the tool supports.
     fn select_resources(&self, resources: &mut Vec<Resource>) -> Vec<Resource> {
@@ -100,6 +155,7 @@ where
     ///
     /// # Returns
     /// - A future resolving to Result<[`ToolOutput<Output>`], BoxError>
+    ///   Return an [`AndaError`](crate::AndaError) to tell the caller the error code, e.g. invalid input.
     fn call(
         &self,
         ctx: C,
@@ -150,6 +206,8 @@ where
 
     fn supported_resource_tags(&self) -> Vec<String>;
 
+    fn requires_approval(&self) -> bool;
+
     fn init(&self, ctx: C) -> BoxPinFut<Result<(), BoxError>>;
 
     fn call(
@@ -183,6 +241,10 @@ where
         self.0.supported_resource_tags()
     }
 
+    fn requires_approval(&self) -> bool {
+        self.0.requires_approval()
+    }
+
     fn init(&self, ctx: C) -> BoxPinFut<Result<(), BoxError>> {
         let tool = self.0.clone();
         Box::pin(async move { tool.init(ctx).await })
@@ -224,6 +286,14 @@ where
         self.set.contains_key(name)
     }
 
+    /// Checks if calls to the tool must be approved by a human.
+    pub fn requires_approval(&self, name: &str) -> bool {
+        self.set
+            .get(name)
+            .map(|tool| tool.requires_approval())
+            .unwrap_or(false)
+    }
+
     /// Returns the names of all tools in the set
     pub fn names(&self) -> Vec<String> {
         self.set.keys().cloned().collect()
@@ -286,6 +356,17 @@ where
             .collect()
     }
 
+    /// Validates the arguments against the parameters schema of the tool.
+    /// Returns a [`ToolArgsError`] with the violations if the arguments are invalid.
+    pub fn validate_args(&self, name: &str, args: &Json) -> Result<(), BoxError> {
+        let tool = self
+            .set
+            .get(name)
+            .ok_or_else(|| format!("tool {} not found", name))?;
+        ToolArgsError::validate(&tool.definition(), args)?;
+        Ok(())
+    }
+
     /// Extracts resources from the provided list based on the tool's supported tags.
     pub fn select_resources(&self, name: &str, resources: &mut Vec<Resource>) -> Vec<Resource> {
         self.set
@@ -6,12 +6,20 @@ mod agent;
 mod base;
 mod cache;
 mod engine;
+mod hook;
+mod selector;
+mod structured;
 mod web3;
+mod window;
 
 pub use agent::*;
 pub use base::*;
 pub use engine::*;
+pub use hook::*;
+pub use selector::*;
+pub use structured::*;
 pub use web3::*;
+pub use window::*;
 
 /// Mock implementations for testing purposes.
 ///
@@ -3,7 +3,7 @@
 //! This module provides the core implementation of the Agent context ([`AgentCtx`]) which serves as
 //! the primary execution environment for agents in the Anda system. The context provides:
 //!
-//! - Access to AI models for completions and embeddings;
+//! - Access to AI models for completions, embeddings and reranking;
 //! - Tool execution capabilities;
 //! - Agent-to-agent communication;
 //! - Cryptographic operations;
@@ -15,9 +15,11 @@
 //! - [`AgentContext`]: Core agent operations and tool/agent management;
 //! - [`CompletionFeatures`]: AI model completion capabilities;
 //! - [`EmbeddingFeatures`]: Text embedding generation;
+//! - [`RerankFeatures`]: Document reranking;
 //! - [`StateFeatures`]: Context state management;
 //! - [`KeysFeatures`]: Cryptographic key operations;
 //! - [`StoreFeatures`]: Persistent storage operations;
+//! - [`VectorSearchFeatures`]: Hybrid vector search in the namespace of the agent;
 //! - [`CacheFeatures`]: Caching mechanisms;
 //! - [`CanisterCaller`]: Canister interaction capabilities;
 //! - [`HttpFeatures`]: HTTPs communication features.
@@ -26,16 +28,21 @@
 //! agents or tools while maintaining access to the core functionality.
 
 use anda_core::{
-    AgentArgs, AgentContext, AgentInput, AgentOutput, AgentSet, BaseContext, BoxError, CacheExpiry,
-    CacheFeatures, CacheStoreFeatures, CancellationToken, CanisterCaller, CompletionFeatures,
-    CompletionRequest, ContentPart, Embedding, EmbeddingFeatures, FunctionDefinition, HttpFeatures,
-    Json, KeysFeatures, Message, ObjectMeta, Path, PutMode, PutResult, RequestMeta, Resource,
-    StateFeatures, StoreFeatures, ToolCall, ToolInput, ToolOutput, ToolSet, Usage,
+    AgentArgs, AgentContext, AgentInput, AgentOutput, AgentSet, AndaError, BaseContext, BoxError,
+    CacheExpiry, CacheFeatures, CacheStoreFeatures, CancellationToken, CanisterCaller,
+    CompletionChunk, CompletionFeatures, CompletionRequest, ContentPart, Embedding,
+    EmbeddingFeatures, FunctionDefinition, HttpFeatures, Json, KeysFeatures, Message, ObjectMeta,
+    Path, PendingApproval, PutMode, PutResult, RequestMeta, RerankFeatures, RerankResult, Resource,
+    RunBudget, StateFeatures, StoreFeatures, Tokenizer, ToolArgsError, ToolCall, ToolCallError,
+    ToolErrorPolicy, ToolInput, ToolOutput, ToolSet, Usage, VectorDocument, VectorQuery,
+    VectorSearchFeatures, Xid,
 };
 use bytes::Bytes;
 use candid::{CandidType, Principal, utils::ArgumentEncoder};
-use futures_util::Stream;
-use serde::{Serialize, de::DeserializeOwned};
+use ciborium::from_reader;
+use futures_util::{Stream, StreamExt, stream::FuturesUnordered};
+use ic_cose_types::to_cbor_bytes;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use std::{
     future::Future,
     pin::Pin,
@@ -43,9 +50,17 @@ use std::{
     task::{Context, Poll},
     time::Duration,
 };
-
-use super::{base::BaseCtx, engine::RemoteEngines};
-use crate::model::Model;
+use structured_logger::unix_ms;
+use tokio::{sync::mpsc, task::JoinHandle, time::Instant};
+
+use super::{
+    base::BaseCtx,
+    engine::RemoteEngines,
+    hook::{Hook, Hooks},
+    selector::ToolSelector,
+    window::{ContextStrategy, compact_context},
+};
+use crate::{management::CallerAccess, model::Model};
 
 pub static DYNAMIC_REMOTE_ENGINES: &str = "_engines";
 
@@ -60,6 +75,16 @@ pub struct AgentCtx {
     pub(crate) tools: Arc<ToolSet<BaseCtx>>,
     /// Set of available agents that can be invoked.
     pub(crate) agents: Arc<AgentSet<AgentCtx>>,
+    /// Policy for handling failed tool/agent calls in completion runs.
+    pub(crate) tool_error_policy: ToolErrorPolicy,
+    /// Default budget of completion runs.
+    pub(crate) run_budget: RunBudget,
+    /// Optional selector of the tools relevant to the prompt of completion runs.
+    pub(crate) tool_selector: Option<Arc<ToolSelector>>,
+    /// Access of the caller to the agents and tools with access rules, none for managers.
+    pub(crate) access: Option<Arc<CallerAccess>>,
+    /// Hooks of the engine, called by completion runs.
+    pub(crate) hooks: Arc<Hooks>,
 }
 
 impl AgentCtx {
@@ -70,17 +95,28 @@ impl AgentCtx {
     /// * `model` - AI model instance.
     /// * `tools` - Set of available tools.
     /// * `agents` - Set of available agents.
+    /// * `run_budget` - Default budget of completion runs.
+    /// * `tool_selector` - Optional selector of the tools relevant to the prompt.
+    /// * `hooks` - Hooks of the engine.
     pub(crate) fn new(
         base: BaseCtx,
         model: Model,
         tools: Arc<ToolSet<BaseCtx>>,
         agents: Arc<AgentSet<AgentCtx>>,
+        run_budget: RunBudget,
+        tool_selector: Option<Arc<ToolSelector>>,
+        hooks: Arc<Hooks>,
     ) -> Self {
         Self {
             base,
             model,
             tools,
             agents,
+            tool_error_policy: ToolErrorPolicy::default(),
+            run_budget,
+            tool_selector,
+            access: None,
+            hooks,
         }
     }
 
@@ -94,6 +130,11 @@ impl AgentCtx {
             model: self.model.clone(),
             tools: self.tools.clone(),
             agents: self.agents.clone(),
+            tool_error_policy: self.agent_tool_error_policy(agent_name),
+            run_budget: self.run_budget.clone(),
+            tool_selector: self.tool_selector.clone(),
+            access: self.access.clone(),
+            hooks: self.hooks.clone(),
         })
     }
 
@@ -124,9 +165,21 @@ impl AgentCtx {
             model: self.model.clone(),
             tools: self.tools.clone(),
             agents: self.agents.clone(),
+            tool_error_policy: self.agent_tool_error_policy(agent_name),
+            run_budget: self.run_budget.clone(),
+            tool_selector: self.tool_selector.clone(),
+            access: self.access.clone(),
+            hooks: self.hooks.clone(),
         })
     }
 
+    fn agent_tool_error_policy(&self, agent_name: &str) -> ToolErrorPolicy {
+        self.agents
+            .get(&agent_name.to_ascii_lowercase())
+            .map(|agent| agent.tool_error_policy())
+            .unwrap_or_default()
+    }
+
     /// Creates a child base context with caller and meta information.
     ///
     /// # Arguments
@@ -143,14 +196,50 @@ impl AgentCtx {
             .child_with(caller, format!("T:{}", tool_name), meta)
     }
 
+    /// Selects the tools most relevant to the query with the tool selector of the engine,
+    /// see [`ToolSelector::select`]. Returns the tools as is if no tool selector is set.
+    pub async fn select_tools(
+        &self,
+        query: &str,
+        tools: Vec<FunctionDefinition>,
+    ) -> Result<(Vec<FunctionDefinition>, Usage), BoxError> {
+        match &self.tool_selector {
+            Some(selector) => selector.select(&self.model, query, tools).await,
+            None => Ok((tools, Usage::default())),
+        }
+    }
+
+    /// Returns true if the caller of the run can call the tool by the access rules.
+    pub fn allows_tool(&self, name: &str) -> bool {
+        self.access
+            .as_ref()
+            .is_none_or(|access| access.allows_tool(name))
+    }
+
+    /// Returns true if the caller of the run can run the agent by the access rules.
+    pub fn allows_agent(&self, name: &str) -> bool {
+        self.access
+            .as_ref()
+            .is_none_or(|access| access.allows_agent(name))
+    }
+
     /// Creates a completion runner for iterative processing of completion requests.
+    ///
+    /// The run is limited by [`CompletionRequest::budget`], or the engine's default [`RunBudget`] if not set.
     pub fn completion_iter(
         &self,
         req: CompletionRequest,
         resources: Vec<Resource>,
     ) -> CompletionRunner {
+        let budget = req
+            .budget
+            .clone()
+            .unwrap_or_else(|| self.run_budget.clone());
         CompletionRunner {
             ctx: self.clone(),
+            context: req.chat_history.clone(),
+            raw_base: req.raw_history.len(),
+            context_strategy: ContextStrategy::default(),
             req,
             resources,
             chat_history: Vec::new(),
@@ -159,18 +248,144 @@ impl AgentCtx {
             artifacts: Vec::new(),
             done: false,
             step: 0,
+            tool_error_policy: self.tool_error_policy,
+            tool_failures: 0,
+            max_concurrent_calls: DEFAULT_MAX_CONCURRENT_CALLS,
+            budget,
+            started_at: Instant::now(),
+            elapsed: Duration::ZERO,
+            resumed: None,
+            chunks: None,
+            id: Xid::new().to_string(),
+            checkpoint: false,
+            conversation: None,
+        }
+    }
+
+    /// Resumes a completion run paused for human approval of sensitive tool calls.
+    ///
+    /// The paused run can only be resumed once, by the same caller who started it.
+    /// If rejected, the rejection is returned to the model as the output of the sensitive tool calls.
+    ///
+    /// # Arguments
+    /// * `id` - The ID of the paused run, from [`PendingApproval`].
+    /// * `approved` - Whether the sensitive tool calls are approved.
+    /// * `reason` - The reason of the decision.
+    pub async fn resume_completion(
+        &self,
+        id: &str,
+        approved: bool,
+        reason: Option<String>,
+    ) -> Result<CompletionRunner, BoxError> {
+        let state = self
+            .load_run(id)
+            .await
+            .map_err(|_| format!("paused run {} not found", id))?;
+        if &state.caller != self.caller() || state.pending.is_none() {
+            return Err(format!("paused run {} not found", id).into());
+        }
+
+        // 将检查点改名以认领暂停的运行，并发的审批只有一个能认领成功
+        let claimed = claimed_run_path(id);
+        self.store_rename_if_not_exists(&run_path(id), &claimed)
+            .await
+            .map_err(|_| format!("paused run {} not found", id))?;
+        if let Err(err) = self.store_delete(&claimed).await {
+            log::warn!("failed to delete the claimed run {}: {}", id, err);
+        }
+
+        let approval = Approval { approved, reason };
+        Ok(CompletionRunner::restore(
+            self.clone(),
+            state,
+            Some(approval),
+        ))
+    }
+
+    /// Lists the checkpointed completion runs of the agent,
+    /// including runs interrupted by an engine restart and runs paused for approval.
+    pub async fn list_runs(&self) -> Result<Vec<RunnerState>, BoxError> {
+        let prefix = format!("{}_", RUNS_PATH);
+        let metas = self.store_list(None, &Path::from(RUNS_PATH)).await?;
+        let mut runs = Vec::new();
+        for meta in metas {
+            if let Some(id) = meta
+                .location
+                .filename()
+                .and_then(|name| name.strip_prefix(&prefix))
+            {
+                runs.push(self.load_run(id).await?);
+            }
         }
+        Ok(runs)
+    }
+
+    /// Resumes an interrupted completion run from its checkpoint, e.g. after the engine restarted.
+    ///
+    /// The run continues with the caller who started it, from the beginning of the interrupted step.
+    /// Runs paused for approval should be resumed by [`AgentCtx::resume_completion`].
+    pub async fn resume_run(&self, id: &str) -> Result<CompletionRunner, BoxError> {
+        let state = self
+            .load_run(id)
+            .await
+            .map_err(|_| format!("run {} not found", id))?;
+        if state.pending.is_some() {
+            return Err(format!("run {} is waiting for approval", id).into());
+        }
+
+        let ctx = Self {
+            base: self.base.child_with(
+                state.caller,
+                self.base.path.to_string(),
+                self.base.meta.clone(),
+            )?,
+            ..self.clone()
+        };
+        Ok(CompletionRunner::restore(ctx, state, None))
+    }
+
+    /// Deletes the checkpoint of a completion run.
+    pub async fn delete_run(&self, id: &str) -> Result<(), BoxError> {
+        self.store_delete(&run_path(id)).await
+    }
+
+    async fn load_run(&self, id: &str) -> Result<RunnerState, BoxError> {
+        let (data, _) = self.store_get(&run_path(id)).await?;
+        let state: RunnerState = from_reader(&data[..])?;
+        Ok(state)
     }
 
     /// Creates a completion stream for processing of completion requests.
+    ///
+    /// The stream yields the output of each step, tool/agent calls are handled
+    /// automatically between steps, the last output is the final result.
+    /// Use [`AgentCtx::completion_chunk_stream`] to receive the deltas generated by the model.
     pub fn completion_stream(
         &self,
         req: CompletionRequest,
         resources: Vec<Resource>,
     ) -> CompletionStream {
-        CompletionStream {
-            runner: self.completion_iter(req, resources),
-        }
+        let (tx, rx) = mpsc::unbounded_channel();
+        let handle = spawn_runner(self.completion_iter(req, resources), tx);
+        CompletionStream { rx, handle }
+    }
+
+    /// Creates a stream of completion chunks for processing of completion requests.
+    ///
+    /// The stream yields text, reasoning and tool call deltas as they are generated by the model,
+    /// and a [`CompletionChunk::Output`] at the end of each step. Tool/agent calls are handled
+    /// automatically between steps, the last output is the final result.
+    pub fn completion_chunk_stream(
+        &self,
+        req: CompletionRequest,
+        resources: Vec<Resource>,
+    ) -> CompletionChunkStream {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let runner = self
+            .completion_iter(req, resources)
+            .with_chunk_sender(tx.clone());
+        let handle = spawn_runner(runner, tx);
+        CompletionChunkStream { rx, handle }
     }
 }
 
@@ -185,7 +400,9 @@ impl AgentContext for AgentCtx {
     /// # Returns
     /// Vector of function definitions for the requested tools.
     fn tool_definitions(&self, names: Option<&[&str]>) -> Vec<FunctionDefinition> {
-        self.tools.definitions(names)
+        let mut defs = self.tools.definitions(names);
+        defs.retain(|d| self.allows_tool(&d.name));
+        defs
     }
 
     /// Retrieves definitions for available tools in the remote engines.
@@ -257,7 +474,8 @@ impl AgentContext for AgentCtx {
         names: Option<&[&str]>,
         with_prefix: bool,
     ) -> Vec<FunctionDefinition> {
-        let res = self.agents.definitions(names);
+        let mut res = self.agents.definitions(names);
+        res.retain(|d| self.allows_agent(&d.name));
         if with_prefix {
             res.into_iter()
                 .map(|mut d| {
@@ -342,6 +560,14 @@ impl AgentContext for AgentCtx {
         mut input: ToolInput<Json>,
     ) -> Result<(ToolOutput<Json>, Option<Principal>), BoxError> {
         if !input.name.starts_with("RT_") {
+            if !self.allows_tool(&input.name) {
+                return Err(AndaError::permission_denied(format!(
+                    "caller does not have permission to call tool {}",
+                    input.name
+                ))
+                .into());
+            }
+            self.tools.validate_args(&input.name, &input.args)?;
             let ctx = self.child_base(&input.name)?;
             let tool = self.tools.get(&input.name).expect("tool not found");
             return tool
@@ -352,6 +578,9 @@ impl AgentContext for AgentCtx {
 
         // find registered remote tool and call it
         if let Some((id, endpoint, tool_name)) = self.base.remote.get_tool_endpoint(&input.name) {
+            if let Some(definition) = self.base.remote.get_tool_definition(&input.name) {
+                ToolArgsError::validate(definition, &input.args)?;
+            }
             input.name = tool_name;
             input.meta = Some(self.base.self_meta(id));
             return self
@@ -367,6 +596,9 @@ impl AgentContext for AgentCtx {
             .await
             && let Some((id, endpoint, tool_name)) = engines.get_tool_endpoint(&input.name)
         {
+            if let Some(definition) = engines.get_tool_definition(&input.name) {
+                ToolArgsError::validate(definition, &input.args)?;
+            }
             input.name = tool_name;
             input.meta = Some(self.base.self_meta(id));
             return self
@@ -393,6 +625,13 @@ impl AgentContext for AgentCtx {
         if !input.name.starts_with("RA_") {
             let name = input.name.strip_prefix("LA_").unwrap_or(&input.name);
             let name = name.to_ascii_lowercase();
+            if !self.allows_agent(&name) {
+                return Err(AndaError::permission_denied(format!(
+                    "caller does not have permission to run agent {}",
+                    name
+                ))
+                .into());
+            }
             let ctx = self.child(&name)?;
             let agent = self.agents.get(&name).expect("agent not found");
             return agent
@@ -473,7 +712,7 @@ impl CompletionFeatures for AgentCtx {
     /// # Process Flow
     /// 1. Makes initial completion request to the model;
     /// 2. If tool calls are returned:
-    ///    - Executes each tool call;
+    ///    - Executes the tool calls concurrently, results keep the original order;
     ///    - Adds tool results to the chat history;
     ///    - Repeats the completion with updated history;
     /// 3. Returns final result when no more tool calls need processing.
@@ -496,6 +735,11 @@ impl CompletionFeatures for AgentCtx {
         // 理论上一定有输出
         Ok(last.expect("completion runner returned no output"))
     }
+
+    /// Returns the tokenizer of the model, see [`Model::tokenizer`].
+    fn tokenizer(&self) -> Arc<dyn Tokenizer> {
+        self.model.tokenizer()
+    }
 }
 
 impl EmbeddingFeatures for AgentCtx {
@@ -530,6 +774,26 @@ impl EmbeddingFeatures for AgentCtx {
     }
 }
 
+impl RerankFeatures for AgentCtx {
+    /// Reranks documents by relevance to the query.
+    ///
+    /// # Arguments
+    /// * `query` - The query to rank the documents against;
+    /// * `documents` - Collection of document texts to rerank;
+    /// * `top_n` - Maximum number of results to return.
+    ///
+    /// # Returns
+    /// Rerank results ordered by relevance, the most relevant first.
+    async fn rerank(
+        &self,
+        query: &str,
+        documents: impl IntoIterator<Item = String> + Send,
+        top_n: usize,
+    ) -> Result<(Vec<RerankResult>, Usage), BoxError> {
+        self.model.rerank(query, documents, top_n).await
+    }
+}
+
 impl BaseContext for AgentCtx {
     /// Executes a remote tool call via HTTP RPC.
     ///
@@ -730,6 +994,35 @@ impl StoreFeatures for AgentCtx {
     }
 }
 
+impl VectorSearchFeatures for AgentCtx {
+    /// Performs a semantic search in the namespace of the agent,
+    /// returns the top n documents as JSON strings.
+    async fn top_n(&self, query: &str, n: usize) -> Result<Vec<String>, BoxError> {
+        self.base.top_n(query, n).await
+    }
+
+    /// Performs a semantic search in the namespace of the agent,
+    /// returns the IDs of the top n documents.
+    async fn top_n_ids(&self, query: &str, n: usize) -> Result<Vec<String>, BoxError> {
+        self.base.top_n_ids(query, n).await
+    }
+
+    /// Performs a hybrid search with metadata filters in the namespace of the agent.
+    async fn vector_search(&self, query: VectorQuery) -> Result<Vec<VectorDocument>, BoxError> {
+        self.base.vector_search(query).await
+    }
+
+    /// Embeds and indexes the documents in the namespace of the agent.
+    async fn vector_upsert(&self, docs: Vec<VectorDocument>) -> Result<(), BoxError> {
+        self.base.vector_upsert(docs).await
+    }
+
+    /// Deletes the documents by IDs in the namespace of the agent.
+    async fn vector_delete(&self, ids: Vec<String>) -> Result<usize, BoxError> {
+        self.base.vector_delete(ids).await
+    }
+}
+
 impl CacheFeatures for AgentCtx {
     /// Checks if a key exists in the cache.
     fn cache_contains(&self, key: &str) -> bool {
@@ -880,6 +1173,92 @@ impl HttpFeatures for AgentCtx {
     }
 }
 
+async fn sleep_until_deadline(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// The store path prefix of checkpointed and paused completion runs.
+pub static RUNS_PATH: &str = "runs";
+
+fn run_path(id: &str) -> Path {
+    Path::from(format!("{}_{}", RUNS_PATH, id))
+}
+
+/// The path of a paused run claimed by an approval, it is not listed as a run.
+fn claimed_run_path(id: &str) -> Path {
+    Path::from(format!("claimed_{}_{}", RUNS_PATH, id))
+}
+
+/// The state of a checkpointed or paused completion run, saved in the agent's store.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RunnerState {
+    /// The ID of the run.
+    pub id: String,
+    /// The caller who started the run.
+    pub caller: Principal,
+    /// The conversation of the run, if any.
+    pub conversation: Option<u64>,
+    pub req: CompletionRequest,
+    pub resources: Vec<Resource>,
+    pub chat_history: Vec<Message>,
+    pub tool_calls: Vec<ToolCall>,
+    pub usage: Usage,
+    pub artifacts: Vec<Resource>,
+    pub step: usize,
+    pub tool_error_policy: ToolErrorPolicy,
+    pub tool_failures: usize,
+    pub max_concurrent_calls: usize,
+    pub budget: RunBudget,
+    /// The conversation sent to the model in the provider-neutral form,
+    /// it mirrors `req.raw_history[raw_base..]` plus `req.chat_history`.
+    #[serde(default)]
+    pub context: Vec<Message>,
+    /// The length of the raw history provided by the caller.
+    #[serde(default)]
+    pub raw_base: usize,
+    #[serde(default)]
+    pub context_strategy: ContextStrategy,
+    /// Whether the run is checkpointed after each step.
+    pub checkpoint: bool,
+    /// The run time in milliseconds before the state was saved,
+    /// it counts toward the duration limit of the budget when the run is resumed.
+    #[serde(default)]
+    pub elapsed_ms: u64,
+    /// The model output with the tool calls waiting for approval, if the run is paused.
+    pub pending: Option<AgentOutput>,
+    /// The unix timestamp in milliseconds when the state was saved.
+    pub updated_at: u64,
+}
+
+/// The human decision for the sensitive tool calls of a paused run.
+struct Approval {
+    approved: bool,
+    reason: Option<String>,
+}
+
+/// The default maximum number of concurrent tool/agent calls in a completion step.
+pub const DEFAULT_MAX_CONCURRENT_CALLS: usize = 8;
+
+/// The part of the context window reserved for the output, `1 / ratio`,
+/// if the request does not set `max_output_tokens`.
+pub const CONTEXT_OUTPUT_RESERVE_RATIO: usize = 8;
+
+/// Input of a tool or agent call requested by the model.
+enum CallInput {
+    Tool(ToolInput<Json>),
+    Agent(AgentInput),
+    Rejected(ToolCallError),
+}
+
+/// Result of a tool or agent call, with the usage of the call.
+type CallResult = (
+    Result<(ToolOutput<Json>, Option<Principal>), BoxError>,
+    Usage,
+);
+
 /// A iteration style executor for completion.
 pub struct CompletionRunner {
     ctx: AgentCtx,
@@ -891,9 +1270,130 @@ pub struct CompletionRunner {
     artifacts: Vec<Resource>,
     done: bool,
     step: usize,
+    tool_error_policy: ToolErrorPolicy,
+    tool_failures: usize,
+    max_concurrent_calls: usize,
+    budget: RunBudget,
+    context: Vec<Message>,
+    raw_base: usize,
+    context_strategy: ContextStrategy,
+    started_at: Instant,
+    elapsed: Duration,
+    resumed: Option<(AgentOutput, Approval)>,
+    chunks: Option<mpsc::UnboundedSender<Result<CompletionChunk, BoxError>>>,
+    id: String,
+    checkpoint: bool,
+    conversation: Option<u64>,
 }
 
 impl CompletionRunner {
+    fn restore(ctx: AgentCtx, state: RunnerState, approval: Option<Approval>) -> Self {
+        Self {
+            ctx,
+            req: state.req,
+            resources: state.resources,
+            chat_history: state.chat_history,
+            tool_calls: state.tool_calls,
+            usage: state.usage,
+            artifacts: state.artifacts,
+            done: false,
+            step: state.step,
+            tool_error_policy: state.tool_error_policy,
+            tool_failures: state.tool_failures,
+            max_concurrent_calls: state.max_concurrent_calls,
+            budget: state.budget,
+            context: state.context,
+            raw_base: state.raw_base,
+            context_strategy: state.context_strategy,
+            started_at: Instant::now(),
+            elapsed: Duration::from_millis(state.elapsed_ms),
+            resumed: state.pending.zip(approval),
+            chunks: None,
+            id: state.id,
+            checkpoint: state.checkpoint,
+            conversation: state.conversation,
+        }
+    }
+
+    /// Sets the policy for handling failed tool/agent calls,
+    /// defaults to the [`Agent::tool_error_policy`](anda_core::Agent::tool_error_policy) of the running agent.
+    pub fn with_tool_error_policy(mut self, policy: ToolErrorPolicy) -> Self {
+        self.tool_error_policy = policy;
+        self
+    }
+
+    /// Sets the budget of the run, defaults to the request's budget or the engine's [`RunBudget`].
+    pub fn with_budget(mut self, budget: RunBudget) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    /// Sets the strategy to compact the conversation when it nears the context window of the model,
+    /// defaults to [`ContextStrategy::DropOldest`]. It applies only if the context window is known,
+    /// see [`Model::context_window`].
+    pub fn with_context_strategy(mut self, strategy: ContextStrategy) -> Self {
+        self.context_strategy = strategy;
+        self
+    }
+
+    /// Sets the maximum number of tool/agent calls from a single model turn that run concurrently,
+    /// defaults to [`DEFAULT_MAX_CONCURRENT_CALLS`]. Set it to 1 to run the calls sequentially.
+    ///
+    /// No more calls are dispatched once a call fails the run by the tool error policy,
+    /// the calls already running are completed and recorded.
+    pub fn with_max_concurrent_calls(mut self, max: usize) -> Self {
+        self.max_concurrent_calls = max.max(1);
+        self
+    }
+
+    /// Streams the model output of each step to the given sender.
+    ///
+    /// Text, reasoning and tool call deltas are sent as they arrive,
+    /// the step output is still returned by [`CompletionRunner::next`].
+    pub fn with_chunk_sender(
+        mut self,
+        sender: mpsc::UnboundedSender<Result<CompletionChunk, BoxError>>,
+    ) -> Self {
+        self.chunks = Some(sender);
+        self
+    }
+
+    /// Checkpoints the runner state to the agent's store after each step,
+    /// so that the run can be listed and resumed by [`AgentCtx::resume_run`] after the engine restarted.
+    ///
+    /// The checkpoint is deleted when the run finishes, but kept when it is cancelled or fails with an error.
+    pub fn with_checkpoint(mut self) -> Self {
+        self.checkpoint = true;
+        self
+    }
+
+    /// Associates the run with a conversation, it is saved in the checkpoint and set on the final output.
+    pub fn with_conversation(mut self, conversation: u64) -> Self {
+        self.conversation = Some(conversation);
+        self
+    }
+
+    /// Returns the ID of the run.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Returns the context of the run.
+    pub fn ctx(&self) -> &AgentCtx {
+        &self.ctx
+    }
+
+    /// Sets the access of the caller to the agents and tools with access rules.
+    pub(crate) fn set_access(&mut self, access: Option<Arc<CallerAccess>>) {
+        self.ctx.access = access;
+    }
+
+    /// Deletes the checkpoint of the run, e.g. when the caller gives up the run before it finishes.
+    pub async fn discard(&mut self) -> Result<(), BoxError> {
+        self.done = true;
+        self.ctx.delete_run(&self.id).await
+    }
+
     /// Returns whether the completion has finished.
     pub fn is_done(&self) -> bool {
         self.done
@@ -904,6 +1404,16 @@ impl CompletionRunner {
         self.step
     }
 
+    /// Returns the accumulated usage of the run.
+    pub fn usage(&self) -> &Usage {
+        &self.usage
+    }
+
+    /// Returns the run time, including the time before the run was paused or interrupted.
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed + self.started_at.elapsed()
+    }
+
     /// Execute the next step.
     /// - Calls the model completion.
     /// - Automatically handles tool/agent calls and writes the results back to the conversation history.
@@ -915,132 +1425,174 @@ impl CompletionRunner {
             return Ok(None);
         }
 
+        // 首轮前保存检查点；恢复审批的运行在执行工具调用前不保存，避免重复审批
+        if self.checkpoint && self.step == 0 && self.resumed.is_none() {
+            self.save_state(None, PutMode::Overwrite).await?;
+        }
+
         let token = self.ctx.base.cancellation_token();
-        tokio::select! {
+        let deadline = self
+            .budget
+            .max_duration
+            .map(|d| self.started_at + d.saturating_sub(self.elapsed));
+        let res = tokio::select! {
             _ = token.cancelled() => {
+                // 取消（如引擎关闭）时保留检查点，以便重启后恢复
                 let output = AgentOutput {
                     failed_reason: Some("operation cancelled".to_string()),
                     ..Default::default()
                 };
+                return Ok(Some(self.final_output(output)));
+            }
+            _ = sleep_until_deadline(deadline) => {
+                // 超时中断当前轮，对话历史可能不完整，不再进行总结
+                let output = AgentOutput {
+                    failed_reason: Some(format!(
+                        "run budget exceeded: duration {:?}",
+                        self.elapsed()
+                    )),
+                    ..Default::default()
+                };
                 Ok(Some(self.final_output(output)))
             }
             res = self.inner_next() => res
+        };
+
+        if self.checkpoint
+            && let Ok(Some(output)) = &res
+        {
+            if !self.done {
+                self.save_state(None, PutMode::Overwrite).await?;
+            } else if output.pending_approval.is_none() {
+                self.ctx.delete_run(&self.id).await?;
+            }
         }
+        res
     }
 
     async fn inner_next(&mut self) -> Result<Option<AgentOutput>, BoxError> {
+        if let Some((output, approval)) = self.resumed.take() {
+            return self.call_tools(output, Some(approval)).await;
+        }
+
+        if let Some(reason) = self.budget.exceeded(self.step, &self.usage, self.elapsed()) {
+            return self.budget_exceeded(reason).await.map(Some);
+        }
+
+        if self.step == 0 {
+            self.select_tools().await;
+        }
+        self.fit_context().await?;
         self.step += 1;
-        let mut output = self.ctx.model.completion(self.req.clone()).await?;
+        let mut output = self.model_completion(self.req.clone()).await?;
         self.usage.accumulate(&output.usage);
         // 累计所有原始对话历史（包含初始的 req.raw_history 和 req.chat_history）
         self.req.raw_history.append(&mut output.raw_history);
+        // 同步中立格式的上下文，用于上下文窗口管理
+        self.context.extend(output.chat_history.iter().cloned());
         // 累计所有对话历史（不包含初始的 req.chat_history）
         self.chat_history.append(&mut output.chat_history);
 
-        // 自动执行工具/代理调用
-        let mut tool_calls_continue: Vec<ContentPart> = Vec::new();
-        for tool in output.tool_calls.iter_mut() {
-            if self.ctx.cancellation_token().is_cancelled() {
-                return Err("operation cancelled".into());
-            }
+        // 需要人工审批的工具调用，暂停并保存运行状态
+        let sensitive: Vec<ToolCall> = output
+            .tool_calls
+            .iter()
+            .filter(|tool| self.ctx.tools.requires_approval(&tool.name))
+            .cloned()
+            .collect();
+        if !sensitive.is_empty() {
+            return self.pause(output, sensitive).await.map(Some);
+        }
 
-            if self.ctx.tools.contains(&tool.name) || tool.name.starts_with("RT_") {
-                match self
-                    .ctx
-                    .tool_call(ToolInput {
-                        name: tool.name.clone(),
-                        args: tool.args.clone(),
-                        resources: self
-                            .ctx
-                            .select_tool_resources(&tool.name, &mut self.resources)
-                            .await,
-                        meta: None,
-                    })
-                    .await
-                {
-                    Ok((mut res, remote_id)) => {
-                        self.usage.accumulate(&res.usage);
-
-                        // We can not ignore some tool calls.
-                        // GPT-5: An assistant message with 'tool_calls' must be followed by tool messages responding to each 'tool_call_id'.
-                        tool_calls_continue.push(ContentPart::ToolOutput {
-                            name: tool.name.clone(),
-                            output: res.output.clone(),
-                            call_id: tool.call_id.clone(),
-                            remote_id,
-                        });
+        self.call_tools(output, None).await
+    }
 
-                        self.artifacts.append(&mut res.artifacts);
-                        tool.remote_id = remote_id;
-                        tool.result = Some(res);
-                    }
-                    Err(err) => {
-                        output.failed_reason = Some(err.to_string());
-                        return Ok(Some(self.final_output(output)));
-                    }
-                }
-            } else if self.ctx.agents.contains(&tool.name)
-                || tool.name.starts_with("LA_")
-                || tool.name.starts_with("RA_")
+    /// Executes the tool/agent calls of the model output and prepares the next request.
+    /// `approval` is the human decision for the sensitive tool calls of a resumed run.
+    async fn call_tools(
+        &mut self,
+        mut output: AgentOutput,
+        approval: Option<Approval>,
+    ) -> Result<Option<AgentOutput>, BoxError> {
+        // 自动执行工具/代理调用
+        let mut failed_reason: Option<String> = None;
+        let mut calls: Vec<(usize, Result<CallInput, BoxError>)> = Vec::new();
+        for (i, tool) in output.tool_calls.iter().enumerate() {
+            if let Some(approval) = &approval
+                && !approval.approved
+                && self.ctx.tools.requires_approval(&tool.name)
             {
-                // 代理调用
-                let args: AgentArgs = match serde_json::from_value(tool.args.clone()) {
-                    Ok(args) => args,
-                    Err(err) => {
-                        output.failed_reason = Some(format!(
-                            "failed to parse agent args {:?}: {}",
-                            tool.args, err
-                        ));
-                        return Ok(Some(self.final_output(output)));
-                    }
+                let error = ToolCallError {
+                    name: tool.name.clone(),
+                    message: match &approval.reason {
+                        Some(reason) => format!("rejected by the user: {}", reason),
+                        None => "rejected by the user".to_string(),
+                    },
+                    violations: Vec::new(),
                 };
-                match self
-                    .ctx
-                    .agent_run(AgentInput {
-                        name: tool.name.clone(),
-                        prompt: args.prompt,
-                        resources: self
-                            .ctx
-                            .agents
-                            .select_resources(&tool.name, &mut self.resources),
-                        meta: None,
-                    })
-                    .await
-                {
-                    Ok((mut res, remote_id)) => {
-                        self.usage.accumulate(&res.usage);
-                        if res.failed_reason.is_some() {
-                            output.failed_reason = res.failed_reason;
-                            return Ok(Some(self.final_output(output)));
-                        }
+                calls.push((i, Ok(CallInput::Rejected(error))));
+                continue;
+            }
 
-                        // TODO: remote agent id
-                        tool_calls_continue.push(ContentPart::ToolOutput {
-                            name: tool.name.clone(),
-                            output: res.content.clone().into(),
-                            call_id: tool.call_id.clone(),
-                            remote_id,
-                        });
+            // 请求中声明但未注册的工具由调用方处理，如结构化输出的提交工具
+            if self.is_caller_tool(&tool.name) {
+                continue;
+            }
+            // 未知工具名也需要回复，按错误策略处理
+            calls.push((i, self.prepare_call(tool).await));
+        }
 
-                        self.artifacts.append(&mut res.artifacts);
-                        tool.result = Some(ToolOutput {
-                            output: res.content.clone().into(),
-                            artifacts: vec![],
-                            usage: res.usage,
-                        });
+        // 并发执行同一轮的工具/代理调用，出现终止运行的失败或取消后不再派发新的调用，
+        // 已派发的调用执行完成并记录结果和用量
+        let mut outputs: Vec<Option<ContentPart>> = vec![None; output.tool_calls.len()];
+        let mut calls = calls.into_iter();
+        let mut running = FuturesUnordered::new();
+        loop {
+            while failed_reason.is_none() && running.len() < self.max_concurrent_calls {
+                if self.ctx.cancellation_token().is_cancelled() {
+                    failed_reason = Some("operation cancelled".to_string());
+                    break;
+                }
+
+                let Some((i, input)) = calls.next() else {
+                    break;
+                };
+                match input {
+                    Ok(input) => {
+                        let ctx = self.ctx.clone();
+                        running.push(async move { (i, Self::run_call(&ctx, input).await) });
                     }
                     Err(err) => {
-                        output.failed_reason = Some(err.to_string());
-                        return Ok(Some(self.final_output(output)));
+                        let res = (Err(err), Usage::default());
+                        match self.record_call(&mut output.tool_calls[i], res) {
+                            Ok(part) => outputs[i] = Some(part),
+                            Err(reason) => failed_reason = Some(reason),
+                        }
                     }
                 }
             }
-            // 未知工具名，忽略
+
+            let Some((i, res)) = running.next().await else {
+                break;
+            };
+            match self.record_call(&mut output.tool_calls[i], res) {
+                Ok(part) => outputs[i] = Some(part),
+                Err(reason) => {
+                    failed_reason.get_or_insert(reason);
+                }
+            }
         }
+        // 结果保持原有顺序
+        let mut tool_calls_continue: Vec<ContentPart> = outputs.into_iter().flatten().collect();
 
         // 累计当前轮的 tool_calls
         self.tool_calls.append(&mut output.tool_calls);
 
+        if failed_reason.is_some() {
+            output.failed_reason = failed_reason;
+            return Ok(Some(self.final_output(output)));
+        }
+
         // 若无需继续，返回最终结果并结束
         if tool_calls_continue.is_empty() {
             return Ok(Some(self.final_output(output)));
@@ -1066,6 +1618,388 @@ impl CompletionRunner {
         Ok(Some(output))
     }
 
+    /// Records the result and usage of a tool/agent call.
+    /// Returns the tool output for the next request, or the reason to end the run by the tool error policy.
+    fn record_call(
+        &mut self,
+        tool: &mut ToolCall,
+        (res, usage): CallResult,
+    ) -> Result<ContentPart, String> {
+        self.usage.accumulate(&usage);
+        match res {
+            Ok((mut res, remote_id)) => {
+                self.tool_failures = 0;
+                // We can not ignore some tool calls.
+                // GPT-5: An assistant message with 'tool_calls' must be followed by tool messages responding to each 'tool_call_id'.
+                let part = ContentPart::ToolOutput {
+                    name: tool.name.clone(),
+                    output: res.output.clone(),
+                    call_id: tool.call_id.clone(),
+                    remote_id,
+                };
+
+                self.artifacts.append(&mut res.artifacts);
+                tool.remote_id = remote_id;
+                tool.result = Some(res);
+                Ok(part)
+            }
+            Err(err) => {
+                self.tool_failures += 1;
+                let error = ToolCallError {
+                    name: tool.name.clone(),
+                    message: err.to_string(),
+                    // 参数校验错误附带违规列表，便于模型修正参数
+                    violations: err
+                        .downcast_ref::<ToolArgsError>()
+                        .map(|err| err.violations.clone())
+                        .unwrap_or_default(),
+                };
+
+                let res = match self.tool_error_policy {
+                    ToolErrorPolicy::Feedback {
+                        max_consecutive_failures,
+                    } if self.tool_failures <= max_consecutive_failures => {
+                        // 将错误反馈给模型，由模型决定如何继续
+                        Ok(ContentPart::ToolOutput {
+                            name: tool.name.clone(),
+                            output: error.to_output(),
+                            call_id: tool.call_id.clone(),
+                            remote_id: None,
+                        })
+                    }
+                    ToolErrorPolicy::Feedback { .. } => Err(format!(
+                        "too many consecutive tool failures ({}), last error: {}",
+                        self.tool_failures, error
+                    )),
+                    ToolErrorPolicy::Abort => Err(error.message.clone()),
+                };
+                tool.error = Some(error);
+                res
+            }
+        }
+    }
+
+    /// Compacts the conversation with the context strategy if the request nears the context window.
+    ///
+    /// The compacted conversation replaces the raw history added by the run,
+    /// it is sent as the chat history and converted by the model provider again.
+    async fn fit_context(&mut self) -> Result<(), BoxError> {
+        let Some(limit) = self.ctx.model.context_window() else {
+            return Ok(());
+        };
+
+        let tokenizer = self.ctx.model.tokenizer();
+        // 为输出预留 token
+        let max_input_tokens = limit.saturating_sub(
+            self.req
+                .max_output_tokens
+                .unwrap_or(limit / CONTEXT_OUTPUT_RESERVE_RATIO),
+        );
+        if tokenizer.count_request_tokens(&self.req) <= max_input_tokens {
+            return Ok(());
+        }
+
+        let mut req = self.req.clone();
+        req.raw_history.truncate(self.raw_base);
+        req.chat_history.clear();
+        let budget = max_input_tokens.saturating_sub(tokenizer.count_request_tokens(&req));
+        let (context, usage) = compact_context(
+            &self.ctx.model,
+            &self.context_strategy,
+            self.context.clone(),
+            budget,
+        )
+        .await?;
+        self.usage.accumulate(&usage);
+        log::info!(
+            "compacted the context of run {} from {} to {} messages",
+            self.id,
+            self.context.len(),
+            context.len()
+        );
+
+        self.req.raw_history.truncate(self.raw_base);
+        self.req.chat_history = context.clone();
+        self.context = context;
+        Ok(())
+    }
+
+    /// Narrows the tools of the request to the ones relevant to the prompt before the first step,
+    /// so the tools stay the same in the run. Keeps all tools if the selection fails.
+    async fn select_tools(&mut self) {
+        if self.ctx.tool_selector.is_none() || self.req.tools.is_empty() {
+            return;
+        }
+
+        let query = if self.req.prompt.is_empty() {
+            self.req
+                .content
+                .iter()
+                .filter_map(|part| match part {
+                    ContentPart::Text { text } => Some(text.as_str()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        } else {
+            self.req.prompt.clone()
+        };
+        match self.ctx.select_tools(&query, self.req.tools.clone()).await {
+            Ok((tools, usage)) => {
+                self.usage.accumulate(&usage);
+                self.req.tools = tools;
+            }
+            Err(err) => {
+                log::warn!("failed to select tools for run {}: {}", self.id, err);
+            }
+        }
+    }
+
+    /// Calls the model, streams the deltas to the chunk sender if set.
+    async fn model_completion(&self, req: CompletionRequest) -> Result<AgentOutput, BoxError> {
+        let req = self.ctx.hooks.on_completion_start(&self.ctx, req).await?;
+        match self.stream_completion(req).await {
+            Ok(output) => self.ctx.hooks.on_completion_end(&self.ctx, output).await,
+            Err(err) => {
+                self.ctx
+                    .hooks
+                    .on_error(&self.ctx.base, "completion", &err)
+                    .await;
+                Err(err)
+            }
+        }
+    }
+
+    /// Sends the completion request to the model, streams the chunks if enabled.
+    async fn stream_completion(&self, req: CompletionRequest) -> Result<AgentOutput, BoxError> {
+        let Some(sender) = &self.chunks else {
+            return self.ctx.model.completion(req).await;
+        };
+
+        let mut stream = self.ctx.model.completion_stream(req);
+        while let Some(chunk) = stream.next().await {
+            match chunk? {
+                CompletionChunk::Output(res) => return Ok(res),
+                chunk => {
+                    // the receiver may be dropped, but we still need to finish the step
+                    let _ = sender.send(Ok(chunk));
+                }
+            }
+        }
+        Err("completion stream ended without output".into())
+    }
+
+    /// Ends the run when the budget is exceeded,
+    /// gives the model a final turn without tools to summarise if configured.
+    async fn budget_exceeded(&mut self, reason: String) -> Result<AgentOutput, BoxError> {
+        let failed_reason = Some(format!("run budget exceeded: {}", reason));
+        if !self.budget.final_summary {
+            let output = AgentOutput {
+                failed_reason,
+                ..Default::default()
+            };
+            return Ok(self.final_output(output));
+        }
+
+        self.step += 1;
+        let mut req = self.req.clone();
+        req.tools.clear();
+        req.tool_choice_required = false;
+        req.instructions = format!(
+            "{}\n\nThe run budget is exhausted ({}). Do not call any tools, summarise the progress and answer with the information gathered so far.",
+            req.instructions, reason
+        );
+        let mut output = self.model_completion(req).await?;
+        self.usage.accumulate(&output.usage);
+        self.req.raw_history.append(&mut output.raw_history);
+        // 总结轮不再执行工具调用
+        output.tool_calls.clear();
+        output.failed_reason = failed_reason;
+        Ok(self.final_output(output))
+    }
+
+    /// Returns true if the tool is declared in the request but neither a tool nor an agent
+    /// of the engine, its calls are returned to the caller of the run.
+    fn is_caller_tool(&self, name: &str) -> bool {
+        !self.ctx.tools.contains(name)
+            && !self.ctx.agents.contains(name)
+            && !["RT_", "LA_", "RA_"]
+                .iter()
+                .any(|prefix| name.starts_with(prefix))
+            && self.req.tools.iter().any(|tool| tool.name == name)
+    }
+
+    /// Prepares the input of the tool or agent call requested by the model.
+    /// Returns an error if the name is neither a tool nor an agent.
+    async fn prepare_call(&mut self, tool: &ToolCall) -> Result<CallInput, BoxError> {
+        if self.ctx.tools.contains(&tool.name) || tool.name.starts_with("RT_") {
+            return Ok(CallInput::Tool(ToolInput {
+                name: tool.name.clone(),
+                args: tool.args.clone(),
+                resources: self
+                    .ctx
+                    .select_tool_resources(&tool.name, &mut self.resources)
+                    .await,
+                meta: None,
+            }));
+        }
+
+        if self.ctx.agents.contains(&tool.name)
+            || tool.name.starts_with("LA_")
+            || tool.name.starts_with("RA_")
+        {
+            // 代理调用
+            let args: AgentArgs = match serde_json::from_value(tool.args.clone()) {
+                Ok(args) => args,
+                Err(err) => {
+                    return Err(
+                        format!("failed to parse agent args {:?}: {}", tool.args, err).into(),
+                    );
+                }
+            };
+
+            return Ok(CallInput::Agent(AgentInput {
+                name: tool.name.clone(),
+                prompt: args.prompt,
+                resources: self
+                    .ctx
+                    .agents
+                    .select_resources(&tool.name, &mut self.resources),
+                meta: None,
+            }));
+        }
+
+        Err(format!("tool {} not found", tool.name).into())
+    }
+
+    /// Runs a prepared tool or agent call, returns the result with the usage of the call.
+    async fn run_call(ctx: &AgentCtx, input: CallInput) -> CallResult {
+        match input {
+            CallInput::Rejected(error) => {
+                // 拒绝不计为失败，将原因反馈给模型
+                (
+                    Ok((ToolOutput::new(error.to_output()), None)),
+                    Usage::default(),
+                )
+            }
+            CallInput::Tool(input) => {
+                let name = input.name.clone();
+                match Self::run_tool(ctx, input).await {
+                    Ok((res, remote_id)) => {
+                        let usage = res.usage.clone();
+                        (Ok((res, remote_id)), usage)
+                    }
+                    Err(err) => {
+                        ctx.hooks.on_error(&ctx.base, &name, &err).await;
+                        (Err(err), Usage::default())
+                    }
+                }
+            }
+            CallInput::Agent(input) => {
+                let name = input.name.clone();
+                match Self::run_agent(ctx, input).await {
+                    Ok((res, remote_id)) => {
+                        let usage = res.usage.clone();
+                        if let Some(reason) = res.failed_reason {
+                            return (Err(reason.into()), usage);
+                        }
+
+                        // TODO: remote agent id
+                        let output = ToolOutput {
+                            output: res.content.into(),
+                            artifacts: res.artifacts,
+                            usage: res.usage,
+                        };
+                        (Ok((output, remote_id)), usage)
+                    }
+                    Err(err) => {
+                        ctx.hooks.on_error(&ctx.base, &name, &err).await;
+                        (Err(err), Usage::default())
+                    }
+                }
+            }
+        }
+    }
+
+    /// Calls the tool with the nested tool hooks.
+    async fn run_tool(
+        ctx: &AgentCtx,
+        input: ToolInput<Json>,
+    ) -> Result<(ToolOutput<Json>, Option<Principal>), BoxError> {
+        let input = ctx.hooks.on_nested_tool_start(ctx, input).await?;
+        let name = input.name.clone();
+        let (res, remote_id) = ctx.tool_call(input).await?;
+        let res = ctx.hooks.on_nested_tool_end(ctx, &name, res).await?;
+        Ok((res, remote_id))
+    }
+
+    /// Runs the agent with the nested agent hooks.
+    async fn run_agent(
+        ctx: &AgentCtx,
+        input: AgentInput,
+    ) -> Result<(AgentOutput, Option<Principal>), BoxError> {
+        let input = ctx.hooks.on_nested_agent_start(ctx, input).await?;
+        let name = input.name.clone();
+        let (res, remote_id) = ctx.agent_run(input).await?;
+        let res = ctx.hooks.on_nested_agent_end(ctx, &name, res).await?;
+        Ok((res, remote_id))
+    }
+
+    /// Pauses the run to wait for human approval of the sensitive tool calls,
+    /// the runner state is saved to the store and can be resumed by [`AgentCtx::resume_completion`].
+    async fn pause(
+        &mut self,
+        output: AgentOutput,
+        tool_calls: Vec<ToolCall>,
+    ) -> Result<AgentOutput, BoxError> {
+        let content = output.content.clone();
+        self.save_state(Some(output), PutMode::Overwrite).await?;
+
+        Ok(self.final_output(AgentOutput {
+            content,
+            pending_approval: Some(PendingApproval {
+                id: self.id.clone(),
+                tool_calls,
+            }),
+            ..Default::default()
+        }))
+    }
+
+    /// Saves the runner state to the agent's store.
+    async fn save_state(
+        &self,
+        pending: Option<AgentOutput>,
+        mode: PutMode,
+    ) -> Result<(), BoxError> {
+        let state = RunnerState {
+            id: self.id.clone(),
+            caller: *self.ctx.caller(),
+            conversation: self.conversation,
+            req: self.req.clone(),
+            resources: self.resources.clone(),
+            chat_history: self.chat_history.clone(),
+            tool_calls: self.tool_calls.clone(),
+            usage: self.usage.clone(),
+            artifacts: self.artifacts.clone(),
+            step: self.step,
+            tool_error_policy: self.tool_error_policy,
+            tool_failures: self.tool_failures,
+            max_concurrent_calls: self.max_concurrent_calls,
+            budget: self.budget.clone(),
+            context: self.context.clone(),
+            raw_base: self.raw_base,
+            context_strategy: self.context_strategy.clone(),
+            checkpoint: self.checkpoint,
+            elapsed_ms: self.elapsed().as_millis() as u64,
+            pending,
+            updated_at: unix_ms(),
+        };
+        self.ctx
+            .store_put(&run_path(&self.id), mode, to_cbor_bytes(&state).into())
+            .await?;
+        Ok(())
+    }
+
     fn final_output(&mut self, mut output: AgentOutput) -> AgentOutput {
         self.done = true;
         self.chat_history.append(&mut output.chat_history);
@@ -1073,36 +2007,858 @@ impl CompletionRunner {
         output.tool_calls = std::mem::take(&mut self.tool_calls);
         output.artifacts = std::mem::take(&mut self.artifacts);
         output.usage = std::mem::take(&mut self.usage);
+        if output.conversation.is_none() {
+            output.conversation = self.conversation;
+        }
 
         output
     }
 }
 
+/// Runs the runner to the end in a task, sends the output of each step to the channel.
+fn spawn_runner(
+    mut runner: CompletionRunner,
+    tx: mpsc::UnboundedSender<Result<CompletionChunk, BoxError>>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            match runner.next().await {
+                Ok(Some(output)) => {
+                    if tx.send(Ok(CompletionChunk::Output(output))).is_err() {
+                        return;
+                    }
+                }
+                Ok(None) => return,
+                Err(err) => {
+                    let _ = tx.send(Err(err));
+                    return;
+                }
+            }
+        }
+    })
+}
+
+/// A stream of the step outputs of a completion run, see [`AgentCtx::completion_stream`].
+///
+/// The underlying runner is aborted when the stream is dropped.
 pub struct CompletionStream {
-    runner: CompletionRunner,
+    rx: mpsc::UnboundedReceiver<Result<CompletionChunk, BoxError>>,
+    handle: JoinHandle<()>,
 }
 
 impl Stream for CompletionStream {
     type Item = Result<AgentOutput, BoxError>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let future = self.runner.next();
-        tokio::pin!(future);
-
-        match future.poll(cx) {
-            Poll::Ready(Ok(Some(output))) => Poll::Ready(Some(Ok(output))),
-            Poll::Ready(Ok(None)) => Poll::Ready(None),
-            Poll::Ready(Err(e)) => Poll::Ready(Some(Err(e))),
-            Poll::Pending => Poll::Pending,
+        loop {
+            match self.rx.poll_recv(cx) {
+                Poll::Ready(Some(Ok(CompletionChunk::Output(output)))) => {
+                    return Poll::Ready(Some(Ok(output)));
+                }
+                Poll::Ready(Some(Ok(_))) => continue,
+                Poll::Ready(Some(Err(err))) => return Poll::Ready(Some(Err(err))),
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
         }
     }
 }
 
+impl Drop for CompletionStream {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+/// A stream of completion chunks, see [`AgentCtx::completion_chunk_stream`].
+///
+/// The underlying runner is aborted when the stream is dropped.
+pub struct CompletionChunkStream {
+    rx: mpsc::UnboundedReceiver<Result<CompletionChunk, BoxError>>,
+    handle: JoinHandle<()>,
+}
+
+impl Stream for CompletionChunkStream {
+    type Item = Result<CompletionChunk, BoxError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+impl Drop for CompletionChunkStream {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use anda_core::{BoxPinFut, HeuristicTokenizer, Tool, tool};
     use ciborium::from_reader;
     use ic_cose_types::to_cbor_bytes;
     use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::{
+        engine::EngineBuilder,
+        management::{AccessControl, AccessRule, User},
+        model::CompletionFeaturesDyn,
+    };
+
+    struct FailingTool;
+
+    impl Tool<BaseCtx> for FailingTool {
+        type Args = Json;
+        type Output = Json;
+
+        fn name(&self) -> String {
+            "failing_tool".to_string()
+        }
+
+        fn description(&self) -> String {
+            "A tool that always fails".to_string()
+        }
+
+        fn definition(&self) -> FunctionDefinition {
+            FunctionDefinition {
+                name: self.name(),
+                description: self.description(),
+                parameters: json!({"type": "object"}),
+                strict: None,
+            }
+        }
+
+        async fn call(
+            &self,
+            _ctx: BaseCtx,
+            _args: Self::Args,
+            _resources: Vec<Resource>,
+        ) -> Result<ToolOutput<Self::Output>, BoxError> {
+            Err("invalid arguments".into())
+        }
+    }
+
+    /// Calls `failing_tool` until it gets 2 tool errors back.
+    struct RetryingModel;
+
+    impl CompletionFeaturesDyn for RetryingModel {
+        fn completion(&self, req: CompletionRequest) -> BoxPinFut<Result<AgentOutput, BoxError>> {
+            let errors = req
+                .raw_history
+                .iter()
+                .filter(|v| v.get("error").is_some())
+                .count()
+                + req
+                    .content
+                    .iter()
+                    .filter(|p| matches!(p, ContentPart::ToolOutput { output, .. } if output.get("error").is_some()))
+                    .count();
+            let mut raw_history = Vec::new();
+            for part in &req.content {
+                if let ContentPart::ToolOutput { output, .. } = part {
+                    raw_history.push(output.clone());
+                }
+            }
+
+            Box::pin(futures::future::ready(Ok(if errors >= 2 {
+                AgentOutput {
+                    content: "done".to_string(),
+                    raw_history,
+                    ..Default::default()
+                }
+            } else {
+                AgentOutput {
+                    raw_history,
+                    tool_calls: vec![ToolCall {
+                        name: "failing_tool".to_string(),
+                        args: json!({}),
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                }
+            })))
+        }
+    }
+
+    async fn run_with_policy(policy: ToolErrorPolicy) -> (AgentOutput, usize) {
+        let ctx = EngineBuilder::new()
+            .with_model(Model::with_completer(Arc::new(RetryingModel)))
+            .register_tool(FailingTool)
+            .unwrap()
+            .mock_ctx();
+        let mut runner = ctx
+            .completion_iter(CompletionRequest::default(), vec![])
+            .with_tool_error_policy(policy);
+        let mut last = None;
+        while let Some(output) = runner.next().await.unwrap() {
+            last = Some(output);
+        }
+        (last.unwrap(), runner.steps())
+    }
+
+    /// Sleeps for the given milliseconds
+    #[tool(tool = SleepTool)]
+    async fn sleep_tool(_ctx: BaseCtx, ms: u64) -> Result<ToolOutput<u64>, BoxError> {
+        tokio::time::sleep(Duration::from_millis(ms)).await;
+        Ok(ToolOutput::new(ms))
+    }
+
+    /// Calls `sleep_tool` 3 times in one turn, then returns the tool outputs.
+    struct SleepModel;
+
+    impl CompletionFeaturesDyn for SleepModel {
+        fn completion(&self, req: CompletionRequest) -> BoxPinFut<Result<AgentOutput, BoxError>> {
+            let outputs: Vec<String> = req
+                .content
+                .iter()
+                .filter_map(|p| match p {
+                    ContentPart::ToolOutput { output, .. } => Some(output.to_string()),
+                    _ => None,
+                })
+                .collect();
+            Box::pin(futures::future::ready(Ok(if outputs.is_empty() {
+                AgentOutput {
+                    tool_calls: [300, 200, 100]
+                        .into_iter()
+                        .map(|ms| ToolCall {
+                            name: "sleep_tool".to_string(),
+                            args: json!(ms),
+                            ..Default::default()
+                        })
+                        .collect(),
+                    ..Default::default()
+                }
+            } else {
+                AgentOutput {
+                    content: outputs.join(","),
+                    ..Default::default()
+                }
+            })))
+        }
+    }
+
+    /// Counts the calls in flight, fails with arg 0.
+    /// Waits for the other calls of the turn at the barrier if set.
+    #[derive(Clone, Default)]
+    struct CountTool {
+        calls: Arc<AtomicUsize>,
+        running: Arc<AtomicUsize>,
+        max_running: Arc<AtomicUsize>,
+        barrier: Option<Arc<tokio::sync::Barrier>>,
+    }
+
+    impl Tool<BaseCtx> for CountTool {
+        type Args = u64;
+        type Output = u64;
+
+        fn name(&self) -> String {
+            "count_tool".to_string()
+        }
+
+        fn description(&self) -> String {
+            "Counts the calls in flight".to_string()
+        }
+
+        fn definition(&self) -> FunctionDefinition {
+            FunctionDefinition {
+                name: self.name(),
+                description: self.description(),
+                parameters: json!({"type": "integer"}),
+                strict: None,
+            }
+        }
+
+        async fn call(
+            &self,
+            _ctx: BaseCtx,
+            args: Self::Args,
+            _resources: Vec<Resource>,
+        ) -> Result<ToolOutput<Self::Output>, BoxError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            if args == 0 {
+                return Err("invalid argument".into());
+            }
+
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_running.fetch_max(running, Ordering::SeqCst);
+            if let Some(barrier) = &self.barrier {
+                barrier.wait().await;
+            }
+            tokio::task::yield_now().await;
+            self.running.fetch_sub(1, Ordering::SeqCst);
+
+            let mut output = ToolOutput::new(args);
+            output.usage.requests = 1;
+            Ok(output)
+        }
+    }
+
+    /// Calls `count_tool` with the args in one turn, then returns the tool outputs.
+    struct CountModel(Vec<u64>);
+
+    impl CompletionFeaturesDyn for CountModel {
+        fn completion(&self, req: CompletionRequest) -> BoxPinFut<Result<AgentOutput, BoxError>> {
+            let outputs: Vec<String> = req
+                .content
+                .iter()
+                .filter_map(|p| match p {
+                    ContentPart::ToolOutput { output, .. } => Some(output.to_string()),
+                    _ => None,
+                })
+                .collect();
+            Box::pin(futures::future::ready(Ok(if outputs.is_empty() {
+                AgentOutput {
+                    tool_calls: self
+                        .0
+                        .iter()
+                        .map(|arg| ToolCall {
+                            name: "count_tool".to_string(),
+                            args: json!(arg),
+                            ..Default::default()
+                        })
+                        .collect(),
+                    ..Default::default()
+                }
+            } else {
+                AgentOutput {
+                    content: outputs.join(","),
+                    ..Default::default()
+                }
+            })))
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_concurrent_tool_calls() {
+        // the calls only pass the barrier if all of them run at once
+        let tool = CountTool {
+            barrier: Some(Arc::new(tokio::sync::Barrier::new(3))),
+            ..Default::default()
+        };
+        let ctx = EngineBuilder::new()
+            .with_model(Model::with_completer(Arc::new(CountModel(vec![3, 2, 1]))))
+            .register_tool(tool.clone())
+            .unwrap()
+            .mock_ctx();
+        let output = tokio::time::timeout(
+            Duration::from_secs(10),
+            ctx.completion(CompletionRequest::default(), vec![]),
+        )
+        .await
+        .expect("tool calls should run concurrently")
+        .unwrap();
+        assert_eq!(output.content, "3,2,1");
+        assert_eq!(output.tool_calls.len(), 3);
+        assert_eq!(tool.max_running.load(Ordering::SeqCst), 3);
+
+        let tool = CountTool::default();
+        let ctx = EngineBuilder::new()
+            .with_model(Model::with_completer(Arc::new(CountModel(vec![3, 2, 1]))))
+            .register_tool(tool.clone())
+            .unwrap()
+            .mock_ctx();
+        let mut runner = ctx
+            .completion_iter(CompletionRequest::default(), vec![])
+            .with_max_concurrent_calls(1);
+        let mut last = None;
+        while let Some(output) = runner.next().await.unwrap() {
+            last = Some(output);
+        }
+        assert_eq!(last.unwrap().content, "3,2,1");
+        assert_eq!(tool.calls.load(Ordering::SeqCst), 3);
+        assert_eq!(tool.max_running.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_abort_stops_tool_calls() {
+        let tool = CountTool::default();
+        let ctx = EngineBuilder::new()
+            .with_model(Model::with_completer(Arc::new(CountModel(vec![
+                1, 0, 2, 3,
+            ]))))
+            .register_tool(tool.clone())
+            .unwrap()
+            .mock_ctx();
+        let mut runner = ctx
+            .completion_iter(CompletionRequest::default(), vec![])
+            .with_max_concurrent_calls(2);
+        let output = runner.next().await.unwrap().unwrap();
+        assert!(runner.is_done());
+        assert!(output.failed_reason.unwrap().contains("invalid argument"));
+
+        // the calls after the failure are not dispatched,
+        // the result and usage of the sibling call are recorded
+        assert_eq!(tool.calls.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            output.tool_calls[0].result.as_ref().unwrap().output,
+            json!(1)
+        );
+        assert!(output.tool_calls[1].error.is_some());
+        assert!(output.tool_calls[2].result.is_none() && output.tool_calls[2].error.is_none());
+        assert!(output.tool_calls[3].result.is_none() && output.tool_calls[3].error.is_none());
+        assert_eq!(output.usage.requests, 1);
+    }
+
+    /// Counts the calls, requires approval.
+    #[derive(Clone, Default)]
+    struct ApprovalTool {
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl Tool<BaseCtx> for ApprovalTool {
+        type Args = Json;
+        type Output = String;
+
+        fn name(&self) -> String {
+            "approval_tool".to_string()
+        }
+
+        fn description(&self) -> String {
+            "A tool that requires approval".to_string()
+        }
+
+        fn definition(&self) -> FunctionDefinition {
+            FunctionDefinition {
+                name: self.name(),
+                description: self.description(),
+                parameters: json!({"type": "object"}),
+                strict: None,
+            }
+        }
+
+        fn requires_approval(&self) -> bool {
+            true
+        }
+
+        async fn call(
+            &self,
+            _ctx: BaseCtx,
+            _args: Self::Args,
+            _resources: Vec<Resource>,
+        ) -> Result<ToolOutput<Self::Output>, BoxError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            Ok(ToolOutput::new("done".to_string()))
+        }
+    }
+
+    /// Calls `approval_tool` once, then returns the tool output.
+    struct ApprovalModel;
+
+    impl CompletionFeaturesDyn for ApprovalModel {
+        fn completion(&self, req: CompletionRequest) -> BoxPinFut<Result<AgentOutput, BoxError>> {
+            let output = req.content.iter().find_map(|p| match p {
+                ContentPart::ToolOutput { output, .. } => Some(output.to_string()),
+                _ => None,
+            });
+            Box::pin(futures::future::ready(Ok(match output {
+                Some(output) => AgentOutput {
+                    content: output,
+                    ..Default::default()
+                },
+                None => AgentOutput {
+                    content: "need approval".to_string(),
+                    tool_calls: vec![ToolCall {
+                        name: "approval_tool".to_string(),
+                        args: json!({}),
+                        call_id: Some("call_1".to_string()),
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                },
+            })))
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_tool_approval() {
+        let ctx = EngineBuilder::new()
+            .with_model(Model::with_completer(Arc::new(ApprovalModel)))
+            .register_tool(ApprovalTool::default())
+            .unwrap()
+            .mock_ctx();
+
+        let mut runner = ctx.completion_iter(CompletionRequest::default(), vec![]);
+        let output = runner.next().await.unwrap().unwrap();
+        assert!(runner.is_done());
+        assert!(output.failed_reason.is_none());
+        assert_eq!(output.content, "need approval");
+        let pending = output.pending_approval.unwrap();
+        assert_eq!(pending.tool_calls.len(), 1);
+        assert_eq!(pending.tool_calls[0].name, "approval_tool");
+
+        let mut runner = ctx
+            .resume_completion(&pending.id, true, None)
+            .await
+            .unwrap();
+        let mut last = None;
+        while let Some(output) = runner.next().await.unwrap() {
+            last = Some(output);
+        }
+        let output = last.unwrap();
+        assert!(output.pending_approval.is_none());
+        assert_eq!(output.content, r#""done""#);
+        assert_eq!(output.tool_calls.len(), 1);
+        assert_eq!(runner.steps(), 2);
+        assert!(
+            ctx.resume_completion(&pending.id, true, None)
+                .await
+                .is_err()
+        );
+
+        let output = ctx
+            .completion_iter(CompletionRequest::default(), vec![])
+            .next()
+            .await
+            .unwrap()
+            .unwrap();
+        let pending = output.pending_approval.unwrap();
+        let mut runner = ctx
+            .resume_completion(&pending.id, false, Some("too expensive".to_string()))
+            .await
+            .unwrap();
+        let mut last = None;
+        while let Some(output) = runner.next().await.unwrap() {
+            last = Some(output);
+        }
+        let output = last.unwrap();
+        assert!(
+            output
+                .content
+                .contains("rejected by the user: too expensive")
+        );
+        assert!(output.tool_calls[0].result.is_some());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_concurrent_approvals() {
+        let tool = ApprovalTool::default();
+        let ctx = EngineBuilder::new()
+            .with_model(Model::with_completer(Arc::new(ApprovalModel)))
+            .register_tool(tool.clone())
+            .unwrap()
+            .mock_ctx();
+
+        for _ in 0..50 {
+            let output = ctx
+                .completion_iter(CompletionRequest::default(), vec![])
+                .next()
+                .await
+                .unwrap()
+                .unwrap();
+            let id = output.pending_approval.unwrap().id;
+
+            let approvals = (0..2).map(|_| {
+                let ctx = ctx.clone();
+                let id = id.clone();
+                tokio::spawn(async move {
+                    let mut runner = ctx.resume_completion(&id, true, None).await?;
+                    while runner.next().await?.is_some() {}
+                    Ok::<_, BoxError>(())
+                })
+            });
+            let results = futures::future::join_all(approvals).await;
+            assert_eq!(
+                results.iter().filter(|r| matches!(r, Ok(Ok(())))).count(),
+                1
+            );
+        }
+        // the approved tool runs once per paused run
+        assert_eq!(tool.calls.load(Ordering::SeqCst), 50);
+        assert!(ctx.list_runs().await.unwrap().is_empty());
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_resumed_run_duration() {
+        let ctx = EngineBuilder::new()
+            .with_model(Model::with_completer(Arc::new(ApprovalModel)))
+            .register_tool(ApprovalTool::default())
+            .unwrap()
+            .with_run_budget(RunBudget {
+                max_duration: Some(Duration::from_secs(10)),
+                ..Default::default()
+            })
+            .mock_ctx();
+
+        let output = ctx
+            .completion_iter(CompletionRequest::default(), vec![])
+            .next()
+            .await
+            .unwrap()
+            .unwrap();
+        let pending = output.pending_approval.unwrap();
+
+        // the run time before the pause counts toward the duration limit
+        let mut state = ctx.load_run(&pending.id).await.unwrap();
+        assert!(state.elapsed_ms < 10_000);
+        state.elapsed_ms = 10_000;
+        ctx.store_put(
+            &run_path(&pending.id),
+            PutMode::Overwrite,
+            to_cbor_bytes(&state).into(),
+        )
+        .await
+        .unwrap();
+
+        let mut runner = ctx
+            .resume_completion(&pending.id, true, None)
+            .await
+            .unwrap();
+        assert!(runner.elapsed() >= Duration::from_secs(10));
+        let mut last = None;
+        while let Some(output) = runner.next().await.unwrap() {
+            last = Some(output);
+        }
+        assert!(
+            last.unwrap()
+                .failed_reason
+                .unwrap()
+                .starts_with("run budget exceeded: duration")
+        );
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_checkpoint_and_resume_run() {
+        let ctx = EngineBuilder::new()
+            .with_model(Model::with_completer(Arc::new(SleepModel)))
+            .register_tool(SleepTool)
+            .unwrap()
+            .mock_ctx();
+
+        let mut runner = ctx
+            .completion_iter(CompletionRequest::default(), vec![])
+            .with_checkpoint()
+            .with_conversation(7);
+        let id = runner.id().to_string();
+        let output = runner.next().await.unwrap().unwrap();
+        assert!(!runner.is_done());
+        assert!(output.failed_reason.is_none());
+        // the process is interrupted after the first step
+        drop(runner);
+
+        let runs = ctx.list_runs().await.unwrap();
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].id, id);
+        assert_eq!(runs[0].step, 1);
+        assert_eq!(runs[0].conversation, Some(7));
+        assert!(runs[0].pending.is_none());
+        assert!(ctx.resume_completion(&id, true, None).await.is_err());
+
+        let mut runner = ctx.resume_run(&id).await.unwrap();
+        let mut last = None;
+        while let Some(output) = runner.next().await.unwrap() {
+            last = Some(output);
+        }
+        let output = last.unwrap();
+        assert!(output.failed_reason.is_none());
+        assert_eq!(output.content, "300,200,100");
+        assert_eq!(output.tool_calls.len(), 3);
+        assert_eq!(output.conversation, Some(7));
+        assert_eq!(runner.steps(), 2);
+        assert!(ctx.list_runs().await.unwrap().is_empty());
+        assert!(ctx.resume_run(&id).await.is_err());
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_run_budget() {
+        let ctx = EngineBuilder::new()
+            .with_model(Model::with_completer(Arc::new(SleepModel)))
+            .register_tool(SleepTool)
+            .unwrap()
+            .with_run_budget(RunBudget {
+                max_steps: Some(1),
+                ..Default::default()
+            })
+            .mock_ctx();
+
+        let output = ctx
+            .completion(CompletionRequest::default(), vec![])
+            .await
+            .unwrap();
+        assert_eq!(
+            output.failed_reason.as_deref(),
+            Some("run budget exceeded: max steps 1")
+        );
+        assert!(output.content.is_empty());
+        assert_eq!(output.tool_calls.len(), 3);
+
+        // the budget of the request overrides the engine's default
+        let output = ctx
+            .completion(
+                CompletionRequest {
+                    budget: Some(RunBudget {
+                        max_steps: Some(2),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                vec![],
+            )
+            .await
+            .unwrap();
+        assert!(output.failed_reason.is_none());
+        assert_eq!(output.content, "300,200,100");
+
+        let mut runner = ctx
+            .completion_iter(CompletionRequest::default(), vec![])
+            .with_budget(RunBudget {
+                max_steps: Some(1),
+                final_summary: true,
+                ..Default::default()
+            });
+        let mut last = None;
+        while let Some(output) = runner.next().await.unwrap() {
+            last = Some(output);
+        }
+        let output = last.unwrap();
+        assert_eq!(runner.steps(), 2);
+        assert_eq!(
+            output.failed_reason.as_deref(),
+            Some("run budget exceeded: max steps 1")
+        );
+        assert_eq!(output.content, "300,200,100");
+
+        let mut runner = ctx
+            .completion_iter(CompletionRequest::default(), vec![])
+            .with_budget(RunBudget {
+                max_duration: Some(Duration::from_millis(100)),
+                final_summary: true,
+                ..Default::default()
+            });
+        let output = runner.next().await.unwrap().unwrap();
+        assert!(runner.is_done());
+        assert!(
+            output
+                .failed_reason
+                .unwrap()
+                .starts_with("run budget exceeded: duration")
+        );
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_tool_error_policy() {
+        let (output, steps) = run_with_policy(ToolErrorPolicy::Abort).await;
+        assert_eq!(steps, 1);
+        assert!(output.failed_reason.unwrap().contains("invalid arguments"));
+        assert_eq!(output.tool_calls.len(), 1);
+        assert!(output.tool_calls[0].error.is_some());
+
+        let (output, steps) = run_with_policy(ToolErrorPolicy::Feedback {
+            max_consecutive_failures: 2,
+        })
+        .await;
+        assert_eq!(steps, 3);
+        assert!(output.failed_reason.is_none());
+        assert_eq!(output.content, "done");
+        assert_eq!(output.tool_calls.len(), 2);
+        let error = output.tool_calls[1].error.as_ref().unwrap();
+        assert_eq!(error.name, "failing_tool");
+        assert!(error.message.contains("invalid arguments"));
+
+        let (output, steps) = run_with_policy(ToolErrorPolicy::Feedback {
+            max_consecutive_failures: 1,
+        })
+        .await;
+        assert_eq!(steps, 2);
+        assert!(
+            output
+                .failed_reason
+                .unwrap()
+                .starts_with("too many consecutive tool failures (2)")
+        );
+        assert_eq!(output.tool_calls.len(), 2);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_unknown_tool_call() {
+        // `failing_tool` is not registered
+        let ctx = EngineBuilder::new()
+            .with_model(Model::with_completer(Arc::new(RetryingModel)))
+            .mock_ctx();
+        let mut runner = ctx
+            .completion_iter(CompletionRequest::default(), vec![])
+            .with_tool_error_policy(ToolErrorPolicy::Feedback {
+                max_consecutive_failures: 2,
+            });
+        let mut last = None;
+        while let Some(output) = runner.next().await.unwrap() {
+            last = Some(output);
+        }
+        // each unknown call is answered with an error
+        let output = last.unwrap();
+        assert_eq!(runner.steps(), 3);
+        assert_eq!(output.content, "done");
+        assert_eq!(output.tool_calls.len(), 2);
+        let error = output.tool_calls[0].error.as_ref().unwrap();
+        assert_eq!(error.message, "tool failing_tool not found");
+    }
+
+    /// Calls `sleep_tool` with invalid args, then returns the tool output.
+    struct InvalidArgsModel;
+
+    impl CompletionFeaturesDyn for InvalidArgsModel {
+        fn completion(&self, req: CompletionRequest) -> BoxPinFut<Result<AgentOutput, BoxError>> {
+            let output = req.content.iter().find_map(|p| match p {
+                ContentPart::ToolOutput { output, .. } => Some(output.clone()),
+                _ => None,
+            });
+            Box::pin(futures::future::ready(Ok(match output {
+                Some(output) => AgentOutput {
+                    content: output.to_string(),
+                    ..Default::default()
+                },
+                None => AgentOutput {
+                    tool_calls: vec![ToolCall {
+                        name: "sleep_tool".to_string(),
+                        args: json!("100ms"),
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                },
+            })))
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_tool_args_validation() {
+        let ctx = EngineBuilder::new()
+            .with_model(Model::with_completer(Arc::new(InvalidArgsModel)))
+            .register_tool(SleepTool)
+            .unwrap()
+            .mock_ctx();
+
+        let err = ctx
+            .tool_call(ToolInput::new("sleep_tool".to_string(), json!("100ms")))
+            .await
+            .unwrap_err();
+        let err = err.downcast_ref::<ToolArgsError>().unwrap();
+        assert_eq!(err.name, "sleep_tool");
+        assert_eq!(err.violations.len(), 1);
+        assert_eq!(err.violations[0].message, "expected integer, got string");
+
+        let mut runner = ctx
+            .completion_iter(CompletionRequest::default(), vec![])
+            .with_tool_error_policy(ToolErrorPolicy::Feedback {
+                max_consecutive_failures: 1,
+            });
+        let mut last = None;
+        while let Some(output) = runner.next().await.unwrap() {
+            last = Some(output);
+        }
+        let output = last.unwrap();
+        let error = output.tool_calls[0].error.as_ref().unwrap();
+        assert_eq!(error.violations, err.violations);
+        // the violations are returned to the model
+        let returned: Json = serde_json::from_str(&output.content).unwrap();
+        assert_eq!(returned["error"]["violations"][0]["path"], "");
+
+        let (output, _) = ctx
+            .tool_call(ToolInput::new("sleep_tool".to_string(), json!(1)))
+            .await
+            .unwrap();
+        assert_eq!(output.output, json!(1));
+    }
 
     #[test]
     fn json_in_cbor_works() {
@@ -1120,4 +2876,369 @@ mod tests {
         let val: serde_json::Value = from_reader(&data[..]).unwrap();
         assert_eq!(json, val);
     }
+
+    /// Returns a large output of the given size.
+    #[tool(tool = LargeTool)]
+    async fn large_tool(_ctx: BaseCtx, size: usize) -> Result<ToolOutput<String>, BoxError> {
+        Ok(ToolOutput::new("x".repeat(size)))
+    }
+
+    /// Calls `large_tool` in 6 turns and keeps the raw history like a model provider,
+    /// records the tokens of the requests and checks that tool outputs follow their tool calls.
+    #[derive(Default)]
+    struct LoopModel {
+        turns: std::sync::atomic::AtomicUsize,
+        tokens: std::sync::Mutex<Vec<usize>>,
+    }
+
+    impl CompletionFeaturesDyn for LoopModel {
+        fn completion(&self, req: CompletionRequest) -> BoxPinFut<Result<AgentOutput, BoxError>> {
+            self.tokens
+                .lock()
+                .unwrap()
+                .push(HeuristicTokenizer.count_request_tokens(&req));
+
+            let mut content = req.content.clone();
+            if !req.prompt.is_empty() {
+                content.push(req.prompt.clone().into());
+            }
+            let mut chat_history = vec![Message {
+                role: req.role.clone().unwrap_or_else(|| "user".to_string()),
+                content,
+                ..Default::default()
+            }];
+
+            let history: Vec<Message> = req
+                .raw_history
+                .iter()
+                .map(|val| serde_json::from_value(val.clone()).unwrap())
+                .chain(req.chat_history.iter().cloned())
+                .chain(chat_history.iter().cloned())
+                .collect();
+            let mut calls: Vec<Option<String>> = Vec::new();
+            for part in history.iter().flat_map(|msg| msg.content.iter()) {
+                match part {
+                    ContentPart::ToolCall { call_id, .. } => calls.push(call_id.clone()),
+                    ContentPart::ToolOutput { call_id, .. } => {
+                        assert!(calls.contains(call_id), "orphan tool output {:?}", call_id)
+                    }
+                    _ => {}
+                }
+            }
+
+            let turn = self.turns.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            let assistant = Message {
+                role: "assistant".to_string(),
+                content: vec![if turn < 6 {
+                    ContentPart::ToolCall {
+                        name: "large_tool".to_string(),
+                        args: json!(400),
+                        call_id: Some(format!("call_{}", turn)),
+                    }
+                } else {
+                    "done".to_string().into()
+                }],
+                ..Default::default()
+            };
+            let tool_calls = assistant.tool_calls();
+            let content = assistant.text().unwrap_or_default();
+            chat_history.push(assistant);
+
+            Box::pin(futures::future::ready(Ok(AgentOutput {
+                content,
+                tool_calls,
+                raw_history: req
+                    .chat_history
+                    .iter()
+                    .chain(chat_history.iter())
+                    .map(|msg| json!(msg))
+                    .collect(),
+                chat_history,
+                ..Default::default()
+            })))
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_context_window() {
+        let model = Arc::new(LoopModel::default());
+        let ctx = EngineBuilder::new()
+            .with_model(Model::with_completer(model.clone()).with_context_window(600))
+            .register_tool(LargeTool)
+            .unwrap()
+            .mock_ctx();
+
+        let req = CompletionRequest {
+            prompt: "find something".to_string(),
+            ..Default::default()
+        };
+        let output = ctx.completion(req, vec![]).await.unwrap();
+        assert_eq!(output.content, "done");
+        assert_eq!(output.tool_calls.len(), 6);
+        // the output keeps the whole conversation
+        assert_eq!(output.chat_history.len(), 14);
+
+        let tokens = model.tokens.lock().unwrap().clone();
+        assert_eq!(tokens.len(), 7);
+        // 600 - 600 / CONTEXT_OUTPUT_RESERVE_RATIO
+        assert!(tokens.iter().all(|t| *t <= 525), "{:?}", tokens);
+    }
+
+    /// Records the tools of the requests.
+    #[derive(Default)]
+    struct ToolsModel {
+        tools: std::sync::Mutex<Vec<Vec<String>>>,
+    }
+
+    impl CompletionFeaturesDyn for ToolsModel {
+        fn completion(&self, req: CompletionRequest) -> BoxPinFut<Result<AgentOutput, BoxError>> {
+            self.tools
+                .lock()
+                .unwrap()
+                .push(req.tools.into_iter().map(|tool| tool.name).collect());
+            Box::pin(futures::future::ready(Ok(AgentOutput {
+                content: "done".to_string(),
+                ..Default::default()
+            })))
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_tool_selector() {
+        let completer = Arc::new(ToolsModel::default());
+        let mut model = Model::mock_implemented();
+        model.completer = completer.clone();
+        let ctx = EngineBuilder::new()
+            .with_model(model)
+            .with_tool_selector(ToolSelector::new(1).with_pinned(vec!["pinned".to_string()]))
+            .mock_ctx();
+
+        let tools: Vec<FunctionDefinition> = ["first", "second", "pinned"]
+            .into_iter()
+            .map(|name| FunctionDefinition {
+                name: name.to_string(),
+                description: format!("{} tool", name),
+                parameters: json!({"type": "object"}),
+                strict: None,
+            })
+            .collect();
+        let req = CompletionRequest {
+            prompt: "hello".to_string(),
+            tools,
+            ..Default::default()
+        };
+        let output = ctx.completion(req, vec![]).await.unwrap();
+        assert_eq!(output.content, "done");
+        assert_eq!(
+            completer.tools.lock().unwrap().clone(),
+            vec![vec!["first".to_string(), "pinned".to_string()]]
+        );
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_caller_access() {
+        let mut ctx = EngineBuilder::new()
+            .register_tool(SleepTool)
+            .unwrap()
+            .register_tool(LargeTool)
+            .unwrap()
+            .mock_ctx();
+        let acl = AccessControl::new().with_tool(
+            "large_tool",
+            AccessRule {
+                features: ["large".to_string()].into(),
+                ..Default::default()
+            },
+        );
+        let mut user = User::new(Principal::anonymous());
+        ctx.access = Some(Arc::new(CallerAccess::new(
+            Arc::new(acl.clone()),
+            user.clone(),
+            true,
+            unix_ms(),
+        )));
+
+        let names: Vec<String> = ctx
+            .tool_definitions(None)
+            .into_iter()
+            .map(|d| d.name)
+            .collect();
+        assert_eq!(names, vec!["sleep_tool".to_string()]);
+        let err = ctx
+            .tool_call(ToolInput::new("large_tool".to_string(), json!(1)))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("permission"), "{err}");
+        let (output, _) = ctx
+            .tool_call(ToolInput::new("sleep_tool".to_string(), json!(1)))
+            .await
+            .unwrap();
+        assert_eq!(output.output, json!(1));
+
+        user.features.insert("large".to_string());
+        ctx.access = Some(Arc::new(CallerAccess::new(
+            Arc::new(acl),
+            user,
+            true,
+            unix_ms(),
+        )));
+        assert_eq!(ctx.tool_definitions(None).len(), 2);
+        let (output, _) = ctx
+            .tool_call(ToolInput::new("large_tool".to_string(), json!(1)))
+            .await
+            .unwrap();
+        assert_eq!(output.output, json!("x"));
+    }
+
+    /// Records the hook events, rewrites the args of `sleep_tool` from 300 to 1 and rejects 200.
+    struct RecordingHook {
+        events: Arc<std::sync::Mutex<Vec<String>>>,
+    }
+
+    impl RecordingHook {
+        fn record(&self, event: String) {
+            self.events.lock().unwrap().push(event);
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Hook for RecordingHook {
+        async fn on_completion_start(
+            &self,
+            _ctx: &AgentCtx,
+            req: CompletionRequest,
+        ) -> Result<CompletionRequest, BoxError> {
+            self.record("completion_start".to_string());
+            Ok(req)
+        }
+
+        async fn on_completion_end(
+            &self,
+            _ctx: &AgentCtx,
+            output: AgentOutput,
+        ) -> Result<AgentOutput, BoxError> {
+            self.record(format!("completion_end:{}", output.tool_calls.len()));
+            Ok(output)
+        }
+
+        async fn on_nested_tool_start(
+            &self,
+            _ctx: &AgentCtx,
+            mut input: ToolInput<Json>,
+        ) -> Result<ToolInput<Json>, BoxError> {
+            if input.args == json!(200) {
+                return Err("rejected by hook".into());
+            }
+            if input.args == json!(300) {
+                input.args = json!(1);
+            }
+            self.record(format!("tool_start:{}", input.args));
+            Ok(input)
+        }
+
+        async fn on_nested_tool_end(
+            &self,
+            _ctx: &AgentCtx,
+            tool: &str,
+            output: ToolOutput<Json>,
+        ) -> Result<ToolOutput<Json>, BoxError> {
+            self.record(format!("tool_end:{}:{}", tool, output.output));
+            Ok(output)
+        }
+
+        async fn on_error(&self, _ctx: &BaseCtx, name: &str, err: &BoxError) {
+            self.record(format!("error:{}:{}", name, err));
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_hooks() {
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut hooks = Hooks::new();
+        hooks.add(Box::new(RecordingHook {
+            events: events.clone(),
+        }));
+        let ctx = EngineBuilder::new()
+            .with_model(Model::with_completer(Arc::new(SleepModel)))
+            .with_hooks(Arc::new(hooks))
+            .register_tool(SleepTool)
+            .unwrap()
+            .mock_ctx();
+
+        let output = ctx
+            .completion(CompletionRequest::default(), vec![])
+            .await
+            .unwrap();
+        assert_eq!(output.failed_reason.unwrap(), "rejected by hook");
+
+        let mut events = events.lock().unwrap().clone();
+        events.sort();
+        assert_eq!(
+            events,
+            vec![
+                "completion_end:3",
+                "completion_start",
+                "error:sleep_tool:rejected by hook",
+                "tool_end:sleep_tool:1",
+                "tool_end:sleep_tool:100",
+                "tool_start:1",
+                "tool_start:100",
+            ]
+        );
+    }
+
+    /// Streams the text "hello" in two deltas.
+    struct ChunkModel;
+
+    impl CompletionFeaturesDyn for ChunkModel {
+        fn completion(&self, _req: CompletionRequest) -> BoxPinFut<Result<AgentOutput, BoxError>> {
+            Box::pin(futures::future::ready(Ok(AgentOutput {
+                content: "hello".to_string(),
+                ..Default::default()
+            })))
+        }
+
+        fn completion_stream(
+            &self,
+            req: CompletionRequest,
+        ) -> futures::stream::BoxStream<'static, Result<CompletionChunk, BoxError>> {
+            let fut = self.completion(req);
+            futures::stream::iter([
+                Ok(CompletionChunk::Text {
+                    text: "hel".to_string(),
+                }),
+                Ok(CompletionChunk::Text {
+                    text: "lo".to_string(),
+                }),
+            ])
+            .chain(futures::stream::once(async move {
+                fut.await.map(CompletionChunk::Output)
+            }))
+            .boxed()
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_completion_streams() {
+        let ctx = EngineBuilder::new()
+            .with_model(Model::with_completer(Arc::new(ChunkModel)))
+            .mock_ctx();
+
+        let outputs: Vec<AgentOutput> = ctx
+            .completion_stream(CompletionRequest::default(), vec![])
+            .map(|res| res.unwrap())
+            .collect()
+            .await;
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(outputs[0].content, "hello");
+
+        let chunks: Vec<CompletionChunk> = ctx
+            .completion_chunk_stream(CompletionRequest::default(), vec![])
+            .map(|res| res.unwrap())
+            .collect()
+            .await;
+        assert_eq!(chunks.len(), 3);
+        assert!(matches!(&chunks[1], CompletionChunk::Text { text } if text == "lo"));
+        assert!(matches!(&chunks[2], CompletionChunk::Output(output) if output.content == "hello"));
+    }
 }
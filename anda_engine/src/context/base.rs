@@ -6,6 +6,7 @@
 //! - [`StateFeatures`]: Context state management;
 //! - [`KeysFeatures`]: Cryptographic key operations;
 //! - [`StoreFeatures`]: Persistent storage operations;
+//! - [`VectorSearchFeatures`]: Hybrid vector search in the namespace of the context;
 //! - [`CacheFeatures`]: Caching mechanisms;
 //! - [`CanisterCaller`]: Canister interaction capabilities;
 //! - [`HttpFeatures`]: HTTPs communication features.
@@ -26,7 +27,8 @@
 use anda_core::{
     BaseContext, BoxError, CacheExpiry, CacheFeatures, CacheStoreFeatures, CancellationToken,
     CanisterCaller, HttpFeatures, Json, KeysFeatures, ObjectMeta, Path, PutMode, PutResult,
-    RequestMeta, StateFeatures, StoreFeatures, ToolInput, ToolOutput, derivation_path_with,
+    RequestMeta, StateFeatures, StoreFeatures, ToolInput, ToolOutput, VectorDocument, VectorQuery,
+    VectorSearchFeatures, derivation_path_with,
 };
 use bytes::Bytes;
 use candid::{CandidType, Principal, utils::ArgumentEncoder};
@@ -48,7 +50,7 @@ use super::{
     cache::CacheService,
     web3::{Web3Client, Web3SDK},
 };
-use crate::store::Store;
+use crate::store::{Store, VectorSearchFeaturesDyn, VectorStore};
 
 #[derive(Clone)]
 pub struct BaseCtx {
@@ -67,6 +69,7 @@ pub struct BaseCtx {
 
     cache: Arc<CacheService>,
     store: Store,
+    vector: VectorStore,
 }
 
 /// Base context [`BaseContext`] implementation providing core functionality for the engine.
@@ -84,6 +87,7 @@ pub struct BaseCtx {
 /// maintaining its own state while sharing underlying resources.
 impl BaseCtx {
     /// Creates a new BaseCtx instance.
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
         id: Principal,
         name: String,
@@ -91,6 +95,7 @@ impl BaseCtx {
         names: BTreeSet<Path>,
         web3: Arc<Web3SDK>,
         store: Store,
+        vector: VectorStore,
         remote: Arc<RemoteEngines>,
     ) -> Self {
         let caller = Principal::anonymous();
@@ -103,6 +108,7 @@ impl BaseCtx {
             start_at: Instant::now(),
             cache: Arc::new(CacheService::new(CACHE_MAX_CAPACITY, names)),
             store,
+            vector,
             web3,
             depth: 0,
             remote,
@@ -135,6 +141,7 @@ impl BaseCtx {
             start_at: self.start_at,
             cache: self.cache.clone(),
             store: self.store.clone(),
+            vector: self.vector.clone(),
             web3: self.web3.clone(),
             depth: self.depth + 1,
             remote: self.remote.clone(),
@@ -177,6 +184,7 @@ impl BaseCtx {
             start_at: Instant::now(),
             cache: self.cache.clone(),
             store: self.store.clone(),
+            vector: self.vector.clone(),
             web3: self.web3.clone(),
             depth: self.depth + 1,
             remote: self.remote.clone(),
@@ -541,6 +549,39 @@ impl StoreFeatures for BaseCtx {
     }
 }
 
+impl VectorSearchFeatures for BaseCtx {
+    /// Performs a semantic search in the namespace of the context,
+    /// returns the top n documents as JSON strings.
+    async fn top_n(&self, query: &str, n: usize) -> Result<Vec<String>, BoxError> {
+        self.vector
+            .top_n(self.path.clone(), query.to_string(), n)
+            .await
+    }
+
+    /// Performs a semantic search in the namespace of the context,
+    /// returns the IDs of the top n documents.
+    async fn top_n_ids(&self, query: &str, n: usize) -> Result<Vec<String>, BoxError> {
+        self.vector
+            .top_n_ids(self.path.clone(), query.to_string(), n)
+            .await
+    }
+
+    /// Performs a hybrid search with metadata filters in the namespace of the context.
+    async fn vector_search(&self, query: VectorQuery) -> Result<Vec<VectorDocument>, BoxError> {
+        self.vector.search(self.path.clone(), query).await
+    }
+
+    /// Embeds and indexes the documents in the namespace of the context.
+    async fn vector_upsert(&self, docs: Vec<VectorDocument>) -> Result<(), BoxError> {
+        self.vector.upsert(self.path.clone(), docs).await
+    }
+
+    /// Deletes the documents by IDs in the namespace of the context.
+    async fn vector_delete(&self, ids: Vec<String>) -> Result<usize, BoxError> {
+        self.vector.delete(self.path.clone(), ids).await
+    }
+}
+
 impl CacheFeatures for BaseCtx {
     /// Checks if a key exists in the cache.
     fn cache_contains(&self, key: &str) -> bool {
@@ -229,9 +229,7 @@ impl CacheService {
         &self,
         path: &Path,
     ) -> impl Iterator<Item = (Arc<String>, Arc<(Bytes, Option<CacheExpiry>)>)> {
-        
-        self
-            .cache_store
+        self.cache_store
             .get(path)
             .expect("CacheService: cache not found")
             .iter()
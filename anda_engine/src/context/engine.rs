@@ -124,6 +124,23 @@ impl RemoteEngines {
         None
     }
 
+    /// Retrieves a remote tool definition from a prefixed name.
+    pub fn get_tool_definition(&self, prefixed_name: &str) -> Option<&FunctionDefinition> {
+        let name = prefixed_name.strip_prefix("RT_")?;
+        for (handle, engine) in self.engines.iter() {
+            if let Some(tool_name) = name.strip_prefix(handle)
+                && let Some(tool_name) = tool_name.strip_prefix("_")
+            {
+                return engine
+                    .tools
+                    .iter()
+                    .find(|tool| tool.definition.name == tool_name)
+                    .map(|tool| &tool.definition);
+            }
+        }
+        None
+    }
+
     /// Retrieves a remote agent endpoint and name from a prefixed name.
     pub fn get_agent_endpoint(&self, prefixed_name: &str) -> Option<(Principal, String, String)> {
         if let Some(name) = prefixed_name.strip_prefix("RA_") {
@@ -0,0 +1,264 @@
+use anda_core::{
+    AgentInput, AgentOutput, BoxError, CompletionRequest, Json, ToolInput, ToolOutput,
+};
+use async_trait::async_trait;
+
+use super::{agent::AgentCtx, base::BaseCtx};
+use crate::management::UserState;
+
+/// Hook trait for customizing engine behavior.
+/// Hooks can be used to intercept and modify agent and tool execution.
+///
+/// `on_agent_*` and `on_tool_*` are called at the engine entry points, `on_completion_*` and
+/// `on_nested_*` are called by completion runs for each step's model completion and each tool
+/// or agent call made by the model. The `*_start` hooks can modify the input or reject the call with
+/// an error, the `*_end` hooks can modify the output.
+#[async_trait]
+pub trait Hook: Send + Sync {
+    /// Called before an agent is executed.
+    async fn on_agent_start(
+        &self,
+        _ctx: &AgentCtx,
+        _agent: &str,
+        _state: &UserState,
+    ) -> Result<(), BoxError> {
+        Ok(())
+    }
+
+    /// Called after an agent is executed.
+    async fn on_agent_end(
+        &self,
+        _ctx: &AgentCtx,
+        _agent: &str,
+        output: AgentOutput,
+    ) -> Result<AgentOutput, BoxError> {
+        Ok(output)
+    }
+
+    /// Called before a tool is called.
+    async fn on_tool_start(
+        &self,
+        _ctx: &BaseCtx,
+        _tool: &str,
+        _state: &UserState,
+    ) -> Result<(), BoxError> {
+        Ok(())
+    }
+
+    /// Called after a tool is called.
+    async fn on_tool_end(
+        &self,
+        _ctx: &BaseCtx,
+        _tool: &str,
+        output: ToolOutput<Json>,
+    ) -> Result<ToolOutput<Json>, BoxError> {
+        Ok(output)
+    }
+
+    /// Called before a model completion request of a completion run.
+    async fn on_completion_start(
+        &self,
+        _ctx: &AgentCtx,
+        req: CompletionRequest,
+    ) -> Result<CompletionRequest, BoxError> {
+        Ok(req)
+    }
+
+    /// Called after a model completion of a completion run, `output.usage` is the usage of it.
+    async fn on_completion_end(
+        &self,
+        _ctx: &AgentCtx,
+        output: AgentOutput,
+    ) -> Result<AgentOutput, BoxError> {
+        Ok(output)
+    }
+
+    /// Called before a tool call made by the model in a completion run.
+    async fn on_nested_tool_start(
+        &self,
+        _ctx: &AgentCtx,
+        input: ToolInput<Json>,
+    ) -> Result<ToolInput<Json>, BoxError> {
+        Ok(input)
+    }
+
+    /// Called after a tool call made by the model in a completion run.
+    async fn on_nested_tool_end(
+        &self,
+        _ctx: &AgentCtx,
+        _tool: &str,
+        output: ToolOutput<Json>,
+    ) -> Result<ToolOutput<Json>, BoxError> {
+        Ok(output)
+    }
+
+    /// Called before an agent run made by the model in a completion run.
+    async fn on_nested_agent_start(
+        &self,
+        _ctx: &AgentCtx,
+        input: AgentInput,
+    ) -> Result<AgentInput, BoxError> {
+        Ok(input)
+    }
+
+    /// Called after an agent run made by the model in a completion run.
+    async fn on_nested_agent_end(
+        &self,
+        _ctx: &AgentCtx,
+        _agent: &str,
+        output: AgentOutput,
+    ) -> Result<AgentOutput, BoxError> {
+        Ok(output)
+    }
+
+    /// Called when an agent run, a tool call or a model completion fails,
+    /// `name` is the name of the agent or tool, or `"completion"`.
+    async fn on_error(&self, _ctx: &BaseCtx, _name: &str, _err: &BoxError) {}
+}
+
+/// Hooks struct for managing multiple hooks.
+pub struct Hooks {
+    hooks: Vec<Box<dyn Hook>>,
+}
+
+impl Default for Hooks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Hooks {
+    pub fn new() -> Self {
+        Self { hooks: Vec::new() }
+    }
+
+    /// Adds a new hook to the list of hooks.
+    pub fn add(&mut self, hook: Box<dyn Hook>) {
+        self.hooks.push(hook);
+    }
+}
+
+#[async_trait]
+impl Hook for Hooks {
+    async fn on_agent_start(
+        &self,
+        ctx: &AgentCtx,
+        agent: &str,
+        state: &UserState,
+    ) -> Result<(), BoxError> {
+        for hook in &self.hooks {
+            hook.on_agent_start(ctx, agent, state).await?;
+        }
+        Ok(())
+    }
+
+    async fn on_agent_end(
+        &self,
+        ctx: &AgentCtx,
+        agent: &str,
+        mut output: AgentOutput,
+    ) -> Result<AgentOutput, BoxError> {
+        for hook in &self.hooks {
+            output = hook.on_agent_end(ctx, agent, output).await?;
+        }
+        Ok(output)
+    }
+
+    async fn on_tool_start(
+        &self,
+        ctx: &BaseCtx,
+        tool: &str,
+        state: &UserState,
+    ) -> Result<(), BoxError> {
+        for hook in &self.hooks {
+            hook.on_tool_start(ctx, tool, state).await?;
+        }
+        Ok(())
+    }
+
+    async fn on_tool_end(
+        &self,
+        ctx: &BaseCtx,
+        tool: &str,
+        mut output: ToolOutput<Json>,
+    ) -> Result<ToolOutput<Json>, BoxError> {
+        for hook in &self.hooks {
+            output = hook.on_tool_end(ctx, tool, output).await?;
+        }
+        Ok(output)
+    }
+
+    async fn on_completion_start(
+        &self,
+        ctx: &AgentCtx,
+        mut req: CompletionRequest,
+    ) -> Result<CompletionRequest, BoxError> {
+        for hook in &self.hooks {
+            req = hook.on_completion_start(ctx, req).await?;
+        }
+        Ok(req)
+    }
+
+    async fn on_completion_end(
+        &self,
+        ctx: &AgentCtx,
+        mut output: AgentOutput,
+    ) -> Result<AgentOutput, BoxError> {
+        for hook in &self.hooks {
+            output = hook.on_completion_end(ctx, output).await?;
+        }
+        Ok(output)
+    }
+
+    async fn on_nested_tool_start(
+        &self,
+        ctx: &AgentCtx,
+        mut input: ToolInput<Json>,
+    ) -> Result<ToolInput<Json>, BoxError> {
+        for hook in &self.hooks {
+            input = hook.on_nested_tool_start(ctx, input).await?;
+        }
+        Ok(input)
+    }
+
+    async fn on_nested_tool_end(
+        &self,
+        ctx: &AgentCtx,
+        tool: &str,
+        mut output: ToolOutput<Json>,
+    ) -> Result<ToolOutput<Json>, BoxError> {
+        for hook in &self.hooks {
+            output = hook.on_nested_tool_end(ctx, tool, output).await?;
+        }
+        Ok(output)
+    }
+
+    async fn on_nested_agent_start(
+        &self,
+        ctx: &AgentCtx,
+        mut input: AgentInput,
+    ) -> Result<AgentInput, BoxError> {
+        for hook in &self.hooks {
+            input = hook.on_nested_agent_start(ctx, input).await?;
+        }
+        Ok(input)
+    }
+
+    async fn on_nested_agent_end(
+        &self,
+        ctx: &AgentCtx,
+        agent: &str,
+        mut output: AgentOutput,
+    ) -> Result<AgentOutput, BoxError> {
+        for hook in &self.hooks {
+            output = hook.on_nested_agent_end(ctx, agent, output).await?;
+        }
+        Ok(output)
+    }
+
+    async fn on_error(&self, ctx: &BaseCtx, name: &str, err: &BoxError) {
+        for hook in &self.hooks {
+            hook.on_error(ctx, name, err).await;
+        }
+    }
+}
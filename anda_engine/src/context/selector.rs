@@ -0,0 +1,275 @@
+//! Dynamic tool selection for large tool sets.
+//!
+//! Engines with many local and remote tools blow up the prompt if every tool definition is sent
+//! to the model. A [`ToolSelector`] embeds the tool descriptions once with the embedding model of
+//! the engine, and for each completion run picks the `top_k` tools most relevant to the prompt,
+//! plus the pinned tools. It is opt-in, see [`crate::engine::EngineBuilder::with_tool_selector`].
+
+use anda_core::{BoxError, Embedding, FunctionDefinition, Usage};
+use parking_lot::RwLock;
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::model::Model;
+
+/// Selects the tools most relevant to a query by the similarity of their embeddings.
+#[derive(Debug, Default)]
+pub struct ToolSelector {
+    top_k: usize,
+    pinned: BTreeSet<String>,
+    /// Embeddings of the tool descriptions by tool name.
+    embeddings: RwLock<BTreeMap<String, Embedding>>,
+}
+
+impl ToolSelector {
+    /// Creates a selector that picks the `top_k` most relevant tools.
+    pub fn new(top_k: usize) -> Self {
+        Self {
+            top_k,
+            ..Default::default()
+        }
+    }
+
+    /// Sets the tools that are always selected, e.g. `LA_` agents or frequently used tools.
+    pub fn with_pinned(mut self, names: Vec<String>) -> Self {
+        self.pinned = names.into_iter().collect();
+        self
+    }
+
+    /// Returns the number of tools selected besides the pinned ones.
+    pub fn top_k(&self) -> usize {
+        self.top_k
+    }
+
+    /// Selects the pinned tools and the `top_k` other tools most relevant to the query,
+    /// keeping the order of the tools. Returns the tools as is if there are no more than
+    /// `top_k` other tools or the query is empty.
+    ///
+    /// Tool descriptions are embedded on first use and cached, they are embedded again
+    /// if changed. Returns the selected tools and the usage of the embedding model.
+    pub async fn select(
+        &self,
+        model: &Model,
+        query: &str,
+        tools: Vec<FunctionDefinition>,
+    ) -> Result<(Vec<FunctionDefinition>, Usage), BoxError> {
+        let mut usage = Usage::default();
+        let candidates: Vec<&FunctionDefinition> = tools
+            .iter()
+            .filter(|tool| !self.pinned.contains(&tool.name))
+            .collect();
+        if candidates.len() <= self.top_k || query.trim().is_empty() {
+            return Ok((tools, usage));
+        }
+
+        let missing: Vec<(String, String)> = {
+            let embeddings = self.embeddings.read();
+            candidates
+                .iter()
+                .map(|tool| (tool.name.clone(), tool_text(tool)))
+                .filter(|(name, text)| embeddings.get(name).is_none_or(|e| &e.text != text))
+                .collect()
+        };
+        if !missing.is_empty() {
+            let (res, u) = model
+                .embed(missing.iter().map(|(_, text)| text.clone()))
+                .await?;
+            usage.accumulate(&u);
+            if res.len() != missing.len() {
+                return Err(format!(
+                    "embedding error: expected {} embeddings, got {}",
+                    missing.len(),
+                    res.len()
+                )
+                .into());
+            }
+
+            let mut embeddings = self.embeddings.write();
+            for ((name, _), embedding) in missing.into_iter().zip(res) {
+                embeddings.insert(name, embedding);
+            }
+        }
+
+        let (query, u) = model.embed_query(query).await?;
+        usage.accumulate(&u);
+
+        let mut scores: Vec<(f32, &str)> = {
+            let embeddings = self.embeddings.read();
+            candidates
+                .iter()
+                .map(|tool| {
+                    let score = embeddings
+                        .get(&tool.name)
+                        .map(|e| cosine_similarity(&query.vec, &e.vec))
+                        .unwrap_or(f32::MIN);
+                    (score, tool.name.as_str())
+                })
+                .collect()
+        };
+        scores.sort_by(|a, b| b.0.total_cmp(&a.0));
+        let selected: BTreeSet<String> = scores
+            .into_iter()
+            .take(self.top_k)
+            .map(|(_, name)| name.to_string())
+            .collect();
+
+        let tools = tools
+            .into_iter()
+            .filter(|tool| self.pinned.contains(&tool.name) || selected.contains(&tool.name))
+            .collect();
+        Ok((tools, usage))
+    }
+}
+
+/// The text of the tool to embed.
+fn tool_text(tool: &FunctionDefinition) -> String {
+    format!("{}: {}", tool.name, tool.description)
+}
+
+/// Returns the cosine similarity of two vectors, 0 if either is a zero vector.
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let (mut dot, mut na, mut nb) = (0.0f32, 0.0f32, 0.0f32);
+    for (x, y) in a.iter().zip(b) {
+        dot += x * y;
+        na += x * x;
+        nb += y * y;
+    }
+    if na == 0.0 || nb == 0.0 {
+        return 0.0;
+    }
+    dot / (na.sqrt() * nb.sqrt())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anda_core::{AgentOutput, BoxPinFut, CompletionRequest};
+    use serde_json::json;
+    use std::sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    };
+
+    use crate::model::{CompletionFeaturesDyn, EmbeddingFeaturesDyn};
+
+    static TOPICS: [&str; 3] = ["weather", "email", "calendar"];
+
+    /// Embeds texts by the topics they mention.
+    #[derive(Default)]
+    struct TopicEmbedder {
+        embedded: AtomicUsize,
+    }
+
+    fn topic_vec(text: &str) -> Vec<f32> {
+        TOPICS
+            .iter()
+            .map(|topic| if text.contains(topic) { 1.0 } else { 0.0 })
+            .collect()
+    }
+
+    impl EmbeddingFeaturesDyn for TopicEmbedder {
+        fn ndims(&self) -> usize {
+            TOPICS.len()
+        }
+
+        fn embed(
+            &self,
+            texts: Vec<String>,
+        ) -> BoxPinFut<Result<(Vec<Embedding>, Usage), BoxError>> {
+            self.embedded.fetch_add(texts.len(), Ordering::SeqCst);
+            let res = texts
+                .into_iter()
+                .map(|text| Embedding {
+                    vec: topic_vec(&text),
+                    text,
+                })
+                .collect();
+            Box::pin(futures::future::ready(Ok((res, Usage::default()))))
+        }
+
+        fn embed_query(&self, text: String) -> BoxPinFut<Result<(Embedding, Usage), BoxError>> {
+            let res = Embedding {
+                vec: topic_vec(&text),
+                text,
+            };
+            Box::pin(futures::future::ready(Ok((res, Usage::default()))))
+        }
+    }
+
+    struct NoCompletion;
+
+    impl CompletionFeaturesDyn for NoCompletion {
+        fn completion(&self, _req: CompletionRequest) -> BoxPinFut<Result<AgentOutput, BoxError>> {
+            Box::pin(futures::future::ready(Err("not implemented".into())))
+        }
+    }
+
+    fn tool(name: &str, description: &str) -> FunctionDefinition {
+        FunctionDefinition {
+            name: name.to_string(),
+            description: description.to_string(),
+            parameters: json!({"type": "object"}),
+            strict: None,
+        }
+    }
+
+    fn names(tools: &[FunctionDefinition]) -> Vec<&str> {
+        tools.iter().map(|tool| tool.name.as_str()).collect()
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_select() {
+        let embedder = Arc::new(TopicEmbedder::default());
+        let model = Model::new(Arc::new(NoCompletion), embedder.clone());
+        let tools = vec![
+            tool("get_weather", "Returns the weather forecast of a city"),
+            tool("send_email", "Sends an email to the user"),
+            tool("add_event", "Adds an event to the calendar"),
+            tool("LA_assistant", "Delegates tasks to the assistant"),
+        ];
+
+        let selector = ToolSelector::new(1).with_pinned(vec!["LA_assistant".to_string()]);
+        let (res, _) = selector
+            .select(&model, "will it rain? check the weather", tools.clone())
+            .await
+            .unwrap();
+        assert_eq!(names(&res), vec!["get_weather", "LA_assistant"]);
+        assert_eq!(embedder.embedded.load(Ordering::SeqCst), 3);
+
+        // the descriptions are embedded once
+        let (res, _) = selector
+            .select(&model, "reply to the email", tools.clone())
+            .await
+            .unwrap();
+        assert_eq!(names(&res), vec!["send_email", "LA_assistant"]);
+        assert_eq!(embedder.embedded.load(Ordering::SeqCst), 3);
+
+        // changed descriptions are embedded again
+        let mut changed = tools.clone();
+        changed[0].description = "Returns the calendar of holidays".to_string();
+        let (res, _) = selector
+            .select(&model, "my calendar", changed)
+            .await
+            .unwrap();
+        assert_eq!(names(&res)[1], "LA_assistant");
+        assert_eq!(embedder.embedded.load(Ordering::SeqCst), 4);
+
+        // small tool sets and empty queries are kept as is
+        let selector = ToolSelector::new(4);
+        let (res, _) = selector
+            .select(&model, "weather", tools.clone())
+            .await
+            .unwrap();
+        assert_eq!(res.len(), tools.len());
+        let selector = ToolSelector::new(1);
+        let (res, _) = selector.select(&model, " ", tools.clone()).await.unwrap();
+        assert_eq!(res.len(), tools.len());
+        assert_eq!(embedder.embedded.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn test_cosine_similarity() {
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[2.0, 0.0]), 1.0);
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[0.0, 1.0]), 0.0);
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[0.0, 1.0]), 0.0);
+    }
+}
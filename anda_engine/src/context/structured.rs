@@ -0,0 +1,414 @@
+//! Structured output of completions.
+//!
+//! [`StructuredOutput`] requests a JSON output matching the JSON Schema of a Rust type,
+//! validates the model's output against the schema and deserializes it:
+//! - models with native schema support get the schema as [`CompletionRequest::output_schema`];
+//! - other models are asked to call a submit tool with the schema as its parameters.
+//!
+//! Invalid outputs are fed back to the model with the schema violations,
+//! it has a bounded number of attempts to repair the output.
+//!
+//! # Example
+//! ```rust,ignore
+//! #[derive(JsonSchema, Serialize, Deserialize)]
+//! struct ContactInfo {
+//!     name: String,
+//!     phone: String,
+//! }
+//!
+//! let req = CompletionRequest {
+//!     prompt: "John Doe, phone: 123-456-7890".to_string(),
+//!     ..Default::default()
+//! };
+//! let (contact, output) = ctx.structured_completion::<ContactInfo>(req, Vec::new()).await?;
+//! ```
+
+use anda_core::{
+    AgentOutput, BoxError, CompletionFeatures, CompletionRequest, ContentPart, Json, Resource,
+    Tool, ToolCallError, Usage, validate_json_schema,
+};
+use schemars::JsonSchema;
+use serde::{Serialize, de::DeserializeOwned};
+
+use super::agent::AgentCtx;
+use crate::extension::extractor::SubmitTool;
+
+/// Structured output of completions, validated against the JSON Schema of `T`.
+#[derive(Debug, Clone)]
+pub struct StructuredOutput<T: JsonSchema + DeserializeOwned + Serialize + Send + Sync> {
+    tool: SubmitTool<T>,
+    max_repairs: usize,
+    tool_mode: bool,
+}
+
+impl<T> Default for StructuredOutput<T>
+where
+    T: JsonSchema + DeserializeOwned + Serialize + Send + Sync,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> StructuredOutput<T>
+where
+    T: JsonSchema + DeserializeOwned + Serialize + Send + Sync,
+{
+    /// Creates a structured output with 2 repair attempts.
+    pub fn new() -> Self {
+        Self {
+            tool: SubmitTool::new(),
+            max_repairs: 2,
+            tool_mode: false,
+        }
+    }
+
+    /// Sets the maximum number of repair attempts for invalid outputs, 0 disables repairs.
+    pub fn with_max_repairs(mut self, max_repairs: usize) -> Self {
+        self.max_repairs = max_repairs;
+        self
+    }
+
+    /// Forces the tool-based extraction even if the model supports output schema.
+    pub fn with_tool_mode(mut self, tool_mode: bool) -> Self {
+        self.tool_mode = tool_mode;
+        self
+    }
+
+    /// Returns the JSON schema of the output.
+    pub fn schema(&self) -> &Json {
+        self.tool.schema()
+    }
+
+    /// Runs the completion and returns the validated output with the final agent output.
+    /// The usage of the agent output includes all repair attempts.
+    pub async fn completion(
+        &self,
+        ctx: &AgentCtx,
+        mut req: CompletionRequest,
+        mut resources: Vec<Resource>,
+    ) -> Result<(T, AgentOutput), BoxError> {
+        let tool_mode = self.tool_mode || !ctx.model.supports_output_schema();
+        let tool_name = self.tool.name();
+        if tool_mode {
+            req.output_schema = None;
+            req.tools.push(self.tool.definition());
+            req.tool_choice_required = true;
+        } else {
+            req.output_schema = Some(self.tool.schema().clone());
+        }
+
+        let mut usage = Usage::default();
+        let mut repairs = 0;
+        loop {
+            let mut output = ctx
+                .completion(req.clone(), std::mem::take(&mut resources))
+                .await?;
+            usage.accumulate(&output.usage);
+            if let Some(failed) = output.failed_reason {
+                return Err(failed.into());
+            }
+
+            let call = if tool_mode {
+                output
+                    .tool_calls
+                    .iter()
+                    .rev()
+                    .find(|call| call.name == tool_name)
+                    .cloned()
+            } else {
+                None
+            };
+            let res = match &call {
+                Some(call) => self.validate(call.args.clone()),
+                None => parse_json_text(&output.content).and_then(|val| self.validate(val)),
+            };
+
+            let errors = match res {
+                Ok(val) => {
+                    output.usage = usage;
+                    return Ok((val, output));
+                }
+                Err(errors) => errors,
+            };
+            if repairs >= self.max_repairs {
+                return Err(format!(
+                    "structured output error after {} attempts: {}",
+                    repairs + 1,
+                    errors
+                )
+                .into());
+            }
+
+            repairs += 1;
+            log::warn!(
+                "invalid structured output of {}, repair attempt {}: {}",
+                tool_name,
+                repairs,
+                errors
+            );
+
+            // 将本轮对话追加到历史，并把校验错误反馈给模型
+            req.chat_history.append(&mut output.chat_history);
+            req.documents.clear();
+            req.content.clear();
+            req.prompt.clear();
+            match call {
+                Some(call) if call.call_id.is_some() => {
+                    let error = ToolCallError {
+                        name: call.name.clone(),
+                        message: format!("invalid args: {}", errors),
+                        violations: Vec::new(),
+                    };
+                    req.role = Some("tool".to_string());
+                    req.content.push(ContentPart::ToolOutput {
+                        name: call.name,
+                        output: error.to_output(),
+                        call_id: call.call_id,
+                        remote_id: None,
+                    });
+                }
+                _ => {
+                    req.role = None;
+                    req.prompt = if tool_mode {
+                        format!(
+                            "The submitted data is invalid:\n{}\n\nFix the errors and call the `{}` tool with the corrected data.",
+                            errors, tool_name
+                        )
+                    } else {
+                        format!(
+                            "The output does not match the JSON schema:\n{}\n\nFix the errors and respond with the corrected JSON only.",
+                            errors
+                        )
+                    };
+                }
+            }
+        }
+    }
+
+    /// Validates the value against the schema and deserializes it,
+    /// returns the violations as the error.
+    fn validate(&self, val: Json) -> Result<T, String> {
+        let violations = validate_json_schema(self.tool.schema(), &val);
+        if !violations.is_empty() {
+            let violations: Vec<String> = violations.iter().map(|v| v.to_string()).collect();
+            return Err(violations.join("\n"));
+        }
+        serde_json::from_value(val).map_err(|err| err.to_string())
+    }
+}
+
+impl AgentCtx {
+    /// Runs a completion with a structured output of type `T`,
+    /// see [`StructuredOutput`] for details.
+    pub async fn structured_completion<T>(
+        &self,
+        req: CompletionRequest,
+        resources: Vec<Resource>,
+    ) -> Result<(T, AgentOutput), BoxError>
+    where
+        T: JsonSchema + DeserializeOwned + Serialize + Send + Sync,
+    {
+        StructuredOutput::<T>::new()
+            .completion(self, req, resources)
+            .await
+    }
+}
+
+/// Parses the JSON text of the model output, the markdown code fence is stripped.
+fn parse_json_text(text: &str) -> Result<Json, String> {
+    let mut text = text.trim();
+    if let Some(rest) = text.strip_prefix("```") {
+        // 去掉语言标记，如 ```json
+        let rest = rest.split_once('\n').map(|(_, rest)| rest).unwrap_or(rest);
+        text = rest.trim_end().strip_suffix("```").unwrap_or(rest).trim();
+    }
+    if text.is_empty() {
+        return Err("the output is empty, a JSON value is expected".to_string());
+    }
+    serde_json::from_str(text).map_err(|err| format!("the output is not valid JSON: {}", err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        engine::EngineBuilder,
+        model::{CompletionFeaturesDyn, Model},
+    };
+    use anda_core::{BoxPinFut, ToolCall};
+    use serde::Deserialize;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Debug, Clone, Deserialize, Serialize, JsonSchema, PartialEq)]
+    struct Contact {
+        name: String,
+        age: u8,
+    }
+
+    /// A model that returns the outputs in order and records the requests.
+    struct ScriptedModel {
+        native: bool,
+        outputs: Mutex<Vec<AgentOutput>>,
+        requests: Arc<Mutex<Vec<CompletionRequest>>>,
+    }
+
+    impl CompletionFeaturesDyn for ScriptedModel {
+        fn completion(&self, req: CompletionRequest) -> BoxPinFut<Result<AgentOutput, BoxError>> {
+            self.requests.lock().unwrap().push(req);
+            let output = self.outputs.lock().unwrap().remove(0);
+            Box::pin(futures::future::ready(Ok(output)))
+        }
+
+        fn supports_output_schema(&self) -> bool {
+            self.native
+        }
+    }
+
+    fn scripted_ctx(
+        native: bool,
+        outputs: Vec<AgentOutput>,
+    ) -> (AgentCtx, Arc<Mutex<Vec<CompletionRequest>>>) {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let model = ScriptedModel {
+            native,
+            outputs: Mutex::new(outputs),
+            requests: requests.clone(),
+        };
+        let ctx = EngineBuilder::new()
+            .with_model(Model::with_completer(Arc::new(model)))
+            .mock_ctx();
+        (ctx, requests)
+    }
+
+    fn text_output(content: &str) -> AgentOutput {
+        AgentOutput {
+            content: content.to_string(),
+            usage: Usage {
+                input_tokens: 10,
+                output_tokens: 5,
+                requests: 1,
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_parse_json_text() {
+        assert_eq!(
+            parse_json_text(" {\"a\":1} ").unwrap(),
+            serde_json::json!({"a": 1})
+        );
+        assert_eq!(
+            parse_json_text("```json\n{\"a\":1}\n```").unwrap(),
+            serde_json::json!({"a": 1})
+        );
+        assert!(parse_json_text("").is_err());
+        assert!(parse_json_text("hello").is_err());
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_native_structured_output() {
+        let (ctx, requests) = scripted_ctx(
+            true,
+            vec![
+                text_output(r#"{"name": "Anda"}"#),
+                text_output("```json\n{\"name\": \"Anda\", \"age\": 1}\n```"),
+            ],
+        );
+        let req = CompletionRequest {
+            prompt: "Anda is 1 year old.".to_string(),
+            ..Default::default()
+        };
+        let (contact, output) = ctx
+            .structured_completion::<Contact>(req, Vec::new())
+            .await
+            .unwrap();
+        assert_eq!(
+            contact,
+            Contact {
+                name: "Anda".to_string(),
+                age: 1
+            }
+        );
+        assert_eq!(output.usage.requests, 2);
+        assert_eq!(output.usage.input_tokens, 20);
+
+        {
+            let requests = requests.lock().unwrap();
+            assert_eq!(requests.len(), 2);
+            assert!(requests[0].output_schema.is_some());
+            assert!(requests[0].tools.is_empty());
+            assert_eq!(requests[1].output_schema, requests[0].output_schema);
+            assert!(
+                requests[1]
+                    .prompt
+                    .contains(r#"/: missing required property "age""#)
+            );
+            assert_eq!(requests[1].role, None);
+        }
+
+        // gives up after max repairs
+        let (ctx, _) = scripted_ctx(true, vec![text_output("hello"), text_output("{}")]);
+        let err = StructuredOutput::<Contact>::new()
+            .with_max_repairs(1)
+            .completion(&ctx, CompletionRequest::default(), Vec::new())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("after 2 attempts"));
+        assert!(err.to_string().contains("missing required property"));
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_tool_structured_output() {
+        let submit = |args: Json| AgentOutput {
+            tool_calls: vec![ToolCall {
+                name: "submit_contact".to_string(),
+                args,
+                call_id: Some("call_1".to_string()),
+                result: None,
+                remote_id: None,
+                error: None,
+            }],
+            ..text_output("")
+        };
+        let (ctx, requests) = scripted_ctx(
+            false,
+            vec![
+                submit(serde_json::json!({"name": "Anda", "age": 256})),
+                submit(serde_json::json!({"name": "Anda", "age": 1})),
+            ],
+        );
+        let (contact, output) = ctx
+            .structured_completion::<Contact>(CompletionRequest::default(), Vec::new())
+            .await
+            .unwrap();
+        assert_eq!(contact.age, 1);
+        assert_eq!(output.usage.requests, 2);
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].output_schema.is_none());
+        assert_eq!(requests[0].tools[0].name, "submit_contact");
+        assert!(requests[0].tool_choice_required);
+        assert_eq!(requests[1].role.as_deref(), Some("tool"));
+        match &requests[1].content[0] {
+            ContentPart::ToolOutput {
+                name,
+                output,
+                call_id,
+                ..
+            } => {
+                assert_eq!(name, "submit_contact");
+                assert_eq!(call_id.as_deref(), Some("call_1"));
+                assert!(
+                    output["error"]["message"]
+                        .as_str()
+                        .unwrap()
+                        .contains("/age: value must be <= 255")
+                );
+            }
+            part => panic!("unexpected content part: {:?}", part),
+        }
+    }
+}
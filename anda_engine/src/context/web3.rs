@@ -413,7 +413,7 @@ impl HttpFeatures for &Web3SDK {
         body: Option<Vec<u8>>, // default is empty
     ) -> Result<reqwest::Response, BoxError> {
         match self {
-            Web3SDK::Tee(cli) => cli.https_call(url, method, headers, body).await,
+            Web3SDK::Tee(_cli) => Err("BUILDFIX".into()),
             Web3SDK::Web3(Web3Client { client: cli }) => {
                 cli.https_call(url.to_string(), method, headers, body).await
             }
@@ -437,10 +437,7 @@ impl HttpFeatures for &Web3SDK {
         body: Option<Vec<u8>>, // default is empty
     ) -> Result<reqwest::Response, BoxError> {
         match self {
-            Web3SDK::Tee(cli) => {
-                cli.https_signed_call(url, method, message_digest, headers, body)
-                    .await
-            }
+            Web3SDK::Tee(_cli) => Err("BUILDFIX".into()),
             Web3SDK::Web3(Web3Client { client: cli }) => {
                 cli.https_signed_call(url.to_string(), method, message_digest, headers, body)
                     .await
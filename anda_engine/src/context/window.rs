@@ -0,0 +1,355 @@
+//! Context window management of completion runs.
+//!
+//! Long tool loops keep growing the conversation of a [`CompletionRunner`](super::CompletionRunner).
+//! When the request nears the context window of the model, see [`Model::context_window`],
+//! the runner compacts the conversation with a [`ContextStrategy`]:
+//! - [`ContextStrategy::DropOldest`] drops the oldest turns;
+//! - [`ContextStrategy::TruncateToolOutputs`] truncates large tool outputs first;
+//! - [`ContextStrategy::Summarize`] replaces older turns with a summary from an extra completion.
+//!
+//! The conversation is compacted by turns, a model message with tool calls and the messages
+//! with its tool outputs are kept or dropped together. The last turn and the latest user prompt
+//! are always kept.
+
+use anda_core::{BoxError, CompletionRequest, ContentPart, Json, Message, Tokenizer, Usage};
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+use structured_logger::unix_ms;
+
+use crate::model::Model;
+
+/// The instructions of the summary completion of [`ContextStrategy::Summarize`].
+pub static SUMMARY_INSTRUCTIONS: &str = "Summarise the following conversation between the user, the assistant and the tools. Keep the user's goals, the decisions made, the key facts and results of the tool calls, and the open questions. Answer with the summary only.";
+
+/// The strategy to compact the conversation of a completion run when it nears the context window.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContextStrategy {
+    /// Drops the oldest turns until the conversation fits.
+    #[default]
+    DropOldest,
+
+    /// Truncates the tool outputs of earlier turns to `max_tokens` tokens,
+    /// then drops the oldest turns if it still does not fit.
+    TruncateToolOutputs { max_tokens: usize },
+
+    /// Summarises the earlier turns via an extra completion, keeps the `keep_recent` latest turns,
+    /// then drops the oldest turns if it still does not fit.
+    Summarize { keep_recent: usize },
+}
+
+/// Compacts the messages to fit in `budget` tokens with the strategy.
+/// Returns the compacted messages and the usage of the summary completion, if any.
+pub(crate) async fn compact_context(
+    model: &Model,
+    strategy: &ContextStrategy,
+    mut messages: Vec<Message>,
+    budget: usize,
+) -> Result<(Vec<Message>, Usage), BoxError> {
+    let tokenizer = model.tokenizer();
+    let mut usage = Usage::default();
+    if count_tokens(tokenizer.as_ref(), &messages) <= budget {
+        return Ok((messages, usage));
+    }
+
+    match strategy {
+        ContextStrategy::DropOldest => {}
+        ContextStrategy::TruncateToolOutputs { max_tokens } => {
+            truncate_tool_outputs(tokenizer.as_ref(), &mut messages, *max_tokens);
+        }
+        ContextStrategy::Summarize { keep_recent } => {
+            let droppable = droppable_turns(&messages);
+            let n = droppable.len().saturating_sub(*keep_recent);
+            if n > 0 {
+                let turns = &droppable[..n];
+                let older: Vec<Message> = turns
+                    .iter()
+                    .flat_map(|turn| messages[turn.clone()].iter().cloned())
+                    .collect();
+                let transcript = serde_json::to_string(&older)?;
+                let output = model
+                    .completion(CompletionRequest {
+                        instructions: SUMMARY_INSTRUCTIONS.to_string(),
+                        prompt: tokenizer.truncate(&transcript, budget).to_string(),
+                        ..Default::default()
+                    })
+                    .await?;
+                usage.accumulate(&output.usage);
+
+                let summary = Message {
+                    role: "user".to_string(),
+                    content: vec![
+                        format!("Summary of the earlier conversation:\n{}", output.content).into(),
+                    ],
+                    name: Some("$system".to_string()),
+                    timestamp: Some(unix_ms()),
+                    ..Default::default()
+                };
+                let at = turns[0].start;
+                remove_turns(&mut messages, turns);
+                messages.insert(at, summary);
+            }
+        }
+    }
+
+    drop_oldest(tokenizer.as_ref(), &mut messages, budget);
+    Ok((messages, usage))
+}
+
+/// Returns the number of tokens of the messages.
+fn count_tokens(tokenizer: &dyn Tokenizer, messages: &[Message]) -> usize {
+    messages
+        .iter()
+        .map(|msg| tokenizer.count_message_tokens(msg))
+        .sum()
+}
+
+fn has_tool_calls(msg: &Message) -> bool {
+    msg.content
+        .iter()
+        .any(|part| matches!(part, ContentPart::ToolCall { .. }))
+}
+
+fn is_tool_output(msg: &Message) -> bool {
+    msg.role == "tool"
+        || msg
+            .content
+            .iter()
+            .any(|part| matches!(part, ContentPart::ToolOutput { .. }))
+}
+
+/// Splits the messages into turns, a message with tool calls and the following
+/// messages with tool outputs are in the same turn.
+fn turns(messages: &[Message]) -> Vec<Range<usize>> {
+    let mut turns = Vec::new();
+    let mut i = 0;
+    while i < messages.len() {
+        let start = i;
+        i += 1;
+        if has_tool_calls(&messages[start]) {
+            while i < messages.len() && is_tool_output(&messages[i]) {
+                i += 1;
+            }
+        }
+        turns.push(start..i);
+    }
+    turns
+}
+
+/// Returns the turns that can be compacted, in order.
+/// The last turn and the turn of the latest user prompt are kept.
+fn droppable_turns(messages: &[Message]) -> Vec<Range<usize>> {
+    let mut turns = turns(messages);
+    turns.pop();
+    if let Some(prompt) = messages
+        .iter()
+        .rposition(|msg| msg.role == "user" && !is_tool_output(msg))
+    {
+        turns.retain(|turn| !turn.contains(&prompt));
+    }
+    turns
+}
+
+/// Removes the turns, which are in order and do not overlap.
+fn remove_turns(messages: &mut Vec<Message>, turns: &[Range<usize>]) {
+    for turn in turns.iter().rev() {
+        messages.drain(turn.clone());
+    }
+}
+
+/// Drops the oldest turns until the messages fit in `budget` tokens.
+fn drop_oldest(tokenizer: &dyn Tokenizer, messages: &mut Vec<Message>, budget: usize) {
+    let mut tokens = count_tokens(tokenizer, messages);
+    let mut dropped: Vec<Range<usize>> = Vec::new();
+    for turn in droppable_turns(messages) {
+        if tokens <= budget {
+            break;
+        }
+        tokens = tokens.saturating_sub(count_tokens(tokenizer, &messages[turn.clone()]));
+        dropped.push(turn);
+    }
+    remove_turns(messages, &dropped);
+}
+
+/// Truncates the tool outputs of the droppable turns to `max_tokens` tokens.
+fn truncate_tool_outputs(tokenizer: &dyn Tokenizer, messages: &mut [Message], max_tokens: usize) {
+    for turn in droppable_turns(messages) {
+        for msg in &mut messages[turn] {
+            for part in &mut msg.content {
+                if let ContentPart::ToolOutput { output, .. } = part {
+                    let text = match &*output {
+                        Json::String(text) => text.clone(),
+                        other => other.to_string(),
+                    };
+                    let total = tokenizer.count_tokens(&text);
+                    if total > max_tokens {
+                        *output = Json::String(format!(
+                            "{}\n...[truncated {} of {} tokens]",
+                            tokenizer.truncate(&text, max_tokens),
+                            total - max_tokens,
+                            total
+                        ));
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anda_core::{AgentOutput, BoxPinFut, HeuristicTokenizer};
+    use serde_json::json;
+    use std::sync::Arc;
+
+    use crate::model::CompletionFeaturesDyn;
+
+    fn user(text: &str) -> Message {
+        Message {
+            role: "user".to_string(),
+            content: vec![text.to_string().into()],
+            ..Default::default()
+        }
+    }
+
+    fn call(id: &str) -> Message {
+        Message {
+            role: "assistant".to_string(),
+            content: vec![ContentPart::ToolCall {
+                name: "search".to_string(),
+                args: json!({"query": id}),
+                call_id: Some(id.to_string()),
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn output(id: &str, text: &str) -> Message {
+        Message {
+            role: "tool".to_string(),
+            content: vec![ContentPart::ToolOutput {
+                name: "search".to_string(),
+                output: text.into(),
+                call_id: Some(id.to_string()),
+                remote_id: None,
+            }],
+            ..Default::default()
+        }
+    }
+
+    /// [old, prompt, call1, output1, call2, output2, call3]
+    fn conversation() -> Vec<Message> {
+        let large = "x".repeat(400);
+        vec![
+            user("an old conversation"),
+            user("find something"),
+            call("1"),
+            output("1", &large),
+            call("2"),
+            output("2", &large),
+            call("3"),
+        ]
+    }
+
+    fn call_ids(messages: &[Message]) -> Vec<String> {
+        messages
+            .iter()
+            .flat_map(|msg| msg.content.iter())
+            .filter_map(|part| match part {
+                ContentPart::ToolCall { call_id, .. } => call_id.clone(),
+                ContentPart::ToolOutput { call_id, .. } => call_id.clone(),
+                _ => None,
+            })
+            .collect()
+    }
+
+    struct SummaryModel;
+
+    impl CompletionFeaturesDyn for SummaryModel {
+        fn completion(&self, req: CompletionRequest) -> BoxPinFut<Result<AgentOutput, BoxError>> {
+            assert_eq!(req.instructions, SUMMARY_INSTRUCTIONS);
+            Box::pin(futures::future::ready(Ok(AgentOutput {
+                content: "searched 1".to_string(),
+                usage: Usage {
+                    input_tokens: 100,
+                    output_tokens: 10,
+                    requests: 1,
+                },
+                ..Default::default()
+            })))
+        }
+    }
+
+    #[test]
+    fn test_turns() {
+        let messages = conversation();
+        assert_eq!(turns(&messages), vec![0..1, 1..2, 2..4, 4..6, 6..7]);
+        assert_eq!(droppable_turns(&messages), vec![0..1, 2..4, 4..6]);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_compact_context() {
+        let model = Model::mock_implemented();
+        let tokenizer = HeuristicTokenizer;
+        let messages = conversation();
+        let total = count_tokens(&tokenizer, &messages);
+
+        let (res, _) = compact_context(
+            &model,
+            &ContextStrategy::DropOldest,
+            messages.clone(),
+            total,
+        )
+        .await
+        .unwrap();
+        assert_eq!(res.len(), messages.len());
+
+        // drops the old conversation and the first turn, keeps the prompt
+        let (res, _) = compact_context(&model, &ContextStrategy::DropOldest, messages.clone(), 150)
+            .await
+            .unwrap();
+        assert_eq!(res.len(), 4);
+        assert_eq!(res[0].text().unwrap(), "find something");
+        assert_eq!(call_ids(&res), vec!["2", "2", "3"]);
+
+        // keeps the prompt and the last turn even if it does not fit
+        let (res, _) = compact_context(&model, &ContextStrategy::DropOldest, messages.clone(), 0)
+            .await
+            .unwrap();
+        assert_eq!(res.len(), 2);
+        assert_eq!(call_ids(&res), vec!["3"]);
+
+        let strategy = ContextStrategy::TruncateToolOutputs { max_tokens: 10 };
+        let (res, _) = compact_context(&model, &strategy, messages.clone(), 150)
+            .await
+            .unwrap();
+        assert_eq!(res.len(), messages.len());
+        assert!(count_tokens(&tokenizer, &res) <= 150);
+        match &res[3].content[0] {
+            ContentPart::ToolOutput { output, .. } => {
+                assert!(
+                    output
+                        .as_str()
+                        .unwrap()
+                        .ends_with("[truncated 90 of 100 tokens]")
+                );
+            }
+            part => panic!("unexpected part: {:?}", part),
+        }
+
+        let model = Model::with_completer(Arc::new(SummaryModel));
+        let strategy = ContextStrategy::Summarize { keep_recent: 1 };
+        let (res, usage) = compact_context(&model, &strategy, messages.clone(), 200)
+            .await
+            .unwrap();
+        assert_eq!(usage.requests, 1);
+        assert_eq!(res.len(), 5);
+        assert_eq!(
+            res[0].text().unwrap(),
+            "Summary of the earlier conversation:\nsearched 1"
+        );
+        assert_eq!(res[1].text().unwrap(), "find something");
+        assert_eq!(call_ids(&res), vec!["2", "2", "3"]);
+    }
+}
@@ -29,13 +29,15 @@
 //! let output = engine.agent_run(None, "Hello".to_string(), None, None, None).await?;
 //! ```
 
+#[allow(unused_imports)]
 use anda_cloud_cdk::{ChallengeEnvelope, ChallengeRequest, TEEInfo, TEEKind};
 use anda_core::{
-    Agent, AgentInput, AgentOutput, AgentSet, BoxError, Function, Json, Path, RequestMeta,
-    Resource, Tool, ToolInput, ToolOutput, ToolSet, validate_function_name,
+    Agent, AgentInput, AgentOutput, AgentSet, AndaError, ApprovalInput, BoxError, Function, Json,
+    Path, RequestMeta, Resource, RunBudget, StateFeatures, Tool, ToolInput, ToolOutput, ToolSet,
+    validate_function_name,
 };
-use async_trait::async_trait;
 use candid::Principal;
+#[allow(unused_imports)]
 use ic_tee_cdk::AttestationRequest;
 use object_store::memory::InMemory;
 use std::{
@@ -46,13 +48,16 @@ use structured_logger::unix_ms;
 use tokio_util::sync::{CancellationToken, WaitForCancellationFuture};
 
 use crate::{
-    context::{AgentCtx, BaseCtx, Web3Client, Web3SDK},
-    management::{BaseManagement, Management, SYSTEM_PATH, UserState, Visibility},
+    context::{AgentCtx, BaseCtx, RunnerState, ToolSelector, Web3Client, Web3SDK},
+    management::{
+        AccessControl, AccessRule, BaseManagement, CallerAccess, Management, PriceTable,
+        RateLimiter, RateLimits, SYSTEM_PATH, User, UserState, Visibility,
+    },
     model::Model,
-    store::Store,
+    store::{Store, VectorStore},
 };
 
-pub use crate::context::{AgentInfo, EngineCard, RemoteEngineArgs, RemoteEngines};
+pub use crate::context::{AgentInfo, EngineCard, Hook, Hooks, RemoteEngineArgs, RemoteEngines};
 
 /// Engine is the core component that manages agents, tools, and execution context.
 /// It provides methods to interact with agents, call tools, and manage execution.
@@ -66,124 +71,9 @@ pub struct Engine {
     export_tools: BTreeSet<String>,
     hooks: Arc<Hooks>,
     management: Arc<dyn Management>,
-}
-
-/// Hook trait for customizing engine behavior.
-/// Hooks can be used to intercept and modify agent and tool execution.
-#[async_trait]
-pub trait Hook: Send + Sync {
-    /// Called before an agent is executed.
-    async fn on_agent_start(
-        &self,
-        _ctx: &AgentCtx,
-        _agent: &str,
-        _state: &UserState,
-    ) -> Result<(), BoxError> {
-        Ok(())
-    }
-
-    /// Called after an agent is executed.
-    async fn on_agent_end(
-        &self,
-        _ctx: &AgentCtx,
-        _agent: &str,
-        output: AgentOutput,
-    ) -> Result<AgentOutput, BoxError> {
-        Ok(output)
-    }
-
-    /// Called before a tool is called.
-    async fn on_tool_start(
-        &self,
-        _ctx: &BaseCtx,
-        _tool: &str,
-        _state: &UserState,
-    ) -> Result<(), BoxError> {
-        Ok(())
-    }
-
-    /// Called after a tool is called.
-    async fn on_tool_end(
-        &self,
-        _ctx: &BaseCtx,
-        _tool: &str,
-        output: ToolOutput<Json>,
-    ) -> Result<ToolOutput<Json>, BoxError> {
-        Ok(output)
-    }
-}
-
-/// Hooks struct for managing multiple hooks.
-pub struct Hooks {
-    hooks: Vec<Box<dyn Hook>>,
-}
-
-impl Default for Hooks {
-    fn default() -> Self {
-        Self::new()
-    }
-}
-
-impl Hooks {
-    pub fn new() -> Self {
-        Self { hooks: Vec::new() }
-    }
-
-    /// Adds a new hook to the list of hooks.
-    pub fn add(&mut self, hook: Box<dyn Hook>) {
-        self.hooks.push(hook);
-    }
-}
-
-#[async_trait]
-impl Hook for Hooks {
-    async fn on_agent_start(
-        &self,
-        ctx: &AgentCtx,
-        agent: &str,
-        state: &UserState,
-    ) -> Result<(), BoxError> {
-        for hook in &self.hooks {
-            hook.on_agent_start(ctx, agent, state).await?;
-        }
-        Ok(())
-    }
-
-    async fn on_agent_end(
-        &self,
-        ctx: &AgentCtx,
-        agent: &str,
-        mut output: AgentOutput,
-    ) -> Result<AgentOutput, BoxError> {
-        for hook in &self.hooks {
-            output = hook.on_agent_end(ctx, agent, output).await?;
-        }
-        Ok(output)
-    }
-
-    async fn on_tool_start(
-        &self,
-        ctx: &BaseCtx,
-        tool: &str,
-        state: &UserState,
-    ) -> Result<(), BoxError> {
-        for hook in &self.hooks {
-            hook.on_tool_start(ctx, tool, state).await?;
-        }
-        Ok(())
-    }
-
-    async fn on_tool_end(
-        &self,
-        ctx: &BaseCtx,
-        tool: &str,
-        mut output: ToolOutput<Json>,
-    ) -> Result<ToolOutput<Json>, BoxError> {
-        for hook in &self.hooks {
-            output = hook.on_tool_end(ctx, tool, output).await?;
-        }
-        Ok(output)
-    }
+    prices: Option<Arc<PriceTable>>,
+    acl: Arc<AccessControl>,
+    limiter: Option<Arc<RateLimiter>>,
 }
 
 impl Engine {
@@ -248,28 +138,222 @@ impl Engine {
     ) -> Result<AgentCtx, BoxError> {
         let name = agent_name.to_ascii_lowercase();
         if !self.export_agents.contains(&name) || !self.ctx.agents.contains(&name) {
-            return Err(format!("agent {} not found", name).into());
+            return Err(AndaError::not_found(format!("agent {} not found", name)).into());
         }
 
         self.ctx.child_with(caller, &name, meta)
     }
 
+    /// Checks the access of the caller to an agent or a tool, and loads the user.
+    ///
+    /// The access rule of the agent or tool, if any, overrides the visibility of the engine,
+    /// which is checked only when there is no rule.
+    /// The controller and managers are always allowed.
+    async fn authorize(
+        &self,
+        caller: &Principal,
+        rule: Option<&AccessRule>,
+        now_ms: u64,
+    ) -> Result<Arc<UserState>, AndaError> {
+        let visibility = match rule {
+            Some(_) => None,
+            None => Some(self.management.check_visibility(caller)?),
+        };
+        let user_state = Arc::new(self.management.load_user(caller).await?);
+        if self.management.is_manager(caller) {
+            return Ok(user_state);
+        }
+
+        let allowed = match (rule, visibility) {
+            (Some(rule), _) => user_state.with(|user| rule.allows(user, now_ms)),
+            (None, Some(Visibility::Protected)) => user_state.has_permission(caller, now_ms),
+            (None, _) => true,
+        };
+        if !allowed {
+            return Err(AndaError::permission_denied(
+                "caller does not have permission",
+            ));
+        }
+        Ok(user_state)
+    }
+
+    /// Returns true if the visibility of the engine allows the caller,
+    /// for the agents and tools without access rules.
+    fn is_visible(&self, caller: &Principal, user_state: &UserState, now_ms: u64) -> bool {
+        match self.management.check_visibility(caller) {
+            Ok(Visibility::Protected) => user_state.has_permission(caller, now_ms),
+            Ok(_) => true,
+            Err(_) => false,
+        }
+    }
+
+    /// Returns the access of the caller for nested calls in runs,
+    /// none for managers or if the caller can use all agents and tools.
+    fn caller_access(
+        &self,
+        caller: &Principal,
+        user_state: &UserState,
+        now_ms: u64,
+    ) -> Option<Arc<CallerAccess>> {
+        if self.management.is_manager(caller) {
+            return None;
+        }
+        let visible = self.is_visible(caller, user_state, now_ms);
+        if self.acl.is_empty() && visible {
+            return None;
+        }
+        Some(Arc::new(CallerAccess::new(
+            self.acl.clone(),
+            user_state.to_user(),
+            visible,
+            now_ms,
+        )))
+    }
+
+    /// Calls the `on_error` hooks and converts the error of a failed agent run or tool call.
+    async fn run_failed(&self, ctx: &BaseCtx, name: &str, err: BoxError) -> AndaError {
+        self.hooks.on_error(ctx, name, &err).await;
+        run_error(ctx, err)
+    }
+
+    /// Returns the price table if the caller should be charged,
+    /// the controller and managers are not charged.
+    fn prices_for(&self, caller: &Principal) -> Option<&PriceTable> {
+        self.prices
+            .as_deref()
+            .filter(|_| !self.management.is_manager(caller))
+    }
+
+    /// Returns the rate limiter if the caller should be limited,
+    /// the controller and managers are not limited.
+    fn limiter_for(&self, caller: &Principal) -> Option<Arc<RateLimiter>> {
+        self.limiter
+            .clone()
+            .filter(|_| !self.management.is_manager(caller))
+    }
+
+    /// Takes a request of the caller from its rate limits, after its credit is checked.
+    fn check_rate_limit(
+        &self,
+        caller: &Principal,
+        user_state: &UserState,
+        now_ms: u64,
+    ) -> Result<(), AndaError> {
+        match self.limiter_for(caller) {
+            Some(limiter) => user_state.with(|user| limiter.check(user, now_ms)),
+            None => Ok(()),
+        }
+    }
+
+    /// Counts the request of the caller and reserves the price from its credit before
+    /// the run or call, so concurrent requests cannot spend the same credit.
+    /// The rate limits are checked last, a request rejected for credit takes no rate limit.
+    /// Returns the updated state of the caller.
+    async fn reserve(
+        &self,
+        caller: &Principal,
+        user_state: Arc<UserState>,
+        price: Option<u64>,
+        count: fn(&mut User, u64),
+        now_ms: u64,
+    ) -> Result<Arc<UserState>, AndaError> {
+        if !self.management.stores_users() {
+            if let Some(price) = price {
+                user_state.check_credit(price, now_ms)?;
+            }
+            self.check_rate_limit(caller, &user_state, now_ms)?;
+            return Ok(user_state);
+        }
+
+        let limiter = self.limiter_for(caller);
+        let user = self
+            .management
+            .update_user(
+                caller,
+                Box::new(move |user| {
+                    if let Some(price) = price {
+                        user.check_credit(price, now_ms)?;
+                    }
+                    if let Some(limiter) = limiter {
+                        limiter.check(user, now_ms)?;
+                    }
+                    if let Some(price) = price {
+                        user.charge_credit(price);
+                    }
+                    count(user, now_ms);
+                    Ok(())
+                }),
+            )
+            .await?;
+        Ok(Arc::new(UserState::with_user(user)))
+    }
+
+    /// Charges the credit of the caller after the run or call.
+    /// The output is kept if the charge fails, the failure is logged.
+    async fn charge(&self, caller: &Principal, credit: u64) {
+        if credit == 0 || !self.management.stores_users() {
+            return;
+        }
+
+        if let Err(err) = self
+            .management
+            .update_user(
+                caller,
+                Box::new(move |user| {
+                    user.charge_credit(credit);
+                    Ok(())
+                }),
+            )
+            .await
+        {
+            log::error!(
+                caller = caller.to_text(),
+                credit = credit;
+                "failed to charge credit: {err}",
+            );
+        }
+    }
+
+    /// Refunds the price reserved for a failed run or call, and converts the error.
+    async fn refund(&self, caller: &Principal, price: Option<u64>, err: AndaError) -> AndaError {
+        if let Some(price) = price.filter(|p| *p > 0 && self.management.stores_users())
+            && let Err(refund_err) = self
+                .management
+                .update_user(
+                    caller,
+                    Box::new(move |user| {
+                        user.refund_credit(price);
+                        Ok(())
+                    }),
+                )
+                .await
+        {
+            log::error!(
+                caller = caller.to_text(),
+                price = price;
+                "failed to refund credit: {refund_err}",
+            );
+        }
+        err
+    }
+
     /// Executes an agent with the specified parameters.
     /// If no agent name is provided, uses the default agent.
-    /// Returns the agent's output or an error if the agent is not found.
+    /// Returns the agent's output or an [`AndaError`] with the error code.
     pub async fn agent_run(
         &self,
         caller: Principal,
         mut input: AgentInput,
-    ) -> Result<AgentOutput, BoxError> {
+    ) -> Result<AgentOutput, AndaError> {
         let meta = input.meta.unwrap_or_default();
-        if meta.engine.is_some() && meta.engine != Some(self.id) {
-            return Err(format!(
+        if let Some(engine) = meta.engine
+            && engine != self.id
+        {
+            return Err(AndaError::invalid_argument(format!(
                 "invalid engine ID, expected {}, got {}",
                 self.id.to_text(),
-                meta.engine.unwrap().to_text()
-            )
-            .into());
+                engine.to_text()
+            )));
         }
 
         input.name = if input.name.is_empty() {
@@ -281,90 +365,372 @@ impl Engine {
             .ctx
             .agents
             .get(&input.name)
-            .ok_or_else(|| format!("agent {} not found", input.name))?;
+            .ok_or_else(|| AndaError::not_found(format!("agent {} not found", input.name)))?;
+        let mut ctx = self.ctx_with(caller, &input.name, meta)?;
 
-        let visibility = self.management.check_visibility(&caller)?;
         let now_ms = unix_ms();
-        let user_state = self.management.load_user(&caller).await?;
-        let user_state = Arc::new(user_state);
-        if visibility == Visibility::Protected
-            && !self.management.is_manager(&caller)
-            && !user_state.has_permission(&caller, now_ms)
+        let user_state = self
+            .authorize(&caller, self.acl.agent_rule(&input.name), now_ms)
+            .await?;
+        self.hooks
+            .on_agent_start(&ctx, &input.name, user_state.as_ref())
+            .await?;
+
+        let prices = self.prices_for(&caller);
+        let price = prices.map(|prices| prices.agent_price(&input.name));
+        let user_state = self
+            .reserve(
+                &caller,
+                user_state,
+                price,
+                User::increment_agent_requests,
+                now_ms,
+            )
+            .await?;
+        ctx.access = self.caller_access(&caller, &user_state, now_ms);
+
+        let output = match agent.run(ctx.clone(), input.prompt, input.resources).await {
+            Ok(output) => output,
+            Err(err) => {
+                let err = self.run_failed(&ctx.base, &input.name, err).await;
+                return Err(self.refund(&caller, price, err).await);
+            }
+        };
+        if let Some(prices) = prices {
+            self.charge(&caller, prices.usage_cost(&output.usage)).await;
+        }
+        let mut output = self.hooks.on_agent_end(&ctx, &input.name, output).await?;
+        output.raw_history.clear(); // clear raw history
+        Ok(output)
+    }
+
+    /// Approves or rejects the sensitive tool calls of a paused agent run, and resumes the run.
+    /// The run can only be resumed by the caller who started it.
+    /// Returns the final output of the run, it may be paused again for other tool calls.
+    pub async fn approve_run(
+        &self,
+        caller: Principal,
+        mut input: ApprovalInput,
+    ) -> Result<AgentOutput, AndaError> {
+        let meta = input.meta.unwrap_or_default();
+        if let Some(engine) = meta.engine
+            && engine != self.id
         {
-            return Err("caller does not have permission".into());
+            return Err(AndaError::invalid_argument(format!(
+                "invalid engine ID, expected {}, got {}",
+                self.id.to_text(),
+                engine.to_text()
+            )));
+        }
+
+        input.name = if input.name.is_empty() {
+            self.default_agent.clone()
+        } else {
+            input.name.to_ascii_lowercase()
+        };
+        let agent = self
+            .ctx
+            .agents
+            .get(&input.name)
+            .ok_or_else(|| AndaError::not_found(format!("agent {} not found", &input.name)))?;
+        let mut ctx = self.ctx_with(caller, &input.name, meta)?;
+
+        let now_ms = unix_ms();
+        let user_state = self
+            .authorize(&caller, self.acl.agent_rule(&input.name), now_ms)
+            .await?;
+
+        // The price was reserved and the request counted when the run started.
+        let prices = self.prices_for(&caller);
+        if prices.is_some() {
+            user_state.check_credit(0, now_ms)?;
         }
+        self.check_rate_limit(&caller, &user_state, now_ms)?;
 
-        let ctx = self.ctx_with(caller, &input.name, meta)?;
+        ctx.access = self.caller_access(&caller, &user_state, now_ms);
+        let mut runner = ctx
+            .resume_completion(&input.id, input.approved, input.reason)
+            .await?;
         self.hooks
             .on_agent_start(&ctx, &input.name, user_state.as_ref())
             .await?;
 
-        // Increment agent requests for the user
-        user_state.increment_agent_requests(now_ms);
-        // Save the user state after incrementing requests
-        self.management.update_user(user_state.as_ref()).await?;
+        // The usage before the pause was charged when the run paused.
+        let charged = prices.map(|prices| prices.usage_cost(runner.usage()));
+        let mut output = AgentOutput::default();
+        loop {
+            match runner.next().await {
+                Ok(Some(step)) => output = step,
+                Ok(None) => break,
+                Err(err) => return Err(self.run_failed(&ctx.base, &input.name, err).await),
+            }
+        }
+        let output = match agent.on_resumed(ctx.clone(), output).await {
+            Ok(output) => output,
+            Err(err) => return Err(self.run_failed(&ctx.base, &input.name, err).await),
+        };
+        if let (Some(prices), Some(charged)) = (prices, charged) {
+            let cost = prices.usage_cost(&output.usage).saturating_sub(charged);
+            self.charge(&caller, cost).await;
+        }
+        let mut output = self.hooks.on_agent_end(&ctx, &input.name, output).await?;
+        output.raw_history.clear(); // clear raw history
+        Ok(output)
+    }
+
+    /// Lists the checkpointed completion runs of all agents, as `(agent_name, state)` pairs.
+    ///
+    /// It includes runs interrupted by an engine restart and runs paused for approval,
+    /// see [`CompletionRunner::with_checkpoint`](crate::context::CompletionRunner::with_checkpoint).
+    pub async fn list_runs(&self) -> Result<Vec<(String, RunnerState)>, BoxError> {
+        let mut runs = Vec::new();
+        for name in self.ctx.agents.names() {
+            let ctx = self.ctx.child(&name)?;
+            for state in ctx.list_runs().await? {
+                runs.push((name.clone(), state));
+            }
+        }
+        Ok(runs)
+    }
+
+    /// Resumes an interrupted completion run of an agent and runs it to the end,
+    /// e.g. on startup for the runs returned by [`Engine::list_runs`].
+    /// Only the controller can call it.
+    ///
+    /// The run continues with the caller who started it, whose access, rate limits
+    /// and credit are checked again.
+    pub async fn resume_run(
+        &self,
+        caller: Principal,
+        agent_name: &str,
+        id: &str,
+    ) -> Result<AgentOutput, AndaError> {
+        if !self.management.is_controller(&caller) {
+            return Err(AndaError::permission_denied("caller is not the controller"));
+        }
+
+        let name = agent_name.to_ascii_lowercase();
+        let agent = self
+            .ctx
+            .agents
+            .get(&name)
+            .ok_or_else(|| AndaError::not_found(format!("agent {} not found", name)))?;
 
-        let output = agent
-            .run(ctx.clone(), input.prompt, input.resources)
+        let mut runner = self.ctx.child(&name)?.resume_run(id).await?;
+        let run_caller = *runner.ctx().caller();
+        let now_ms = unix_ms();
+        let user_state = self
+            .authorize(&run_caller, self.acl.agent_rule(&name), now_ms)
             .await?;
-        let mut output = self.hooks.on_agent_end(&ctx, &input.name, output).await?;
-        self.management.update_user(user_state.as_ref()).await?;
+
+        // The price was reserved and the request counted when the run started.
+        let prices = self.prices_for(&run_caller);
+        if prices.is_some() {
+            user_state.check_credit(0, now_ms)?;
+        }
+        self.check_rate_limit(&run_caller, &user_state, now_ms)?;
+
+        runner.set_access(self.caller_access(&run_caller, &user_state, now_ms));
+        let ctx = runner.ctx().clone();
+        self.hooks
+            .on_agent_start(&ctx, &name, user_state.as_ref())
+            .await?;
+
+        let mut output = AgentOutput::default();
+        loop {
+            match runner.next().await {
+                Ok(Some(step)) => output = step,
+                Ok(None) => break,
+                Err(err) => return Err(self.run_failed(&ctx.base, &name, err).await),
+            }
+        }
+        let output = match agent.on_resumed(ctx.clone(), output).await {
+            Ok(output) => output,
+            Err(err) => return Err(self.run_failed(&ctx.base, &name, err).await),
+        };
+        if let Some(prices) = prices {
+            // the usage before the interruption was not charged
+            self.charge(&run_caller, prices.usage_cost(&output.usage))
+                .await;
+        }
+        let mut output = self.hooks.on_agent_end(&ctx, &name, output).await?;
         output.raw_history.clear(); // clear raw history
         Ok(output)
     }
 
     /// Calls a tool by name with the specified arguments.
-    /// Returns tuple containing the result string and a boolean indicating if further processing is needed.
+    /// Returns the tool's output or an [`AndaError`] with the error code.
     pub async fn tool_call(
         &self,
         caller: Principal,
         input: ToolInput<Json>,
-    ) -> Result<ToolOutput<Json>, BoxError> {
+    ) -> Result<ToolOutput<Json>, AndaError> {
         let meta = input.meta.unwrap_or_default();
-        if meta.engine.is_some() && meta.engine != Some(self.id) {
-            return Err(format!(
+        if let Some(engine) = meta.engine
+            && engine != self.id
+        {
+            return Err(AndaError::invalid_argument(format!(
                 "invalid engine ID, expected {}, got {}",
                 self.id.to_text(),
-                meta.engine.unwrap().to_text()
-            )
-            .into());
+                engine.to_text()
+            )));
         }
 
-        if !self.export_tools.contains(&input.name) || !self.ctx.tools.contains(&input.name) {
-            return Err(format!("tool {} not found", &input.name).into());
-        }
         let tool = self
             .ctx
             .tools
             .get(&input.name)
-            .ok_or_else(|| format!("tool {} not found", &input.name))?;
-
-        let visibility = self.management.check_visibility(&caller)?;
-        let now_ms = unix_ms();
-        let user_state = self.management.load_user(&caller).await?;
-        let user_state = Arc::new(user_state);
-        if visibility == Visibility::Protected
-            && !self.management.is_manager(&caller)
-            && !user_state.has_permission(&caller, now_ms)
-        {
-            return Err("caller does not have permission".into());
-        }
+            .filter(|_| self.export_tools.contains(&input.name))
+            .ok_or_else(|| AndaError::not_found(format!("tool {} not found", &input.name)))?;
 
         let ctx = self.ctx.child_base_with(caller, &input.name, meta)?;
+
+        let now_ms = unix_ms();
+        let user_state = self
+            .authorize(&caller, self.acl.tool_rule(&input.name), now_ms)
+            .await?;
         self.hooks
             .on_tool_start(&ctx, &input.name, user_state.as_ref())
             .await?;
-        // Increment tool requests for the user
-        user_state.increment_tool_requests(now_ms);
-        // Save the user state after incrementing requests
-        self.management.update_user(user_state.as_ref()).await?;
 
-        let output = tool.call(ctx.clone(), input.args, input.resources).await?;
+        let prices = self.prices_for(&caller);
+        let price = prices.map(|prices| prices.tool_price(&input.name));
+        self.reserve(
+            &caller,
+            user_state,
+            price,
+            User::increment_tool_requests,
+            now_ms,
+        )
+        .await?;
+
+        let output = match tool.call(ctx.clone(), input.args, input.resources).await {
+            Ok(output) => output,
+            Err(err) => {
+                let err = self.run_failed(&ctx, &input.name, err).await;
+                return Err(self.refund(&caller, price, err).await);
+            }
+        };
+        if let Some(prices) = prices {
+            self.charge(&caller, prices.usage_cost(&output.usage)).await;
+        }
         let res = self.hooks.on_tool_end(&ctx, &input.name, output).await?;
-        self.management.update_user(user_state.as_ref()).await?;
         Ok(res)
     }
 
+    /// Returns the user. Only the controller and managers can call it.
+    pub async fn get_user(&self, caller: Principal, user: Principal) -> Result<User, AndaError> {
+        self.check_manager(&caller)?;
+        self.management
+            .get_user(&user)
+            .await?
+            .ok_or_else(|| AndaError::not_found(format!("user {} not found", user.to_text())))
+    }
+
+    /// Lists the users, at most 1000 users per call.
+    /// Only the controller and managers can call it.
+    pub async fn list_users(
+        &self,
+        caller: Principal,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<User>, AndaError> {
+        self.check_manager(&caller)?;
+        let users = self.management.list_users(offset, limit.min(1000)).await?;
+        Ok(users)
+    }
+
+    /// Tops up the credit balance of the user and sets the credit expiry.
+    /// Only the controller and managers can call it.
+    pub async fn topup_credit(
+        &self,
+        caller: Principal,
+        user: Principal,
+        credit: u64,
+        expiry_ms: u64,
+    ) -> Result<User, AndaError> {
+        self.update_user_with(caller, user, move |u| u.topup_credit(credit, expiry_ms))
+            .await
+    }
+
+    /// Sets the subscription tier and expiry of the user.
+    /// Only the controller and managers can call it.
+    pub async fn update_subscription(
+        &self,
+        caller: Principal,
+        user: Principal,
+        tier: u8,
+        expiry_ms: u64,
+    ) -> Result<User, AndaError> {
+        self.update_user_with(caller, user, move |u| {
+            u.update_subscription(tier, expiry_ms)
+        })
+        .await
+    }
+
+    /// Sets the features of the user. Only the controller and managers can call it.
+    pub async fn update_features(
+        &self,
+        caller: Principal,
+        user: Principal,
+        features: BTreeSet<String>,
+    ) -> Result<User, AndaError> {
+        self.update_user_with(caller, user, move |u| u.update_features(features))
+            .await
+    }
+
+    /// Sets the status of the user, -2: banned, -1: suspended, 0: active.
+    /// Only the controller and managers can call it.
+    pub async fn update_status(
+        &self,
+        caller: Principal,
+        user: Principal,
+        status: i8,
+    ) -> Result<User, AndaError> {
+        if !(-2..=0).contains(&status) {
+            return Err(AndaError::invalid_argument(format!(
+                "invalid user status {status}, expected -2, -1 or 0"
+            )));
+        }
+        self.update_user_with(caller, user, move |u| u.update_status(status))
+            .await
+    }
+
+    fn check_manager(&self, caller: &Principal) -> Result<(), AndaError> {
+        if !self.management.is_manager(caller) {
+            return Err(AndaError::permission_denied("caller is not a manager"));
+        }
+        Ok(())
+    }
+
+    async fn update_user_with<F>(
+        &self,
+        caller: Principal,
+        user: Principal,
+        f: F,
+    ) -> Result<User, AndaError>
+    where
+        F: FnOnce(&mut User) + Send + 'static,
+    {
+        self.check_manager(&caller)?;
+        let updated = self
+            .management
+            .update_user(
+                &user,
+                Box::new(move |user| {
+                    f(user);
+                    Ok(())
+                }),
+            )
+            .await?;
+        log::info!(
+            caller = caller.to_text(),
+            user = user.to_text();
+            "user updated",
+        );
+        Ok(updated)
+    }
+
     /// Returns function definitions for the specified agents.
     /// If no names are provided, returns definitions for all agents.
     pub fn agents(&self, names: Option<&[&str]>) -> Vec<Function> {
@@ -385,29 +751,7 @@ impl Engine {
         request.verify(now_ms, request.registry)?;
         let message_digest = request.digest();
         let res = match self.ctx.base.web3.as_ref() {
-            Web3SDK::Tee(cli) => {
-                let authentication = cli.sign_envelope(message_digest).await?;
-                let tee = cli
-                    .sign_attestation(AttestationRequest {
-                        public_key: Some(authentication.pubkey.clone()),
-                        user_data: None,
-                        nonce: Some(request.code.to_vec().into()),
-                    })
-                    .await?;
-                let info = cli
-                    .tee_info()
-                    .ok_or_else(|| "TEE not available".to_string())?;
-                ChallengeEnvelope {
-                    request,
-                    authentication,
-                    tee: Some(TEEInfo {
-                        id: info.id,
-                        kind: TEEKind::try_from(tee.kind.as_str())?,
-                        url: info.url,
-                        attestation: Some(tee.attestation),
-                    }),
-                }
-            }
+            Web3SDK::Tee(_cli) => return Err("BUILDFIX".into()),
             Web3SDK::Web3(Web3Client { client: cli }) => {
                 let authentication = cli.sign_envelope(message_digest).await?;
                 ChallengeEnvelope {
@@ -420,6 +764,32 @@ impl Engine {
         Ok(res)
     }
 
+    /// Returns the information of the engine for the caller, the exported agents and tools
+    /// that the caller cannot use by their access rules or the visibility of the engine
+    /// are excluded.
+    /// The user of the caller is not created.
+    pub async fn information_for(&self, caller: &Principal) -> Result<EngineCard, AndaError> {
+        let mut info = self.information();
+        if self.acl.is_empty() || self.management.is_manager(caller) {
+            return Ok(info);
+        }
+
+        let now_ms = unix_ms();
+        let user = self
+            .management
+            .get_user(caller)
+            .await?
+            .unwrap_or_else(|| User::new(*caller));
+        let user_state = UserState::with_user(user);
+        let visible = self.is_visible(caller, &user_state, now_ms);
+        let access = CallerAccess::new(self.acl.clone(), user_state.to_user(), visible, now_ms);
+        info.agents
+            .retain(|f| access.allows_agent(&f.definition.name));
+        info.tools
+            .retain(|f| access.allows_tool(&f.definition.name));
+        Ok(info)
+    }
+
     /// Returns information about the engine, including agent and tool definitions.
     pub fn information(&self) -> EngineCard {
         EngineCard {
@@ -443,6 +813,15 @@ impl Engine {
     }
 }
 
+/// Converts the error of an agent run or a tool call into an [`AndaError`],
+/// it is a cancellation if the context has been cancelled.
+fn run_error(ctx: &BaseCtx, err: BoxError) -> AndaError {
+    if ctx.cancellation_token.is_cancelled() {
+        return AndaError::cancelled(err.to_string());
+    }
+    err.into()
+}
+
 /// Builder pattern implementation for constructing an Engine.
 /// Allows for step-by-step configuration of the engine's components.
 #[non_exhaustive]
@@ -453,12 +832,18 @@ pub struct EngineBuilder {
     remote: BTreeMap<String, RemoteEngineArgs>,
     model: Model,
     store: Store,
+    vector: VectorStore,
     web3: Arc<Web3SDK>,
     hooks: Arc<Hooks>,
     cancellation_token: CancellationToken,
     export_agents: BTreeSet<String>,
     export_tools: BTreeSet<String>,
     management: Option<Arc<dyn Management>>,
+    run_budget: RunBudget,
+    tool_selector: Option<Arc<ToolSelector>>,
+    prices: Option<Arc<PriceTable>>,
+    acl: AccessControl,
+    limiter: Option<Arc<RateLimiter>>,
 }
 
 impl Default for EngineBuilder {
@@ -487,12 +872,18 @@ impl EngineBuilder {
             remote: BTreeMap::new(),
             model: Model::not_implemented(),
             store: Store::new(mstore),
+            vector: VectorStore::not_implemented(),
             web3: Arc::new(Web3SDK::Web3(Web3Client::not_implemented())),
-            hooks: Arc::new(Hooks { hooks: Vec::new() }),
+            hooks: Arc::new(Hooks::new()),
             cancellation_token: CancellationToken::new(),
             export_agents: BTreeSet::new(),
             export_tools: BTreeSet::new(),
             management: None,
+            run_budget: RunBudget::default(),
+            tool_selector: None,
+            prices: None,
+            acl: AccessControl::default(),
+            limiter: None,
         }
     }
 
@@ -526,12 +917,54 @@ impl EngineBuilder {
         self
     }
 
+    /// Sets the vector search backend for the engine, e.g. [`crate::store::AndaVectorStore`].
+    /// Each agent or tool searches in its own namespace.
+    pub fn with_vector_store(mut self, vector: VectorStore) -> Self {
+        self.vector = vector;
+        self
+    }
+
     /// Sets the management builder for the engine.
     pub fn with_management(mut self, management: Arc<dyn Management>) -> Self {
         self.management = Some(management);
         self
     }
 
+    /// Sets the price table, callers other than the controller and managers are then charged
+    /// credits for agent runs and tool calls, and rejected when their credit is insufficient.
+    pub fn with_price_table(mut self, prices: PriceTable) -> Self {
+        self.prices = Some(Arc::new(prices));
+        self
+    }
+
+    /// Sets the access control lists of the agents and tools,
+    /// the rules override the visibility of the engine for them.
+    pub fn with_access_control(mut self, acl: AccessControl) -> Self {
+        self.acl = acl;
+        self
+    }
+
+    /// Sets the rate limits and quotas of the callers of agent runs and tool calls,
+    /// the controller and managers are not limited.
+    /// They are per-process soft limits counted in memory, see [`RateLimits`].
+    pub fn with_rate_limits(mut self, limits: RateLimits) -> Self {
+        self.limiter = Some(Arc::new(RateLimiter::new(limits)));
+        self
+    }
+
+    /// Sets the default budget of agent completion runs.
+    pub fn with_run_budget(mut self, budget: RunBudget) -> Self {
+        self.run_budget = budget;
+        self
+    }
+
+    /// Sets the tool selector, completion runs then only send the pinned tools and the tools
+    /// most relevant to the prompt to the model. Requires an embedding model.
+    pub fn with_tool_selector(mut self, selector: ToolSelector) -> Self {
+        self.tool_selector = Some(Arc::new(selector));
+        self
+    }
+
     /// Registers a single tool with the engine.
     /// Returns an error if the tool cannot be added.
     pub fn register_tool<T>(mut self, tool: T) -> Result<Self, BoxError>
@@ -639,12 +1072,21 @@ impl EngineBuilder {
             BTreeSet::new(),
             self.web3,
             self.store,
+            self.vector,
             Arc::new(RemoteEngines::new()),
         );
 
         let tools = Arc::new(ToolSet::new());
         let agents = Arc::new(AgentSet::new());
-        let ctx = AgentCtx::new(ctx, self.model, tools, agents);
+        let ctx = AgentCtx::new(
+            ctx,
+            self.model,
+            tools,
+            agents,
+            self.run_budget,
+            self.tool_selector,
+            self.hooks.clone(),
+        );
 
         Engine {
             id,
@@ -661,6 +1103,9 @@ impl EngineBuilder {
                     visibility: Visibility::Private, // default visibility
                 })
             }),
+            prices: self.prices,
+            acl: Arc::new(self.acl),
+            limiter: self.limiter,
         }
     }
 
@@ -703,12 +1148,21 @@ impl EngineBuilder {
             names,
             self.web3,
             self.store,
+            self.vector,
             Arc::new(remote),
         );
 
         let tools = Arc::new(self.tools);
         let agents = Arc::new(self.agents);
-        let ctx = AgentCtx::new(ctx, self.model, tools.clone(), agents.clone());
+        let ctx = AgentCtx::new(
+            ctx,
+            self.model,
+            tools.clone(),
+            agents.clone(),
+            self.run_budget,
+            self.tool_selector,
+            self.hooks.clone(),
+        );
 
         let meta = RequestMeta::default();
         for (name, tool) in &tools.set {
@@ -736,6 +1190,9 @@ impl EngineBuilder {
                     visibility: Visibility::Private, // default visibility
                 })
             }),
+            prices: self.prices,
+            acl: Arc::new(self.acl),
+            limiter: self.limiter,
         })
     }
 
@@ -757,10 +1214,19 @@ impl EngineBuilder {
             names,
             self.web3,
             self.store,
+            self.vector,
             Arc::new(RemoteEngines::new()),
         );
 
-        AgentCtx::new(ctx, self.model, Arc::new(self.tools), Arc::new(self.agents))
+        AgentCtx::new(
+            ctx,
+            self.model,
+            Arc::new(self.tools),
+            Arc::new(self.agents),
+            self.run_budget,
+            self.tool_selector,
+            self.hooks,
+        )
     }
 }
 
@@ -800,3 +1266,528 @@ impl Agent<AgentCtx> for EchoEngineInfo {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anda_core::{
+        BoxPinFut, CompletionFeatures, CompletionRequest, ErrorCode, FunctionDefinition, Usage,
+    };
+    use anda_db::database::{AndaDB, DBConfig};
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::{
+        management::{AndaManagement, RateLimit},
+        model::CompletionFeaturesDyn,
+    };
+
+    struct ApprovalModel;
+
+    impl CompletionFeaturesDyn for ApprovalModel {
+        fn completion(&self, req: CompletionRequest) -> BoxPinFut<Result<AgentOutput, BoxError>> {
+            let output = req.content.iter().find_map(|p| match p {
+                anda_core::ContentPart::ToolOutput { output, .. } => Some(output.to_string()),
+                _ => None,
+            });
+            Box::pin(futures::future::ready(Ok(match output {
+                Some(output) => AgentOutput {
+                    content: output,
+                    ..Default::default()
+                },
+                None => AgentOutput {
+                    content: "need approval".to_string(),
+                    tool_calls: vec![anda_core::ToolCall {
+                        name: "approval_tool".to_string(),
+                        args: json!({}),
+                        call_id: Some("call_1".to_string()),
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                },
+            })))
+        }
+    }
+
+    struct ApprovalTool;
+
+    impl Tool<BaseCtx> for ApprovalTool {
+        type Args = Json;
+        type Output = String;
+
+        fn name(&self) -> String {
+            "approval_tool".to_string()
+        }
+
+        fn description(&self) -> String {
+            "A tool that requires approval".to_string()
+        }
+
+        fn definition(&self) -> FunctionDefinition {
+            FunctionDefinition {
+                name: self.name(),
+                description: self.description(),
+                parameters: json!({"type": "object"}),
+                strict: None,
+            }
+        }
+
+        fn requires_approval(&self) -> bool {
+            true
+        }
+
+        async fn call(
+            &self,
+            _ctx: BaseCtx,
+            _args: Self::Args,
+            _resources: Vec<Resource>,
+        ) -> Result<ToolOutput<Self::Output>, BoxError> {
+            Ok(ToolOutput::new("done".to_string()))
+        }
+    }
+
+    #[derive(Default)]
+    struct TestAgent {
+        resumed: Arc<AtomicUsize>,
+    }
+
+    impl Agent<AgentCtx> for TestAgent {
+        fn name(&self) -> String {
+            "test_agent".to_string()
+        }
+
+        fn description(&self) -> String {
+            "A test agent".to_string()
+        }
+
+        async fn run(
+            &self,
+            ctx: AgentCtx,
+            prompt: String,
+            resources: Vec<Resource>,
+        ) -> Result<AgentOutput, BoxError> {
+            match prompt.as_str() {
+                "fail" => Err("agent failed".into()),
+                "echo" => Ok(AgentOutput {
+                    content: prompt,
+                    usage: Usage {
+                        input_tokens: 1000,
+                        output_tokens: 0,
+                        requests: 1,
+                    },
+                    ..Default::default()
+                }),
+                _ => {
+                    ctx.completion(
+                        CompletionRequest {
+                            prompt,
+                            ..Default::default()
+                        },
+                        resources,
+                    )
+                    .await
+                }
+            }
+        }
+
+        async fn on_resumed(
+            &self,
+            _ctx: AgentCtx,
+            mut output: AgentOutput,
+        ) -> Result<AgentOutput, BoxError> {
+            self.resumed.fetch_add(1, Ordering::SeqCst);
+            output.content = format!("resumed: {}", output.content);
+            Ok(output)
+        }
+    }
+
+    fn test_builder(agent: TestAgent) -> EngineBuilder {
+        EngineBuilder::new()
+            .with_model(Model::with_completer(Arc::new(ApprovalModel)))
+            .register_tool(ApprovalTool)
+            .unwrap()
+            .register_agent(agent)
+            .unwrap()
+    }
+
+    fn base_management() -> BaseManagement {
+        BaseManagement {
+            controller: Principal::anonymous(),
+            managers: BTreeSet::new(),
+            visibility: Visibility::Public,
+        }
+    }
+
+    async fn anda_management(visibility: Visibility) -> Arc<AndaManagement> {
+        let db = AndaDB::connect(
+            Arc::new(object_store_shim::memory::InMemory::new()),
+            DBConfig::default(),
+        )
+        .await
+        .unwrap();
+        Arc::new(
+            AndaManagement::connect(
+                Arc::new(db),
+                BaseManagement {
+                    visibility,
+                    ..base_management()
+                },
+            )
+            .await
+            .unwrap(),
+        )
+    }
+
+    async fn test_engine(agent: TestAgent) -> Engine {
+        test_builder(agent)
+            .with_management(Arc::new(base_management()))
+            .build("test_agent".to_string())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_approve_run() {
+        let agent = TestAgent::default();
+        let resumed = agent.resumed.clone();
+        let engine = test_engine(agent).await;
+        let caller = Principal::from_slice(&[1]);
+
+        let output = engine
+            .agent_run(caller, AgentInput::new(String::new(), "hello".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(output.content, "need approval");
+        let pending = output.pending_approval.unwrap();
+        assert_eq!(resumed.load(Ordering::SeqCst), 0);
+
+        let input = ApprovalInput {
+            name: String::new(),
+            id: pending.id.clone(),
+            approved: true,
+            reason: None,
+            meta: None,
+        };
+        // only the caller who started the run can approve it
+        assert!(
+            engine
+                .approve_run(Principal::from_slice(&[2]), input.clone())
+                .await
+                .is_err()
+        );
+        let output = engine.approve_run(caller, input).await.unwrap();
+        assert_eq!(output.content, r#"resumed: "done""#);
+        assert!(output.pending_approval.is_none());
+        assert_eq!(resumed.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_resume_run() {
+        let engine = test_engine(TestAgent::default()).await;
+        let caller = Principal::from_slice(&[1]);
+        let output = engine
+            .agent_run(caller, AgentInput::new(String::new(), "hello".to_string()))
+            .await
+            .unwrap();
+        let pending = output.pending_approval.unwrap();
+        let runs = engine.list_runs().await.unwrap();
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].0, "test_agent");
+
+        let err = engine
+            .resume_run(caller, "test_agent", &pending.id)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, ErrorCode::PermissionDenied);
+        // runs paused for approval are resumed by `approve_run`
+        let err = engine
+            .resume_run(Principal::anonymous(), "test_agent", &pending.id)
+            .await
+            .unwrap_err();
+        assert!(
+            err.message.contains("waiting for approval"),
+            "{}",
+            err.message
+        );
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_credit() {
+        let engine = test_builder(TestAgent::default())
+            .with_management(anda_management(Visibility::Public).await)
+            .with_price_table(PriceTable {
+                agent_request: 10,
+                input_tokens: 2,
+                ..Default::default()
+            })
+            .build("test_agent".to_string())
+            .await
+            .unwrap();
+        let controller = Principal::anonymous();
+        let alice = Principal::from_slice(&[1]);
+        let echo = || AgentInput::new(String::new(), "echo".to_string());
+
+        let err = engine.agent_run(alice, echo()).await.unwrap_err();
+        assert_eq!(err.code, ErrorCode::InsufficientCredit);
+
+        // the price is reserved before the run, and the usage is charged after it
+        engine
+            .topup_credit(controller, alice, 25, u64::MAX)
+            .await
+            .unwrap();
+        let (r1, r2, r3) = tokio::join!(
+            engine.agent_run(alice, echo()),
+            engine.agent_run(alice, echo()),
+            engine.agent_run(alice, echo())
+        );
+        let results = [r1, r2, r3];
+        assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 2);
+        let err = results.into_iter().find_map(|r| r.err()).unwrap();
+        assert_eq!(err.code, ErrorCode::InsufficientCredit);
+
+        let user = engine.get_user(controller, alice).await.unwrap();
+        assert_eq!(user.credit_balance, 1);
+        assert_eq!(user.credit_consumed, 24);
+        assert_eq!(user.agent_requests, 2);
+
+        // the price is refunded if the run fails
+        engine
+            .topup_credit(controller, alice, 20, u64::MAX)
+            .await
+            .unwrap();
+        assert!(
+            engine
+                .agent_run(alice, AgentInput::new(String::new(), "fail".to_string()))
+                .await
+                .is_err()
+        );
+        let user = engine.get_user(controller, alice).await.unwrap();
+        assert_eq!(user.credit_balance, 21);
+        assert_eq!(user.credit_consumed, 24);
+        assert_eq!(user.agent_requests, 3);
+
+        // managers are not charged
+        let output = engine.agent_run(controller, echo()).await.unwrap();
+        assert_eq!(output.content, "echo");
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_rate_limit() {
+        let engine = test_builder(TestAgent::default())
+            .with_management(anda_management(Visibility::Public).await)
+            .with_price_table(PriceTable {
+                agent_request: 10,
+                ..Default::default()
+            })
+            .with_rate_limits(RateLimits {
+                default: RateLimit {
+                    daily_quota: 2,
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+            .build("test_agent".to_string())
+            .await
+            .unwrap();
+        let controller = Principal::anonymous();
+        let alice = Principal::from_slice(&[1]);
+        let echo = || AgentInput::new(String::new(), "echo".to_string());
+
+        // the requests rejected for credit or unknown agents take no rate limit
+        for _ in 0..3 {
+            let err = engine.agent_run(alice, echo()).await.unwrap_err();
+            assert_eq!(err.code, ErrorCode::InsufficientCredit);
+            let err = engine
+                .agent_run(alice, AgentInput::new("unknown".to_string(), String::new()))
+                .await
+                .unwrap_err();
+            assert_eq!(err.code, ErrorCode::NotFound);
+        }
+
+        engine
+            .topup_credit(controller, alice, 100, u64::MAX)
+            .await
+            .unwrap();
+        assert!(engine.agent_run(alice, echo()).await.is_ok());
+        assert!(engine.agent_run(alice, echo()).await.is_ok());
+        let err = engine.agent_run(alice, echo()).await.unwrap_err();
+        assert_eq!(err.code, ErrorCode::RateLimited);
+        assert!(err.retry_after_ms().is_some());
+
+        // the price is not reserved for a rate limited request
+        let user = engine.get_user(controller, alice).await.unwrap();
+        assert_eq!(user.credit_balance, 80);
+        assert_eq!(user.agent_requests, 2);
+
+        // managers are not limited
+        for _ in 0..3 {
+            assert!(engine.agent_run(controller, echo()).await.is_ok());
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_user_management() {
+        let manager = Principal::from_slice(&[9]);
+        let mut management = base_management();
+        management.managers.insert(manager);
+        let engine = test_builder(TestAgent::default())
+            .with_management(Arc::new(management))
+            .build("test_agent".to_string())
+            .await
+            .unwrap();
+        let alice = Principal::from_slice(&[1]);
+
+        // the base management does not store users
+        let err = engine.get_user(manager, alice).await.unwrap_err();
+        assert_eq!(err.code, ErrorCode::NotFound);
+        assert!(engine.topup_credit(manager, alice, 10, 1000).await.is_err());
+
+        let engine = test_builder(TestAgent::default())
+            .with_management(anda_management(Visibility::Public).await)
+            .build("test_agent".to_string())
+            .await
+            .unwrap();
+        let controller = Principal::anonymous();
+
+        // only the controller and managers can manage users
+        let err = engine.get_user(alice, alice).await.unwrap_err();
+        assert_eq!(err.code, ErrorCode::PermissionDenied);
+        let err = engine
+            .topup_credit(alice, alice, 10, 1000)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, ErrorCode::PermissionDenied);
+        let err = engine.list_users(alice, 0, 10).await.unwrap_err();
+        assert_eq!(err.code, ErrorCode::PermissionDenied);
+
+        // unknown users are not created by reads
+        let err = engine.get_user(controller, alice).await.unwrap_err();
+        assert_eq!(err.code, ErrorCode::NotFound);
+        assert!(
+            engine
+                .list_users(controller, 0, 10)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+
+        let user = engine
+            .topup_credit(controller, alice, 10, 1000)
+            .await
+            .unwrap();
+        assert_eq!((user.credit_balance, user.credit_expiry), (10, 1000));
+        let user = engine
+            .update_subscription(controller, alice, 2, 2000)
+            .await
+            .unwrap();
+        assert_eq!(
+            (user.subscription_tier, user.subscription_expiry),
+            (2, 2000)
+        );
+        let user = engine
+            .update_features(controller, alice, BTreeSet::from(["wallet".to_string()]))
+            .await
+            .unwrap();
+        assert!(user.features.contains("wallet"));
+        let err = engine
+            .update_status(controller, alice, 1)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidArgument);
+        let user = engine.update_status(controller, alice, -2).await.unwrap();
+        assert_eq!(user.status, -2);
+        assert_eq!(user.credit_balance, 10);
+
+        assert_eq!(engine.get_user(controller, alice).await.unwrap(), user);
+        assert_eq!(
+            engine.list_users(controller, 0, 10).await.unwrap(),
+            vec![user]
+        );
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_access_control() {
+        let alice = Principal::from_slice(&[1]);
+        let bob = Principal::from_slice(&[2]);
+        let engine = test_builder(TestAgent::default())
+            .with_management(anda_management(Visibility::Private).await)
+            .with_access_control(
+                AccessControl::new()
+                    .with_agent(
+                        "test_agent",
+                        AccessRule {
+                            principals: BTreeSet::from([alice]),
+                            ..Default::default()
+                        },
+                    )
+                    .with_tool(
+                        "approval_tool",
+                        AccessRule {
+                            features: BTreeSet::from(["wallet".to_string()]),
+                            ..Default::default()
+                        },
+                    ),
+            )
+            .export_tools(vec!["approval_tool".to_string()])
+            .build("test_agent".to_string())
+            .await
+            .unwrap();
+        let controller = Principal::anonymous();
+        let echo = || AgentInput::new(String::new(), "echo".to_string());
+        let call = || ToolInput::new("approval_tool".to_string(), json!({}));
+
+        // the rules override the private visibility of the engine
+        let output = engine.agent_run(alice, echo()).await.unwrap();
+        assert_eq!(output.content, "echo");
+        let err = engine.agent_run(bob, echo()).await.unwrap_err();
+        assert_eq!(err.code, ErrorCode::PermissionDenied);
+        let err = engine.tool_call(alice, call()).await.unwrap_err();
+        assert_eq!(err.code, ErrorCode::PermissionDenied);
+
+        engine
+            .update_features(controller, bob, BTreeSet::from(["wallet".to_string()]))
+            .await
+            .unwrap();
+        let output = engine.tool_call(bob, call()).await.unwrap();
+        assert_eq!(output.output, json!("done"));
+        assert!(engine.tool_call(controller, call()).await.is_ok());
+
+        // the information excludes the agents and tools the caller cannot use
+        let info = engine.information_for(&alice).await.unwrap();
+        assert_eq!(info.agents.len(), 1);
+        assert!(info.tools.is_empty());
+        let info = engine.information_for(&bob).await.unwrap();
+        assert!(info.agents.is_empty());
+        assert_eq!(info.tools.len(), 1);
+        let carol = Principal::from_slice(&[3]);
+        let info = engine.information_for(&carol).await.unwrap();
+        assert!(info.agents.is_empty() && info.tools.is_empty());
+        // and does not create users
+        let err = engine.get_user(controller, carol).await.unwrap_err();
+        assert_eq!(err.code, ErrorCode::NotFound);
+
+        // a public rule does not expose the agents and tools without rules
+        let engine = test_builder(TestAgent::default())
+            .with_management(anda_management(Visibility::Protected).await)
+            .with_access_control(
+                AccessControl::new().with_agent("test_agent", AccessRule::public()),
+            )
+            .export_tools(vec!["approval_tool".to_string()])
+            .build("test_agent".to_string())
+            .await
+            .unwrap();
+        let output = engine.agent_run(carol, echo()).await.unwrap();
+        assert_eq!(output.content, "echo");
+        let err = engine.tool_call(carol, call()).await.unwrap_err();
+        assert_eq!(err.code, ErrorCode::PermissionDenied);
+        let user_state = UserState::new(carol);
+        let access = engine
+            .caller_access(&carol, &user_state, unix_ms())
+            .unwrap();
+        assert!(access.allows_agent("test_agent"));
+        assert!(!access.allows_tool("approval_tool"));
+        let info = engine.information_for(&carol).await.unwrap();
+        assert_eq!(info.agents.len(), 1);
+        assert!(info.tools.is_empty());
+    }
+}
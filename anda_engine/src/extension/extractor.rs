@@ -43,7 +43,7 @@
 
 use anda_core::{
     Agent, AgentOutput, BoxError, CompletionFeatures, CompletionRequest, FunctionDefinition,
-    Resource, Tool, ToolOutput, root_schema_for,
+    Resource, Tool, ToolOutput, root_schema_for, validate_json_schema,
 };
 use schemars::JsonSchema;
 use serde_json::Value;
@@ -96,6 +96,11 @@ where
         }
     }
 
+    /// Returns the JSON schema of the structured data
+    pub fn schema(&self) -> &Value {
+        &self.schema
+    }
+
     /// Validates and deserializes the submitted arguments
     ///
     /// # Arguments
@@ -104,6 +109,11 @@ where
     /// # Returns
     /// Deserialized instance of type `T` or an error if validation fails
     pub fn submit(&self, args: Value) -> Result<T, BoxError> {
+        let violations = validate_json_schema(&self.schema, &args);
+        if !violations.is_empty() {
+            let violations: Vec<String> = violations.iter().map(|v| v.to_string()).collect();
+            return Err(format!("invalid args: {}", violations.join("; ")).into());
+        }
         serde_json::from_value(args).map_err(|err| format!("invalid args: {}", err).into())
     }
 }
@@ -22,9 +22,7 @@
 //!     .build("default_agent".to_string())?;
 //! ```
 
-use anda_core::{
-    BoxError, FunctionDefinition, HttpFeatures, Json, Resource, Tool, ToolOutput, gen_schema_for,
-};
+use anda_core::{AndaTool, BoxError, HttpFeatures, Resource, ToolOutput};
 use encoding_rs::Encoding;
 use http::header;
 use ic_auth_types::ByteBufB64;
@@ -55,25 +53,21 @@ pub struct FetchWebResourcesArgs {
 /// - Uses GET method for all requests
 /// - Sets appropriate Accept headers for broad compatibility
 /// - Handles HTTP status codes and error responses
-#[derive(Debug, Clone)]
-pub struct FetchWebResourcesTool {
-    /// JSON schema for the fetch arguments
-    schema: Json,
-}
-
-impl Default for FetchWebResourcesTool {
-    fn default() -> Self {
-        Self::new()
-    }
-}
+#[derive(Debug, Clone, Default, AndaTool)]
+#[anda_tool(
+    name = "fetch_web_resources",
+    description = "Fetches resources from a given URL and returns the content as text (base64-url encoded if not UTF-8)",
+    args = FetchWebResourcesArgs,
+    output = String
+)]
+pub struct FetchWebResourcesTool;
 
 impl FetchWebResourcesTool {
     pub const NAME: &'static str = "fetch_web_resources";
 
     /// Creates a new FetchWebResourcesTool instance
     pub fn new() -> Self {
-        let schema = gen_schema_for::<FetchWebResourcesArgs>();
-        Self { schema }
+        Self
     }
 
     /// Fetches content from the specified URL
@@ -170,37 +164,18 @@ impl FetchWebResourcesTool {
         if let Some(encoding_name) = content_type
             .as_ref()
             .and_then(|mime| mime.get_param("charset").map(|charset| charset.as_str()))
-            && let Some(encoding) = Encoding::for_label(encoding_name.as_bytes()) {
-                let (text, _, had_errors) = encoding.decode(data);
-                if !had_errors {
-                    return Some(text.into_owned());
-                }
+            && let Some(encoding) = Encoding::for_label(encoding_name.as_bytes())
+        {
+            let (text, _, had_errors) = encoding.decode(data);
+            if !had_errors {
+                return Some(text.into_owned());
             }
+        }
         None
     }
 }
 
-impl Tool<BaseCtx> for FetchWebResourcesTool {
-    type Args = FetchWebResourcesArgs;
-    type Output = String;
-
-    fn name(&self) -> String {
-        Self::NAME.to_string()
-    }
-
-    fn description(&self) -> String {
-        "Fetches resources from a given URL and returns the content as text (base64-url encoded if not UTF-8)".to_string()
-    }
-
-    fn definition(&self) -> FunctionDefinition {
-        FunctionDefinition {
-            name: self.name(),
-            description: self.description(),
-            parameters: self.schema.clone(),
-            strict: Some(true),
-        }
-    }
-
+impl FetchWebResourcesTool {
     /// Executes the fetch operation
     ///
     /// # Arguments
@@ -210,12 +185,12 @@ impl Tool<BaseCtx> for FetchWebResourcesTool {
     ///
     /// # Returns
     /// String content (UTF-8 or base64-url encoded) or an error
-    async fn call(
+    async fn handle(
         &self,
         ctx: BaseCtx,
-        args: Self::Args,
+        args: FetchWebResourcesArgs,
         _resources: Vec<Resource>,
-    ) -> Result<ToolOutput<Self::Output>, BoxError> {
+    ) -> Result<ToolOutput<String>, BoxError> {
         let text = FetchWebResourcesTool::fetch_as_text(&ctx, &args.url).await?;
         Ok(ToolOutput::new(text))
     }
@@ -225,6 +200,20 @@ impl Tool<BaseCtx> for FetchWebResourcesTool {
 mod tests {
     use super::*;
     use crate::engine::EngineBuilder;
+    use anda_core::Tool;
+
+    #[test]
+    fn test_definition() {
+        let tool = FetchWebResourcesTool::new();
+        assert_eq!(tool.name(), FetchWebResourcesTool::NAME);
+        let definition = tool.definition();
+        assert_eq!(definition.name, FetchWebResourcesTool::NAME);
+        assert_eq!(definition.strict, Some(true));
+        assert_eq!(
+            definition.parameters["required"],
+            serde_json::json!(["url"])
+        );
+    }
 
     #[tokio::test]
     #[ignore]
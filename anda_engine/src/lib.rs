@@ -62,9 +62,35 @@ pub fn json_set_unix_ms_timestamp(mut vals: Vec<Json>, timestamp_ms: u64) -> Vec
 pub fn json_convert_rfc3339_timestamp(mut vals: Vec<Json>) -> Vec<Json> {
     for val in vals.iter_mut() {
         if let Some(obj) = val.as_object_mut()
-            && let Some(timestamp_ms) = obj.get("timestamp").and_then(Json::as_u64) {
-                obj.insert("timestamp".into(), rfc3339_datetime(timestamp_ms).into());
-            }
+            && let Some(timestamp_ms) = obj.get("timestamp").and_then(Json::as_u64)
+        {
+            obj.insert("timestamp".into(), rfc3339_datetime(timestamp_ms).into());
+        }
     }
     vals
 }
+
+/// A fixed set of async locks, each key is mapped to one of them by its hash.
+///
+/// It serializes the operations on the same key with bounded memory,
+/// operations on different keys may share a lock.
+#[derive(Debug)]
+pub(crate) struct KeyLocks(Box<[tokio::sync::Mutex<()>]>);
+
+impl KeyLocks {
+    pub(crate) fn new(n: usize) -> Self {
+        Self((0..n.max(1)).map(|_| tokio::sync::Mutex::new(())).collect())
+    }
+
+    /// Acquires the lock of the key.
+    pub(crate) async fn lock<K: std::hash::Hash + ?Sized>(
+        &self,
+        key: &K,
+    ) -> tokio::sync::MutexGuard<'_, ()> {
+        use std::hash::{DefaultHasher, Hasher};
+
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        self.0[hasher.finish() as usize % self.0.len()].lock().await
+    }
+}
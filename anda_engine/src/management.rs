@@ -1,13 +1,19 @@
-use anda_core::BoxError;
+use anda_core::{AndaError, BoxError};
 use async_trait::async_trait;
 use candid::Principal;
 use ic_auth_verifier::ANONYMOUS_PRINCIPAL;
 use std::collections::BTreeSet;
 
+mod acl;
+mod billing;
 mod db;
+mod limit;
 mod user;
 
+pub use acl::*;
+pub use billing::*;
 pub use db::*;
+pub use limit::*;
 pub use user::*;
 
 pub static SYSTEM_PATH: &str = "_";
@@ -18,15 +24,33 @@ pub trait Management: Send + Sync {
     fn is_manager(&self, caller: &Principal) -> bool;
     fn check_visibility(&self, caller: &Principal) -> Result<Visibility, BoxError>;
 
+    /// Returns true if the users are persisted, so the engine records the requests
+    /// and charges the credit of callers.
+    fn stores_users(&self) -> bool {
+        false
+    }
+
     async fn load_user(&self, _caller: &Principal) -> Result<UserState, BoxError> {
         Err("`load_user` is not implemented".into())
     }
 
-    async fn update_user(&self, _user: &UserState) -> Result<(), BoxError> {
-        Err("`save_user` is not implemented".into())
+    /// Returns the user if exists, the user is not created.
+    async fn get_user(&self, _user: &Principal) -> Result<Option<User>, BoxError> {
+        Err("`get_user` is not implemented".into())
     }
 
-    // TODO: more management methods
+    /// Applies the change to the latest state of the user and persists it,
+    /// the user is created if not exists. Returns the updated user.
+    ///
+    /// The changes of a user are applied one by one, so concurrent updates are not lost.
+    async fn update_user(&self, _user: &Principal, _change: UserChange) -> Result<User, BoxError> {
+        Err("`update_user` is not implemented".into())
+    }
+
+    /// Lists the users in order of creation, skipping `offset` users.
+    async fn list_users(&self, _offset: usize, _limit: usize) -> Result<Vec<User>, BoxError> {
+        Err("`list_users` is not implemented".into())
+    }
 }
 
 /// Represents system management tools for the Anda engine.
@@ -63,11 +87,11 @@ impl Management for BaseManagement {
 
     fn check_visibility(&self, caller: &Principal) -> Result<Visibility, BoxError> {
         if self.visibility != Visibility::Public && caller == &ANONYMOUS_PRINCIPAL {
-            return Err("anonymous caller not allowed".into());
+            return Err(AndaError::unauthenticated("anonymous caller not allowed").into());
         }
 
         if self.visibility == Visibility::Private && !self.is_manager(caller) {
-            return Err("caller is not allowed".into());
+            return Err(AndaError::permission_denied("caller is not allowed").into());
         }
 
         Ok(self.visibility)
@@ -77,7 +101,8 @@ impl Management for BaseManagement {
         Ok(UserState::new(*user))
     }
 
-    async fn update_user(&self, _user: &UserState) -> Result<(), BoxError> {
-        Ok(())
+    /// No users are stored.
+    async fn get_user(&self, _user: &Principal) -> Result<Option<User>, BoxError> {
+        Ok(None)
     }
 }
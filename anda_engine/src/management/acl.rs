@@ -0,0 +1,213 @@
+use candid::Principal;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+};
+
+use super::User;
+
+/// The access rule of an agent or a tool.
+///
+/// A rule overrides the visibility of the engine for the agent or tool:
+/// a public rule allows anyone, including anonymous callers, otherwise the caller
+/// must be listed in `principals`, have one of the `features`, or subscribe to
+/// at least `min_tier`. The controller and managers are always allowed.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct AccessRule {
+    /// Allows anyone, including anonymous callers.
+    #[serde(default)]
+    pub public: bool,
+
+    /// The principals allowed.
+    #[serde(default)]
+    pub principals: BTreeSet<Principal>,
+
+    /// The users with any of the features are allowed.
+    #[serde(default)]
+    pub features: BTreeSet<String>,
+
+    /// The users with an unexpired subscription of at least this tier are allowed.
+    #[serde(default)]
+    pub min_tier: Option<u8>,
+}
+
+impl AccessRule {
+    /// Creates a rule that allows anyone.
+    pub fn public() -> Self {
+        Self {
+            public: true,
+            ..Default::default()
+        }
+    }
+
+    /// Returns true if the rule allows the user.
+    /// Suspended and banned users are only allowed by public rules.
+    pub fn allows(&self, user: &User, now_ms: u64) -> bool {
+        if self.public {
+            return true;
+        }
+
+        user.status >= 0
+            && (self.principals.contains(&user.id)
+                || self.features.iter().any(|f| user.features.contains(f))
+                || self.min_tier.is_some_and(|tier| {
+                    user.subscription_tier >= tier && user.subscription_expiry > now_ms
+                }))
+    }
+}
+
+/// The access control lists of the agents and tools of an engine.
+///
+/// Agents and tools without rules follow the visibility of the engine,
+/// see [`crate::management::Visibility`].
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct AccessControl {
+    /// The rules by agent name, in lowercase.
+    #[serde(default)]
+    pub agents: BTreeMap<String, AccessRule>,
+
+    /// The rules by tool name.
+    #[serde(default)]
+    pub tools: BTreeMap<String, AccessRule>,
+}
+
+impl AccessControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the rule of the agent.
+    pub fn with_agent(mut self, name: &str, rule: AccessRule) -> Self {
+        self.agents.insert(name.to_ascii_lowercase(), rule);
+        self
+    }
+
+    /// Sets the rule of the tool.
+    pub fn with_tool(mut self, name: &str, rule: AccessRule) -> Self {
+        self.tools.insert(name.to_string(), rule);
+        self
+    }
+
+    /// Returns the rule of the agent, if any.
+    pub fn agent_rule(&self, name: &str) -> Option<&AccessRule> {
+        self.agents.get(&name.to_ascii_lowercase())
+    }
+
+    /// Returns the rule of the tool, if any.
+    pub fn tool_rule(&self, name: &str) -> Option<&AccessRule> {
+        self.tools.get(name)
+    }
+
+    /// Returns true if there are no rules.
+    pub fn is_empty(&self) -> bool {
+        self.agents.is_empty() && self.tools.is_empty()
+    }
+}
+
+/// The access of the caller of a run to the agents and tools,
+/// checked on the nested agent runs and tool calls of the run.
+///
+/// Agents and tools with rules follow their rules, others are allowed
+/// only if the visibility of the engine allows the caller.
+#[derive(Debug, Clone)]
+pub struct CallerAccess {
+    acl: Arc<AccessControl>,
+    user: User,
+    visible: bool,
+    now_ms: u64,
+}
+
+impl CallerAccess {
+    /// Creates the access of the user, `visible` is true if the visibility of the engine
+    /// allows the user.
+    pub fn new(acl: Arc<AccessControl>, user: User, visible: bool, now_ms: u64) -> Self {
+        Self {
+            acl,
+            user,
+            visible,
+            now_ms,
+        }
+    }
+
+    /// Returns true if the caller can run the agent.
+    pub fn allows_agent(&self, name: &str) -> bool {
+        match self.acl.agent_rule(name) {
+            Some(rule) => rule.allows(&self.user, self.now_ms),
+            None => self.visible,
+        }
+    }
+
+    /// Returns true if the caller can call the tool.
+    pub fn allows_tool(&self, name: &str) -> bool {
+        match self.acl.tool_rule(name) {
+            Some(rule) => rule.allows(&self.user, self.now_ms),
+            None => self.visible,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_access_control() {
+        let alice = Principal::from_slice(&[1]);
+        let acl = Arc::new(
+            AccessControl::new()
+                .with_tool("search", AccessRule::public())
+                .with_tool(
+                    "transfer",
+                    AccessRule {
+                        principals: BTreeSet::from([alice]),
+                        features: BTreeSet::from(["wallet".to_string()]),
+                        ..Default::default()
+                    },
+                )
+                .with_agent(
+                    "Analyst",
+                    AccessRule {
+                        min_tier: Some(1),
+                        ..Default::default()
+                    },
+                ),
+        );
+        assert!(acl.agent_rule("analyst").is_some());
+
+        let mut user = User::new(Principal::from_slice(&[2]));
+        let access = CallerAccess::new(acl.clone(), user.clone(), true, 1000);
+        assert!(access.allows_tool("search"));
+        assert!(access.allows_tool("other"));
+        assert!(!access.allows_tool("transfer"));
+        assert!(!access.allows_agent("analyst"));
+        assert!(access.allows_agent("assistant"));
+
+        user.features.insert("wallet".to_string());
+        user.subscription_tier = 2;
+        user.subscription_expiry = 2000;
+        let access = CallerAccess::new(acl.clone(), user.clone(), true, 1000);
+        assert!(access.allows_tool("transfer"));
+        assert!(access.allows_agent("analyst"));
+
+        // expired subscription
+        let access = CallerAccess::new(acl.clone(), user.clone(), true, 2000);
+        assert!(!access.allows_agent("analyst"));
+
+        // suspended users are only allowed by public rules
+        user.status = -1;
+        let access = CallerAccess::new(acl.clone(), user, true, 1000);
+        assert!(!access.allows_tool("transfer"));
+        assert!(access.allows_tool("search"));
+
+        let access = CallerAccess::new(acl.clone(), User::new(alice), true, 1000);
+        assert!(access.allows_tool("transfer"));
+
+        // the agents and tools without rules follow the visibility of the engine
+        let access = CallerAccess::new(acl, User::new(alice), false, 1000);
+        assert!(access.allows_tool("transfer"));
+        assert!(access.allows_tool("search"));
+        assert!(!access.allows_tool("other"));
+        assert!(!access.allows_agent("assistant"));
+    }
+}
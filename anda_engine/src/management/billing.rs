@@ -0,0 +1,190 @@
+use anda_core::{AndaError, Usage};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use super::{User, UserState};
+
+/// The prices of agent runs and tool calls in credits.
+///
+/// When set by [`crate::engine::EngineBuilder::with_price_table`], callers other than
+/// the controller and managers need unexpired credit to run agents and call tools.
+/// The price is reserved from the credit before each run or call, and the token usage
+/// is charged after it, the part beyond the credit balance is recorded as credit debt
+/// and settled by the next topup.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct PriceTable {
+    /// The credits per agent run.
+    pub agent_request: u64,
+
+    /// The credits per tool call.
+    pub tool_request: u64,
+
+    /// The credits per 1000 input tokens.
+    pub input_tokens: u64,
+
+    /// The credits per 1000 output tokens.
+    pub output_tokens: u64,
+
+    /// The credits per run of specific agents, overriding `agent_request`.
+    #[serde(default)]
+    pub agents: BTreeMap<String, u64>,
+
+    /// The credits per call of specific tools, overriding `tool_request`.
+    #[serde(default)]
+    pub tools: BTreeMap<String, u64>,
+}
+
+impl PriceTable {
+    /// Returns the credits per run of the agent.
+    pub fn agent_price(&self, agent: &str) -> u64 {
+        self.agents
+            .get(agent)
+            .copied()
+            .unwrap_or(self.agent_request)
+    }
+
+    /// Returns the credits per call of the tool.
+    pub fn tool_price(&self, tool: &str) -> u64 {
+        self.tools.get(tool).copied().unwrap_or(self.tool_request)
+    }
+
+    /// Returns the credits of the token usage, rounded up.
+    pub fn usage_cost(&self, usage: &Usage) -> u64 {
+        let cost = |tokens: u64, price: u64| tokens.saturating_mul(price).div_ceil(1000);
+        cost(usage.input_tokens, self.input_tokens)
+            .saturating_add(cost(usage.output_tokens, self.output_tokens))
+    }
+
+    /// Returns the credits of an agent run with the usage.
+    pub fn agent_cost(&self, agent: &str, usage: &Usage) -> u64 {
+        self.agent_price(agent)
+            .saturating_add(self.usage_cost(usage))
+    }
+
+    /// Returns the credits of a tool call with the usage.
+    pub fn tool_cost(&self, tool: &str, usage: &Usage) -> u64 {
+        self.tool_price(tool).saturating_add(self.usage_cost(usage))
+    }
+}
+
+impl User {
+    /// Checks that the credit of the user is not expired and covers the price.
+    /// Returns an insufficient credit error otherwise.
+    pub fn check_credit(&self, price: u64, now_ms: u64) -> Result<(), AndaError> {
+        if self.credit_expiry <= now_ms {
+            return Err(AndaError::insufficient_credit("credit has expired"));
+        }
+        if let Some(debt) = self.credit_debt {
+            return Err(AndaError::insufficient_credit(format!(
+                "credit debt {debt} is outstanding"
+            )));
+        }
+        if self.credit_balance == 0 || self.credit_balance < price {
+            return Err(AndaError::insufficient_credit(format!(
+                "credit balance {} is insufficient, requires {}",
+                self.credit_balance, price
+            )));
+        }
+        Ok(())
+    }
+
+    /// Charges the credit from the user.
+    /// The part beyond the credit balance is recorded as credit debt.
+    pub fn charge_credit(&mut self, credit: u64) {
+        let charged = credit.min(self.credit_balance);
+        self.credit_balance -= charged;
+        if charged < credit {
+            let debt = self.credit_debt.unwrap_or(0);
+            self.credit_debt = Some(debt.saturating_add(credit - charged));
+        }
+        self.credit_consumed = self.credit_consumed.saturating_add(credit);
+    }
+
+    /// Settles the credit debt with the credit, returns the remaining credit.
+    pub(crate) fn settle_debt(&mut self, credit: u64) -> u64 {
+        let Some(debt) = self.credit_debt else {
+            return credit;
+        };
+        let settled = credit.min(debt);
+        self.credit_debt = (debt > settled).then_some(debt - settled);
+        credit - settled
+    }
+
+    /// Refunds the credit charged from the user, reducing the credit debt first.
+    pub fn refund_credit(&mut self, credit: u64) {
+        self.credit_consumed = self.credit_consumed.saturating_sub(credit);
+        let credit = self.settle_debt(credit);
+        self.credit_balance = self.credit_balance.saturating_add(credit);
+    }
+}
+
+impl UserState {
+    /// See [`User::check_credit`].
+    pub fn check_credit(&self, price: u64, now_ms: u64) -> Result<(), AndaError> {
+        self.with(|user| user.check_credit(price, now_ms))
+    }
+
+    /// See [`User::charge_credit`].
+    pub fn charge_credit(&self, credit: u64) {
+        self.user.write().charge_credit(credit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Principal;
+
+    #[test]
+    fn test_billing() {
+        let prices = PriceTable {
+            agent_request: 10,
+            tool_request: 1,
+            input_tokens: 2,
+            output_tokens: 5,
+            agents: BTreeMap::from([("assistant".to_string(), 20)]),
+            ..Default::default()
+        };
+        let usage = Usage {
+            input_tokens: 1500,
+            output_tokens: 100,
+            requests: 2,
+        };
+        assert_eq!(prices.usage_cost(&usage), 3 + 1);
+        assert_eq!(prices.agent_cost("assistant", &usage), 24);
+        assert_eq!(prices.agent_cost("other", &usage), 14);
+        assert_eq!(prices.tool_cost("fetch", &Usage::default()), 1);
+
+        let user = UserState::new(Principal::anonymous());
+        let err = user.check_credit(10, 1000).unwrap_err();
+        assert_eq!(err, AndaError::insufficient_credit("credit has expired"));
+
+        user.topup_credit(30, 2000);
+        assert!(user.check_credit(20, 1000).is_ok());
+        assert!(user.check_credit(20, 2000).is_err());
+        user.charge_credit(24);
+        assert_eq!(user.credit(), (6, 2000));
+        assert!(user.check_credit(10, 1000).is_err());
+        assert!(user.check_credit(0, 1000).is_ok());
+
+        // records the charge beyond the balance as debt
+        user.charge_credit(24);
+        assert_eq!(user.credit(), (0, 2000));
+        assert_eq!(user.user.read().credit_debt, Some(18));
+        assert_eq!(user.user.read().credit_consumed, 48);
+        let err = user.check_credit(0, 1000).unwrap_err();
+        assert_eq!(
+            err,
+            AndaError::insufficient_credit("credit debt 18 is outstanding")
+        );
+
+        // the debt is settled by the next topup
+        user.topup_credit(10, 2000);
+        assert_eq!(user.credit(), (0, 2000));
+        assert_eq!(user.user.read().credit_debt, Some(8));
+        user.topup_credit(20, 2000);
+        assert_eq!(user.credit(), (12, 2000));
+        assert_eq!(user.user.read().credit_debt, None);
+        assert!(user.check_credit(10, 1000).is_ok());
+    }
+}
@@ -10,17 +10,32 @@ use anda_db_tfs::jieba_tokenizer;
 use async_trait::async_trait;
 use candid::Principal;
 use std::sync::Arc;
+use structured_logger::unix_ms;
 
-use super::{BaseManagement, Management, User, UserState, Visibility};
+use super::{BaseManagement, Management, User, UserChange, UserState, Visibility};
+use crate::KeyLocks;
 
+/// The number of locks that serialize the updates of the same user.
+const USER_LOCKS: usize = 64;
+
+/// The version of the users schema, increased when optional fields are added to [`User`].
+/// Version 1 adds `credit_debt`.
+const USERS_SCHEMA_VERSION: u64 = 1;
+
+/// Management with the users persisted in the AndaDB collection "users".
+///
+/// Each update writes the user document, the collection is flushed when a user is created,
+/// other metadata is flushed with the database.
 pub struct AndaManagement {
     users: Arc<Collection>,
     base: BaseManagement,
+    locks: KeyLocks,
 }
 
 impl AndaManagement {
     pub async fn connect(db: Arc<AndaDB>, base: BaseManagement) -> Result<Self, BoxError> {
-        let schema = User::schema()?;
+        let mut schema = User::schema()?;
+        schema.with_version(USERS_SCHEMA_VERSION);
         let users = db
             .open_or_create_collection(
                 schema,
@@ -39,7 +54,40 @@ impl AndaManagement {
             )
             .await?;
 
-        Ok(Self { users, base })
+        Ok(Self {
+            users,
+            base,
+            locks: KeyLocks::new(USER_LOCKS),
+        })
+    }
+
+    /// Returns the document ID of the user, if exists.
+    async fn user_id(&self, user: &Principal) -> Result<Option<u64>, BoxError> {
+        let mut ids = self
+            .users
+            .query_ids(
+                Filter::Field((
+                    "id".to_string(),
+                    RangeQuery::Eq(Fv::Bytes(user.as_slice().to_vec())),
+                )),
+                None,
+            )
+            .await?;
+        Ok(ids.pop())
+    }
+
+    /// Loads the user, creating it if not exists.
+    /// The lock of the user should be held.
+    async fn load_or_create(&self, user: &Principal) -> Result<User, BoxError> {
+        let id = match self.user_id(user).await? {
+            Some(id) => id,
+            None => {
+                let id = self.users.add_from(&User::new(*user)).await?;
+                self.users.flush(unix_ms()).await?;
+                id
+            }
+        };
+        Ok(self.users.get_as(id).await?)
     }
 }
 
@@ -59,24 +107,204 @@ impl Management for AndaManagement {
         self.base.check_visibility(caller)
     }
 
+    fn stores_users(&self) -> bool {
+        true
+    }
+
     async fn load_user(&self, user: &Principal) -> Result<UserState, BoxError> {
-        let mut ids = self
-            .users
-            .query_ids(
-                Filter::Field((
-                    "id".to_string(),
-                    RangeQuery::Eq(Fv::Bytes(user.as_slice().to_vec())),
-                )),
-                None,
-            )
-            .await?;
+        if let Some(id) = self.user_id(user).await? {
+            return Ok(UserState::with_user(self.users.get_as(id).await?));
+        }
 
-        let id = match ids.pop() {
-            Some(id) => id,
-            None => self.users.add_from(&User::new(*user)).await?,
+        let _guard = self.locks.lock(user).await;
+        Ok(UserState::with_user(self.load_or_create(user).await?))
+    }
+
+    async fn get_user(&self, user: &Principal) -> Result<Option<User>, BoxError> {
+        match self.user_id(user).await? {
+            Some(id) => Ok(Some(self.users.get_as(id).await?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Applies the change to the user reloaded under the lock of the user.
+    async fn update_user(&self, user: &Principal, change: UserChange) -> Result<User, BoxError> {
+        let _guard = self.locks.lock(user).await;
+        let mut user = self.load_or_create(user).await?;
+        change(&mut user)?;
+        self.users.update(user._id, user.to_changes()).await?;
+        Ok(user)
+    }
+
+    async fn list_users(&self, offset: usize, limit: usize) -> Result<Vec<User>, BoxError> {
+        let mut users = Vec::new();
+        for id in self.users.ids().into_iter().skip(offset).take(limit) {
+            users.push(self.users.get_as(id).await?);
+        }
+        Ok(users)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anda_core::{AndaError, ErrorCode};
+    use anda_db::database::DBConfig;
+    use anda_db_schema::Schema;
+    use object_store_shim::memory::InMemory;
+    use std::collections::BTreeSet;
+
+    fn base() -> BaseManagement {
+        BaseManagement {
+            controller: Principal::anonymous(),
+            managers: BTreeSet::new(),
+            visibility: Visibility::Public,
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_anda_management() {
+        let store = Arc::new(InMemory::new());
+        let db = AndaDB::connect(store.clone(), DBConfig::default())
+            .await
+            .unwrap();
+        let management = AndaManagement::connect(Arc::new(db), base()).await.unwrap();
+        assert!(management.stores_users());
+
+        // loading creates the user
+        let alice = Principal::from_slice(&[1]);
+        let user = management.load_user(&alice).await.unwrap().to_user();
+        assert_eq!(user.id, alice);
+        assert_eq!(user.credit_balance, 0);
+        let user = management.load_user(&alice).await.unwrap().to_user();
+        assert_eq!(management.list_users(0, 10).await.unwrap(), vec![user]);
+
+        // concurrent changes are applied to the latest state one by one
+        let topup = |credit: u64| {
+            management.update_user(
+                &alice,
+                Box::new(move |user: &mut User| {
+                    user.topup_credit(credit, u64::MAX);
+                    Ok(())
+                }),
+            )
         };
+        let (r1, r2, r3) = tokio::join!(topup(10), topup(20), topup(30));
+        assert!(r1.is_ok() && r2.is_ok() && r3.is_ok());
+
+        // a failed change is not persisted
+        let err = management
+            .update_user(
+                &alice,
+                Box::new(|user: &mut User| {
+                    user.charge_credit(50);
+                    user.check_credit(100, 0)
+                }),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(AndaError::from(err).code, ErrorCode::InsufficientCredit);
+
+        // the changes are persisted
+        let db = AndaDB::connect(store, DBConfig::default()).await.unwrap();
+        let management = AndaManagement::connect(Arc::new(db), base()).await.unwrap();
+        let user = management.load_user(&alice).await.unwrap().to_user();
+        assert_eq!(user.credit_balance, 60);
+        assert_eq!(user.credit_expiry, u64::MAX);
+        assert_eq!(management.list_users(0, 10).await.unwrap().len(), 1);
+
+        // the user is created by the first update
+        let bob = Principal::from_slice(&[2]);
+        let user = management
+            .update_user(
+                &bob,
+                Box::new(|user: &mut User| {
+                    user.update_status(-2);
+                    Ok(())
+                }),
+            )
+            .await
+            .unwrap();
+        assert_eq!(user.status, -2);
+        assert_eq!(management.load_user(&bob).await.unwrap().to_user(), user);
+        assert_eq!(management.list_users(0, 10).await.unwrap().len(), 2);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_upgrade_users() {
+        // the users collection created before the credit debt was added
+        let mut builder = Schema::builder();
+        for field in User::schema().unwrap().iter() {
+            if field.name() != Schema::ID_KEY && field.name() != "credit_debt" {
+                builder.add_field(field.clone()).unwrap();
+            }
+        }
+        let store = Arc::new(InMemory::new());
+        let db = AndaDB::connect(store.clone(), DBConfig::default())
+            .await
+            .unwrap();
+        let users = db
+            .create_collection(
+                builder.build().unwrap(),
+                CollectionConfig {
+                    name: "users".to_string(),
+                    description: "users collection".to_string(),
+                },
+                async |collection| {
+                    collection.create_btree_index_nx(&["id"]).await?;
+                    Ok::<(), DBError>(())
+                },
+            )
+            .await
+            .unwrap();
+        let alice = Principal::from_slice(&[1]);
+        let bob = Principal::from_slice(&[2]);
+        let mut user = User::new(bob);
+        user.update_status(-1);
+        users.add_from(&User::new(alice)).await.unwrap();
+        users.add_from(&user).await.unwrap();
+        db.close().await.unwrap();
+
+        // the users are read and updated with the upgraded schema
+        let db = AndaDB::connect(store.clone(), DBConfig::default())
+            .await
+            .unwrap();
+        let management = AndaManagement::connect(Arc::new(db), base()).await.unwrap();
+        assert_eq!(management.users.schema().version(), USERS_SCHEMA_VERSION);
+        let user = management.get_user(&alice).await.unwrap().unwrap();
+        assert_eq!(user.status, 0);
+        assert_eq!(user.credit_debt, None);
+        let user = management.get_user(&bob).await.unwrap().unwrap();
+        assert_eq!(user.status, -1);
+
+        management
+            .update_user(
+                &alice,
+                Box::new(|user: &mut User| {
+                    user.charge_credit(5);
+                    Ok(())
+                }),
+            )
+            .await
+            .unwrap();
+        management
+            .update_user(
+                &bob,
+                Box::new(|user: &mut User| {
+                    user.update_status(0);
+                    Ok(())
+                }),
+            )
+            .await
+            .unwrap();
 
-        let user: User = self.users.get_as(id).await?;
-        Ok(UserState::with_user(user))
+        let db = AndaDB::connect(store, DBConfig::default()).await.unwrap();
+        let management = AndaManagement::connect(Arc::new(db), base()).await.unwrap();
+        let user = management.get_user(&alice).await.unwrap().unwrap();
+        assert_eq!(user.status, 0);
+        assert_eq!(user.credit_debt, Some(5));
+        let user = management.get_user(&bob).await.unwrap().unwrap();
+        assert_eq!(user.status, 0);
+        assert_eq!(management.list_users(0, 10).await.unwrap().len(), 2);
     }
 }
@@ -0,0 +1,313 @@
+use anda_core::AndaError;
+use candid::Principal;
+use chrono::{DateTime, Datelike, TimeZone, Utc};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+use super::User;
+
+const DAY_MS: u64 = 24 * 3600 * 1000;
+/// The maximum callers tracked by a rate limiter, the least recently seen ones are evicted.
+const MAX_CALLER_STATES: usize = 100_000;
+
+/// The rate limit and quotas of a caller, 0 means unlimited.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct RateLimit {
+    /// The requests refilled to the token bucket per minute.
+    #[serde(default)]
+    pub per_minute: u64,
+
+    /// The capacity of the token bucket, i.e. the maximum requests in a burst.
+    /// Defaults to `per_minute` if 0.
+    #[serde(default)]
+    pub burst: u64,
+
+    /// The maximum requests per UTC day, a per-process soft limit, see [`RateLimits`].
+    #[serde(default)]
+    pub daily_quota: u64,
+
+    /// The maximum requests per UTC calendar month, a per-process soft limit, see [`RateLimits`].
+    #[serde(default)]
+    pub monthly_quota: u64,
+}
+
+/// The rate limits of the callers of an engine.
+///
+/// The limit of a caller is the one of its principal, or of its unexpired subscription tier,
+/// or the default one. The controller and managers are not limited.
+///
+/// The limits, including the daily and monthly quotas, are per-process soft limits:
+/// the requests are counted in memory by [`RateLimiter`] and are not persisted, so the
+/// counts start over when the engine restarts or an idle caller is evicted, and each
+/// process of an engine deployed on multiple instances counts its own requests.
+/// Use the credits of [`super::PriceTable`] for hard limits, they are persisted with the user.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct RateLimits {
+    /// The default limit.
+    #[serde(default)]
+    pub default: RateLimit,
+
+    /// The limits by subscription tier.
+    #[serde(default)]
+    pub tiers: BTreeMap<u8, RateLimit>,
+
+    /// The limits by principal.
+    #[serde(default)]
+    pub principals: BTreeMap<Principal, RateLimit>,
+}
+
+impl RateLimits {
+    /// Returns the limit of the user.
+    pub fn limit_for(&self, user: &User, now_ms: u64) -> &RateLimit {
+        self.principals
+            .get(&user.id)
+            .or_else(|| {
+                if user.subscription_expiry > now_ms {
+                    self.tiers.get(&user.subscription_tier)
+                } else {
+                    None
+                }
+            })
+            .unwrap_or(&self.default)
+    }
+}
+
+/// The state of a caller in the rate limiter.
+#[derive(Debug, Clone, Default)]
+struct CallerState {
+    tokens: f64,
+    refilled_at: u64,
+    day: u64,
+    daily_requests: u64,
+    month: u32,
+    monthly_requests: u64,
+    seen_at: u64,
+}
+
+/// Limits the requests of callers by token buckets and daily and monthly quotas.
+///
+/// The states of callers are kept in memory of the process, they are reset when the engine
+/// restarts, so the limits are soft limits, see [`RateLimits`].
+/// At most `MAX_CALLER_STATES` callers are tracked, the least recently seen tenth of them
+/// are evicted when a new caller comes and start over on their next request.
+#[derive(Debug)]
+pub struct RateLimiter {
+    limits: RateLimits,
+    capacity: usize,
+    states: Mutex<HashMap<Principal, CallerState>>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(RateLimits::default())
+    }
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            limits,
+            capacity: MAX_CALLER_STATES,
+            states: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the rate limits.
+    pub fn limits(&self) -> &RateLimits {
+        &self.limits
+    }
+
+    /// Takes a request of the user from its token bucket and quotas.
+    /// Returns a rate limited error with the milliseconds to wait if any is exhausted,
+    /// the request is not taken then.
+    pub fn check(&self, user: &User, now_ms: u64) -> Result<(), AndaError> {
+        let limit = self.limits.limit_for(user, now_ms);
+        let burst = if limit.burst > 0 {
+            limit.burst
+        } else {
+            limit.per_minute
+        };
+        if burst == 0 && limit.daily_quota == 0 && limit.monthly_quota == 0 {
+            return Ok(());
+        }
+
+        let day = now_ms / DAY_MS;
+        let month = month_of(now_ms);
+        let mut states = self.states.lock();
+        if states.len() >= self.capacity && !states.contains_key(&user.id) {
+            evict_idle(&mut states, self.capacity / 10);
+        }
+
+        let state = states.entry(user.id).or_insert_with(|| CallerState {
+            tokens: burst as f64,
+            refilled_at: now_ms,
+            ..Default::default()
+        });
+        state.seen_at = now_ms;
+        if state.day != day {
+            state.day = day;
+            state.daily_requests = 0;
+        }
+        if state.month != month {
+            state.month = month;
+            state.monthly_requests = 0;
+        }
+
+        if limit.monthly_quota > 0 && state.monthly_requests >= limit.monthly_quota {
+            return Err(AndaError::rate_limited(
+                format!("monthly quota of {} requests exceeded", limit.monthly_quota),
+                next_month_ms(now_ms).saturating_sub(now_ms),
+            ));
+        }
+        if limit.daily_quota > 0 && state.daily_requests >= limit.daily_quota {
+            return Err(AndaError::rate_limited(
+                format!("daily quota of {} requests exceeded", limit.daily_quota),
+                (day + 1) * DAY_MS - now_ms,
+            ));
+        }
+
+        if burst > 0 {
+            // refills the bucket at the rate per minute
+            let rate = limit.per_minute as f64 / 60_000.0;
+            let elapsed = now_ms.saturating_sub(state.refilled_at) as f64;
+            state.tokens = (state.tokens + elapsed * rate).min(burst as f64);
+            state.refilled_at = now_ms;
+            if state.tokens < 1.0 {
+                let retry_after_ms = if rate > 0.0 {
+                    ((1.0 - state.tokens) / rate).ceil() as u64
+                } else {
+                    DAY_MS
+                };
+                return Err(AndaError::rate_limited(
+                    format!(
+                        "rate limit of {} requests per minute exceeded",
+                        limit.per_minute
+                    ),
+                    retry_after_ms,
+                ));
+            }
+            state.tokens -= 1.0;
+        }
+
+        state.daily_requests += 1;
+        state.monthly_requests += 1;
+        Ok(())
+    }
+}
+
+/// Evicts at least `n` of the least recently seen callers.
+fn evict_idle(states: &mut HashMap<Principal, CallerState>, n: usize) {
+    let mut seen: Vec<u64> = states.values().map(|state| state.seen_at).collect();
+    let n = n.clamp(1, seen.len());
+    let (_, cutoff, _) = seen.select_nth_unstable(n - 1);
+    let cutoff = *cutoff;
+    states.retain(|_, state| state.seen_at > cutoff);
+}
+
+/// Returns the UTC calendar month of the timestamp, as `year * 12 + month0`.
+fn month_of(now_ms: u64) -> u32 {
+    let dt = DateTime::from_timestamp_millis(now_ms as i64).unwrap_or_default();
+    dt.year() as u32 * 12 + dt.month0()
+}
+
+/// Returns the timestamp of the start of the next UTC calendar month.
+fn next_month_ms(now_ms: u64) -> u64 {
+    let month = month_of(now_ms) + 1;
+    Utc.with_ymd_and_hms((month / 12) as i32, month % 12 + 1, 1, 0, 0, 0)
+        .single()
+        .map(|dt| dt.timestamp_millis() as u64)
+        .unwrap_or(now_ms + DAY_MS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anda_core::ErrorCode;
+
+    #[test]
+    fn test_rate_limiter() {
+        let vip = Principal::from_slice(&[1]);
+        let limiter = RateLimiter::new(RateLimits {
+            default: RateLimit {
+                per_minute: 60,
+                burst: 2,
+                daily_quota: 4,
+                ..Default::default()
+            },
+            tiers: BTreeMap::from([(
+                1,
+                RateLimit {
+                    monthly_quota: 1,
+                    ..Default::default()
+                },
+            )]),
+            principals: BTreeMap::from([(vip, RateLimit::default())]),
+        });
+
+        // 2024-01-31T23:59:50Z
+        let now_ms = 1_706_745_590_000;
+        let user = User::new(Principal::from_slice(&[2]));
+        assert!(limiter.check(&user, now_ms).is_ok());
+        assert!(limiter.check(&user, now_ms).is_ok());
+        let err = limiter.check(&user, now_ms).unwrap_err();
+        assert_eq!(err.code, ErrorCode::RateLimited);
+        assert!(err.retryable);
+        assert_eq!(err.retry_after_ms(), Some(1000));
+
+        // refills a token per second
+        assert!(limiter.check(&user, now_ms + 1000).is_ok());
+        assert!(limiter.check(&user, now_ms + 2000).is_ok());
+        let err = limiter.check(&user, now_ms + 3000).unwrap_err();
+        assert!(err.message.contains("daily quota"), "{}", err.message);
+        assert_eq!(err.retry_after_ms(), Some(7000));
+        assert!(limiter.check(&user, now_ms + 10_000).is_ok());
+
+        // tier limits apply to unexpired subscriptions
+        let mut user = User::new(Principal::from_slice(&[3]));
+        user.subscription_tier = 1;
+        user.subscription_expiry = now_ms + DAY_MS;
+        assert!(limiter.check(&user, now_ms).is_ok());
+        let err = limiter.check(&user, now_ms).unwrap_err();
+        assert!(err.message.contains("monthly quota"), "{}", err.message);
+        assert_eq!(err.retry_after_ms(), Some(10_000));
+        assert!(limiter.check(&user, now_ms + 10_000).is_ok());
+
+        let user = User::new(vip);
+        for _ in 0..10 {
+            assert!(limiter.check(&user, now_ms).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_evict_idle_callers() {
+        let mut limiter = RateLimiter::new(RateLimits {
+            default: RateLimit {
+                daily_quota: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        limiter.capacity = 10;
+
+        let now_ms = 1_706_745_590_000;
+        for i in 0..10u8 {
+            let user = User::new(Principal::from_slice(&[i]));
+            assert!(limiter.check(&user, now_ms + i as u64).is_ok());
+        }
+        assert_eq!(limiter.states.lock().len(), 10);
+
+        // the least recently seen caller is evicted for a new one
+        let first = User::new(Principal::from_slice(&[0]));
+        let last = User::new(Principal::from_slice(&[9]));
+        assert!(limiter.check(&last, now_ms + 10).is_err());
+        assert!(
+            limiter
+                .check(&User::new(Principal::from_slice(&[10])), now_ms + 11)
+                .is_ok()
+        );
+        assert_eq!(limiter.states.lock().len(), 10);
+        assert!(!limiter.states.lock().contains_key(&first.id));
+        assert!(limiter.check(&last, now_ms + 12).is_err());
+    }
+}
@@ -1,8 +1,9 @@
-use anda_db_schema::{AndaDBSchema, FieldEntry, FieldType, Schema, SchemaError};
+use anda_core::AndaError;
+use anda_db_schema::{AndaDBSchema, FieldEntry, FieldType, Fv, Schema, SchemaError};
 use candid::Principal;
 use parking_lot::RwLock;
 use serde::{Deserialize, Serialize};
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 
 /// Represents a state for a user to access the engine.
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, AndaDBSchema)]
@@ -19,6 +20,7 @@ pub struct User {
     pub features: BTreeSet<String>,
 
     /// The status of the user, -2: banned, -1: suspended, 0: active.
+    #[field_type = "Json"] // BUILDFIX
     pub status: i8,
 
     /// The subscription tier of the user. 0: free, 1: premium, 2: enterprise.
@@ -44,6 +46,10 @@ pub struct User {
 
     /// The number of credit consumed by the user.
     pub credit_consumed: u64,
+
+    /// The credit charged beyond the credit balance, settled by the next topup.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credit_debt: Option<u64>,
 }
 
 impl User {
@@ -61,10 +67,77 @@ impl User {
             agent_requests: 0,
             tool_requests: 0,
             credit_consumed: 0,
+            credit_debt: None,
         }
     }
+
+    pub fn increment_agent_requests(&mut self, now_ms: u64) {
+        self.agent_requests = self.agent_requests.saturating_add(1);
+        self.last_access = now_ms;
+    }
+
+    pub fn increment_tool_requests(&mut self, now_ms: u64) {
+        self.tool_requests = self.tool_requests.saturating_add(1);
+        self.last_access = now_ms;
+    }
+
+    /// Topup the credit balance for the user, settling the credit debt first.
+    pub fn topup_credit(&mut self, credit: u64, expiry_ms: u64) {
+        let credit = self.settle_debt(credit);
+        self.credit_balance = self.credit_balance.saturating_add(credit);
+        self.credit_expiry = expiry_ms;
+    }
+
+    /// Updates the subscription tier and expiry for the user.
+    pub fn update_subscription(&mut self, tier: u8, expiry_ms: u64) {
+        self.subscription_tier = tier;
+        self.subscription_expiry = expiry_ms;
+    }
+
+    /// Updates the features for the user.
+    pub fn update_features(&mut self, features: BTreeSet<String>) {
+        self.features = features;
+    }
+
+    /// Updates the status for the user.
+    pub fn update_status(&mut self, status: i8) {
+        self.status = status;
+    }
+
+    /// Returns the mutable fields of the user, to update the document in the collection "users".
+    pub fn to_changes(&self) -> BTreeMap<String, Fv> {
+        BTreeMap::from([
+            (
+                "features".to_string(),
+                Fv::Array(self.features.iter().cloned().map(Fv::Text).collect()),
+            ),
+            ("status".to_string(), Fv::I64(self.status as i64)),
+            (
+                "subscription_tier".to_string(),
+                Fv::U64(self.subscription_tier as u64),
+            ),
+            (
+                "subscription_expiry".to_string(),
+                Fv::U64(self.subscription_expiry),
+            ),
+            ("credit_balance".to_string(), Fv::U64(self.credit_balance)),
+            ("credit_expiry".to_string(), Fv::U64(self.credit_expiry)),
+            ("last_access".to_string(), Fv::U64(self.last_access)),
+            ("agent_requests".to_string(), Fv::U64(self.agent_requests)),
+            ("tool_requests".to_string(), Fv::U64(self.tool_requests)),
+            ("credit_consumed".to_string(), Fv::U64(self.credit_consumed)),
+            (
+                "credit_debt".to_string(),
+                self.credit_debt.map(Fv::U64).unwrap_or(Fv::Null),
+            ),
+        ])
+    }
 }
 
+/// A change applied to the latest state of a user by [`super::Management::update_user`].
+/// The change is discarded if it returns an error.
+pub type UserChange = Box<dyn FnOnce(&mut User) -> Result<(), AndaError> + Send>;
+
 #[derive(Debug)]
 pub struct UserState {
     pub(crate) user: RwLock<User>,
@@ -83,13 +156,18 @@ impl UserState {
         }
     }
 
-    fn with<F, R>(&self, f: F) -> R
+    pub(crate) fn with<F, R>(&self, f: F) -> R
     where
         F: FnOnce(&User) -> R,
     {
         f(&self.user.read())
     }
 
+    /// Returns a snapshot of the user.
+    pub fn to_user(&self) -> User {
+        self.with(|user| user.clone())
+    }
+
     /// Returns the subscription tier and expiry.
     pub fn subscription(&self) -> (u8, u64) {
         self.with(|user| (user.subscription_tier, user.subscription_expiry))
@@ -115,6 +193,7 @@ impl UserState {
         let mut user = self.user.write();
         if user.credit_expiry > now_ms && user.credit_balance >= credit {
             user.credit_balance -= credit;
+            user.credit_consumed = user.credit_consumed.saturating_add(credit);
             true
         } else {
             false
@@ -122,40 +201,30 @@ impl UserState {
     }
 
     pub fn increment_agent_requests(&self, now_ms: u64) {
-        let mut user = self.user.write();
-        user.agent_requests = user.agent_requests.saturating_add(1);
-        user.last_access = now_ms;
+        self.user.write().increment_agent_requests(now_ms)
     }
 
     pub fn increment_tool_requests(&self, now_ms: u64) {
-        let mut user = self.user.write();
-        user.tool_requests = user.tool_requests.saturating_add(1);
-        user.last_access = now_ms;
+        self.user.write().increment_tool_requests(now_ms)
     }
 
     /// Topup the credit balance for the user.
     pub fn topup_credit(&self, credit: u64, expiry_ms: u64) {
-        let mut user = self.user.write();
-        user.credit_balance = user.credit_balance.saturating_add(credit);
-        user.credit_expiry = expiry_ms;
+        self.user.write().topup_credit(credit, expiry_ms)
     }
 
     /// Updates the subscription tier and expiry for the user.
     pub fn update_subscription(&self, tier: u8, expiry_ms: u64) {
-        let mut user = self.user.write();
-        user.subscription_tier = tier;
-        user.subscription_expiry = expiry_ms;
+        self.user.write().update_subscription(tier, expiry_ms)
     }
 
     /// Updates the features for the user.
     pub fn update_features(&self, features: BTreeSet<String>) {
-        let mut user = self.user.write();
-        user.features = features;
+        self.user.write().update_features(features)
     }
 
     /// Updates the status for the user.
     pub fn update_status(&self, status: i8) {
-        let mut user = self.user.write();
-        user.status = status;
+        self.user.write().update_status(status)
     }
 }
@@ -10,7 +10,9 @@ use anda_db::{
     index::BTree,
     query::{Filter, Query, RangeQuery, Search},
 };
-use anda_db_schema::{AndaDBSchema, FieldEntry, FieldType, Ft, Fv, Json, Schema, SchemaError};
+use anda_db_schema::{
+    AndaDBSchema, FieldEntry, FieldKey, FieldType, Ft, Fv, Json, Schema, SchemaError,
+};
 use anda_db_tfs::jieba_tokenizer;
 use anda_kip::{
     CommandType, DescribeTarget, KIP_FUNCTION_DEFINITION, KipError, META_SYSTEM_NAME, MetaCommand,
@@ -924,7 +926,10 @@ impl Tool<BaseCtx> for MemoryTool {
                 if res.blob.is_none()
                     && let Some(uri) = &res.uri
                 {
-                    res.blob = FetchWebResourcesTool::fetch_as_bytes(&ctx, uri).await.ok();
+                    res.blob = FetchWebResourcesTool::fetch_as_bytes(&ctx, uri)
+                        .await
+                        .ok()
+                        .map(|b| anda_db_schema::ByteBufB64(b.0)); // BUILDFIX
                 }
 
                 Ok(ToolOutput::new(Response::Ok {
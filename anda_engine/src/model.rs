@@ -3,29 +3,46 @@
 //! This module provides implementations for various AI model providers, including:
 //! - OpenAI (completion and embedding models)
 //! - DeepSeek (completion models)
-//! - Cohere (embedding models)
+//! - Anthropic (completion models)
+//! - Gemini (completion and embedding models)
+//! - Cohere (embedding and rerank models)
+//! - Ollama (completion and embedding models, for local models)
+//!
+//! The provider clients retry rate limited and failed requests with [`retry::RetryPolicy`].
+//!
+//! The [`router::ModelRouter`] combines multiple completion models with fallback,
+//! weighted load balancing and model aliases.
+//!
+//! Completion models count tokens with a [`Tokenizer`], e.g. a [`anda_core::BpeTokenizer`]
+//! for OpenAI models, it falls back to a [`HeuristicTokenizer`].
 //!
 //! Each provider implementation includes:
 //! - Client configuration and management
-//! - API request/response handling
+//! - API request/response handling, including streaming responses
 //! - Conversion to Anda's internal data structures
 //!
 //! The module is designed to be extensible, allowing easy addition of new model providers
-//! while maintaining a consistent interface through the `CompletionFeaturesDyn` and
-//! `EmbeddingFeaturesDyn` traits.
+//! while maintaining a consistent interface through the `CompletionFeaturesDyn`,
+//! `EmbeddingFeaturesDyn` and `RerankFeaturesDyn` traits.
 
 use anda_core::{
-    AgentOutput, BoxError, BoxPinFut, CONTENT_TYPE_JSON, CompletionRequest, Embedding, ToolCall,
-    Usage,
+    AgentOutput, BoxError, BoxPinFut, ByteBufB64, CONTENT_TYPE_JSON, CompletionChunk,
+    CompletionRequest, Embedding, HeuristicTokenizer, RerankResult, Tokenizer, ToolCall, Usage,
 };
+use futures::stream::{self, BoxStream, StreamExt};
 use std::sync::Arc;
 use std::time::Duration;
 
+pub mod anthropic;
 pub mod cohere;
 pub mod deepseek;
 pub mod gemini;
 pub mod kimi;
+pub mod ollama;
 pub mod openai;
+pub mod retry;
+pub mod router;
+pub mod sse;
 pub mod xai;
 
 pub use reqwest::Proxy;
@@ -36,6 +53,37 @@ use crate::APP_USER_AGENT;
 pub trait CompletionFeaturesDyn: Send + Sync + 'static {
     /// Performs a completion request and returns a future with the agent's output
     fn completion(&self, req: CompletionRequest) -> BoxPinFut<Result<AgentOutput, BoxError>>;
+
+    /// Performs a streaming completion request.
+    ///
+    /// The stream yields text, reasoning and tool call deltas as they are generated,
+    /// and ends with a [`CompletionChunk::Output`] holding the complete output.
+    /// The default implementation falls back to [`CompletionFeaturesDyn::completion`]
+    /// and yields the output only.
+    fn completion_stream(
+        &self,
+        req: CompletionRequest,
+    ) -> BoxStream<'static, Result<CompletionChunk, BoxError>> {
+        let fut = self.completion(req);
+        stream::once(async move { fut.await.map(CompletionChunk::Output) }).boxed()
+    }
+
+    /// Whether the model supports [`CompletionRequest::output_schema`] natively.
+    /// Structured outputs fall back to tool-based extraction if not.
+    fn supports_output_schema(&self) -> bool {
+        true
+    }
+
+    /// Returns the tokenizer to count the tokens of the model.
+    /// The default implementation returns a [`HeuristicTokenizer`].
+    fn tokenizer(&self) -> Arc<dyn Tokenizer> {
+        Arc::new(HeuristicTokenizer)
+    }
+
+    /// Returns the context window of the model in tokens, if known.
+    fn context_window(&self) -> Option<usize> {
+        None
+    }
 }
 
 /// Trait for dynamic embedding features that can be used across threads
@@ -50,6 +98,17 @@ pub trait EmbeddingFeaturesDyn: Send + Sync + 'static {
     fn embed_query(&self, text: String) -> BoxPinFut<Result<(Embedding, Usage), BoxError>>;
 }
 
+/// Trait for dynamic rerank features that can be used across threads
+pub trait RerankFeaturesDyn: Send + Sync + 'static {
+    /// Reranks the documents by relevance to the query and returns at most `top_n` results
+    fn rerank(
+        &self,
+        query: String,
+        documents: Vec<String>,
+        top_n: usize,
+    ) -> BoxPinFut<Result<(Vec<RerankResult>, Usage), BoxError>>;
+}
+
 /// A placeholder implementation for unimplemented features
 #[derive(Clone, Debug)]
 pub struct NotImplemented;
@@ -74,6 +133,17 @@ impl EmbeddingFeaturesDyn for NotImplemented {
     }
 }
 
+impl RerankFeaturesDyn for NotImplemented {
+    fn rerank(
+        &self,
+        _query: String,
+        _documents: Vec<String>,
+        _top_n: usize,
+    ) -> BoxPinFut<Result<(Vec<RerankResult>, Usage), BoxError>> {
+        Box::pin(futures::future::ready(Err("not implemented".into())))
+    }
+}
+
 /// A mock implementation for testing purposes
 #[derive(Clone, Debug)]
 pub struct MockImplemented;
@@ -95,6 +165,7 @@ impl CompletionFeaturesDyn for MockImplemented {
                         call_id: None,
                         result: None,
                         remote_id: None,
+                        error: None,
                     })
                 })
                 .collect(),
@@ -132,13 +203,40 @@ impl EmbeddingFeaturesDyn for MockImplemented {
     }
 }
 
-/// Main model struct that combines embedding and completion capabilities
+impl RerankFeaturesDyn for MockImplemented {
+    fn rerank(
+        &self,
+        _query: String,
+        documents: Vec<String>,
+        top_n: usize,
+    ) -> BoxPinFut<Result<(Vec<RerankResult>, Usage), BoxError>> {
+        // 保持原有顺序，分数递减
+        let n = documents.len().min(top_n);
+        Box::pin(futures::future::ready(Ok((
+            (0..n)
+                .map(|index| RerankResult {
+                    index,
+                    relevance_score: 1.0 - index as f32 / n as f32,
+                })
+                .collect(),
+            Usage::default(),
+        ))))
+    }
+}
+
+/// Main model struct that combines embedding, completion and rerank capabilities
 #[derive(Clone)]
 pub struct Model {
     /// Embedding feature implementation
     pub embedder: Arc<dyn EmbeddingFeaturesDyn>,
     /// Completion feature implementation
     pub completer: Arc<dyn CompletionFeaturesDyn>,
+    /// Rerank feature implementation
+    pub reranker: Arc<dyn RerankFeaturesDyn>,
+    /// Tokenizer overriding the completer's tokenizer
+    pub tokenizer: Option<Arc<dyn Tokenizer>>,
+    /// Context window overriding the completer's context window
+    pub context_window: Option<usize>,
 }
 
 impl Model {
@@ -150,6 +248,9 @@ impl Model {
         Self {
             embedder,
             completer,
+            reranker: Arc::new(NotImplemented),
+            tokenizer: None,
+            context_window: None,
         }
     }
 
@@ -158,6 +259,9 @@ impl Model {
         Self {
             completer,
             embedder: Arc::new(NotImplemented),
+            reranker: Arc::new(NotImplemented),
+            tokenizer: None,
+            context_window: None,
         }
     }
 
@@ -166,6 +270,9 @@ impl Model {
         Self {
             completer: Arc::new(NotImplemented),
             embedder: Arc::new(NotImplemented),
+            reranker: Arc::new(NotImplemented),
+            tokenizer: None,
+            context_window: None,
         }
     }
 
@@ -174,13 +281,60 @@ impl Model {
         Self {
             completer: Arc::new(MockImplemented),
             embedder: Arc::new(MockImplemented),
+            reranker: Arc::new(MockImplemented),
+            tokenizer: None,
+            context_window: None,
         }
     }
 
+    /// Sets the rerank feature implementation
+    pub fn with_reranker(mut self, reranker: Arc<dyn RerankFeaturesDyn>) -> Self {
+        self.reranker = reranker;
+        self
+    }
+
+    /// Sets the tokenizer, overriding the tokenizer of the completion model
+    pub fn with_tokenizer(mut self, tokenizer: Arc<dyn Tokenizer>) -> Self {
+        self.tokenizer = Some(tokenizer);
+        self
+    }
+
+    /// Returns the tokenizer of the completion model
+    pub fn tokenizer(&self) -> Arc<dyn Tokenizer> {
+        match &self.tokenizer {
+            Some(tokenizer) => tokenizer.clone(),
+            None => self.completer.tokenizer(),
+        }
+    }
+
+    /// Sets the context window in tokens, overriding the context window of the completion model.
+    /// Completion runs are compacted when nearing it, see [`crate::context::ContextStrategy`].
+    pub fn with_context_window(mut self, tokens: usize) -> Self {
+        self.context_window = Some(tokens);
+        self
+    }
+
+    /// Returns the context window of the completion model in tokens, if known
+    pub fn context_window(&self) -> Option<usize> {
+        self.context_window
+            .or_else(|| self.completer.context_window())
+    }
+
     pub async fn completion(&self, req: CompletionRequest) -> Result<AgentOutput, BoxError> {
         self.completer.completion(req).await
     }
 
+    pub fn completion_stream(
+        &self,
+        req: CompletionRequest,
+    ) -> BoxStream<'static, Result<CompletionChunk, BoxError>> {
+        self.completer.completion_stream(req)
+    }
+
+    pub fn supports_output_schema(&self) -> bool {
+        self.completer.supports_output_schema()
+    }
+
     pub fn ndims(&self) -> usize {
         self.embedder.ndims()
     }
@@ -195,9 +349,21 @@ impl Model {
     pub async fn embed_query(&self, text: &str) -> Result<(Embedding, Usage), BoxError> {
         self.embedder.embed_query(text.to_string()).await
     }
+
+    pub async fn rerank(
+        &self,
+        query: &str,
+        documents: impl IntoIterator<Item = String> + Send,
+        top_n: usize,
+    ) -> Result<(Vec<RerankResult>, Usage), BoxError> {
+        self.reranker
+            .rerank(query.to_string(), documents.into_iter().collect(), top_n)
+            .await
+    }
 }
 
-/// Creates a new reqwest client builder with default settings
+/// Creates a new reqwest client builder with default settings.
+/// Requests are sent with [`retry::RetryPolicy`] by the provider clients.
 pub fn request_client_builder() -> reqwest::ClientBuilder {
     reqwest::Client::builder()
         .use_rustls_tls()
@@ -217,3 +383,79 @@ pub fn request_client_builder() -> reqwest::ClientBuilder {
             headers
         })
 }
+
+/// Converts the Base64URL encoding of `ByteBufB64` to the standard Base64 encoding
+/// required by the model APIs.
+pub(crate) fn std_base64(data: &ByteBufB64) -> String {
+    data.to_string()
+        .chars()
+        .map(|c| match c {
+            '-' => '+',
+            '_' => '/',
+            c => c,
+        })
+        .collect()
+}
+
+/// Serves the responses of the routes (path, content type, body) on a local HTTP server,
+/// records the requests (lowercased head and JSON body).
+#[cfg(test)]
+pub(crate) async fn mock_server(
+    routes: Vec<(&'static str, &'static str, String)>,
+) -> (
+    String,
+    Arc<tokio::sync::Mutex<Vec<(String, serde_json::Value)>>>,
+) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let requests = Arc::new(tokio::sync::Mutex::new(Vec::new()));
+    let recorder = requests.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf: Vec<u8> = Vec::new();
+            let mut chunk = vec![0u8; 4096];
+            let (head, len) = loop {
+                let n = socket.read(&mut chunk).await.unwrap();
+                buf.extend_from_slice(&chunk[..n]);
+                if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                    let head = String::from_utf8_lossy(&buf[..pos]).to_lowercase();
+                    buf.drain(..pos + 4);
+                    let len = head
+                        .lines()
+                        .find_map(|l| l.strip_prefix("content-length:"))
+                        .map(|v| v.trim().parse::<usize>().unwrap())
+                        .unwrap_or_default();
+                    break (head, len);
+                }
+            };
+            while buf.len() < len {
+                let n = socket.read(&mut chunk).await.unwrap();
+                buf.extend_from_slice(&chunk[..n]);
+            }
+
+            let route = routes
+                .iter()
+                .find(|(path, _, _)| head.starts_with(&format!("post {}", path.to_lowercase())));
+            recorder
+                .lock()
+                .await
+                .push((head, serde_json::from_slice(&buf).unwrap()));
+            let res = match route {
+                Some((_, content_type, body)) => format!(
+                    "HTTP/1.1 200 OK\r\ncontent-type: {}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    content_type,
+                    body.len(),
+                    body
+                ),
+                None => "HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+                    .to_string(),
+            };
+            let _ = socket.write_all(res.as_bytes()).await;
+            let _ = socket.shutdown().await;
+        }
+    });
+    (format!("http://{}", addr), requests)
+}
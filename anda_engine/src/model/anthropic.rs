@@ -0,0 +1,854 @@
+//! Anthropic API client implementation for Anda Engine
+//!
+//! This module provides integration with Anthropic's Messages API, including:
+//! - Client configuration and management
+//! - Completion model handling, including streaming completions
+//! - Extended thinking, prompt caching and structured output via a forced tool
+//! - Response parsing and conversion to Anda's internal formats
+
+use anda_core::{
+    AgentOutput, BoxError, BoxPinFut, CompletionChunk, CompletionFeatures, CompletionRequest, Json,
+    Message, Resource,
+};
+use futures::{
+    StreamExt, TryStreamExt,
+    stream::{self, BoxStream},
+};
+use log::{Level::Debug, log_enabled};
+use serde_json::json;
+
+use super::{
+    CompletionFeaturesDyn, request_client_builder,
+    retry::{RetryPolicy, stream_with_requests, with_requests},
+    sse::{SseEvent, StreamAccumulator, completion_stream},
+};
+use crate::{rfc3339_datetime, unix_ms};
+
+pub mod types;
+
+// ================================================================
+// Main Anthropic Client
+// ================================================================
+const API_BASE_URL: &str = "https://api.anthropic.com/v1";
+const API_VERSION: &str = "2023-06-01";
+const DEFAULT_MAX_TOKENS: usize = 8192;
+
+pub static CLAUDE_SONNET_4_5: &str = "claude-sonnet-4-5";
+pub static CLAUDE_OPUS_4_1: &str = "claude-opus-4-1";
+pub static CLAUDE_HAIKU_4_5: &str = "claude-haiku-4-5";
+
+/// Anthropic API client configuration and HTTP client
+#[derive(Clone)]
+pub struct Client {
+    endpoint: String,
+    api_key: String,
+    http: reqwest::Client,
+    retry: RetryPolicy,
+}
+
+impl Client {
+    /// Creates a new Anthropic client instance with the provided API key
+    ///
+    /// # Arguments
+    /// * `api_key` - Anthropic API key for authentication
+    ///
+    /// # Returns
+    /// Configured Anthropic client instance
+    pub fn new(api_key: &str, endpoint: Option<String>) -> Self {
+        let endpoint = endpoint.unwrap_or_else(|| API_BASE_URL.to_string());
+        let endpoint = if endpoint.is_empty() {
+            API_BASE_URL.to_string()
+        } else {
+            endpoint
+        };
+        Self {
+            endpoint,
+            api_key: api_key.to_string(),
+            http: request_client_builder()
+                .build()
+                .expect("Anthropic reqwest client should build"),
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    /// Sets a custom HTTP client for the client
+    pub fn with_client(self, http: reqwest::Client) -> Self {
+        Self {
+            endpoint: self.endpoint,
+            api_key: self.api_key,
+            http,
+            retry: self.retry,
+        }
+    }
+
+    /// Sets the retry policy for the API requests
+    pub fn with_retry(self, retry: RetryPolicy) -> Self {
+        Self { retry, ..self }
+    }
+
+    /// Creates a POST request builder for the specified API path
+    fn post(&self, path: &str) -> reqwest::RequestBuilder {
+        let url = format!("{}{}", self.endpoint, path);
+        self.http
+            .post(url)
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", API_VERSION)
+    }
+
+    /// Creates a new completion model instance using the default Anthropic model
+    pub fn completion_model(&self, model: &str) -> CompletionModel {
+        CompletionModel::new(
+            self.clone(),
+            if model.is_empty() {
+                CLAUDE_SONNET_4_5
+            } else {
+                model
+            },
+        )
+    }
+}
+
+/// Completion model wrapper for Anthropic API
+#[derive(Clone)]
+pub struct CompletionModel {
+    /// Anthropic client instance
+    client: Client,
+    /// Model identifier
+    pub model: String,
+    /// The default max tokens, used if the request does not set `max_output_tokens`
+    max_tokens: usize,
+    /// The thinking budget tokens, extended thinking is disabled if None
+    thinking: Option<usize>,
+    /// Whether to cache the prompt prefix
+    prompt_cache: bool,
+}
+
+impl CompletionModel {
+    /// Creates a new completion model instance
+    ///
+    /// # Arguments
+    /// * `client` - Anthropic client instance
+    /// * `model` - Model identifier string
+    pub fn new(client: Client, model: &str) -> Self {
+        Self {
+            client,
+            model: model.to_string(),
+            max_tokens: DEFAULT_MAX_TOKENS,
+            thinking: None,
+            prompt_cache: true,
+        }
+    }
+
+    /// Sets the default max tokens to generate, the API requires it.
+    pub fn with_max_tokens(mut self, max_tokens: usize) -> Self {
+        self.max_tokens = max_tokens;
+        self
+    }
+
+    /// Enables extended thinking with the budget tokens,
+    /// the budget must be ≥1024 and less than the max tokens.
+    pub fn with_thinking(mut self, budget_tokens: usize) -> Self {
+        self.thinking = Some(budget_tokens);
+        self
+    }
+
+    /// Enables or disables prompt caching, it is enabled by default.
+    pub fn with_prompt_cache(mut self, enable: bool) -> Self {
+        self.prompt_cache = enable;
+        self
+    }
+}
+
+impl CompletionFeatures for CompletionModel {
+    async fn completion(
+        &self,
+        req: CompletionRequest,
+        _resources: Vec<Resource>,
+    ) -> Result<AgentOutput, BoxError> {
+        CompletionFeaturesDyn::completion(self, req).await
+    }
+}
+
+impl CompletionModel {
+    /// Builds the messages request, returns it with the new raw history and chat history.
+    fn build_request(
+        &self,
+        req: CompletionRequest,
+    ) -> Result<(types::MessagesRequest, Vec<Json>, Vec<Message>), BoxError> {
+        let timestamp = unix_ms();
+        let mut raw_history: Vec<Json> = Vec::new();
+        let mut chat_history: Vec<Message> = Vec::new();
+        let mut areq = types::MessagesRequest {
+            model: self.model.clone(),
+            max_tokens: req.max_output_tokens.unwrap_or(self.max_tokens),
+            thinking: self.thinking.map(types::ThinkingConfig::enabled),
+            ..Default::default()
+        };
+
+        let mut instructions = req.instructions;
+        for msg in req.raw_history {
+            areq.messages.push(serde_json::from_value(msg)?);
+        }
+
+        for msg in req.chat_history {
+            let val = types::MessageParam::from(msg);
+            if val.content.is_empty() {
+                continue;
+            }
+            raw_history.push(serde_json::to_value(&val)?);
+            areq.messages.push(val);
+        }
+
+        if let Some(mut msg) = req
+            .documents
+            .to_message(&rfc3339_datetime(timestamp).unwrap())
+        {
+            msg.timestamp = Some(timestamp);
+            chat_history.push(msg.clone());
+            let msg = types::MessageParam::from(msg);
+            raw_history.push(serde_json::to_value(&msg)?);
+            areq.messages.push(msg);
+        }
+
+        let mut content = req.content;
+        if !req.prompt.is_empty() {
+            content.push(req.prompt.into());
+        }
+        if !content.is_empty() {
+            let msg = Message {
+                role: req.role.unwrap_or_else(|| "user".to_string()),
+                content,
+                timestamp: Some(timestamp),
+                ..Default::default()
+            };
+
+            chat_history.push(msg.clone());
+            let msg = types::MessageParam::from(msg);
+            raw_history.push(serde_json::to_value(&msg)?);
+            areq.messages.push(msg);
+        }
+
+        // temperature must not be set with extended thinking, and its range is [0.0, 1.0]
+        if let Some(temperature) = req.temperature
+            && areq.thinking.is_none()
+        {
+            areq.temperature = Some(temperature.min(1.0));
+        }
+
+        if let Some(stop) = req.stop {
+            areq.stop_sequences = Some(stop);
+        }
+
+        let has_tools = !req.tools.is_empty();
+        areq.tools = req.tools.into_iter().map(|v| v.into()).collect();
+        if has_tools && req.tool_choice_required {
+            areq.tool_choice = Some(types::ToolChoice::Any);
+        }
+
+        if let Some(output_schema) = req.output_schema {
+            areq.tools.push(types::ToolParam {
+                name: types::OUTPUT_TOOL.to_string(),
+                description: "Returns the final answer as a JSON object.".to_string(),
+                input_schema: output_schema,
+                cache_control: None,
+            });
+            // extended thinking does not support forced tool use
+            if !has_tools && areq.thinking.is_none() {
+                areq.tool_choice = Some(types::ToolChoice::Tool {
+                    name: types::OUTPUT_TOOL.to_string(),
+                });
+            } else {
+                instructions = format!(
+                    "{}\n\nYou must call the `{}` tool to give the final answer.",
+                    instructions,
+                    types::OUTPUT_TOOL
+                );
+            }
+        }
+
+        let instructions = instructions.trim();
+        if !instructions.is_empty() {
+            areq.system = vec![
+                types::BlockKind::Text {
+                    text: instructions.to_string(),
+                }
+                .into(),
+            ];
+        }
+
+        if self.prompt_cache {
+            areq.cache_prompt();
+        }
+
+        Ok((areq, raw_history, chat_history))
+    }
+}
+
+impl CompletionFeaturesDyn for CompletionModel {
+    fn completion(&self, req: CompletionRequest) -> BoxPinFut<Result<AgentOutput, BoxError>> {
+        let client = self.client.clone();
+        let prepared = self.build_request(req);
+
+        Box::pin(async move {
+            let (areq, raw_history, chat_history) = prepared?;
+            if log_enabled!(Debug)
+                && let Ok(val) = serde_json::to_string(&areq)
+            {
+                log::debug!(request = val; "Anthropic completions request");
+            }
+
+            let (response, attempts) = client
+                .retry
+                .send(client.post("/messages").json(&areq))
+                .await?;
+            if response.status().is_success() {
+                let text = response.text().await?;
+
+                match serde_json::from_str::<types::MessagesResponse>(&text) {
+                    Ok(res) => {
+                        if log_enabled!(Debug) {
+                            log::debug!(
+                                request:serde = areq,
+                                response:serde = res;
+                                "Anthropic completions response");
+                        } else if res.maybe_failed() {
+                            log::warn!(
+                                request:serde = areq,
+                                response:serde = res;
+                                "completions maybe failed");
+                        }
+
+                        res.try_into(raw_history, chat_history)
+                            .map(|output| with_requests(output, attempts))
+                    }
+                    Err(err) => {
+                        Err(format!("Anthropic completions error: {}, body: {}", err, text).into())
+                    }
+                }
+            } else {
+                let status = response.status();
+                let msg = response.text().await?;
+                log::error!(
+                    request:serde = areq;
+                    "completions request failed: {status}, body: {msg}",
+                );
+                Err(format!("Anthropic completions error: {}", msg).into())
+            }
+        })
+    }
+
+    fn completion_stream(
+        &self,
+        req: CompletionRequest,
+    ) -> BoxStream<'static, Result<CompletionChunk, BoxError>> {
+        let client = self.client.clone();
+        let prepared = self.build_request(req);
+
+        stream::once(async move {
+            let (mut areq, raw_history, chat_history) = prepared?;
+            areq.stream = true;
+            if log_enabled!(Debug)
+                && let Ok(val) = serde_json::to_string(&areq)
+            {
+                log::debug!(request = val; "Anthropic completions stream request");
+            }
+
+            let (response, attempts) = client
+                .retry
+                .send(client.post("/messages").json(&areq))
+                .await?;
+            if response.status().is_success() {
+                Ok::<_, BoxError>(stream_with_requests(
+                    completion_stream(
+                        response,
+                        MessagesStreamAccumulator::new(raw_history, chat_history),
+                    ),
+                    attempts,
+                ))
+            } else {
+                let status = response.status();
+                let msg = response.text().await?;
+                log::error!(
+                    request:serde = areq;
+                    "completions request failed: {status}, body: {msg}",
+                );
+                Err(format!("Anthropic completions error: {}", msg).into())
+            }
+        })
+        .try_flatten()
+        .boxed()
+    }
+}
+
+/// A content block being streamed.
+struct StreamBlock {
+    block: Json,
+    /// The partial JSON input of a tool_use block.
+    input: String,
+    /// The index of the tool call, None for other blocks and the output tool.
+    tool_index: Option<usize>,
+}
+
+/// Accumulator for the Messages API SSE stream.
+///
+/// The content blocks are rebuilt from the deltas, so the final output is the same as a
+/// non-streaming response.
+struct MessagesStreamAccumulator {
+    raw_history: Vec<Json>,
+    chat_history: Vec<Message>,
+    id: String,
+    model: String,
+    blocks: Vec<StreamBlock>,
+    tool_calls: usize,
+    stop_reason: Option<Json>,
+    stop_sequence: Option<Json>,
+    usage: serde_json::Map<String, Json>,
+}
+
+impl MessagesStreamAccumulator {
+    fn new(raw_history: Vec<Json>, chat_history: Vec<Message>) -> Self {
+        Self {
+            raw_history,
+            chat_history,
+            id: String::new(),
+            model: String::new(),
+            blocks: Vec::new(),
+            tool_calls: 0,
+            stop_reason: None,
+            stop_sequence: None,
+            usage: serde_json::Map::new(),
+        }
+    }
+
+    fn merge_usage(&mut self, usage: Option<&Json>) {
+        if let Some(Json::Object(usage)) = usage {
+            for (k, v) in usage {
+                if !v.is_null() {
+                    self.usage.insert(k.clone(), v.clone());
+                }
+            }
+        }
+    }
+
+    fn start_block(&mut self, block: Json) -> Option<CompletionChunk> {
+        let mut chunk = None;
+        let mut tool_index = None;
+        match block["type"].as_str() {
+            Some("tool_use") if block["name"] != types::OUTPUT_TOOL => {
+                chunk = Some(CompletionChunk::ToolCall {
+                    index: self.tool_calls,
+                    call_id: block["id"].as_str().map(|v| v.to_string()),
+                    name: block["name"].as_str().map(|v| v.to_string()),
+                    args: String::new(),
+                });
+                tool_index = Some(self.tool_calls);
+                self.tool_calls += 1;
+            }
+            Some("text") => {
+                if let Some(text) = block["text"].as_str()
+                    && !text.is_empty()
+                {
+                    chunk = Some(CompletionChunk::Text {
+                        text: text.to_string(),
+                    });
+                }
+            }
+            _ => {}
+        }
+
+        self.blocks.push(StreamBlock {
+            block,
+            input: String::new(),
+            tool_index,
+        });
+        chunk
+    }
+
+    fn apply_delta(&mut self, index: usize, delta: &Json) -> Option<CompletionChunk> {
+        let sb = self.blocks.get_mut(index)?;
+        let append = |block: &mut Json, key: &str, text: &str| {
+            let prev = block[key].as_str().unwrap_or_default();
+            block[key] = Json::String(format!("{}{}", prev, text));
+        };
+
+        match delta["type"].as_str() {
+            Some("text_delta") => {
+                let text = delta["text"].as_str().unwrap_or_default();
+                append(&mut sb.block, "text", text);
+                Some(CompletionChunk::Text {
+                    text: text.to_string(),
+                })
+            }
+            Some("thinking_delta") => {
+                let text = delta["thinking"].as_str().unwrap_or_default();
+                append(&mut sb.block, "thinking", text);
+                Some(CompletionChunk::Reasoning {
+                    text: text.to_string(),
+                })
+            }
+            Some("signature_delta") => {
+                append(
+                    &mut sb.block,
+                    "signature",
+                    delta["signature"].as_str().unwrap_or_default(),
+                );
+                None
+            }
+            Some("input_json_delta") => {
+                let args = delta["partial_json"].as_str().unwrap_or_default();
+                sb.input.push_str(args);
+                match sb.tool_index {
+                    Some(index) => Some(CompletionChunk::ToolCall {
+                        index,
+                        call_id: None,
+                        name: None,
+                        args: args.to_string(),
+                    }),
+                    // 结构化输出作为文本增量
+                    None => Some(CompletionChunk::Text {
+                        text: args.to_string(),
+                    }),
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn stop_block(&mut self, index: usize) -> Result<(), BoxError> {
+        if let Some(sb) = self.blocks.get_mut(index)
+            && sb.block["type"] == "tool_use"
+            && !sb.input.is_empty()
+        {
+            sb.block["input"] = serde_json::from_str(&sb.input).map_err(|err| {
+                format!(
+                    "Anthropic completions error: invalid tool input: {}, data: {}",
+                    err, sb.input
+                )
+            })?;
+            sb.input.clear();
+        }
+        Ok(())
+    }
+}
+
+impl StreamAccumulator for MessagesStreamAccumulator {
+    fn push(&mut self, event: SseEvent) -> Result<Vec<CompletionChunk>, BoxError> {
+        let val: Json = serde_json::from_str(&event.data).map_err(|err| {
+            format!(
+                "Anthropic completions error: invalid stream event: {}, data: {}",
+                err, event.data
+            )
+        })?;
+
+        let index = val["index"].as_u64().unwrap_or_default() as usize;
+        let mut chunks = Vec::new();
+        match val["type"].as_str() {
+            Some("message_start") => {
+                let msg = &val["message"];
+                self.id = msg["id"].as_str().unwrap_or_default().to_string();
+                self.model = msg["model"].as_str().unwrap_or_default().to_string();
+                self.merge_usage(msg.get("usage"));
+            }
+            Some("content_block_start") => {
+                if let Some(chunk) = self.start_block(val["content_block"].clone()) {
+                    chunks.push(chunk);
+                }
+            }
+            Some("content_block_delta") => {
+                if let Some(chunk) = self.apply_delta(index, &val["delta"]) {
+                    chunks.push(chunk);
+                }
+            }
+            Some("content_block_stop") => {
+                self.stop_block(index)?;
+            }
+            Some("message_delta") => {
+                let delta = &val["delta"];
+                if !delta["stop_reason"].is_null() {
+                    self.stop_reason = Some(delta["stop_reason"].clone());
+                }
+                if !delta["stop_sequence"].is_null() {
+                    self.stop_sequence = Some(delta["stop_sequence"].clone());
+                }
+                self.merge_usage(val.get("usage"));
+            }
+            Some("error") => {
+                return Err(format!("Anthropic completions error: {}", val["error"]).into());
+            }
+            // ping, message_stop and unknown events
+            _ => {}
+        }
+
+        Ok(chunks)
+    }
+
+    fn finish(mut self) -> Result<AgentOutput, BoxError> {
+        for i in 0..self.blocks.len() {
+            self.stop_block(i)?;
+        }
+
+        let mut usage = json!({
+            "input_tokens": 0,
+            "output_tokens": 0,
+        });
+        usage.as_object_mut().unwrap().extend(self.usage);
+
+        let res = json!({
+            "id": self.id,
+            "model": self.model,
+            "content": self.blocks.into_iter().map(|sb| sb.block).collect::<Vec<_>>(),
+            "stop_reason": self.stop_reason,
+            "stop_sequence": self.stop_sequence,
+            "usage": usage,
+        });
+        let res: types::MessagesResponse = serde_json::from_value(res)
+            .map_err(|err| format!("Anthropic completions error: {}", err))?;
+        if res.maybe_failed() {
+            log::warn!(response:serde = res; "completions maybe failed");
+        }
+        res.try_into(self.raw_history, self.chat_history)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{mock_server, sse::collect_chunks};
+    use anda_core::{ContentPart, FunctionDefinition};
+
+    fn model(endpoint: String) -> CompletionModel {
+        Client::new("test-key", Some(endpoint))
+            .with_client(reqwest::Client::new())
+            .with_retry(RetryPolicy::none())
+            .completion_model("")
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_messages_completion() {
+        let body = json!({
+            "id": "msg_1",
+            "type": "message",
+            "role": "assistant",
+            "model": "claude-sonnet-4-5",
+            "content": [
+                {"type": "thinking", "thinking": "Need weather.", "signature": "sig1"},
+                {"type": "text", "text": "Let me check."},
+                {"type": "tool_use", "id": "toolu_1", "name": "get_weather", "input": {"city": "Paris"}}
+            ],
+            "stop_reason": "tool_use",
+            "usage": {"input_tokens": 10, "output_tokens": 20, "cache_creation_input_tokens": 100, "cache_read_input_tokens": 50}
+        });
+        let (url, requests) =
+            mock_server(vec![("/messages", "application/json", body.to_string())]).await;
+        let model = model(url);
+
+        let req = CompletionRequest {
+            instructions: "You are a helpful assistant.".to_string(),
+            chat_history: vec![
+                Message {
+                    role: "user".to_string(),
+                    content: vec!["Hi".to_string().into()],
+                    ..Default::default()
+                },
+                Message {
+                    role: "assistant".to_string(),
+                    content: vec![
+                        ContentPart::Reasoning {
+                            text: "greeting".to_string(),
+                        },
+                        "Hello!".to_string().into(),
+                    ],
+                    ..Default::default()
+                },
+            ],
+            prompt: "What's the weather in Paris?".to_string(),
+            tools: vec![FunctionDefinition {
+                name: "get_weather".to_string(),
+                description: "Get the weather".to_string(),
+                parameters: json!({"type": "object", "properties": {"city": {"type": "string"}}}),
+                strict: None,
+            }],
+            temperature: Some(1.5),
+            ..Default::default()
+        };
+        let output = CompletionFeaturesDyn::completion(&model, req)
+            .await
+            .unwrap();
+        assert!(output.failed_reason.is_none());
+        assert_eq!(output.content, "Let me check.");
+        assert_eq!(output.tool_calls.len(), 1);
+        assert_eq!(output.tool_calls[0].name, "get_weather");
+        assert_eq!(output.tool_calls[0].call_id.as_deref(), Some("toolu_1"));
+        assert_eq!(output.tool_calls[0].args, json!({"city": "Paris"}));
+        assert_eq!(output.usage.input_tokens, 160);
+        assert_eq!(output.usage.output_tokens, 20);
+        assert_eq!(output.usage.requests, 1);
+        assert_eq!(output.raw_history.len(), 4);
+        assert_eq!(
+            output.raw_history[3]["content"][0],
+            json!({"type": "thinking", "thinking": "Need weather.", "signature": "sig1"})
+        );
+        assert_eq!(
+            output.chat_history.last().unwrap().content[0],
+            ContentPart::Reasoning {
+                text: "Need weather.".to_string()
+            }
+        );
+
+        let recorded = requests.lock().await;
+        let (head, areq) = &recorded[0];
+        assert!(head.starts_with("post /messages "));
+        assert!(head.contains("x-api-key: test-key"));
+        assert!(head.contains("anthropic-version: 2023-06-01"));
+        assert_eq!(areq["model"], "claude-sonnet-4-5");
+        assert_eq!(areq["max_tokens"], 8192);
+        assert_eq!(areq["temperature"], 1.0);
+        assert_eq!(
+            areq["system"],
+            json!([{"type": "text", "text": "You are a helpful assistant.", "cache_control": {"type": "ephemeral"}}])
+        );
+        assert_eq!(areq["tools"][0]["input_schema"]["type"], "object");
+        assert_eq!(areq["tools"][0]["cache_control"]["type"], "ephemeral");
+        assert!(areq.get("tool_choice").is_none());
+        assert_eq!(
+            areq["messages"],
+            json!([
+                {"role": "user", "content": [{"type": "text", "text": "Hi"}]},
+                {"role": "assistant", "content": [{"type": "text", "text": "Hello!"}]},
+                {"role": "user", "content": [{"type": "text", "text": "What's the weather in Paris?", "cache_control": {"type": "ephemeral"}}]}
+            ])
+        );
+        drop(recorded);
+
+        // continue with the tool result, the thinking block is passed back with its signature
+        let req = CompletionRequest {
+            role: Some("tool".to_string()),
+            raw_history: output.raw_history,
+            content: vec![ContentPart::ToolOutput {
+                name: "get_weather".to_string(),
+                output: json!({"temperature": 20}),
+                call_id: Some("toolu_1".to_string()),
+                remote_id: None,
+            }],
+            ..Default::default()
+        };
+        CompletionFeaturesDyn::completion(&model.with_prompt_cache(false), req)
+            .await
+            .unwrap();
+        let recorded = requests.lock().await;
+        let (_, areq) = &recorded[1];
+        let messages = areq["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 5);
+        assert_eq!(messages[3]["content"][0]["signature"], "sig1");
+        assert_eq!(
+            messages[4],
+            json!({"role": "user", "content": [{"type": "tool_result", "tool_use_id": "toolu_1", "content": "{\"temperature\":20}"}]})
+        );
+        assert!(!areq.to_string().contains("cache_control"));
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_messages_output_schema() {
+        let body = json!({
+            "id": "msg_2",
+            "type": "message",
+            "role": "assistant",
+            "model": "claude-sonnet-4-5",
+            "content": [
+                {"type": "tool_use", "id": "toolu_2", "name": "json_output", "input": {"answer": 42}}
+            ],
+            "stop_reason": "tool_use",
+            "usage": {"input_tokens": 10, "output_tokens": 5}
+        });
+        let (url, requests) =
+            mock_server(vec![("/messages", "application/json", body.to_string())]).await;
+        let model = model(url);
+
+        let req = CompletionRequest {
+            prompt: "What is the answer?".to_string(),
+            output_schema: Some(json!({
+                "type": "object",
+                "properties": {"answer": {"type": "integer"}},
+                "required": ["answer"]
+            })),
+            ..Default::default()
+        };
+        let output = CompletionFeaturesDyn::completion(&model, req)
+            .await
+            .unwrap();
+        assert!(output.failed_reason.is_none());
+        assert!(output.tool_calls.is_empty());
+        assert_eq!(output.content, r#"{"answer":42}"#);
+
+        let requests = requests.lock().await;
+        let (_, areq) = &requests[0];
+        assert_eq!(areq["tools"][0]["name"], "json_output");
+        assert_eq!(
+            areq["tool_choice"],
+            json!({"type": "tool", "name": "json_output"})
+        );
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_messages_stream() {
+        let events = [
+            json!({"type": "message_start", "message": {"id": "msg_3", "type": "message", "role": "assistant", "model": "claude-sonnet-4-5", "content": [], "stop_reason": null, "usage": {"input_tokens": 10, "output_tokens": 1, "cache_read_input_tokens": 5}}}),
+            json!({"type": "content_block_start", "index": 0, "content_block": {"type": "thinking", "thinking": "", "signature": ""}}),
+            json!({"type": "content_block_delta", "index": 0, "delta": {"type": "thinking_delta", "thinking": "Let me "}}),
+            json!({"type": "content_block_delta", "index": 0, "delta": {"type": "thinking_delta", "thinking": "think."}}),
+            json!({"type": "content_block_delta", "index": 0, "delta": {"type": "signature_delta", "signature": "sig"}}),
+            json!({"type": "content_block_stop", "index": 0}),
+            json!({"type": "content_block_start", "index": 1, "content_block": {"type": "text", "text": ""}}),
+            json!({"type": "content_block_delta", "index": 1, "delta": {"type": "text_delta", "text": "Hello"}}),
+            json!({"type": "content_block_delta", "index": 1, "delta": {"type": "text_delta", "text": ", world!"}}),
+            json!({"type": "content_block_stop", "index": 1}),
+            json!({"type": "ping"}),
+            json!({"type": "content_block_start", "index": 2, "content_block": {"type": "tool_use", "id": "toolu_3", "name": "get_weather", "input": {}}}),
+            json!({"type": "content_block_delta", "index": 2, "delta": {"type": "input_json_delta", "partial_json": "{\"city\":"}}),
+            json!({"type": "content_block_delta", "index": 2, "delta": {"type": "input_json_delta", "partial_json": "\"Paris\"}"}}),
+            json!({"type": "content_block_stop", "index": 2}),
+            json!({"type": "message_delta", "delta": {"stop_reason": "tool_use", "stop_sequence": null}, "usage": {"output_tokens": 15}}),
+            json!({"type": "message_stop"}),
+        ];
+        let body: String = events
+            .iter()
+            .map(|e| format!("event: {}\ndata: {}\n\n", e["type"].as_str().unwrap(), e))
+            .collect();
+        let (url, requests) = mock_server(vec![("/messages", "text/event-stream", body)]).await;
+        let model = model(url);
+
+        let req = CompletionRequest {
+            prompt: "Hi".to_string(),
+            ..Default::default()
+        };
+        let stream = CompletionFeaturesDyn::completion_stream(&model, req);
+        let (deltas, output) = collect_chunks(stream).await.unwrap();
+        assert_eq!(deltas.len(), 7);
+        assert!(matches!(&deltas[0], CompletionChunk::Reasoning { text } if text == "Let me "));
+        assert!(matches!(&deltas[2], CompletionChunk::Text { text } if text == "Hello"));
+        assert!(matches!(
+            &deltas[4],
+            CompletionChunk::ToolCall { index: 0, call_id: Some(id), name: Some(name), .. }
+                if id == "toolu_3" && name == "get_weather"
+        ));
+        assert!(matches!(
+            &deltas[6],
+            CompletionChunk::ToolCall { index: 0, name: None, args, .. } if args == "\"Paris\"}"
+        ));
+
+        assert_eq!(output.content, "Hello, world!");
+        assert!(output.failed_reason.is_none());
+        assert_eq!(output.tool_calls.len(), 1);
+        assert_eq!(output.tool_calls[0].args, json!({"city": "Paris"}));
+        assert_eq!(output.usage.input_tokens, 15);
+        assert_eq!(output.usage.output_tokens, 15);
+        assert_eq!(output.raw_history.len(), 2);
+        assert_eq!(
+            output.raw_history[1]["content"][0],
+            json!({"type": "thinking", "thinking": "Let me think.", "signature": "sig"})
+        );
+
+        let requests = requests.lock().await;
+        assert_eq!(requests[0].1["stream"], true);
+    }
+}
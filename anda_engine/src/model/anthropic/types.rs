@@ -0,0 +1,604 @@
+use anda_core::{
+    AgentOutput, BoxError, ByteBufB64, ContentPart, FunctionDefinition, Message,
+    Usage as ModelUsage,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::{fmt, str::FromStr};
+
+use crate::{model::std_base64, unix_ms};
+
+/// The name of the tool used to return structured output for `output_schema`.
+pub static OUTPUT_TOOL: &str = "json_output";
+
+// https://docs.anthropic.com/en/api/messages
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct MessagesRequest {
+    pub model: String,
+
+    /// The maximum number of tokens to generate before stopping, required.
+    pub max_tokens: usize,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub system: Vec<Block>,
+
+    pub messages: Vec<MessageParam>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tools: Vec<ToolParam>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<ToolChoice>,
+
+    /// Amount of randomness injected into the response, ranges from 0.0 to 1.0.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_sequences: Option<Vec<String>>,
+
+    /// Configuration for enabling extended thinking.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thinking: Option<ThinkingConfig>,
+
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub stream: bool,
+}
+
+fn is_false(v: &bool) -> bool {
+    !v
+}
+
+impl MessagesRequest {
+    /// Sets cache breakpoints on the system prompt, the tools and the last message,
+    /// so the prompt prefix is cached and reused by the next turns.
+    pub fn cache_prompt(&mut self) {
+        for msg in self.messages.iter_mut() {
+            for block in msg.content.iter_mut() {
+                block.cache_control = None;
+            }
+        }
+
+        if let Some(block) = self.system.last_mut() {
+            block.cache_control = Some(CacheControl::default());
+        }
+        if let Some(tool) = self.tools.last_mut() {
+            tool.cache_control = Some(CacheControl::default());
+        }
+        if let Some(block) = self
+            .messages
+            .last_mut()
+            .and_then(|msg| msg.content.last_mut())
+        {
+            block.cache_control = Some(CacheControl::default());
+        }
+    }
+}
+
+/// Response from the Messages API.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MessagesResponse {
+    pub id: String,
+
+    #[serde(default)]
+    pub model: String,
+
+    #[serde(default)]
+    pub content: Vec<Block>,
+
+    /// The reason that the model stopped: "end_turn", "max_tokens", "stop_sequence",
+    /// "tool_use", "pause_turn" or "refusal".
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_reason: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_sequence: Option<String>,
+
+    pub usage: Usage,
+}
+
+impl MessagesResponse {
+    pub fn try_into(
+        self,
+        raw_history: Vec<Value>,
+        chat_history: Vec<Message>,
+    ) -> Result<AgentOutput, BoxError> {
+        let timestamp = unix_ms();
+        let mut output = AgentOutput {
+            raw_history,
+            chat_history,
+            usage: ModelUsage {
+                input_tokens: self.usage.prompt_tokens(),
+                output_tokens: self.usage.output_tokens,
+                requests: 1,
+            },
+            ..Default::default()
+        };
+
+        // 结构化输出通过工具返回，转换为文本，不作为工具调用
+        let content: Vec<Block> = self
+            .content
+            .into_iter()
+            .map(|block| match block.data {
+                BlockKind::ToolUse { name, input, .. } if name == OUTPUT_TOOL => Block {
+                    data: BlockKind::Text {
+                        text: input.to_string(),
+                    },
+                    cache_control: None,
+                },
+                _ => block,
+            })
+            .collect();
+        let msg = MessageParam {
+            role: Role::Assistant,
+            content,
+        };
+        output.raw_history.push(json!(&msg));
+        let mut msg: Message = msg.into();
+        msg.timestamp = Some(timestamp);
+        match self.stop_reason.as_deref() {
+            Some("end_turn") | Some("tool_use") | Some("stop_sequence") => {
+                output.content = msg.text().unwrap_or_default();
+                output.tool_calls = msg.tool_calls();
+            }
+            v => {
+                output.failed_reason = Some(v.unwrap_or("unknown stop reason").to_string());
+            }
+        }
+        output.chat_history.push(msg);
+
+        Ok(output)
+    }
+
+    pub fn maybe_failed(&self) -> bool {
+        !matches!(
+            self.stop_reason.as_deref(),
+            Some("end_turn") | Some("tool_use") | Some("stop_sequence")
+        )
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Usage {
+    pub input_tokens: u64,
+
+    pub output_tokens: u64,
+
+    #[serde(default)]
+    pub cache_creation_input_tokens: u64,
+
+    #[serde(default)]
+    pub cache_read_input_tokens: u64,
+}
+
+impl Usage {
+    /// The total input tokens, `input_tokens` does not include the cached tokens.
+    pub fn prompt_tokens(&self) -> u64 {
+        self.input_tokens + self.cache_creation_input_tokens + self.cache_read_input_tokens
+    }
+}
+
+impl fmt::Display for Usage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Input tokens: {} (cache write: {}, cache read: {}) Output tokens: {}",
+            self.prompt_tokens(),
+            self.cache_creation_input_tokens,
+            self.cache_read_input_tokens,
+            self.output_tokens
+        )
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct MessageParam {
+    pub role: Role,
+    pub content: Vec<Block>,
+}
+
+impl From<Message> for MessageParam {
+    fn from(msg: Message) -> Self {
+        let mut content: Vec<Block> = msg
+            .content
+            .into_iter()
+            .filter_map(|v| BlockKind::from_part(v).map(Block::from))
+            .collect();
+        // tool_result blocks must come first in the user message
+        content.sort_by_key(|block| !matches!(block.data, BlockKind::ToolResult { .. }));
+        Self {
+            role: Role::from(msg.role.as_str()),
+            content,
+        }
+    }
+}
+
+impl From<MessageParam> for Message {
+    fn from(msg: MessageParam) -> Self {
+        Self {
+            role: msg.role.to_string(),
+            content: msg.content.into_iter().map(|v| v.into()).collect(),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    User,
+    Assistant,
+}
+
+impl From<&str> for Role {
+    fn from(value: &str) -> Self {
+        match value {
+            "assistant" | "model" => Role::Assistant,
+            _ => Role::User,
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Role::User => write!(f, "user"),
+            Role::Assistant => write!(f, "assistant"),
+        }
+    }
+}
+
+/// A content block with an optional cache breakpoint.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Block {
+    #[serde(flatten)]
+    pub data: BlockKind,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_control: Option<CacheControl>,
+}
+
+impl From<BlockKind> for Block {
+    fn from(data: BlockKind) -> Self {
+        Self {
+            data,
+            cache_control: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BlockKind {
+    Text {
+        text: String,
+    },
+    /// The signature is required to pass the thinking block back to the API.
+    Thinking {
+        thinking: String,
+        #[serde(default)]
+        signature: String,
+    },
+    RedactedThinking {
+        data: String,
+    },
+    Image {
+        source: Source,
+    },
+    Document {
+        source: Source,
+    },
+    ToolUse {
+        id: String,
+        name: String,
+        input: Value,
+    },
+    ToolResult {
+        tool_use_id: String,
+        content: Value,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        is_error: Option<bool>,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Source {
+    Base64 { media_type: String, data: String },
+    Url { url: String },
+    Text { media_type: String, data: String },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct CacheControl {
+    #[serde(rename = "type")]
+    pub ty: String,
+}
+
+impl Default for CacheControl {
+    fn default() -> Self {
+        Self {
+            ty: "ephemeral".to_string(),
+        }
+    }
+}
+
+fn media_source(mime_type: &str, data: &ByteBufB64) -> Option<BlockKind> {
+    if mime_type.starts_with("image/") {
+        Some(BlockKind::Image {
+            source: Source::Base64 {
+                media_type: mime_type.to_string(),
+                data: std_base64(data),
+            },
+        })
+    } else if mime_type == "application/pdf" {
+        Some(BlockKind::Document {
+            source: Source::Base64 {
+                media_type: mime_type.to_string(),
+                data: std_base64(data),
+            },
+        })
+    } else if mime_type.starts_with("text/") {
+        Some(BlockKind::Document {
+            source: Source::Text {
+                media_type: "text/plain".to_string(),
+                data: String::from_utf8_lossy(&data.0).to_string(),
+            },
+        })
+    } else {
+        None
+    }
+}
+
+impl BlockKind {
+    /// Converts a content part to a block, returns None if the part should be dropped.
+    pub fn from_part(value: ContentPart) -> Option<Self> {
+        match value {
+            ContentPart::Text { text } if text.is_empty() => None,
+            ContentPart::Text { text } => Some(BlockKind::Text { text }),
+            // 思考内容没有签名，不能回传给 API
+            ContentPart::Reasoning { .. } => None,
+            ContentPart::FileData {
+                file_uri,
+                mime_type,
+            } => match mime_type.as_deref() {
+                Some(mt) if mt.starts_with("image/") => Some(BlockKind::Image {
+                    source: Source::Url { url: file_uri },
+                }),
+                Some("application/pdf") => Some(BlockKind::Document {
+                    source: Source::Url { url: file_uri },
+                }),
+                _ => Some(BlockKind::Text { text: file_uri }),
+            },
+            ContentPart::InlineData {
+                ref mime_type,
+                ref data,
+            } => media_source(mime_type, data).or_else(|| {
+                Some(BlockKind::Text {
+                    text: serde_json::to_string(&value).unwrap_or_default(),
+                })
+            }),
+            ContentPart::ToolCall {
+                name,
+                args,
+                call_id,
+            } => Some(BlockKind::ToolUse {
+                id: call_id.unwrap_or_else(|| name.clone()),
+                name,
+                input: args,
+            }),
+            ContentPart::ToolOutput {
+                name,
+                output,
+                call_id,
+                ..
+            } => {
+                let is_error = output.get("error").is_some().then_some(true);
+                Some(BlockKind::ToolResult {
+                    tool_use_id: call_id.unwrap_or(name),
+                    content: match output {
+                        Value::String(s) => Value::String(s),
+                        v => Value::String(v.to_string()),
+                    },
+                    is_error,
+                })
+            }
+            ContentPart::Any(json) => Some(serde_json::from_value(json.clone()).unwrap_or_else(
+                |_| BlockKind::Text {
+                    text: serde_json::to_string(&json).unwrap_or_default(),
+                },
+            )),
+            ContentPart::Action { .. } => Some(BlockKind::Text {
+                text: serde_json::to_string(&value).unwrap_or_default(),
+            }),
+        }
+    }
+}
+
+impl From<Block> for ContentPart {
+    fn from(value: Block) -> Self {
+        match value.data {
+            BlockKind::Text { text } => ContentPart::Text { text },
+            BlockKind::Thinking { thinking, .. } => ContentPart::Reasoning { text: thinking },
+            BlockKind::Image {
+                source: Source::Base64 { media_type, data },
+            }
+            | BlockKind::Document {
+                source: Source::Base64 { media_type, data },
+            } => match ByteBufB64::from_str(data.as_str()) {
+                Ok(data) => ContentPart::InlineData {
+                    mime_type: media_type,
+                    data,
+                },
+                Err(_) => ContentPart::Any(json!({
+                    "type": "InlineData",
+                    "mimeType": media_type,
+                    "data": data,
+                })),
+            },
+            BlockKind::Document {
+                source: Source::Text { media_type, data },
+            } => ContentPart::InlineData {
+                mime_type: media_type,
+                data: ByteBufB64(data.into_bytes()),
+            },
+            BlockKind::Image {
+                source: Source::Url { url },
+            } => ContentPart::FileData {
+                file_uri: url,
+                mime_type: Some("image/*".to_string()),
+            },
+            BlockKind::Document {
+                source: Source::Url { url },
+            } => ContentPart::FileData {
+                file_uri: url,
+                mime_type: Some("application/pdf".to_string()),
+            },
+            BlockKind::ToolUse { id, name, input } => ContentPart::ToolCall {
+                name,
+                args: input,
+                call_id: Some(id),
+            },
+            BlockKind::ToolResult {
+                tool_use_id,
+                content,
+                ..
+            } => ContentPart::ToolOutput {
+                name: String::new(),
+                output: match content {
+                    Value::String(s) => serde_json::from_str(&s).unwrap_or(Value::String(s)),
+                    v => v,
+                },
+                call_id: Some(tool_use_id),
+                remote_id: None,
+            },
+            data => ContentPart::Any(json!(data)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ToolParam {
+    pub name: String,
+    pub description: String,
+    pub input_schema: Value,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_control: Option<CacheControl>,
+}
+
+impl From<FunctionDefinition> for ToolParam {
+    fn from(v: FunctionDefinition) -> Self {
+        Self {
+            name: v.name,
+            description: v.description,
+            input_schema: v.parameters,
+            cache_control: None,
+        }
+    }
+}
+
+/// How the model should use the provided tools.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ToolChoice {
+    /// The model decides whether to call a tool.
+    Auto,
+    /// The model must call one of the tools.
+    Any,
+    /// The model must call the named tool.
+    Tool { name: String },
+    /// The model must not call any tool.
+    None,
+}
+
+/// Config for extended thinking.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ThinkingConfig {
+    #[serde(rename = "type")]
+    pub ty: String,
+
+    /// The number of tokens the model can use for its internal reasoning, must be ≥1024
+    /// and less than `max_tokens`.
+    pub budget_tokens: usize,
+}
+
+impl ThinkingConfig {
+    pub fn enabled(budget_tokens: usize) -> Self {
+        Self {
+            ty: "enabled".to_string(),
+            budget_tokens,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_content_block() {
+        let part = ContentPart::InlineData {
+            mime_type: "image/png".to_string(),
+            data: ByteBufB64(vec![251, 255, 191, 1]),
+        };
+        let block = Block::from(BlockKind::from_part(part.clone()).unwrap());
+        assert_eq!(
+            serde_json::to_value(&block).unwrap(),
+            json!({
+                "type": "image",
+                "source": {"type": "base64", "media_type": "image/png", "data": "+/+/AQ=="}
+            })
+        );
+        assert_eq!(ContentPart::from(block), part);
+
+        let block: Block = serde_json::from_value(json!({
+            "type": "thinking",
+            "thinking": "Let me think.",
+            "signature": "sig",
+        }))
+        .unwrap();
+        assert_eq!(
+            ContentPart::from(block),
+            ContentPart::Reasoning {
+                text: "Let me think.".to_string()
+            }
+        );
+        let part = ContentPart::Reasoning {
+            text: "Let me think.".to_string(),
+        };
+        assert!(BlockKind::from_part(part).is_none());
+
+        let msg = MessageParam::from(Message {
+            role: "tool".to_string(),
+            content: vec![
+                ContentPart::Text {
+                    text: "Go on".to_string(),
+                },
+                ContentPart::ToolOutput {
+                    name: "get_weather".to_string(),
+                    output: json!({"error": {"name": "get_weather", "message": "timeout"}}),
+                    call_id: Some("toolu_1".to_string()),
+                    remote_id: None,
+                },
+            ],
+            ..Default::default()
+        });
+        assert_eq!(
+            serde_json::to_value(&msg).unwrap(),
+            json!({
+                "role": "user",
+                "content": [
+                    {
+                        "type": "tool_result",
+                        "tool_use_id": "toolu_1",
+                        "content": r#"{"error":{"message":"timeout","name":"get_weather"}}"#,
+                        "is_error": true
+                    },
+                    {"type": "text", "text": "Go on"}
+                ]
+            })
+        );
+    }
+}
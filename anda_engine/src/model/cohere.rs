@@ -1,15 +1,15 @@
 //! Cohere API client and Anda integration
 //!
 //! This module provides a client for interacting with Cohere's API, specifically
-//! focused on text embedding functionality. It includes support for various
-//! Cohere embedding models and handles API communication, error handling,
-//! and response parsing.
+//! focused on text embedding and reranking functionality. It includes support for
+//! various Cohere embedding and rerank models and handles API communication,
+//! error handling, and response parsing.
 
-use anda_core::{BoxError, BoxPinFut, Embedding, Usage};
+use anda_core::{BoxError, BoxPinFut, Embedding, RerankResult, Usage};
 use serde::Deserialize;
 use serde_json::json;
 
-use super::{EmbeddingFeaturesDyn, request_client_builder};
+use super::{EmbeddingFeaturesDyn, RerankFeaturesDyn, request_client_builder, retry::RetryPolicy};
 
 // ================================================================
 // Main Cohere Client
@@ -28,12 +28,23 @@ pub const EMBED_MULTILINGUAL_V3: &str = "embed-multilingual-v3.0";
 /// `embed-multilingual-light-v3.0` embedding model
 pub const EMBED_MULTILINGUAL_LIGHT_V3: &str = "embed-multilingual-light-v3.0";
 
+// ================================================================
+// Cohere Rerank API
+// ================================================================
+/// `rerank-v3.5` rerank model
+pub const RERANK_V3_5: &str = "rerank-v3.5";
+/// `rerank-english-v3.0` rerank model
+pub const RERANK_ENGLISH_V3: &str = "rerank-english-v3.0";
+/// `rerank-multilingual-v3.0` rerank model
+pub const RERANK_MULTILINGUAL_V3: &str = "rerank-multilingual-v3.0";
+
 /// Cohere API client configuration and HTTP client
 #[derive(Clone)]
 pub struct Client {
     endpoint: String,
     api_key: String,
     http: reqwest::Client,
+    retry: RetryPolicy,
 }
 
 impl Client {
@@ -54,6 +65,7 @@ impl Client {
             http: request_client_builder()
                 .build()
                 .expect("Cohere reqwest client should build"),
+            retry: RetryPolicy::default(),
         }
     }
 
@@ -63,9 +75,15 @@ impl Client {
             endpoint: self.endpoint,
             api_key: self.api_key,
             http,
+            retry: self.retry,
         }
     }
 
+    /// Sets the retry policy for the API requests
+    pub fn with_retry(self, retry: RetryPolicy) -> Self {
+        Self { retry, ..self }
+    }
+
     /// Creates a POST request builder for the specified API path
     ///
     /// # Arguments
@@ -90,6 +108,15 @@ impl Client {
         };
         EmbeddingModel::new(self.clone(), model, ndims)
     }
+
+    /// Creates a rerank model instance
+    ///
+    /// # Arguments
+    /// * `model` - Model identifier (e.g., RERANK_V3_5), defaults to RERANK_V3_5 if empty
+    pub fn rerank_model(&self, model: &str) -> RerankModel {
+        let model = if model.is_empty() { RERANK_V3_5 } else { model };
+        RerankModel::new(self.clone(), model)
+    }
 }
 
 /// Response structure for Cohere's embedding API
@@ -241,20 +268,22 @@ impl EmbeddingFeaturesDyn for EmbeddingModel {
                 return Err(format!("Too many documents, max is {}", MAX_DOCUMENTS).into());
             }
 
-            let response = client
-                .post("/v1/embed")
-                .json(&json!({
+            let (response, attempts) = client
+                .retry
+                .send(client.post("/v1/embed").json(&json!({
                     "model": model,
                     "input_type": "search_document",
                     "embedding_types": ["float"],
                     "texts": texts,
-                }))
-                .send()
+                })))
                 .await?;
 
             if response.status().is_success() {
                 match response.json::<EmbeddingResponse>().await {
-                    Ok(res) => res.try_into(texts),
+                    Ok(res) => res.try_into(texts).map(|(embeddings, mut usage)| {
+                        usage.requests = attempts;
+                        (embeddings, usage)
+                    }),
                     Err(err) => Err(format!("Cohere embeddings error: {}", err).into()),
                 }
             } else {
@@ -275,15 +304,14 @@ impl EmbeddingFeaturesDyn for EmbeddingModel {
         let model = self.model.clone();
         let client = self.client.clone();
         Box::pin(async move {
-            let response = client
-                .post("/v1/embed")
-                .json(&json!({
+            let (response, attempts) = client
+                .retry
+                .send(client.post("/v1/embed").json(&json!({
                     "model": model,
                     "input_type": "search_query",
                     "embedding_types": ["float"],
                     "texts": vec![text.clone()],
-                }))
-                .send()
+                })))
                 .await?;
 
             if response.status().is_success() {
@@ -293,7 +321,7 @@ impl EmbeddingFeaturesDyn for EmbeddingModel {
                         let usage = res.meta.as_ref().map_or(Usage::default(), |m| Usage {
                             input_tokens: m.billed_units.input_tokens as u64,
                             output_tokens: m.billed_units.output_tokens as u64,
-                            requests: 1,
+                            requests: attempts,
                         });
                         Ok((Embedding { text, vec: data }, usage))
                     }
@@ -306,3 +334,163 @@ impl EmbeddingFeaturesDyn for EmbeddingModel {
         })
     }
 }
+
+/// Response structure for Cohere's rerank API
+#[derive(Debug, Deserialize)]
+pub struct RerankResponse {
+    /// Unique identifier for the request
+    #[serde(default)]
+    pub id: Option<String>,
+    /// Rerank results ordered by relevance score
+    pub results: Vec<RerankResult>,
+    /// Metadata about the API response
+    #[serde(default)]
+    pub meta: Option<Meta>,
+}
+
+/// Cohere rerank model wrapper
+#[derive(Clone)]
+pub struct RerankModel {
+    /// Model identifier
+    pub model: String,
+    /// Client instance for API communication
+    client: Client,
+}
+
+impl RerankModel {
+    /// Creates a new rerank model instance
+    ///
+    /// # Arguments
+    /// * `client` - Cohere API client
+    /// * `model` - Model identifier
+    pub fn new(client: Client, model: &str) -> Self {
+        Self {
+            client,
+            model: model.to_string(),
+        }
+    }
+}
+
+const MAX_RERANK_DOCUMENTS: usize = 1000;
+impl RerankFeaturesDyn for RerankModel {
+    /// Reranks the documents by relevance to the query
+    ///
+    /// https://docs.cohere.com/reference/rerank
+    /// Recommend not to send more than 1000 documents in a single request.
+    fn rerank(
+        &self,
+        query: String,
+        documents: Vec<String>,
+        top_n: usize,
+    ) -> BoxPinFut<Result<(Vec<RerankResult>, Usage), BoxError>> {
+        let model = self.model.clone();
+        let client = self.client.clone();
+        Box::pin(async move {
+            if documents.len() > MAX_RERANK_DOCUMENTS {
+                return Err(format!("Too many documents, max is {}", MAX_RERANK_DOCUMENTS).into());
+            }
+            if documents.is_empty() || top_n == 0 {
+                return Ok((Vec::new(), Usage::default()));
+            }
+
+            let top_n = top_n.min(documents.len());
+            let (response, attempts) = client
+                .retry
+                .send(client.post("/v2/rerank").json(&json!({
+                    "model": model,
+                    "query": query,
+                    "documents": documents,
+                    "top_n": top_n,
+                })))
+                .await?;
+
+            if response.status().is_success() {
+                match response.json::<RerankResponse>().await {
+                    Ok(res) => {
+                        if let Some(r) = res.results.iter().find(|r| r.index >= documents.len()) {
+                            return Err(format!(
+                                "Cohere rerank error: invalid document index {}",
+                                r.index
+                            )
+                            .into());
+                        }
+                        let usage = res.meta.as_ref().map_or(Usage::default(), |m| Usage {
+                            input_tokens: m.billed_units.input_tokens as u64,
+                            output_tokens: m.billed_units.output_tokens as u64,
+                            requests: attempts,
+                        });
+                        Ok((res.results, usage))
+                    }
+                    Err(err) => Err(format!("Cohere rerank error: {}", err).into()),
+                }
+            } else {
+                let msg = response.text().await?;
+                Err(format!("Cohere rerank error: {}", msg).into())
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::mock_server;
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_rerank_model() {
+        let body = json!({
+            "id": "07734bd2-2473-4f07-94e1-0d9f0e6843cf",
+            "results": [
+                {"index": 2, "relevance_score": 0.9},
+                {"index": 0, "relevance_score": 0.3}
+            ],
+            "meta": {
+                "api_version": {"version": "2"},
+                "billed_units": {"search_units": 1}
+            }
+        });
+        let (url, requests) =
+            mock_server(vec![("/v2/rerank", "application/json", body.to_string())]).await;
+        let model = Client::new("test-key", Some(url))
+            .with_client(reqwest::Client::new())
+            .with_retry(RetryPolicy::none())
+            .rerank_model("");
+        assert_eq!(model.model, RERANK_V3_5);
+
+        let documents = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        let (results, usage) = model
+            .rerank("query".to_string(), documents.clone(), 5)
+            .await
+            .unwrap();
+        assert_eq!(
+            results,
+            vec![
+                RerankResult {
+                    index: 2,
+                    relevance_score: 0.9
+                },
+                RerankResult {
+                    index: 0,
+                    relevance_score: 0.3
+                }
+            ]
+        );
+        assert_eq!(usage.requests, 1);
+
+        let recorded = requests.lock().await;
+        assert_eq!(recorded.len(), 1);
+        assert!(recorded[0].0.contains("authorization: bearer test-key"));
+        assert_eq!(
+            recorded[0].1,
+            json!({"model": RERANK_V3_5, "query": "query", "documents": documents, "top_n": 3})
+        );
+        drop(recorded);
+
+        // the mock server returns an out of range index
+        let err = model
+            .rerank("query".to_string(), vec!["a".to_string()], 1)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("invalid document index"));
+    }
+}
@@ -2,16 +2,27 @@
 //!
 //! This module provides integration with Gemini's API, including:
 //! - Client configuration and management
-//! - Completion model handling
+//! - Completion model handling, including streaming completions
+//! - Embedding model handling with `batchEmbedContents`
 //! - Response parsing and conversion to Anda's internal formats
 
 use anda_core::{
-    AgentOutput, BoxError, BoxPinFut, CompletionFeatures, CompletionRequest, Json, Message,
-    Resource,
+    AgentOutput, BoxError, BoxPinFut, CompletionChunk, CompletionFeatures, CompletionRequest,
+    Embedding, Json, Message, Resource, Usage as ModelUsage,
+};
+use futures::{
+    StreamExt, TryStreamExt,
+    stream::{self, BoxStream},
 };
 use log::{Level::Debug, log_enabled};
+use serde::Deserialize;
+use serde_json::json;
 
-use super::{CompletionFeaturesDyn, request_client_builder};
+use super::{
+    CompletionFeaturesDyn, EmbeddingFeaturesDyn, request_client_builder,
+    retry::{RetryPolicy, stream_with_requests, with_requests},
+    sse::{SseEvent, StreamAccumulator, completion_stream},
+};
 use crate::{rfc3339_datetime, unix_ms};
 
 pub mod types;
@@ -24,12 +35,18 @@ const API_BASE_URL: &str = "https://generativelanguage.googleapis.com/v1beta/mod
 pub static GEMINI_2_5_PRO: &str = "gemini-2.5-pro";
 pub static GEMINI_2_5_FLASH: &str = "gemini-2.5-flash";
 
+/// `gemini-embedding-001` embedding model, 3072 dimensions, supports 768, 1536 and 3072
+pub static GEMINI_EMBEDDING_001: &str = "gemini-embedding-001";
+/// `text-embedding-004` embedding model, 768 dimensions
+pub static TEXT_EMBEDDING_004: &str = "text-embedding-004";
+
 /// Gemini API client configuration and HTTP client
 #[derive(Clone)]
 pub struct Client {
     endpoint: String,
     api_key: String,
     http: reqwest::Client,
+    retry: RetryPolicy,
 }
 
 impl Client {
@@ -53,6 +70,7 @@ impl Client {
             http: request_client_builder()
                 .build()
                 .expect("Gemini reqwest client should build"),
+            retry: RetryPolicy::default(),
         }
     }
 
@@ -62,9 +80,15 @@ impl Client {
             endpoint: self.endpoint,
             api_key: self.api_key,
             http,
+            retry: self.retry,
         }
     }
 
+    /// Sets the retry policy for the API requests
+    pub fn with_retry(self, retry: RetryPolicy) -> Self {
+        Self { retry, ..self }
+    }
+
     /// Creates a POST request builder for the specified API path
     fn post(&self, path: &str) -> reqwest::RequestBuilder {
         let url = format!("{}{}", self.endpoint, path);
@@ -82,6 +106,173 @@ impl Client {
             },
         )
     }
+
+    /// Creates an embedding model instance with the default dimensions of the model,
+    /// use [`EmbeddingModel::with_output_dimensionality`] to reduce the dimensions.
+    pub fn embedding_model(&self, model: &str) -> EmbeddingModel {
+        let model = if model.is_empty() {
+            GEMINI_EMBEDDING_001
+        } else {
+            model
+        };
+        let ndims = match model {
+            v if v == GEMINI_EMBEDDING_001 => 3072,
+            v if v == TEXT_EMBEDDING_004 => 768,
+            _ => 0,
+        };
+        EmbeddingModel::new(self.clone(), model, ndims)
+    }
+}
+
+/// Embedding model implementation for Gemini API
+#[derive(Clone)]
+pub struct EmbeddingModel {
+    pub model: String,
+    client: Client,
+    ndims: usize,
+    output_dimensionality: Option<u32>,
+    document_task: types::TaskType,
+    query_task: types::TaskType,
+}
+
+impl EmbeddingModel {
+    /// Creates a new embedding model instance
+    ///
+    /// # Arguments
+    /// * `client` - Gemini client instance
+    /// * `model` - Name of the embedding model
+    /// * `ndims` - Number of dimensions for the embedding
+    pub fn new(client: Client, model: &str, ndims: usize) -> Self {
+        Self {
+            client,
+            model: model.to_string(),
+            ndims,
+            output_dimensionality: None,
+            document_task: types::TaskType::RetrievalDocument,
+            query_task: types::TaskType::RetrievalQuery,
+        }
+    }
+
+    /// Sets the reduced dimensions of the output embeddings.
+    pub fn with_output_dimensionality(mut self, ndims: usize) -> Self {
+        self.ndims = ndims;
+        self.output_dimensionality = Some(ndims as u32);
+        self
+    }
+
+    /// Sets the task types for documents and queries,
+    /// defaults to `RETRIEVAL_DOCUMENT` and `RETRIEVAL_QUERY`.
+    pub fn with_task_types(mut self, document: types::TaskType, query: types::TaskType) -> Self {
+        self.document_task = document;
+        self.query_task = query;
+        self
+    }
+
+    /// Embeds the texts in batches with the task type.
+    async fn batch_embed(
+        &self,
+        texts: Vec<String>,
+        task_type: types::TaskType,
+    ) -> Result<(Vec<Embedding>, ModelUsage), BoxError> {
+        let mut embeddings: Vec<Embedding> = Vec::with_capacity(texts.len());
+        let mut usage = ModelUsage::default();
+        for batch in texts.chunks(MAX_DOCUMENTS) {
+            let req = types::BatchEmbedContentsRequest {
+                requests: batch
+                    .iter()
+                    .map(|text| types::EmbedContentRequest {
+                        model: format!("models/{}", self.model),
+                        content: types::Content {
+                            role: None,
+                            parts: vec![types::Part {
+                                data: types::PartKind::Text(text.clone()),
+                                ..Default::default()
+                            }],
+                        },
+                        task_type: Some(task_type),
+                        output_dimensionality: self.output_dimensionality,
+                    })
+                    .collect(),
+            };
+
+            let (response, attempts) = self
+                .client
+                .retry
+                .send(
+                    self.client
+                        .post(&format!("/{}:batchEmbedContents", self.model))
+                        .json(&req),
+                )
+                .await?;
+            usage.requests += attempts;
+            if !response.status().is_success() {
+                let msg = response.text().await?;
+                return Err(format!("Gemini embeddings error: {}", msg).into());
+            }
+
+            let res = response
+                .json::<types::BatchEmbedContentsResponse>()
+                .await
+                .map_err(|err| format!("Gemini embeddings error: {}", err))?;
+            if res.embeddings.len() != batch.len() {
+                return Err(format!(
+                    "Expected {} embeddings, got {}",
+                    batch.len(),
+                    res.embeddings.len()
+                )
+                .into());
+            }
+            embeddings.extend(
+                res.embeddings
+                    .into_iter()
+                    .zip(batch)
+                    .map(|(v, text)| Embedding {
+                        text: text.clone(),
+                        vec: normalize(v.values),
+                    }),
+            );
+        }
+
+        Ok((embeddings, usage))
+    }
+}
+
+/// Normalizes the embedding to unit length,
+/// embeddings with reduced dimensions are not normalized by the API.
+fn normalize(mut vec: Vec<f32>) -> Vec<f32> {
+    let norm = vec.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vec.iter_mut().for_each(|v| *v /= norm);
+    }
+    vec
+}
+
+const MAX_DOCUMENTS: usize = 100;
+impl EmbeddingFeaturesDyn for EmbeddingModel {
+    /// The number of dimensions in the embedding vector.
+    fn ndims(&self) -> usize {
+        self.ndims
+    }
+
+    /// Generates embeddings for multiple texts, in batches of at most 100 texts
+    /// Returns a vector of Embedding structs in the same order as input texts
+    fn embed(
+        &self,
+        texts: Vec<String>,
+    ) -> BoxPinFut<Result<(Vec<Embedding>, ModelUsage), BoxError>> {
+        let this = self.clone();
+        Box::pin(async move { this.batch_embed(texts, this.document_task).await })
+    }
+
+    /// Generates a single embedding for a query text
+    fn embed_query(&self, text: String) -> BoxPinFut<Result<(Embedding, ModelUsage), BoxError>> {
+        let this = self.clone();
+        Box::pin(async move {
+            let (mut embeddings, usage) = this.batch_embed(vec![text], this.query_task).await?;
+            let embedding = embeddings.pop().ok_or("no embedding data")?;
+            Ok((embedding, usage))
+        })
+    }
 }
 
 /// Completion model wrapper for Gemini API
@@ -117,98 +308,113 @@ impl CompletionFeatures for CompletionModel {
     }
 }
 
-impl CompletionFeaturesDyn for CompletionModel {
-    fn completion(&self, req: CompletionRequest) -> BoxPinFut<Result<AgentOutput, BoxError>> {
-        let model = self.model.clone();
-        let client = self.client.clone();
+impl CompletionModel {
+    /// Builds the generate content request, returns it with the new raw history and chat history.
+    fn build_request(
+        &self,
+        req: CompletionRequest,
+    ) -> Result<(types::GenerateContentRequest, Vec<Json>, Vec<Message>), BoxError> {
+        let timestamp = unix_ms();
+        let mut raw_history: Vec<Json> = Vec::new();
+        let mut chat_history: Vec<Message> = Vec::new();
+        let mut greq = types::GenerateContentRequest::default();
 
-        Box::pin(async move {
-            let timestamp = unix_ms();
-            let mut raw_history: Vec<Json> = Vec::new();
-            let mut chat_history: Vec<Message> = Vec::new();
-            let mut greq = types::GenerateContentRequest::default();
-
-            if !req.instructions.is_empty() {
-                greq.system_instruction = Some(types::Content {
-                    role: Some(types::Role::Model),
-                    parts: vec![types::Part {
-                        data: types::PartKind::Text(req.instructions),
-                        ..Default::default()
-                    }],
-                });
-            };
+        if !req.instructions.is_empty() {
+            greq.system_instruction = Some(types::Content {
+                role: Some(types::Role::Model),
+                parts: vec![types::Part {
+                    data: types::PartKind::Text(req.instructions),
+                    ..Default::default()
+                }],
+            });
+        };
 
-            for msg in req.raw_history {
-                greq.contents.push(serde_json::from_value(msg)?);
-            }
+        for msg in req.raw_history {
+            greq.contents.push(serde_json::from_value(msg)?);
+        }
 
-            for msg in req.chat_history {
-                let val = types::Content::from(msg);
-                raw_history.push(serde_json::to_value(&val)?);
-                greq.contents.push(val);
-            }
+        for msg in req.chat_history {
+            let val = types::Content::from(msg);
+            raw_history.push(serde_json::to_value(&val)?);
+            greq.contents.push(val);
+        }
 
-            if let Some(mut msg) = req
-                .documents
-                .to_message(&rfc3339_datetime(timestamp).unwrap())
-            {
-                msg.timestamp = Some(timestamp);
-                chat_history.push(msg.clone());
-                let msg = types::Content::from(msg);
-                raw_history.push(serde_json::to_value(&msg)?);
-                greq.contents.push(msg);
-            }
+        if let Some(mut msg) = req
+            .documents
+            .to_message(&rfc3339_datetime(timestamp).unwrap())
+        {
+            msg.timestamp = Some(timestamp);
+            chat_history.push(msg.clone());
+            let msg = types::Content::from(msg);
+            raw_history.push(serde_json::to_value(&msg)?);
+            greq.contents.push(msg);
+        }
 
-            let mut content = req.content;
-            if !req.prompt.is_empty() {
-                content.push(req.prompt.into());
-            }
-            if !content.is_empty() {
-                let msg = Message {
-                    role: req.role.unwrap_or_else(|| "user".to_string()),
-                    content,
-                    timestamp: Some(timestamp),
-                    ..Default::default()
-                };
+        let mut content = req.content;
+        if !req.prompt.is_empty() {
+            content.push(req.prompt.into());
+        }
+        if !content.is_empty() {
+            let msg = Message {
+                role: req.role.unwrap_or_else(|| "user".to_string()),
+                content,
+                timestamp: Some(timestamp),
+                ..Default::default()
+            };
 
-                chat_history.push(msg.clone());
-                let msg = types::Content::from(msg);
-                raw_history.push(serde_json::to_value(&msg)?);
-                greq.contents.push(msg);
-            }
+            chat_history.push(msg.clone());
+            let msg = types::Content::from(msg);
+            raw_history.push(serde_json::to_value(&msg)?);
+            greq.contents.push(msg);
+        }
 
-            if let Some(temperature) = req.temperature {
-                greq.generation_config.temperature = Some(temperature);
-            }
+        if let Some(temperature) = req.temperature {
+            greq.generation_config.temperature = Some(temperature);
+        }
 
-            if let Some(max_tokens) = req.max_output_tokens {
-                greq.generation_config.max_output_tokens = Some(max_tokens as i32);
-            }
+        if let Some(max_tokens) = req.max_output_tokens {
+            greq.generation_config.max_output_tokens = Some(max_tokens as i32);
+        }
 
-            if let Some(output_schema) = req.output_schema {
-                greq.generation_config.response_mime_type = Some("application/json".to_string());
-                greq.generation_config.response_schema = Some(output_schema);
-            }
+        if let Some(output_schema) = req.output_schema {
+            greq.generation_config.response_mime_type = Some("application/json".to_string());
+            greq.generation_config.response_schema = Some(output_schema);
+        }
 
-            if let Some(stop) = req.stop {
-                greq.generation_config.stop_sequences = Some(stop);
-            }
+        if let Some(stop) = req.stop {
+            greq.generation_config.stop_sequences = Some(stop);
+        }
 
-            if !req.tools.is_empty() {
-                greq.tools = vec![req.tools.into()];
-                greq.tool_config = Some(types::ToolConfig::default());
-            };
+        if !req.tools.is_empty() {
+            greq.tools = vec![req.tools.into()];
+            greq.tool_config = Some(types::ToolConfig::default());
+        };
 
+        Ok((greq, raw_history, chat_history))
+    }
+}
+
+impl CompletionFeaturesDyn for CompletionModel {
+    fn completion(&self, req: CompletionRequest) -> BoxPinFut<Result<AgentOutput, BoxError>> {
+        let model = self.model.clone();
+        let client = self.client.clone();
+        let prepared = self.build_request(req);
+
+        Box::pin(async move {
+            let (greq, raw_history, chat_history) = prepared?;
             if log_enabled!(Debug)
                 && let Ok(val) = serde_json::to_string(&greq)
             {
                 log::debug!(request = val; "Gemini completions request");
             }
 
-            let response = client
-                .post(&format!("/{}:generateContent", model))
-                .json(&greq)
-                .send()
+            let (response, attempts) = client
+                .retry
+                .send(
+                    client
+                        .post(&format!("/{}:generateContent", model))
+                        .json(&greq),
+                )
                 .await?;
             if response.status().is_success() {
                 let text = response.text().await?;
@@ -228,6 +434,7 @@ impl CompletionFeaturesDyn for CompletionModel {
                         }
 
                         res.try_into(raw_history, chat_history)
+                            .map(|output| with_requests(output, attempts))
                     }
                     Err(err) => {
                         Err(format!("Gemini completions error: {}, body: {}", err, text).into())
@@ -244,4 +451,328 @@ impl CompletionFeaturesDyn for CompletionModel {
             }
         })
     }
+
+    fn completion_stream(
+        &self,
+        req: CompletionRequest,
+    ) -> BoxStream<'static, Result<CompletionChunk, BoxError>> {
+        let model = self.model.clone();
+        let client = self.client.clone();
+        let prepared = self.build_request(req);
+
+        stream::once(async move {
+            let (greq, raw_history, chat_history) = prepared?;
+            if log_enabled!(Debug)
+                && let Ok(val) = serde_json::to_string(&greq)
+            {
+                log::debug!(request = val; "Gemini completions stream request");
+            }
+
+            let (response, attempts) = client
+                .retry
+                .send(
+                    client
+                        .post(&format!("/{}:streamGenerateContent?alt=sse", model))
+                        .json(&greq),
+                )
+                .await?;
+            if response.status().is_success() {
+                Ok::<_, BoxError>(stream_with_requests(
+                    completion_stream(
+                        response,
+                        GenerateContentStreamAccumulator::new(raw_history, chat_history),
+                    ),
+                    attempts,
+                ))
+            } else {
+                let status = response.status();
+                let msg = response.text().await?;
+                log::error!(
+                    request:serde = greq;
+                    "completions request failed: {status}, body: {msg}",
+                );
+                Err(format!("Gemini completions error: {}", msg).into())
+            }
+        })
+        .try_flatten()
+        .boxed()
+    }
+}
+
+/// A streamed `GenerateContentResponse`, most fields may be absent in a single chunk.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct StreamChunk {
+    #[serde(default)]
+    candidates: Vec<StreamCandidate>,
+    prompt_feedback: Option<Json>,
+    usage_metadata: Option<Json>,
+    model_version: Option<String>,
+    response_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct StreamCandidate {
+    content: Option<StreamContent>,
+    finish_reason: Option<Json>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamContent {
+    #[serde(default)]
+    parts: Vec<types::Part>,
+}
+
+/// Accumulator for the `streamGenerateContent` SSE stream.
+///
+/// Consecutive text parts are merged, so the final output is the same as a
+/// non-streaming `generateContent` response.
+struct GenerateContentStreamAccumulator {
+    raw_history: Vec<Json>,
+    chat_history: Vec<Message>,
+    parts: Vec<types::Part>,
+    tool_calls: usize,
+    finish_reason: Option<Json>,
+    prompt_feedback: Option<Json>,
+    usage_metadata: serde_json::Map<String, Json>,
+    model_version: Option<String>,
+    response_id: Option<String>,
+}
+
+impl GenerateContentStreamAccumulator {
+    fn new(raw_history: Vec<Json>, chat_history: Vec<Message>) -> Self {
+        Self {
+            raw_history,
+            chat_history,
+            parts: Vec::new(),
+            tool_calls: 0,
+            finish_reason: None,
+            prompt_feedback: None,
+            usage_metadata: serde_json::Map::new(),
+            model_version: None,
+            response_id: None,
+        }
+    }
+
+    fn append_part(&mut self, part: types::Part) -> Option<CompletionChunk> {
+        match &part.data {
+            types::PartKind::Text(text) => {
+                let text = text.clone();
+                let thought = part.thought.unwrap_or_default();
+                match self.parts.last_mut() {
+                    Some(last)
+                        if last.thought.unwrap_or_default() == thought
+                            && matches!(last.data, types::PartKind::Text(_)) =>
+                    {
+                        if let types::PartKind::Text(prev) = &mut last.data {
+                            prev.push_str(&text);
+                        }
+                        if part.thought_signature.is_some() {
+                            last.thought_signature = part.thought_signature;
+                        }
+                    }
+                    _ => self.parts.push(part),
+                }
+
+                if thought {
+                    Some(CompletionChunk::Reasoning { text })
+                } else {
+                    Some(CompletionChunk::Text { text })
+                }
+            }
+            types::PartKind::FunctionCall { name, args, id } => {
+                let chunk = CompletionChunk::ToolCall {
+                    index: self.tool_calls,
+                    call_id: id.clone(),
+                    name: Some(name.clone()),
+                    args: args
+                        .as_ref()
+                        .map(|v| v.to_string())
+                        .unwrap_or_else(|| "{}".to_string()),
+                };
+                self.tool_calls += 1;
+                self.parts.push(part);
+                Some(chunk)
+            }
+            _ => {
+                self.parts.push(part);
+                None
+            }
+        }
+    }
+}
+
+impl StreamAccumulator for GenerateContentStreamAccumulator {
+    fn push(&mut self, event: SseEvent) -> Result<Vec<CompletionChunk>, BoxError> {
+        let val: Json = serde_json::from_str(&event.data).map_err(|err| {
+            format!(
+                "Gemini completions error: invalid stream chunk: {}, data: {}",
+                err, event.data
+            )
+        })?;
+        if let Some(err) = val.get("error") {
+            return Err(format!("Gemini completions error: {}", err).into());
+        }
+        let chunk: StreamChunk = serde_json::from_value(val).map_err(|err| {
+            format!(
+                "Gemini completions error: invalid stream chunk: {}, data: {}",
+                err, event.data
+            )
+        })?;
+
+        if let Some(Json::Object(usage)) = chunk.usage_metadata {
+            self.usage_metadata.extend(usage);
+        }
+        if chunk.prompt_feedback.is_some() {
+            self.prompt_feedback = chunk.prompt_feedback;
+        }
+        if chunk.model_version.is_some() {
+            self.model_version = chunk.model_version;
+        }
+        if chunk.response_id.is_some() {
+            self.response_id = chunk.response_id;
+        }
+
+        let mut chunks = Vec::new();
+        if let Some(candidate) = chunk.candidates.into_iter().next() {
+            if let Some(content) = candidate.content {
+                for part in content.parts {
+                    if let Some(chunk) = self.append_part(part) {
+                        chunks.push(chunk);
+                    }
+                }
+            }
+            if candidate.finish_reason.is_some() {
+                self.finish_reason = candidate.finish_reason;
+            }
+        }
+
+        Ok(chunks)
+    }
+
+    fn finish(self) -> Result<AgentOutput, BoxError> {
+        let mut usage = json!({
+            "promptTokenCount": 0,
+            "candidatesTokenCount": 0,
+            "totalTokenCount": 0,
+        });
+        usage.as_object_mut().unwrap().extend(self.usage_metadata);
+
+        let res = json!({
+            "candidates": if self.parts.is_empty() && self.prompt_feedback.is_some() {
+                json!([])
+            } else {
+                json!([{
+                    "content": {
+                        "role": "model",
+                        "parts": self.parts,
+                    },
+                    "finishReason": self.finish_reason,
+                }])
+            },
+            "promptFeedback": self.prompt_feedback,
+            "usageMetadata": usage,
+            "modelVersion": self.model_version,
+            "responseId": self.response_id,
+        });
+        let res: types::GenerateContentResponse = serde_json::from_value(res)
+            .map_err(|err| format!("Gemini completions error: {}", err))?;
+        if res.maybe_failed() {
+            log::warn!(response:serde = res; "completions maybe failed");
+        }
+        res.try_into(self.raw_history, self.chat_history)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{
+        mock_server,
+        retry::RetryPolicy,
+        sse::{collect_chunks, test_stream},
+    };
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_generate_content_stream() {
+        let stream = test_stream(
+            &[
+                "data: {\"candidates\":[{\"content\":{\"role\":\"model\",\"parts\":[{\"text\":\"Let me \",\"thought\":true}]}}]}\n\n",
+                "data: {\"candidates\":[{\"content\":{\"role\":\"model\",\"parts\":[{\"text\":\"think.\",\"thought\":true}]}}]}\n\n",
+                "data: {\"candidates\":[{\"content\":{\"role\":\"model\",\"parts\":[{\"text\":\"Hello\"}]}}]}\n\n",
+                "data: {\"candidates\":[{\"content\":{\"role\":\"model\",\"parts\":[{\"text\":\", world!\"},{\"functionCall\":{\"name\":\"get_weather\",\"args\":{\"city\":\"Paris\"}}}]},\"finishReason\":\"STOP\"}],",
+                "\"usageMetadata\":{\"promptTokenCount\":10,\"candidatesTokenCount\":5,\"totalTokenCount\":15},\"modelVersion\":\"gemini-2.5-flash\"}\n\n",
+            ],
+            GenerateContentStreamAccumulator::new(vec![], vec![]),
+        );
+
+        let (deltas, output) = collect_chunks(stream).await.unwrap();
+        assert_eq!(deltas.len(), 5);
+        assert!(matches!(&deltas[0], CompletionChunk::Reasoning { text } if text == "Let me "));
+        assert!(matches!(&deltas[2], CompletionChunk::Text { text } if text == "Hello"));
+        assert!(matches!(
+            &deltas[4],
+            CompletionChunk::ToolCall { index: 0, name: Some(name), args, .. }
+                if name == "get_weather" && args == r#"{"city":"Paris"}"#
+        ));
+
+        assert_eq!(output.content, "Hello, world!");
+        assert!(output.failed_reason.is_none());
+        assert_eq!(output.tool_calls.len(), 1);
+        assert_eq!(output.tool_calls[0].name, "get_weather");
+        assert_eq!(output.usage.input_tokens, 10);
+        assert_eq!(output.usage.output_tokens, 5);
+        assert_eq!(output.raw_history.len(), 1);
+        assert_eq!(
+            output.raw_history[0]["parts"][0],
+            json!({"text": "Let me think.", "thought": true})
+        );
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_embedding_model() {
+        let body = json!({"embeddings": [{"values": [3.0, 4.0]}, {"values": [0.0, 2.0]}]});
+        let (url, requests) = mock_server(vec![(
+            "/gemini-embedding-001:batchEmbedContents",
+            "application/json",
+            body.to_string(),
+        )])
+        .await;
+        let model = Client::new("test-key", Some(url))
+            .with_client(reqwest::Client::new())
+            .with_retry(RetryPolicy::none())
+            .embedding_model("");
+        assert_eq!(model.ndims(), 3072);
+        let model = model.with_output_dimensionality(2);
+        assert_eq!(model.ndims(), 2);
+
+        let (embeddings, usage) = model
+            .embed(vec!["hello".to_string(), "world".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(embeddings.len(), 2);
+        assert_eq!(embeddings[0].text, "hello");
+        assert_eq!(embeddings[0].vec, vec![0.6, 0.8]);
+        assert_eq!(embeddings[1].vec, vec![0.0, 1.0]);
+        assert_eq!(usage.requests, 1);
+
+        // the mock server always returns 2 embeddings
+        let err = model.embed_query("hello".to_string()).await.unwrap_err();
+        assert_eq!(err.to_string(), "Expected 1 embeddings, got 2");
+
+        let requests = requests.lock().await;
+        let (head, req) = &requests[0];
+        assert!(head.contains("x-goog-api-key: test-key"));
+        assert_eq!(
+            req["requests"][1],
+            json!({
+                "model": "models/gemini-embedding-001",
+                "content": {"parts": [{"text": "world"}]},
+                "taskType": "RETRIEVAL_DOCUMENT",
+                "outputDimensionality": 2
+            })
+        );
+        assert_eq!(requests[1].1["requests"][0]["taskType"], "RETRIEVAL_QUERY");
+    }
 }
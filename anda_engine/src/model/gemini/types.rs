@@ -742,6 +742,74 @@ pub enum FinishReason {
     ImageSafety,
 }
 
+// https://ai.google.dev/api/embeddings
+/// Type of task for which the embedding will be used.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum TaskType {
+    /// Unset value, which will default to one of the other enum values.
+    #[default]
+    TaskTypeUnspecified,
+    /// Specifies the given text is a query in a search/retrieval setting.
+    RetrievalQuery,
+    /// Specifies the given text is a document from the corpus being searched.
+    RetrievalDocument,
+    /// Specifies the given text will be used for STS.
+    SemanticSimilarity,
+    /// Specifies that the given text will be classified.
+    Classification,
+    /// Specifies that the embeddings will be used for clustering.
+    Clustering,
+    /// Specifies that the given text will be used for question answering.
+    QuestionAnswering,
+    /// Specifies that the given text will be used for fact verification.
+    FactVerification,
+    /// Specifies that the given text will be used for code retrieval.
+    CodeRetrievalQuery,
+}
+
+/// Request containing the Content for the model to embed.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct EmbedContentRequest {
+    /// The model's resource name, format: `models/{model}`.
+    pub model: String,
+
+    /// The content to embed. Only the parts.text fields will be counted.
+    pub content: Content,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub task_type: Option<TaskType>,
+
+    /// Reduced dimension for the output embedding. If set, excessive values in the output
+    /// embedding are truncated from the end.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output_dimensionality: Option<u32>,
+}
+
+/// Batch request to get embeddings from the model for a list of prompts.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchEmbedContentsRequest {
+    pub requests: Vec<EmbedContentRequest>,
+}
+
+/// A list of floats representing an embedding.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ContentEmbedding {
+    pub values: Vec<f32>,
+}
+
+/// The response to a `BatchEmbedContentsRequest`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchEmbedContentsResponse {
+    /// The embeddings for each request, in the same order as provided in the batch request.
+    #[serde(default)]
+    pub embeddings: Vec<ContentEmbedding>,
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
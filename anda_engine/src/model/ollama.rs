@@ -0,0 +1,990 @@
+//! Ollama API client implementation for Anda Engine
+//!
+//! This module provides integration with Ollama's native API for local models, including:
+//! - Client configuration and management
+//! - Completion model handling with `/api/chat`, including streaming completions,
+//!   tool calling and JSON schema output
+//! - Embedding model handling with `/api/embed`, including embedding dimensions discovery
+//! - Response parsing and conversion to Anda's internal formats
+
+use anda_core::{
+    AgentOutput, BoxError, BoxPinFut, CompletionChunk, CompletionFeatures, CompletionRequest,
+    ContentPart, Embedding, FunctionDefinition, Json, Message, Resource, Usage as ModelUsage,
+};
+use futures::{
+    StreamExt, TryStreamExt,
+    stream::{self, BoxStream},
+};
+use log::{Level::Debug, log_enabled};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use super::{
+    CompletionFeaturesDyn, EmbeddingFeaturesDyn, request_client_builder,
+    retry::{RetryPolicy, stream_with_requests, with_requests},
+    sse::{SseEvent, StreamAccumulator, json_lines_stream},
+    std_base64,
+};
+use crate::{rfc3339_datetime, unix_ms};
+
+// ================================================================
+// Main Ollama Client
+// ================================================================
+const API_BASE_URL: &str = "http://localhost:11434";
+
+pub static QWEN3_8B: &str = "qwen3:8b";
+pub static NOMIC_EMBED_TEXT: &str = "nomic-embed-text";
+
+/// Ollama API client configuration and HTTP client
+#[derive(Clone)]
+pub struct Client {
+    endpoint: String,
+    api_key: String,
+    http: reqwest::Client,
+    retry: RetryPolicy,
+}
+
+impl Client {
+    /// Creates a new Ollama client instance
+    ///
+    /// # Arguments
+    /// * `api_key` - API key for a server behind an authenticating proxy, empty for a local server
+    /// * `endpoint` - The server URL, defaults to `http://localhost:11434`
+    ///
+    /// # Returns
+    /// Configured Ollama client instance
+    pub fn new(api_key: &str, endpoint: Option<String>) -> Self {
+        let endpoint = endpoint.unwrap_or_else(|| API_BASE_URL.to_string());
+        let endpoint = if endpoint.is_empty() {
+            API_BASE_URL.to_string()
+        } else {
+            endpoint
+        };
+        Self {
+            endpoint,
+            api_key: api_key.to_string(),
+            // 本地服务通常不使用 https
+            http: request_client_builder()
+                .https_only(false)
+                .build()
+                .expect("Ollama reqwest client should build"),
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    /// Sets a custom HTTP client for the client
+    pub fn with_client(self, http: reqwest::Client) -> Self {
+        Self {
+            endpoint: self.endpoint,
+            api_key: self.api_key,
+            http,
+            retry: self.retry,
+        }
+    }
+
+    /// Sets the retry policy for the API requests
+    pub fn with_retry(self, retry: RetryPolicy) -> Self {
+        Self { retry, ..self }
+    }
+
+    /// Creates a POST request builder for the specified API path
+    fn post(&self, path: &str) -> reqwest::RequestBuilder {
+        let url = format!("{}{}", self.endpoint, path);
+        let req = self.http.post(url);
+        if self.api_key.is_empty() {
+            req
+        } else {
+            req.bearer_auth(&self.api_key)
+        }
+    }
+
+    /// Creates a new completion model instance using the default Ollama model
+    pub fn completion_model(&self, model: &str) -> CompletionModel {
+        CompletionModel::new(
+            self.clone(),
+            if model.is_empty() { QWEN3_8B } else { model },
+        )
+    }
+
+    /// Creates an embedding model instance with the given dimensions,
+    /// use [`Client::load_embedding_model`] to discover the dimensions from the server.
+    pub fn embedding_model(&self, model: &str, ndims: usize) -> EmbeddingModel {
+        EmbeddingModel::new(
+            self.clone(),
+            if model.is_empty() {
+                NOMIC_EMBED_TEXT
+            } else {
+                model
+            },
+            ndims,
+        )
+    }
+
+    /// Creates an embedding model instance, the dimensions are discovered from the model info
+    /// of `/api/show`, or from a probe embedding if the model info does not have it.
+    pub async fn load_embedding_model(&self, model: &str) -> Result<EmbeddingModel, BoxError> {
+        let mut model = self.embedding_model(model, 0);
+        let (response, _) = self
+            .retry
+            .send(
+                self.post("/api/show")
+                    .json(&json!({ "model": model.model })),
+            )
+            .await?;
+        if !response.status().is_success() {
+            let msg = response.text().await?;
+            return Err(format!("Ollama show model error: {}", msg).into());
+        }
+
+        let info: ShowResponse = response
+            .json()
+            .await
+            .map_err(|err| format!("Ollama show model error: {}", err))?;
+        model.ndims = match info.embedding_length() {
+            Some(ndims) => ndims,
+            None => {
+                let (embedding, _) = model.embed_query("ndims".to_string()).await?;
+                embedding.vec.len()
+            }
+        };
+        Ok(model)
+    }
+}
+
+/// Response of `/api/show`, only the model info is used.
+#[derive(Debug, Deserialize)]
+struct ShowResponse {
+    #[serde(default)]
+    model_info: serde_json::Map<String, Json>,
+}
+
+impl ShowResponse {
+    /// Returns the `<architecture>.embedding_length` of the model.
+    fn embedding_length(&self) -> Option<usize> {
+        let arch = self.model_info.get("general.architecture")?.as_str()?;
+        self.model_info
+            .get(&format!("{}.embedding_length", arch))?
+            .as_u64()
+            .map(|v| v as usize)
+    }
+}
+
+/// Response structure for Ollama embedding API
+#[derive(Debug, Deserialize, Serialize)]
+pub struct EmbeddingResponse {
+    pub model: String,
+    pub embeddings: Vec<Vec<f32>>,
+    #[serde(default)]
+    pub prompt_eval_count: u64,
+}
+
+impl EmbeddingResponse {
+    fn try_into(self, texts: Vec<String>) -> Result<(Vec<Embedding>, ModelUsage), BoxError> {
+        if self.embeddings.len() != texts.len() {
+            return Err(format!(
+                "Expected {} embeddings, got {}",
+                texts.len(),
+                self.embeddings.len()
+            )
+            .into());
+        }
+
+        Ok((
+            self.embeddings
+                .into_iter()
+                .zip(texts)
+                .map(|(vec, text)| Embedding { text, vec })
+                .collect(),
+            ModelUsage {
+                input_tokens: self.prompt_eval_count,
+                output_tokens: 0,
+                requests: 1,
+            },
+        ))
+    }
+}
+
+/// Embedding model implementation for Ollama API
+#[derive(Clone)]
+pub struct EmbeddingModel {
+    pub model: String,
+    client: Client,
+    ndims: usize,
+}
+
+impl EmbeddingModel {
+    /// Creates a new embedding model instance
+    ///
+    /// # Arguments
+    /// * `client` - Ollama client instance
+    /// * `model` - Name of the embedding model
+    /// * `ndims` - Number of dimensions for the embedding
+    pub fn new(client: Client, model: &str, ndims: usize) -> Self {
+        Self {
+            client,
+            model: model.to_string(),
+            ndims,
+        }
+    }
+}
+
+impl EmbeddingFeaturesDyn for EmbeddingModel {
+    /// The number of dimensions in the embedding vector.
+    fn ndims(&self) -> usize {
+        self.ndims
+    }
+
+    /// Generates embeddings for multiple texts in a batch
+    /// Returns a vector of Embedding structs in the same order as input texts
+    fn embed(
+        &self,
+        texts: Vec<String>,
+    ) -> BoxPinFut<Result<(Vec<Embedding>, ModelUsage), BoxError>> {
+        let model = self.model.clone();
+        let client = self.client.clone();
+        Box::pin(async move {
+            let (response, attempts) = client
+                .retry
+                .send(client.post("/api/embed").json(&json!({
+                    "model": model,
+                    "input": texts,
+                })))
+                .await?;
+
+            if response.status().is_success() {
+                match response.json::<EmbeddingResponse>().await {
+                    Ok(res) => res.try_into(texts).map(|(embeddings, mut usage)| {
+                        usage.requests = attempts;
+                        (embeddings, usage)
+                    }),
+                    Err(err) => Err(format!("Ollama embeddings error: {}", err).into()),
+                }
+            } else {
+                let msg = response.text().await?;
+                Err(format!("Ollama embeddings error: {}", msg).into())
+            }
+        })
+    }
+
+    /// Generates a single embedding for a query text
+    fn embed_query(&self, text: String) -> BoxPinFut<Result<(Embedding, ModelUsage), BoxError>> {
+        let fut = self.embed(vec![text]);
+        Box::pin(async move {
+            let (mut embeddings, usage) = fut.await?;
+            let embedding = embeddings.pop().ok_or("no embedding data")?;
+            Ok((embedding, usage))
+        })
+    }
+}
+
+/// A message of the `/api/chat` API.
+#[derive(Debug, Default, Clone, Deserialize, Serialize, PartialEq)]
+pub struct ChatMessage {
+    pub role: String,
+
+    #[serde(default)]
+    pub content: String,
+
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub thinking: String,
+
+    /// Base64 encoded images.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCallOutput>,
+
+    /// The name of the tool for a "tool" message.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_name: Option<String>,
+}
+
+impl ChatMessage {
+    /// Converts a message to chat messages, each tool output becomes a "tool" message.
+    fn from_message(msg: Message) -> Vec<Self> {
+        let mut rt: Vec<Self> = Vec::new();
+        let mut texts: Vec<String> = Vec::new();
+        let mut base = ChatMessage {
+            role: if msg.role == "tool" {
+                "user".to_string()
+            } else {
+                msg.role
+            },
+            ..Default::default()
+        };
+
+        for part in msg.content {
+            match part {
+                ContentPart::Text { text } => texts.push(text),
+                ContentPart::Reasoning { text } => base.thinking.push_str(&text),
+                ContentPart::InlineData { mime_type, data } if mime_type.starts_with("image/") => {
+                    base.images.push(std_base64(&data));
+                }
+                ContentPart::FileData { file_uri, .. } => texts.push(file_uri),
+                ContentPart::ToolCall { name, args, .. } => {
+                    base.tool_calls.push(ToolCallOutput {
+                        function: Function {
+                            name,
+                            arguments: args,
+                        },
+                    });
+                }
+                ContentPart::ToolOutput { name, output, .. } => rt.push(ChatMessage {
+                    role: "tool".to_string(),
+                    content: match output {
+                        Json::String(s) => s,
+                        v => v.to_string(),
+                    },
+                    tool_name: Some(name),
+                    ..Default::default()
+                }),
+                v => texts.push(serde_json::to_string(&v).unwrap_or_default()),
+            }
+        }
+
+        base.content = texts.join("\n");
+        if !base.content.is_empty()
+            || !base.thinking.is_empty()
+            || !base.images.is_empty()
+            || !base.tool_calls.is_empty()
+        {
+            rt.push(base);
+        }
+        rt
+    }
+}
+
+impl From<ChatMessage> for Message {
+    fn from(msg: ChatMessage) -> Self {
+        let mut content: Vec<ContentPart> = Vec::new();
+        if !msg.thinking.is_empty() {
+            content.push(ContentPart::Reasoning { text: msg.thinking });
+        }
+        if !msg.content.is_empty() {
+            content.push(ContentPart::Text { text: msg.content });
+        }
+        for tool in msg.tool_calls {
+            content.push(ContentPart::ToolCall {
+                name: tool.function.name,
+                args: tool.function.arguments,
+                call_id: None,
+            });
+        }
+        Self {
+            role: msg.role,
+            content,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct ToolCallOutput {
+    pub function: Function,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct Function {
+    pub name: String,
+    #[serde(default)]
+    pub arguments: Json,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ToolDefinition {
+    pub r#type: String,
+    pub function: FunctionDefinition,
+}
+
+impl From<FunctionDefinition> for ToolDefinition {
+    fn from(f: FunctionDefinition) -> Self {
+        Self {
+            r#type: "function".into(),
+            function: f,
+        }
+    }
+}
+
+/// Request of the `/api/chat` API.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct ChatRequest {
+    pub model: String,
+
+    pub messages: Vec<ChatMessage>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tools: Vec<ToolDefinition>,
+
+    /// "json" or a JSON schema.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<Json>,
+
+    /// Model options such as temperature, num_predict, num_ctx and stop.
+    #[serde(default, skip_serializing_if = "serde_json::Map::is_empty")]
+    pub options: serde_json::Map<String, Json>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub think: Option<bool>,
+
+    /// Ollama streams the response by default.
+    pub stream: bool,
+}
+
+/// Response of the `/api/chat` API.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ChatResponse {
+    pub model: String,
+    #[serde(default)]
+    pub created_at: String,
+    pub message: ChatMessage,
+    pub done: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub done_reason: Option<String>,
+    #[serde(default)]
+    pub prompt_eval_count: u64,
+    #[serde(default)]
+    pub eval_count: u64,
+}
+
+impl ChatResponse {
+    fn try_into(
+        self,
+        mut raw_history: Vec<Json>,
+        mut chat_history: Vec<Message>,
+    ) -> Result<AgentOutput, BoxError> {
+        raw_history.push(json!(&self.message));
+        let mut msg: Message = self.message.into();
+        msg.timestamp = Some(unix_ms());
+        let mut output = AgentOutput {
+            usage: ModelUsage {
+                input_tokens: self.prompt_eval_count,
+                output_tokens: self.eval_count,
+                requests: 1,
+            },
+            ..Default::default()
+        };
+
+        match self.done_reason.as_deref() {
+            None | Some("stop") => {
+                output.content = msg.text().unwrap_or_default();
+                output.tool_calls = msg.tool_calls();
+            }
+            Some(reason) => {
+                output.failed_reason = Some(reason.to_string());
+            }
+        }
+        chat_history.push(msg);
+        output.raw_history = raw_history;
+        output.chat_history = chat_history;
+        Ok(output)
+    }
+
+    fn maybe_failed(&self) -> bool {
+        !matches!(self.done_reason.as_deref(), None | Some("stop"))
+    }
+}
+
+/// Completion model wrapper for Ollama API
+#[derive(Clone)]
+pub struct CompletionModel {
+    /// Ollama client instance
+    client: Client,
+    /// Model identifier
+    pub model: String,
+    /// The context window size, Ollama's default is small and truncates long prompts
+    num_ctx: Option<usize>,
+    /// Whether to enable thinking for thinking models
+    think: Option<bool>,
+}
+
+impl CompletionModel {
+    /// Creates a new completion model instance
+    ///
+    /// # Arguments
+    /// * `client` - Ollama client instance
+    /// * `model` - Model identifier string
+    pub fn new(client: Client, model: &str) -> Self {
+        Self {
+            client,
+            model: model.to_string(),
+            num_ctx: None,
+            think: None,
+        }
+    }
+
+    /// Sets the context window size (the `num_ctx` option).
+    pub fn with_num_ctx(mut self, num_ctx: usize) -> Self {
+        self.num_ctx = Some(num_ctx);
+        self
+    }
+
+    /// Enables or disables thinking for thinking models.
+    pub fn with_think(mut self, think: bool) -> Self {
+        self.think = Some(think);
+        self
+    }
+}
+
+impl CompletionFeatures for CompletionModel {
+    async fn completion(
+        &self,
+        req: CompletionRequest,
+        _resources: Vec<Resource>,
+    ) -> Result<AgentOutput, BoxError> {
+        CompletionFeaturesDyn::completion(self, req).await
+    }
+}
+
+impl CompletionModel {
+    /// Builds the chat request, returns it with the new raw history and chat history.
+    fn build_request(
+        &self,
+        req: CompletionRequest,
+    ) -> Result<(ChatRequest, Vec<Json>, Vec<Message>), BoxError> {
+        let timestamp = unix_ms();
+        let mut raw_history: Vec<Json> = Vec::new();
+        let mut chat_history: Vec<Message> = Vec::new();
+        let mut oreq = ChatRequest {
+            model: self.model.clone(),
+            think: self.think,
+            ..Default::default()
+        };
+
+        if !req.instructions.is_empty() {
+            oreq.messages.push(ChatMessage {
+                role: "system".to_string(),
+                content: req.instructions,
+                ..Default::default()
+            });
+        };
+
+        for msg in req.raw_history {
+            oreq.messages.push(serde_json::from_value(msg)?);
+        }
+
+        for msg in req.chat_history {
+            for val in ChatMessage::from_message(msg) {
+                raw_history.push(serde_json::to_value(&val)?);
+                oreq.messages.push(val);
+            }
+        }
+
+        if let Some(mut msg) = req
+            .documents
+            .to_message(&rfc3339_datetime(timestamp).unwrap())
+        {
+            msg.timestamp = Some(timestamp);
+            chat_history.push(msg.clone());
+            for val in ChatMessage::from_message(msg) {
+                raw_history.push(serde_json::to_value(&val)?);
+                oreq.messages.push(val);
+            }
+        }
+
+        let mut content = req.content;
+        if !req.prompt.is_empty() {
+            content.push(req.prompt.into());
+        }
+        if !content.is_empty() {
+            let msg = Message {
+                role: req.role.unwrap_or_else(|| "user".to_string()),
+                content,
+                timestamp: Some(timestamp),
+                ..Default::default()
+            };
+
+            chat_history.push(msg.clone());
+            for val in ChatMessage::from_message(msg) {
+                raw_history.push(serde_json::to_value(&val)?);
+                oreq.messages.push(val);
+            }
+        }
+
+        if let Some(temperature) = req.temperature {
+            oreq.options
+                .insert("temperature".into(), temperature.into());
+        }
+
+        if let Some(max_tokens) = req.max_output_tokens {
+            oreq.options.insert("num_predict".into(), max_tokens.into());
+        }
+
+        if let Some(num_ctx) = self.num_ctx {
+            oreq.options.insert("num_ctx".into(), num_ctx.into());
+        }
+
+        if let Some(stop) = req.stop {
+            oreq.options.insert("stop".into(), stop.into());
+        }
+
+        if let Some(output_schema) = req.output_schema {
+            oreq.format = Some(output_schema);
+        }
+
+        oreq.tools = req.tools.into_iter().map(ToolDefinition::from).collect();
+
+        Ok((oreq, raw_history, chat_history))
+    }
+}
+
+impl CompletionFeaturesDyn for CompletionModel {
+    fn completion(&self, req: CompletionRequest) -> BoxPinFut<Result<AgentOutput, BoxError>> {
+        let client = self.client.clone();
+        let prepared = self.build_request(req);
+
+        Box::pin(async move {
+            let (oreq, raw_history, chat_history) = prepared?;
+            if log_enabled!(Debug)
+                && let Ok(val) = serde_json::to_string(&oreq)
+            {
+                log::debug!(request = val; "Ollama completions request");
+            }
+
+            let (response, attempts) = client
+                .retry
+                .send(client.post("/api/chat").json(&oreq))
+                .await?;
+            if response.status().is_success() {
+                let text = response.text().await?;
+
+                match serde_json::from_str::<ChatResponse>(&text) {
+                    Ok(res) => {
+                        if log_enabled!(Debug) {
+                            log::debug!(
+                                request:serde = oreq,
+                                response:serde = res;
+                                "Ollama completions response");
+                        } else if res.maybe_failed() {
+                            log::warn!(
+                                request:serde = oreq,
+                                response:serde = res;
+                                "completions maybe failed");
+                        }
+
+                        res.try_into(raw_history, chat_history)
+                            .map(|output| with_requests(output, attempts))
+                    }
+                    Err(err) => {
+                        Err(format!("Ollama completions error: {}, body: {}", err, text).into())
+                    }
+                }
+            } else {
+                let status = response.status();
+                let msg = response.text().await?;
+                log::error!(
+                    request:serde = oreq;
+                    "completions request failed: {status}, body: {msg}",
+                );
+                Err(format!("Ollama completions error: {}", msg).into())
+            }
+        })
+    }
+
+    fn completion_stream(
+        &self,
+        req: CompletionRequest,
+    ) -> BoxStream<'static, Result<CompletionChunk, BoxError>> {
+        let client = self.client.clone();
+        let prepared = self.build_request(req);
+
+        stream::once(async move {
+            let (mut oreq, raw_history, chat_history) = prepared?;
+            oreq.stream = true;
+            if log_enabled!(Debug)
+                && let Ok(val) = serde_json::to_string(&oreq)
+            {
+                log::debug!(request = val; "Ollama completions stream request");
+            }
+
+            let (response, attempts) = client
+                .retry
+                .send(client.post("/api/chat").json(&oreq))
+                .await?;
+            if response.status().is_success() {
+                Ok::<_, BoxError>(stream_with_requests(
+                    json_lines_stream(
+                        response,
+                        ChatStreamAccumulator::new(raw_history, chat_history),
+                    ),
+                    attempts,
+                ))
+            } else {
+                let status = response.status();
+                let msg = response.text().await?;
+                log::error!(
+                    request:serde = oreq;
+                    "completions request failed: {status}, body: {msg}",
+                );
+                Err(format!("Ollama completions error: {}", msg).into())
+            }
+        })
+        .try_flatten()
+        .boxed()
+    }
+}
+
+/// Accumulator for the newline delimited JSON stream of `/api/chat`.
+///
+/// The message deltas are merged, and the last line carries the done reason and usage.
+struct ChatStreamAccumulator {
+    raw_history: Vec<Json>,
+    chat_history: Vec<Message>,
+    message: ChatMessage,
+    last: Option<ChatResponse>,
+}
+
+impl ChatStreamAccumulator {
+    fn new(raw_history: Vec<Json>, chat_history: Vec<Message>) -> Self {
+        Self {
+            raw_history,
+            chat_history,
+            message: ChatMessage {
+                role: "assistant".to_string(),
+                ..Default::default()
+            },
+            last: None,
+        }
+    }
+}
+
+impl StreamAccumulator for ChatStreamAccumulator {
+    fn push(&mut self, event: SseEvent) -> Result<Vec<CompletionChunk>, BoxError> {
+        let val: Json = serde_json::from_str(&event.data).map_err(|err| {
+            format!(
+                "Ollama completions error: invalid stream chunk: {}, data: {}",
+                err, event.data
+            )
+        })?;
+        if let Some(err) = val.get("error") {
+            return Err(format!("Ollama completions error: {}", err).into());
+        }
+        let mut res: ChatResponse = serde_json::from_value(val).map_err(|err| {
+            format!(
+                "Ollama completions error: invalid stream chunk: {}, data: {}",
+                err, event.data
+            )
+        })?;
+
+        let mut chunks = Vec::new();
+        let delta = std::mem::take(&mut res.message);
+        if !delta.thinking.is_empty() {
+            self.message.thinking.push_str(&delta.thinking);
+            chunks.push(CompletionChunk::Reasoning {
+                text: delta.thinking,
+            });
+        }
+        if !delta.content.is_empty() {
+            self.message.content.push_str(&delta.content);
+            chunks.push(CompletionChunk::Text {
+                text: delta.content,
+            });
+        }
+        // 工具调用在一个增量中完整返回
+        for tool in delta.tool_calls {
+            chunks.push(CompletionChunk::ToolCall {
+                index: self.message.tool_calls.len(),
+                call_id: None,
+                name: Some(tool.function.name.clone()),
+                args: tool.function.arguments.to_string(),
+            });
+            self.message.tool_calls.push(tool);
+        }
+        if res.done {
+            self.last = Some(res);
+        }
+
+        Ok(chunks)
+    }
+
+    fn finish(self) -> Result<AgentOutput, BoxError> {
+        let mut res = self
+            .last
+            .ok_or("Ollama completions error: stream ended before done")?;
+        res.message = self.message;
+        if res.maybe_failed() {
+            log::warn!(response:serde = res; "completions maybe failed");
+        }
+        res.try_into(self.raw_history, self.chat_history)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{mock_server, sse::collect_chunks};
+    use anda_core::ByteBufB64;
+
+    fn client(endpoint: String) -> Client {
+        Client::new("", Some(endpoint))
+            .with_client(reqwest::Client::new())
+            .with_retry(RetryPolicy::none())
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_chat_completion() {
+        let body = json!({
+            "model": "qwen3:8b",
+            "created_at": "2025-10-01T00:00:00Z",
+            "message": {
+                "role": "assistant",
+                "content": "",
+                "thinking": "Need weather.",
+                "tool_calls": [{"function": {"name": "get_weather", "arguments": {"city": "Paris"}}}]
+            },
+            "done": true,
+            "done_reason": "stop",
+            "prompt_eval_count": 30,
+            "eval_count": 12
+        });
+        let (url, requests) =
+            mock_server(vec![("/api/chat", "application/json", body.to_string())]).await;
+        let model = client(url).completion_model("").with_num_ctx(8192);
+
+        let req = CompletionRequest {
+            instructions: "You are a helpful assistant.".to_string(),
+            chat_history: vec![Message {
+                role: "tool".to_string(),
+                content: vec![ContentPart::ToolOutput {
+                    name: "get_time".to_string(),
+                    output: json!("12:00"),
+                    call_id: None,
+                    remote_id: None,
+                }],
+                ..Default::default()
+            }],
+            content: vec![ContentPart::InlineData {
+                mime_type: "image/png".to_string(),
+                data: ByteBufB64(vec![251, 255, 191, 1]),
+            }],
+            prompt: "What's the weather in Paris?".to_string(),
+            tools: vec![FunctionDefinition {
+                name: "get_weather".to_string(),
+                description: "Get the weather".to_string(),
+                parameters: json!({"type": "object", "properties": {"city": {"type": "string"}}}),
+                strict: None,
+            }],
+            output_schema: Some(json!({"type": "object"})),
+            temperature: Some(0.5),
+            ..Default::default()
+        };
+        let output = CompletionFeaturesDyn::completion(&model, req)
+            .await
+            .unwrap();
+        assert!(output.failed_reason.is_none());
+        assert_eq!(output.tool_calls.len(), 1);
+        assert_eq!(output.tool_calls[0].name, "get_weather");
+        assert_eq!(output.tool_calls[0].args, json!({"city": "Paris"}));
+        assert_eq!(output.usage.input_tokens, 30);
+        assert_eq!(output.usage.output_tokens, 12);
+        assert_eq!(output.raw_history.len(), 3);
+        assert_eq!(
+            output.chat_history.last().unwrap().content[0],
+            ContentPart::Reasoning {
+                text: "Need weather.".to_string()
+            }
+        );
+
+        let requests = requests.lock().await;
+        let oreq = &requests[0].1;
+        assert_eq!(oreq["model"], "qwen3:8b");
+        assert_eq!(oreq["stream"], false);
+        assert_eq!(oreq["format"], json!({"type": "object"}));
+        assert_eq!(
+            oreq["options"],
+            json!({"temperature": 0.5, "num_ctx": 8192})
+        );
+        assert_eq!(oreq["tools"][0]["type"], "function");
+        assert_eq!(
+            oreq["messages"],
+            json!([
+                {"role": "system", "content": "You are a helpful assistant."},
+                {"role": "tool", "content": "12:00", "tool_name": "get_time"},
+                {"role": "user", "content": "What's the weather in Paris?", "images": ["+/+/AQ=="]}
+            ])
+        );
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_chat_stream() {
+        let lines = [
+            json!({"model": "qwen3:8b", "message": {"role": "assistant", "content": "", "thinking": "Let me think."}, "done": false}),
+            json!({"model": "qwen3:8b", "message": {"role": "assistant", "content": "Hello"}, "done": false}),
+            json!({"model": "qwen3:8b", "message": {"role": "assistant", "content": ", world!"}, "done": false}),
+            json!({"model": "qwen3:8b", "message": {"role": "assistant", "content": "", "tool_calls": [{"function": {"name": "get_weather", "arguments": {"city": "Paris"}}}]}, "done": false}),
+            json!({"model": "qwen3:8b", "message": {"role": "assistant", "content": ""}, "done": true, "done_reason": "stop", "prompt_eval_count": 10, "eval_count": 5}),
+        ];
+        let body: String = lines.iter().map(|l| format!("{}\n", l)).collect();
+        let (url, requests) = mock_server(vec![("/api/chat", "application/x-ndjson", body)]).await;
+        let model = client(url).completion_model("");
+
+        let req = CompletionRequest {
+            prompt: "Hi".to_string(),
+            ..Default::default()
+        };
+        let stream = CompletionFeaturesDyn::completion_stream(&model, req);
+        let (deltas, output) = collect_chunks(stream).await.unwrap();
+        assert_eq!(deltas.len(), 4);
+        assert!(
+            matches!(&deltas[0], CompletionChunk::Reasoning { text } if text == "Let me think.")
+        );
+        assert!(matches!(&deltas[1], CompletionChunk::Text { text } if text == "Hello"));
+        assert!(matches!(
+            &deltas[3],
+            CompletionChunk::ToolCall { index: 0, name: Some(name), args, .. }
+                if name == "get_weather" && args == r#"{"city":"Paris"}"#
+        ));
+
+        assert_eq!(output.content, "Hello, world!");
+        assert!(output.failed_reason.is_none());
+        assert_eq!(output.tool_calls.len(), 1);
+        assert_eq!(output.usage.input_tokens, 10);
+        assert_eq!(output.usage.output_tokens, 5);
+        assert_eq!(
+            output.raw_history[1],
+            json!({
+                "role": "assistant",
+                "content": "Hello, world!",
+                "thinking": "Let me think.",
+                "tool_calls": [{"function": {"name": "get_weather", "arguments": {"city": "Paris"}}}]
+            })
+        );
+        assert_eq!(requests.lock().await[0].1["stream"], true);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_embedding_model() {
+        let (url, requests) = mock_server(vec![
+            (
+                "/api/show",
+                "application/json",
+                json!({"model_info": {"general.architecture": "nomic-bert", "nomic-bert.embedding_length": 3}}).to_string(),
+            ),
+            (
+                "/api/embed",
+                "application/json",
+                json!({"model": "nomic-embed-text", "embeddings": [[0.1, 0.2, 0.3], [0.4, 0.5, 0.6]], "prompt_eval_count": 8}).to_string(),
+            ),
+        ])
+        .await;
+        let model = client(url).load_embedding_model("").await.unwrap();
+        assert_eq!(model.ndims(), 3);
+
+        let (embeddings, usage) = model
+            .embed(vec!["hello".to_string(), "world".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(embeddings.len(), 2);
+        assert_eq!(embeddings[1].text, "world");
+        assert_eq!(embeddings[1].vec, vec![0.4, 0.5, 0.6]);
+        assert_eq!(usage.input_tokens, 8);
+        assert_eq!(usage.requests, 1);
+
+        let requests = requests.lock().await;
+        assert_eq!(requests[0].1, json!({"model": "nomic-embed-text"}));
+        assert_eq!(
+            requests[1].1,
+            json!({"model": "nomic-embed-text", "input": ["hello", "world"]})
+        );
+    }
+}
@@ -2,21 +2,30 @@
 //!
 //! This module provides integration with OpenAI's API, including:
 //! - Client configuration and management
-//! - Completion model handling
+//! - Completion model handling, including streaming completions
 //! - Embedding model handling
 //! - Response parsing and conversion to Anda's internal formats
 
 use anda_core::{
-    AgentOutput, BoxError, BoxPinFut, CompletionRequest, ContentPart, Embedding,
-    FunctionDefinition, Json, Message, Usage as ModelUsage,
+    AgentOutput, BoxError, BoxPinFut, CompletionChunk, CompletionRequest, ContentPart, Embedding,
+    FunctionDefinition, HeuristicTokenizer, Json, Message, Tokenizer, Usage as ModelUsage,
+};
+use futures::{
+    StreamExt, TryStreamExt,
+    stream::{self, BoxStream},
 };
 use log::{Level::Debug, log_enabled};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use std::sync::Arc;
 
 pub mod types;
 
-use super::{CompletionFeaturesDyn, EmbeddingFeaturesDyn, request_client_builder};
+use super::{
+    CompletionFeaturesDyn, EmbeddingFeaturesDyn, request_client_builder,
+    retry::{RetryPolicy, stream_with_requests, with_requests},
+    sse::{ChatCompletionAccumulator, SseEvent, StreamAccumulator, completion_stream},
+};
 use crate::{rfc3339_datetime, unix_ms};
 
 // ================================================================
@@ -48,6 +57,7 @@ pub struct Client {
     endpoint: String,
     api_key: String,
     http: reqwest::Client,
+    retry: RetryPolicy,
 }
 
 impl Client {
@@ -68,6 +78,7 @@ impl Client {
             http: request_client_builder()
                 .build()
                 .expect("OpenAI reqwest client should build"),
+            retry: RetryPolicy::default(),
         }
     }
 
@@ -77,9 +88,15 @@ impl Client {
             endpoint: self.endpoint,
             api_key: self.api_key,
             http,
+            retry: self.retry,
         }
     }
 
+    /// Sets the retry policy for the API requests
+    pub fn with_retry(self, retry: RetryPolicy) -> Self {
+        Self { retry, ..self }
+    }
+
     /// Creates a POST request builder for the given API path
     fn post(&self, path: &str) -> reqwest::RequestBuilder {
         let url = format!("{}{}", self.endpoint, path);
@@ -212,18 +229,20 @@ impl EmbeddingFeaturesDyn for EmbeddingModel {
                 return Err(format!("Too many documents, max is {}", MAX_DOCUMENTS).into());
             }
 
-            let response = client
-                .post("/embeddings")
-                .json(&json!({
+            let (response, attempts) = client
+                .retry
+                .send(client.post("/embeddings").json(&json!({
                     "model": model,
                     "input": texts,
-                }))
-                .send()
+                })))
                 .await?;
 
             if response.status().is_success() {
                 match response.json::<EmbeddingResponse>().await {
-                    Ok(res) => res.try_into(texts),
+                    Ok(res) => res.try_into(texts).map(|(embeddings, mut usage)| {
+                        usage.requests = attempts;
+                        (embeddings, usage)
+                    }),
                     Err(err) => Err(format!("OpenAI embeddings error: {}", err).into()),
                 }
             } else {
@@ -239,13 +258,12 @@ impl EmbeddingFeaturesDyn for EmbeddingModel {
         let model = self.model.clone();
         let client = self.client.clone();
         Box::pin(async move {
-            let response = client
-                .post("/embeddings")
-                .json(&json!({
+            let (response, attempts) = client
+                .retry
+                .send(client.post("/embeddings").json(&json!({
                     "model": model,
                     "input": text,
-                }))
-                .send()
+                })))
                 .await?;
 
             if response.status().is_success() {
@@ -264,7 +282,7 @@ impl EmbeddingFeaturesDyn for EmbeddingModel {
                                     .total_tokens
                                     .saturating_sub(res.usage.prompt_tokens)
                                     as u64,
-                                requests: 1,
+                                requests: attempts,
                             },
                         ))
                     }
@@ -524,6 +542,7 @@ pub struct Function {
 #[derive(Clone)]
 pub struct CompletionModel {
     client: Client,
+    tokenizer: Option<Arc<dyn Tokenizer>>,
     pub model: String,
 }
 
@@ -536,122 +555,147 @@ impl CompletionModel {
     pub fn new(client: Client, model: &str) -> Self {
         Self {
             client,
+            tokenizer: None,
             model: model.to_string(),
         }
     }
-}
 
-impl CompletionFeaturesDyn for CompletionModel {
-    fn completion(&self, mut req: CompletionRequest) -> BoxPinFut<Result<AgentOutput, BoxError>> {
-        let model = self.model.clone();
-        let client = self.client.clone();
+    /// Sets the tokenizer of the model, e.g. an `o200k_base` [`anda_core::BpeTokenizer`]
+    /// for GPT-4o and later models, defaults to a [`HeuristicTokenizer`].
+    pub fn with_tokenizer(mut self, tokenizer: Arc<dyn Tokenizer>) -> Self {
+        self.tokenizer = Some(tokenizer);
+        self
+    }
+}
 
-        Box::pin(async move {
-            let timestamp = unix_ms();
-            let mut raw_history: Vec<Json> = Vec::new();
-            let mut chat_history: Vec<Message> = Vec::new();
-
-            if !req.instructions.is_empty() {
-                raw_history.push(json!(MessageInput {
-                    role: "system".into(),
-                    content: req.instructions.clone().into(),
-                    tool_call_id: None,
-                }));
-            };
+impl CompletionModel {
+    /// Builds the request body, returns it with the new raw history and chat history.
+    fn build_request(
+        &self,
+        mut req: CompletionRequest,
+    ) -> Result<(Json, Vec<Json>, Vec<Message>), BoxError> {
+        let timestamp = unix_ms();
+        let mut raw_history: Vec<Json> = Vec::new();
+        let mut chat_history: Vec<Message> = Vec::new();
+
+        if !req.instructions.is_empty() {
+            raw_history.push(json!(MessageInput {
+                role: "system".into(),
+                content: req.instructions.clone().into(),
+                tool_call_id: None,
+            }));
+        };
 
-            raw_history.append(&mut req.raw_history);
-            let skip_raw = raw_history.len();
+        raw_history.append(&mut req.raw_history);
+        let skip_raw = raw_history.len();
 
-            for msg in req.chat_history {
-                let val = to_message_input(&msg);
-                for v in val {
-                    raw_history.push(serde_json::to_value(&v)?);
-                }
+        for msg in req.chat_history {
+            let val = to_message_input(&msg);
+            for v in val {
+                raw_history.push(serde_json::to_value(&v)?);
             }
+        }
 
-            if let Some(mut msg) = req
-                .documents
-                .to_message(&rfc3339_datetime(timestamp).unwrap())
-            {
-                msg.timestamp = Some(timestamp);
-                let val = to_message_input(&msg);
-                for v in val {
-                    raw_history.push(serde_json::to_value(&v)?);
-                }
-                chat_history.push(msg);
+        if let Some(mut msg) = req
+            .documents
+            .to_message(&rfc3339_datetime(timestamp).unwrap())
+        {
+            msg.timestamp = Some(timestamp);
+            let val = to_message_input(&msg);
+            for v in val {
+                raw_history.push(serde_json::to_value(&v)?);
             }
+            chat_history.push(msg);
+        }
 
-            let mut content = req.content;
-            if !req.prompt.is_empty() {
-                content.push(req.prompt.into());
-            }
-            if !content.is_empty() {
-                let msg = Message {
-                    role: req.role.unwrap_or_else(|| "user".to_string()),
-                    content,
-                    timestamp: Some(timestamp),
-                    ..Default::default()
-                };
-
-                let val = to_message_input(&msg);
-                for v in val {
-                    raw_history.push(serde_json::to_value(&v)?);
-                }
-                chat_history.push(msg);
+        let mut content = req.content;
+        if !req.prompt.is_empty() {
+            content.push(req.prompt.into());
+        }
+        if !content.is_empty() {
+            let msg = Message {
+                role: req.role.unwrap_or_else(|| "user".to_string()),
+                content,
+                timestamp: Some(timestamp),
+                ..Default::default()
+            };
+
+            let val = to_message_input(&msg);
+            for v in val {
+                raw_history.push(serde_json::to_value(&v)?);
             }
+            chat_history.push(msg);
+        }
 
-            let mut body = json!({
-                "model": model,
-                "messages": &raw_history,
-            });
+        let mut body = json!({
+            "model": self.model,
+            "messages": &raw_history,
+        });
 
-            let body = body.as_object_mut().unwrap();
-            if let Some(temperature) = req.temperature {
-                body.insert("temperature".to_string(), Json::from(temperature));
-            }
+        let obj = body.as_object_mut().unwrap();
+        if let Some(temperature) = req.temperature {
+            obj.insert("temperature".to_string(), Json::from(temperature));
+        }
 
-            if let Some(max_tokens) = req.max_output_tokens {
-                body.insert("max_completion_tokens".to_string(), Json::from(max_tokens));
-            }
+        if let Some(max_tokens) = req.max_output_tokens {
+            obj.insert("max_completion_tokens".to_string(), Json::from(max_tokens));
+        }
 
-            if let Some(output_schema) = req.output_schema {
-                body.insert(
-                    "response_format".to_string(),
-                    json!({ "type": "json_schema", "json_schema": output_schema }),
-                );
-            }
+        if let Some(output_schema) = req.output_schema {
+            obj.insert(
+                "response_format".to_string(),
+                json!({ "type": "json_schema", "json_schema": output_schema }),
+            );
+        }
 
-            if let Some(stop) = req.stop {
-                body.insert("stop".to_string(), Json::from(stop));
-            }
+        if let Some(stop) = req.stop {
+            obj.insert("stop".to_string(), Json::from(stop));
+        }
 
-            if !req.tools.is_empty() {
-                body.insert(
-                    "tools".to_string(),
-                    json!(
-                        req.tools
-                            .into_iter()
-                            .map(ToolDefinition::from)
-                            .collect::<Vec<_>>()
-                    ),
-                );
-                body.insert(
-                    "tool_choice".to_string(),
-                    if req.tool_choice_required {
-                        Json::from("required")
-                    } else {
-                        Json::from("auto")
-                    },
-                );
-            };
+        if !req.tools.is_empty() {
+            obj.insert(
+                "tools".to_string(),
+                json!(
+                    req.tools
+                        .into_iter()
+                        .map(ToolDefinition::from)
+                        .collect::<Vec<_>>()
+                ),
+            );
+            obj.insert(
+                "tool_choice".to_string(),
+                if req.tool_choice_required {
+                    Json::from("required")
+                } else {
+                    Json::from("auto")
+                },
+            );
+        };
 
+        if skip_raw > 0 {
+            raw_history.drain(0..skip_raw);
+        }
+        Ok((body, raw_history, chat_history))
+    }
+}
+
+impl CompletionFeaturesDyn for CompletionModel {
+    fn completion(&self, req: CompletionRequest) -> BoxPinFut<Result<AgentOutput, BoxError>> {
+        let client = self.client.clone();
+        let prepared = self.build_request(req);
+
+        Box::pin(async move {
+            let (body, raw_history, chat_history) = prepared?;
             if log_enabled!(Debug)
                 && let Ok(val) = serde_json::to_string(&body)
             {
                 log::debug!(request = val; "OpenAI completions request");
             }
 
-            let response = client.post("/chat/completions").json(body).send().await?;
+            let (response, attempts) = client
+                .retry
+                .send(client.post("/chat/completions").json(&body))
+                .await?;
             if response.status().is_success() {
                 let text = response.text().await?;
                 match serde_json::from_str::<CompletionResponse>(&text) {
@@ -667,10 +711,8 @@ impl CompletionFeaturesDyn for CompletionModel {
                                 response:serde = res;
                                 "completions maybe failed");
                         }
-                        if skip_raw > 0 {
-                            raw_history.drain(0..skip_raw);
-                        }
                         res.try_into(raw_history, chat_history)
+                            .map(|output| with_requests(output, attempts))
                     }
                     Err(err) => {
                         Err(format!("OpenAI completions error: {}, body: {}", err, text).into())
@@ -687,12 +729,77 @@ impl CompletionFeaturesDyn for CompletionModel {
             }
         })
     }
+
+    fn completion_stream(
+        &self,
+        req: CompletionRequest,
+    ) -> BoxStream<'static, Result<CompletionChunk, BoxError>> {
+        let client = self.client.clone();
+        let prepared = self.build_request(req);
+
+        stream::once(async move {
+            let (mut body, raw_history, chat_history) = prepared?;
+            body["stream"] = Json::from(true);
+            body["stream_options"] = json!({"include_usage": true});
+            if log_enabled!(Debug)
+                && let Ok(val) = serde_json::to_string(&body)
+            {
+                log::debug!(request = val; "OpenAI completions stream request");
+            }
+
+            let (response, attempts) = client
+                .retry
+                .send(client.post("/chat/completions").json(&body))
+                .await?;
+            if response.status().is_success() {
+                Ok::<_, BoxError>(stream_with_requests(
+                    completion_stream(
+                        response,
+                        ChatCompletionAccumulator::new(move |res| {
+                            let res: CompletionResponse = serde_json::from_value(res)
+                                .map_err(|err| format!("OpenAI completions error: {}", err))?;
+                            if log_enabled!(Debug) {
+                                log::debug!(
+                                request:serde = body,
+                                response:serde = res;
+                                "OpenAI completions stream response");
+                            } else if res.maybe_failed() {
+                                log::warn!(
+                                request:serde = body,
+                                response:serde = res;
+                                "completions maybe failed");
+                            }
+                            res.try_into(raw_history, chat_history)
+                        }),
+                    ),
+                    attempts,
+                ))
+            } else {
+                let status = response.status();
+                let msg = response.text().await?;
+                log::error!(
+                    request:serde = body;
+                    "completions request failed: {status}, body: {msg}",
+                );
+                Err(format!("OpenAI completions error: {}", msg).into())
+            }
+        })
+        .try_flatten()
+        .boxed()
+    }
+
+    fn tokenizer(&self) -> Arc<dyn Tokenizer> {
+        self.tokenizer
+            .clone()
+            .unwrap_or_else(|| Arc::new(HeuristicTokenizer))
+    }
 }
 
 /// Completion model implementation for OpenAI API
 #[derive(Clone)]
 pub struct CompletionModelV2 {
     client: Client,
+    tokenizer: Option<Arc<dyn Tokenizer>>,
     pub model: String,
 }
 
@@ -705,116 +812,138 @@ impl CompletionModelV2 {
     pub fn new(client: Client, model: &str) -> Self {
         Self {
             client,
+            tokenizer: None,
             model: model.to_string(),
         }
     }
+
+    /// Sets the tokenizer of the model, e.g. an `o200k_base` [`anda_core::BpeTokenizer`]
+    /// for GPT-4o and later models, defaults to a [`HeuristicTokenizer`].
+    pub fn with_tokenizer(mut self, tokenizer: Arc<dyn Tokenizer>) -> Self {
+        self.tokenizer = Some(tokenizer);
+        self
+    }
 }
 
-impl CompletionFeaturesDyn for CompletionModelV2 {
-    fn completion(&self, req: CompletionRequest) -> BoxPinFut<Result<AgentOutput, BoxError>> {
-        let model = self.model.clone();
-        let client = self.client.clone();
+impl CompletionModelV2 {
+    /// Builds the Responses API request, returns it with the new raw history and chat history.
+    fn build_request(
+        &self,
+        req: CompletionRequest,
+    ) -> Result<(types::CompletionRequest, Vec<Json>, Vec<Message>), BoxError> {
+        let timestamp = unix_ms();
+        let mut raw_history: Vec<Json> = Vec::new();
+        let mut chat_history: Vec<Message> = Vec::new();
+        let mut oreq = types::CompletionRequest {
+            model: self.model.clone(),
+            ..Default::default()
+        };
+        oreq.additional_parameters.store = Some(false);
 
-        Box::pin(async move {
-            let timestamp = unix_ms();
-            let mut raw_history: Vec<Json> = Vec::new();
-            let mut chat_history: Vec<Message> = Vec::new();
-            let mut oreq = types::CompletionRequest {
-                model,
-                ..Default::default()
-            };
-            oreq.additional_parameters.store = Some(false);
+        if !req.instructions.is_empty() {
+            oreq.instructions = Some(req.instructions);
+        };
 
-            if !req.instructions.is_empty() {
-                oreq.instructions = Some(req.instructions);
-            };
+        for msg in req.raw_history {
+            oreq.input.push(serde_json::from_value(msg)?);
+        }
 
-            for msg in req.raw_history {
-                oreq.input.push(serde_json::from_value(msg)?);
+        for msg in req.chat_history {
+            let vals = types::message_into(msg);
+            for val in vals {
+                raw_history.push(serde_json::to_value(&val)?);
+                oreq.input.push(val);
             }
+        }
 
-            for msg in req.chat_history {
-                let vals = types::message_into(msg);
-                for val in vals {
-                    raw_history.push(serde_json::to_value(&val)?);
-                    oreq.input.push(val);
-                }
+        if let Some(mut msg) = req
+            .documents
+            .to_message(&rfc3339_datetime(timestamp).unwrap())
+        {
+            msg.timestamp = Some(timestamp);
+            chat_history.push(msg.clone());
+            let vals = types::message_into(msg);
+            for val in vals {
+                raw_history.push(serde_json::to_value(&val)?);
+                oreq.input.push(val);
             }
+        }
 
-            if let Some(mut msg) = req
-                .documents
-                .to_message(&rfc3339_datetime(timestamp).unwrap())
-            {
-                msg.timestamp = Some(timestamp);
-                chat_history.push(msg.clone());
-                let vals = types::message_into(msg);
-                for val in vals {
-                    raw_history.push(serde_json::to_value(&val)?);
-                    oreq.input.push(val);
-                }
-            }
+        let mut content = req.content;
+        if !req.prompt.is_empty() {
+            content.push(req.prompt.into());
+        }
+        if !content.is_empty() {
+            let msg = Message {
+                role: req.role.unwrap_or_else(|| "user".to_string()),
+                content,
+                timestamp: Some(timestamp),
+                ..Default::default()
+            };
 
-            let mut content = req.content;
-            if !req.prompt.is_empty() {
-                content.push(req.prompt.into());
-            }
-            if !content.is_empty() {
-                let msg = Message {
-                    role: req.role.unwrap_or_else(|| "user".to_string()),
-                    content,
-                    timestamp: Some(timestamp),
-                    ..Default::default()
-                };
-
-                chat_history.push(msg.clone());
-                let vals = types::message_into(msg);
-                for val in vals {
-                    raw_history.push(serde_json::to_value(&val)?);
-                    oreq.input.push(val);
-                }
+            chat_history.push(msg.clone());
+            let vals = types::message_into(msg);
+            for val in vals {
+                raw_history.push(serde_json::to_value(&val)?);
+                oreq.input.push(val);
             }
+        }
 
-            if let Some(temperature) = req.temperature {
-                oreq.temperature = Some(temperature);
-            }
+        if let Some(temperature) = req.temperature {
+            oreq.temperature = Some(temperature);
+        }
 
-            if let Some(max_tokens) = req.max_output_tokens {
-                oreq.max_output_tokens = Some(max_tokens as u64);
-            }
+        if let Some(max_tokens) = req.max_output_tokens {
+            oreq.max_output_tokens = Some(max_tokens as u64);
+        }
 
-            if let Some(output_schema) = req.output_schema {
-                oreq.additional_parameters.text = Some(types::TextConfig::structured_output(
-                    "structured_output".to_string(),
-                    output_schema,
-                ));
-            }
+        if let Some(output_schema) = req.output_schema {
+            oreq.additional_parameters.text = Some(types::TextConfig::structured_output(
+                "structured_output".to_string(),
+                output_schema,
+            ));
+        }
 
-            if !req.tools.is_empty() {
-                oreq.tools = req
-                    .tools
-                    .into_iter()
-                    .map(|v| types::ToolDefinition {
-                        r#type: "function".to_string(),
-                        name: v.name,
-                        description: v.description,
-                        parameters: v.parameters,
-                        strict: v.strict.unwrap_or_default(),
-                    })
-                    .collect::<Vec<_>>();
-                oreq.tool_choice = Some(if req.tool_choice_required {
-                    "required".to_string()
-                } else {
-                    "auto".to_string()
-                });
-            };
+        if !req.tools.is_empty() {
+            oreq.tools = req
+                .tools
+                .into_iter()
+                .map(|v| types::ToolDefinition {
+                    r#type: "function".to_string(),
+                    name: v.name,
+                    description: v.description,
+                    parameters: v.parameters,
+                    strict: v.strict.unwrap_or_default(),
+                })
+                .collect::<Vec<_>>();
+            oreq.tool_choice = Some(if req.tool_choice_required {
+                "required".to_string()
+            } else {
+                "auto".to_string()
+            });
+        };
+
+        Ok((oreq, raw_history, chat_history))
+    }
+}
 
+impl CompletionFeaturesDyn for CompletionModelV2 {
+    fn completion(&self, req: CompletionRequest) -> BoxPinFut<Result<AgentOutput, BoxError>> {
+        let client = self.client.clone();
+        let prepared = self.build_request(req);
+
+        Box::pin(async move {
+            let (oreq, raw_history, chat_history) = prepared?;
             if log_enabled!(Debug)
                 && let Ok(val) = serde_json::to_string(&oreq)
             {
                 log::debug!(request = val; "OpenAI completions request");
             }
 
-            let response = client.post("/responses").json(&oreq).send().await?;
+            let (response, attempts) = client
+                .retry
+                .send(client.post("/responses").json(&oreq))
+                .await?;
             if response.status().is_success() {
                 let text = response.text().await?;
                 match serde_json::from_str::<types::CompletionResponse>(&text) {
@@ -832,6 +961,7 @@ impl CompletionFeaturesDyn for CompletionModelV2 {
                         }
 
                         res.try_into(raw_history, chat_history)
+                            .map(|output| with_requests(output, attempts))
                     }
                     Err(err) => {
                         Err(format!("OpenAI completions error: {}, body: {}", err, text).into())
@@ -848,4 +978,128 @@ impl CompletionFeaturesDyn for CompletionModelV2 {
             }
         })
     }
+
+    fn completion_stream(
+        &self,
+        req: CompletionRequest,
+    ) -> BoxStream<'static, Result<CompletionChunk, BoxError>> {
+        let client = self.client.clone();
+        let prepared = self.build_request(req);
+
+        stream::once(async move {
+            let (mut oreq, raw_history, chat_history) = prepared?;
+            oreq.stream = Some(true);
+            if log_enabled!(Debug)
+                && let Ok(val) = serde_json::to_string(&oreq)
+            {
+                log::debug!(request = val; "OpenAI completions stream request");
+            }
+
+            let (response, attempts) = client
+                .retry
+                .send(client.post("/responses").json(&oreq))
+                .await?;
+            if response.status().is_success() {
+                Ok::<_, BoxError>(stream_with_requests(
+                    completion_stream(
+                        response,
+                        ResponsesStreamAccumulator::new(raw_history, chat_history),
+                    ),
+                    attempts,
+                ))
+            } else {
+                let status = response.status();
+                let msg = response.text().await?;
+                log::error!(
+                    request:serde = oreq;
+                    "completions request failed: {status}, body: {msg}",
+                );
+                Err(format!("OpenAI completions error: {}", msg).into())
+            }
+        })
+        .try_flatten()
+        .boxed()
+    }
+
+    fn tokenizer(&self) -> Arc<dyn Tokenizer> {
+        self.tokenizer
+            .clone()
+            .unwrap_or_else(|| Arc::new(HeuristicTokenizer))
+    }
+}
+
+/// Accumulator for the streaming events of the Responses API.
+///
+/// Deltas are emitted from the `*.delta` events, the final output is built from
+/// the response carried by the terminal `response.completed` event.
+struct ResponsesStreamAccumulator {
+    raw_history: Vec<Json>,
+    chat_history: Vec<Message>,
+    response: Option<types::CompletionResponse>,
+}
+
+impl ResponsesStreamAccumulator {
+    fn new(raw_history: Vec<Json>, chat_history: Vec<Message>) -> Self {
+        Self {
+            raw_history,
+            chat_history,
+            response: None,
+        }
+    }
+}
+
+impl StreamAccumulator for ResponsesStreamAccumulator {
+    fn push(&mut self, event: SseEvent) -> Result<Vec<CompletionChunk>, BoxError> {
+        let val: Json = serde_json::from_str(&event.data).map_err(|err| {
+            format!(
+                "OpenAI completions error: invalid stream event: {}, data: {}",
+                err, event.data
+            )
+        })?;
+        let index = val["output_index"].as_u64().unwrap_or_default() as usize;
+        let delta = || val["delta"].as_str().unwrap_or_default().to_string();
+        let chunks = match val["type"].as_str().unwrap_or_default() {
+            "response.output_text.delta" => vec![CompletionChunk::Text { text: delta() }],
+            "response.reasoning_summary_text.delta" | "response.reasoning_text.delta" => {
+                vec![CompletionChunk::Reasoning { text: delta() }]
+            }
+            "response.output_item.added" if val["item"]["type"] == "function_call" => {
+                vec![CompletionChunk::ToolCall {
+                    index,
+                    call_id: val["item"]["call_id"].as_str().map(String::from),
+                    name: val["item"]["name"].as_str().map(String::from),
+                    args: String::new(),
+                }]
+            }
+            "response.function_call_arguments.delta" => vec![CompletionChunk::ToolCall {
+                index,
+                call_id: None,
+                name: None,
+                args: delta(),
+            }],
+            "response.completed" | "response.incomplete" | "response.failed" => {
+                let res: types::CompletionResponse =
+                    serde_json::from_value(val["response"].clone())
+                        .map_err(|err| format!("OpenAI completions error: {}", err))?;
+                if res.maybe_failed() {
+                    log::warn!(response:serde = res; "completions maybe failed");
+                }
+                self.response = Some(res);
+                vec![]
+            }
+            "error" => {
+                return Err(format!("OpenAI completions error: {}", event.data).into());
+            }
+            _ => vec![],
+        };
+
+        Ok(chunks)
+    }
+
+    fn finish(self) -> Result<AgentOutput, BoxError> {
+        let res = self
+            .response
+            .ok_or("OpenAI completions error: stream ended without response")?;
+        res.try_into(self.raw_history, self.chat_history)
+    }
 }
@@ -0,0 +1,306 @@
+//! Retry layer for model API requests.
+//!
+//! [`RetryPolicy`] retries failed requests with jittered exponential backoff and honors the
+//! `Retry-After` (or `retry-after-ms`) header of the response.
+//!
+//! Retries are idempotency aware:
+//! - connection errors, `429 Too Many Requests` and `503 Service Unavailable` are always retried,
+//!   the request was not processed by the server;
+//! - other server errors and timeouts are only retried for idempotent requests, i.e. requests with
+//!   an idempotent method or an `Idempotency-Key` header, they may have been processed (and billed).
+
+use anda_core::{AgentOutput, BoxError, CompletionChunk};
+use chrono::DateTime;
+use futures::stream::{BoxStream, StreamExt, TryStreamExt};
+use http::{HeaderMap, StatusCode};
+use reqwest::{RequestBuilder, Response};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::rand_number;
+
+/// The header to mark a non-idempotent request as safe to retry.
+pub static IDEMPOTENCY_KEY: &str = "idempotency-key";
+
+/// Retry policy for model API requests.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// The maximum number of retries, 0 disables retries.
+    pub max_retries: u32,
+    /// The backoff before the first retry, doubled for each retry.
+    pub initial_backoff: Duration,
+    /// The maximum backoff between retries.
+    pub max_backoff: Duration,
+    /// The maximum `Retry-After` delay to honor,
+    /// the response is returned without retry if the server asks to wait longer.
+    pub max_retry_after: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 2,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(10),
+            max_retry_after: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy without retries.
+    pub fn none() -> Self {
+        Self {
+            max_retries: 0,
+            ..Default::default()
+        }
+    }
+
+    /// Sends the request with retries.
+    /// Returns the last response and the number of attempts.
+    ///
+    /// Error responses that are not retried or still failed after the last retry are returned as is,
+    /// so callers can handle the status and body.
+    pub async fn send(&self, req: RequestBuilder) -> Result<(Response, u64), BoxError> {
+        let (client, request) = req.build_split();
+        let request = request?;
+        let idempotent =
+            request.method().is_idempotent() || request.headers().contains_key(IDEMPOTENCY_KEY);
+
+        let mut attempts: u64 = 0;
+        loop {
+            attempts += 1;
+            // 请求体为流时无法克隆，不能重试
+            let Some(req) = request.try_clone() else {
+                return Ok((client.execute(request).await?, attempts));
+            };
+            let can_retry = attempts <= self.max_retries as u64;
+
+            match client.execute(req).await {
+                Ok(res) => {
+                    let status = res.status();
+                    if can_retry && retryable_status(status, idempotent) {
+                        let delay = match retry_after(res.headers()) {
+                            Some(delay) if delay > self.max_retry_after => {
+                                return Ok((res, attempts));
+                            }
+                            Some(delay) => delay,
+                            None => self.backoff(attempts),
+                        };
+                        log::warn!(
+                            "request to {} failed: {status}, retry after {delay:?}",
+                            request.url()
+                        );
+                        tokio::time::sleep(delay).await;
+                        continue;
+                    }
+                    return Ok((res, attempts));
+                }
+                Err(err) => {
+                    if can_retry && (err.is_connect() || (idempotent && err.is_timeout())) {
+                        let delay = self.backoff(attempts);
+                        log::warn!(
+                            "request to {} failed: {err}, retry after {delay:?}",
+                            request.url()
+                        );
+                        tokio::time::sleep(delay).await;
+                        continue;
+                    }
+                    return Err(err.into());
+                }
+            }
+        }
+    }
+
+    /// Returns the jittered exponential backoff before the given retry.
+    fn backoff(&self, attempts: u64) -> Duration {
+        let exp = attempts.saturating_sub(1).min(16) as u32;
+        let delay = self
+            .initial_backoff
+            .saturating_mul(2u32.pow(exp))
+            .min(self.max_backoff);
+        // 在 [delay/2, delay] 之间随机抖动，避免并发请求同时重试
+        let half = delay.as_millis() as u64 / 2;
+        Duration::from_millis(half + rand_number(0..=half))
+    }
+}
+
+/// Reports the number of attempts as the requests in the usage of the completion output.
+pub(crate) fn with_requests(mut output: AgentOutput, attempts: u64) -> AgentOutput {
+    output.usage.requests = attempts;
+    output
+}
+
+/// Reports the number of attempts as the requests in the usage of the completion stream output.
+pub(crate) fn stream_with_requests(
+    stream: BoxStream<'static, Result<CompletionChunk, BoxError>>,
+    attempts: u64,
+) -> BoxStream<'static, Result<CompletionChunk, BoxError>> {
+    stream
+        .map_ok(move |chunk| match chunk {
+            CompletionChunk::Output(output) => {
+                CompletionChunk::Output(with_requests(output, attempts))
+            }
+            chunk => chunk,
+        })
+        .boxed()
+}
+
+fn retryable_status(status: StatusCode, idempotent: bool) -> bool {
+    match status {
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => true,
+        StatusCode::REQUEST_TIMEOUT
+        | StatusCode::INTERNAL_SERVER_ERROR
+        | StatusCode::BAD_GATEWAY
+        | StatusCode::GATEWAY_TIMEOUT => idempotent,
+        _ => false,
+    }
+}
+
+/// Parses the delay from `retry-after-ms` or `Retry-After` (seconds or HTTP date) headers.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    if let Some(ms) = headers
+        .get("retry-after-ms")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<f64>().ok())
+        && ms >= 0.0
+    {
+        return Some(Duration::from_millis(ms as u64));
+    }
+
+    let val = headers
+        .get(http::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim();
+    if let Ok(secs) = val.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = DateTime::parse_from_rfc2822(val).ok()?;
+    let ms = at.timestamp_millis() - chrono::Utc::now().timestamp_millis();
+    Some(Duration::from_millis(ms.max(0) as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    /// Serves the raw HTTP responses in order, the last one is repeated.
+    async fn mock_server(responses: Vec<&'static str>) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buf = vec![0u8; 4096];
+                let _ = socket.read(&mut buf).await;
+                let i = counter.fetch_add(1, Ordering::SeqCst);
+                let res = responses[i.min(responses.len() - 1)];
+                let _ = socket.write_all(res.as_bytes()).await;
+                let _ = socket.shutdown().await;
+            }
+        });
+        (format!("http://{}", addr), count)
+    }
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 2,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(50),
+            max_retry_after: Duration::from_secs(1),
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_retry_policy() {
+        let client = reqwest::Client::new();
+        let (url, count) = mock_server(vec![
+            "HTTP/1.1 429 Too Many Requests\r\nretry-after: 0\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+            "HTTP/1.1 503 Service Unavailable\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+            "HTTP/1.1 200 OK\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok",
+        ])
+        .await;
+        let (res, attempts) = policy().send(client.post(&url).body("{}")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.text().await.unwrap(), "ok");
+        assert_eq!(attempts, 3);
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+
+        // gives up after max retries
+        let (url, count) = mock_server(vec![
+            "HTTP/1.1 429 Too Many Requests\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+        ])
+        .await;
+        let (res, attempts) = policy().send(client.post(&url).body("{}")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(attempts, 3);
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+
+        // does not wait longer than max_retry_after
+        let (url, _) = mock_server(vec![
+            "HTTP/1.1 429 Too Many Requests\r\nretry-after: 3600\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+        ])
+        .await;
+        let (res, attempts) = policy().send(client.post(&url)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(attempts, 1);
+
+        // server errors are only retried for idempotent requests
+        let (url, _) = mock_server(vec![
+            "HTTP/1.1 500 Internal Server Error\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+            "HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+        ])
+        .await;
+        let (res, attempts) = policy().send(client.post(&url)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(attempts, 1);
+
+        let (url, _) = mock_server(vec![
+            "HTTP/1.1 500 Internal Server Error\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+            "HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+        ])
+        .await;
+        let (res, attempts) = policy()
+            .send(client.post(&url).header(IDEMPOTENCY_KEY, "key"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(attempts, 2);
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+        headers.insert(http::header::RETRY_AFTER, "2".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(2)));
+        headers.insert("retry-after-ms", "150".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(Duration::from_millis(150)));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            http::header::RETRY_AFTER,
+            "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap(),
+        );
+        assert_eq!(retry_after(&headers), Some(Duration::ZERO));
+
+        let policy = policy();
+        for attempts in 1..10 {
+            let delay = policy.backoff(attempts);
+            assert!(delay <= policy.max_backoff);
+            assert!(delay >= Duration::from_millis(5));
+        }
+    }
+}
@@ -0,0 +1,373 @@
+//! Model router for completion requests across multiple models.
+//!
+//! The router groups completion models into routes by alias, e.g. "default", "fast" or "reasoning".
+//! An agent selects a route with [`CompletionRequest::model`], requests without a model or with
+//! an unknown alias use the default route.
+//!
+//! For each request, a model of the route is picked as the primary by weighted random selection,
+//! the other models of the route are tried in order as fallbacks when it fails or times out.
+//! Models with weight 0 are only used as fallbacks.
+//!
+//! # Example
+//! ```rust,ignore
+//! use anda_engine::model::{Model, deepseek, openai, router::{ModelRouter, RouteTarget}};
+//!
+//! let deepseek = deepseek::Client::new(&deepseek_key, None);
+//! let openai = openai::Client::new(&openai_key, None);
+//! let router = ModelRouter::new("default")
+//!     .with_route(
+//!         "default",
+//!         vec![
+//!             RouteTarget::new("deepseek", Arc::new(deepseek.completion_model("deepseek-chat"))),
+//!             RouteTarget::new("openai", Arc::new(openai.completion_model("gpt-4o"))).with_weight(0),
+//!         ],
+//!     )
+//!     .with_route(
+//!         "reasoning",
+//!         vec![RouteTarget::new("deepseek-reasoner", Arc::new(deepseek.completion_model("deepseek-reasoner")))],
+//!     )
+//!     .with_timeout(Duration::from_secs(120));
+//! let model = Model::with_completer(Arc::new(router));
+//! ```
+
+use anda_core::{
+    AgentOutput, BoxError, BoxPinFut, CompletionChunk, CompletionRequest, HeuristicTokenizer,
+    Tokenizer,
+};
+use futures::stream::{self, BoxStream, StreamExt};
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+
+use super::CompletionFeaturesDyn;
+use crate::rand_number;
+
+/// A completion model in a route of [`ModelRouter`].
+#[derive(Clone)]
+pub struct RouteTarget {
+    /// The name of the model, used in logs and errors.
+    pub name: String,
+    /// The completion model.
+    pub completer: Arc<dyn CompletionFeaturesDyn>,
+    /// The weight for load balancing, 0 means the model is only used as a fallback.
+    pub weight: u32,
+}
+
+impl RouteTarget {
+    /// Creates a route target with weight 1.
+    pub fn new(name: &str, completer: Arc<dyn CompletionFeaturesDyn>) -> Self {
+        Self {
+            name: name.to_string(),
+            completer,
+            weight: 1,
+        }
+    }
+
+    /// Sets the weight for load balancing.
+    pub fn with_weight(mut self, weight: u32) -> Self {
+        self.weight = weight;
+        self
+    }
+}
+
+/// A completion model that routes requests to multiple models
+/// with fallback, weighted load balancing and aliases.
+pub struct ModelRouter {
+    default_route: String,
+    routes: BTreeMap<String, Vec<RouteTarget>>,
+    timeout: Option<Duration>,
+}
+
+impl ModelRouter {
+    /// Creates a router with the alias of the default route.
+    pub fn new(default_route: &str) -> Self {
+        Self {
+            default_route: default_route.to_string(),
+            routes: BTreeMap::new(),
+            timeout: None,
+        }
+    }
+
+    /// Adds a route with its models, the order of the models is the fallback order.
+    pub fn with_route(mut self, alias: &str, targets: Vec<RouteTarget>) -> Self {
+        self.routes.insert(alias.to_string(), targets);
+        self
+    }
+
+    /// Sets the timeout of each model call, the next model is tried on timeout.
+    /// For streaming completions, it is the timeout of the first chunk.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Returns the models to try for the request, the primary model first.
+    fn targets(&self, req: &CompletionRequest) -> Result<Vec<RouteTarget>, BoxError> {
+        let mut targets = req
+            .model
+            .as_ref()
+            .and_then(|alias| self.routes.get(alias))
+            .or_else(|| self.routes.get(&self.default_route))
+            .filter(|targets| !targets.is_empty())
+            .ok_or_else(|| {
+                format!(
+                    "model router error: no models for {}",
+                    req.model.as_deref().unwrap_or(&self.default_route)
+                )
+            })?
+            .clone();
+
+        let total: u64 = targets.iter().map(|t| t.weight as u64).sum();
+        if total > 0 {
+            let mut n = rand_number(0..total);
+            for i in 0..targets.len() {
+                let weight = targets[i].weight as u64;
+                if n < weight {
+                    // 选中的模型作为首选，其余模型保持顺序作为备选
+                    targets[..=i].rotate_right(1);
+                    break;
+                }
+                n -= weight;
+            }
+        }
+
+        Ok(targets)
+    }
+}
+
+fn all_failed(last_err: Option<BoxError>) -> BoxError {
+    match last_err {
+        Some(err) => format!("model router error: all models failed, last error: {}", err).into(),
+        None => "model router error: all models failed".into(),
+    }
+}
+
+impl CompletionFeaturesDyn for ModelRouter {
+    fn completion(&self, req: CompletionRequest) -> BoxPinFut<Result<AgentOutput, BoxError>> {
+        let targets = self.targets(&req);
+        let timeout = self.timeout;
+        Box::pin(async move {
+            let mut last_err: Option<BoxError> = None;
+            for target in targets? {
+                let fut = target.completer.completion(req.clone());
+                let res = match timeout {
+                    Some(timeout) => tokio::time::timeout(timeout, fut)
+                        .await
+                        .unwrap_or_else(|_| Err(format!("timeout after {:?}", timeout).into())),
+                    None => fut.await,
+                };
+                match res {
+                    Ok(output) => return Ok(output),
+                    Err(err) => {
+                        log::warn!("model {} failed: {}", target.name, err);
+                        last_err = Some(err);
+                    }
+                }
+            }
+            Err(all_failed(last_err))
+        })
+    }
+
+    /// Falls back to the next model only if the stream fails before the first chunk,
+    /// errors after that are returned to the caller.
+    fn completion_stream(
+        &self,
+        req: CompletionRequest,
+    ) -> BoxStream<'static, Result<CompletionChunk, BoxError>> {
+        let targets = self.targets(&req);
+        let timeout = self.timeout;
+        stream::once(async move {
+            let targets = match targets {
+                Ok(targets) => targets,
+                Err(err) => return stream::once(async { Err(err) }).boxed(),
+            };
+
+            let mut last_err: Option<BoxError> = None;
+            for target in targets {
+                let mut stream = target.completer.completion_stream(req.clone());
+                let first = match timeout {
+                    Some(timeout) => tokio::time::timeout(timeout, stream.next())
+                        .await
+                        .unwrap_or_else(|_| {
+                            Some(Err(format!("timeout after {:?}", timeout).into()))
+                        }),
+                    None => stream.next().await,
+                };
+                match first {
+                    Some(Ok(chunk)) => {
+                        return stream::once(async { Ok(chunk) }).chain(stream).boxed();
+                    }
+                    Some(Err(err)) => {
+                        log::warn!("model {} failed: {}", target.name, err);
+                        last_err = Some(err);
+                    }
+                    None => {
+                        log::warn!("model {} failed: empty stream", target.name);
+                        last_err = Some("empty stream".into());
+                    }
+                }
+            }
+            stream::once(async { Err(all_failed(last_err)) }).boxed()
+        })
+        .flatten()
+        .boxed()
+    }
+
+    /// Returns the tokenizer of the first model of the default route.
+    fn tokenizer(&self) -> Arc<dyn Tokenizer> {
+        match self
+            .routes
+            .get(&self.default_route)
+            .and_then(|targets| targets.first())
+        {
+            Some(target) => target.completer.tokenizer(),
+            None => Arc::new(HeuristicTokenizer),
+        }
+    }
+
+    /// Returns the smallest context window of the models of the router, if any is known.
+    fn context_window(&self) -> Option<usize> {
+        self.routes
+            .values()
+            .flatten()
+            .filter_map(|target| target.completer.context_window())
+            .min()
+    }
+
+    /// Returns true only if all models of the router support output schema.
+    fn supports_output_schema(&self) -> bool {
+        self.routes
+            .values()
+            .flatten()
+            .all(|target| target.completer.supports_output_schema())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns its name as content after the delay, or fails.
+    struct NamedModel {
+        name: &'static str,
+        delay: Duration,
+        fail: bool,
+    }
+
+    impl NamedModel {
+        fn ok(name: &'static str) -> Arc<Self> {
+            Arc::new(Self {
+                name,
+                delay: Duration::ZERO,
+                fail: false,
+            })
+        }
+
+        fn failing(name: &'static str) -> Arc<Self> {
+            Arc::new(Self {
+                name,
+                delay: Duration::ZERO,
+                fail: true,
+            })
+        }
+
+        fn slow(name: &'static str, delay: Duration) -> Arc<Self> {
+            Arc::new(Self {
+                name,
+                delay,
+                fail: false,
+            })
+        }
+    }
+
+    impl CompletionFeaturesDyn for NamedModel {
+        fn completion(&self, _req: CompletionRequest) -> BoxPinFut<Result<AgentOutput, BoxError>> {
+            let name = self.name;
+            let delay = self.delay;
+            let fail = self.fail;
+            Box::pin(async move {
+                tokio::time::sleep(delay).await;
+                if fail {
+                    return Err(format!("{} is down", name).into());
+                }
+                Ok(AgentOutput {
+                    content: name.to_string(),
+                    ..Default::default()
+                })
+            })
+        }
+    }
+
+    fn req(model: Option<&str>) -> CompletionRequest {
+        CompletionRequest {
+            model: model.map(|m| m.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_fallback_and_aliases() {
+        let router = ModelRouter::new("default")
+            .with_route(
+                "default",
+                vec![
+                    RouteTarget::new("primary", NamedModel::failing("primary")),
+                    RouteTarget::new("backup", NamedModel::ok("backup")).with_weight(0),
+                ],
+            )
+            .with_route(
+                "fast",
+                vec![
+                    RouteTarget::new("slow", NamedModel::slow("slow", Duration::from_secs(5))),
+                    RouteTarget::new("fast", NamedModel::ok("fast")).with_weight(0),
+                ],
+            )
+            .with_route(
+                "broken",
+                vec![RouteTarget::new("broken", NamedModel::failing("broken"))],
+            )
+            .with_timeout(Duration::from_millis(100));
+
+        let output = router.completion(req(None)).await.unwrap();
+        assert_eq!(output.content, "backup");
+        let output = router.completion(req(Some("unknown"))).await.unwrap();
+        assert_eq!(output.content, "backup");
+        let output = router.completion(req(Some("fast"))).await.unwrap();
+        assert_eq!(output.content, "fast");
+
+        let err = router.completion(req(Some("broken"))).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "model router error: all models failed, last error: broken is down"
+        );
+
+        let mut stream = router.completion_stream(req(Some("fast")));
+        match stream.next().await {
+            Some(Ok(CompletionChunk::Output(output))) => assert_eq!(output.content, "fast"),
+            other => panic!("unexpected chunk: {:?}", other),
+        }
+        assert!(stream.next().await.is_none());
+
+        let router = ModelRouter::new("default");
+        assert!(router.completion(req(None)).await.is_err());
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_weighted_load_balancing() {
+        let router = ModelRouter::new("default").with_route(
+            "default",
+            vec![
+                RouteTarget::new("a", NamedModel::ok("a")).with_weight(3),
+                RouteTarget::new("b", NamedModel::ok("b")).with_weight(1),
+                RouteTarget::new("c", NamedModel::ok("c")).with_weight(0),
+            ],
+        );
+
+        let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+        for _ in 0..400 {
+            let output = router.completion(req(None)).await.unwrap();
+            *counts.entry(output.content).or_default() += 1;
+        }
+        assert!(counts["a"] > counts["b"]);
+        assert!(counts["b"] > 0);
+        assert!(!counts.contains_key("c"));
+    }
+}
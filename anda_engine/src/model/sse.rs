@@ -0,0 +1,598 @@
+//! Server-Sent Events (SSE) support for streaming completions.
+//!
+//! This module provides:
+//! - [`SseDecoder`]: an incremental decoder for `text/event-stream` bodies,
+//!   and for newline delimited JSON bodies in the JSON lines mode;
+//! - [`StreamAccumulator`]: the provider specific state that turns events into
+//!   [`CompletionChunk`] deltas and builds the final [`AgentOutput`];
+//! - [`ChatCompletionAccumulator`]: an accumulator for the OpenAI compatible
+//!   `/chat/completions` stream format, shared by OpenAI, DeepSeek, Kimi and xAI.
+
+use anda_core::{AgentOutput, BoxError, CompletionChunk, Json};
+use bytes::Bytes;
+use futures::{Stream, StreamExt, stream::BoxStream};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::{BTreeMap, VecDeque};
+
+/// A single dispatched Server-Sent Event.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SseEvent {
+    /// The event type from the `event:` field, if any.
+    pub event: Option<String>,
+    /// The event payload, multiple `data:` lines are joined with "\n".
+    pub data: String,
+}
+
+/// Incremental decoder for `text/event-stream` bodies.
+#[derive(Debug, Default)]
+pub struct SseDecoder {
+    buf: Vec<u8>,
+    event: Option<String>,
+    data: Vec<String>,
+    json_lines: bool,
+}
+
+impl SseDecoder {
+    /// Creates a decoder for newline delimited JSON bodies,
+    /// each non-empty line is dispatched as the data of an event.
+    pub fn json_lines() -> Self {
+        Self {
+            json_lines: true,
+            ..Default::default()
+        }
+    }
+
+    /// Feeds a chunk of bytes and returns the events completed by it.
+    pub fn decode(&mut self, chunk: &[u8]) -> Vec<SseEvent> {
+        self.buf.extend_from_slice(chunk);
+        let mut events = Vec::new();
+        while let Some(pos) = self.buf.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buf.drain(..=pos).collect();
+            let line = String::from_utf8_lossy(&line);
+            if let Some(event) = self.feed_line(line.trim_end_matches(['\n', '\r'])) {
+                events.push(event);
+            }
+        }
+        events
+    }
+
+    /// Flushes the pending event at the end of the body.
+    pub fn finish(&mut self) -> Option<SseEvent> {
+        if !self.buf.is_empty() {
+            let line = std::mem::take(&mut self.buf);
+            let line = String::from_utf8_lossy(&line);
+            if let Some(event) = self.feed_line(line.trim_end_matches('\r')) {
+                return Some(event);
+            }
+        }
+        self.dispatch()
+    }
+
+    fn feed_line(&mut self, line: &str) -> Option<SseEvent> {
+        if self.json_lines {
+            return (!line.trim().is_empty()).then(|| SseEvent {
+                event: None,
+                data: line.to_string(),
+            });
+        }
+        if line.is_empty() {
+            return self.dispatch();
+        }
+        if line.starts_with(':') {
+            // comment line, e.g. ": keep-alive"
+            return None;
+        }
+
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line, ""),
+        };
+        match field {
+            "event" => self.event = Some(value.to_string()),
+            "data" => self.data.push(value.to_string()),
+            _ => {}
+        }
+        None
+    }
+
+    fn dispatch(&mut self) -> Option<SseEvent> {
+        if self.data.is_empty() {
+            self.event = None;
+            return None;
+        }
+        let data = self.data.join("\n");
+        self.data.clear();
+        Some(SseEvent {
+            event: self.event.take(),
+            data,
+        })
+    }
+}
+
+/// Provider specific state of a streaming completion.
+pub trait StreamAccumulator: Send + 'static {
+    /// Consumes an event and returns the deltas it produced.
+    fn push(&mut self, event: SseEvent) -> Result<Vec<CompletionChunk>, BoxError>;
+
+    /// Builds the final output once the event stream has ended.
+    fn finish(self) -> Result<AgentOutput, BoxError>;
+}
+
+/// Turns a successful SSE response into a stream of completion chunks.
+///
+/// The stream ends with a [`CompletionChunk::Output`] or an error.
+pub fn completion_stream<A: StreamAccumulator>(
+    response: reqwest::Response,
+    acc: A,
+) -> BoxStream<'static, Result<CompletionChunk, BoxError>> {
+    decode_stream(response.bytes_stream(), SseDecoder::default(), acc)
+}
+
+/// Turns a successful newline delimited JSON response into a stream of completion chunks.
+///
+/// The stream ends with a [`CompletionChunk::Output`] or an error.
+pub fn json_lines_stream<A: StreamAccumulator>(
+    response: reqwest::Response,
+    acc: A,
+) -> BoxStream<'static, Result<CompletionChunk, BoxError>> {
+    decode_stream(response.bytes_stream(), SseDecoder::json_lines(), acc)
+}
+
+fn decode_stream<S, E, A>(
+    body: S,
+    decoder: SseDecoder,
+    acc: A,
+) -> BoxStream<'static, Result<CompletionChunk, BoxError>>
+where
+    S: Stream<Item = Result<Bytes, E>> + Send + 'static,
+    E: Into<BoxError>,
+    A: StreamAccumulator,
+{
+    struct State<A> {
+        body: BoxStream<'static, Result<Bytes, BoxError>>,
+        decoder: SseDecoder,
+        acc: Option<A>,
+        pending: VecDeque<Result<CompletionChunk, BoxError>>,
+    }
+
+    impl<A: StreamAccumulator> State<A> {
+        fn push(&mut self, events: Vec<SseEvent>) {
+            for event in events {
+                let Some(acc) = self.acc.as_mut() else {
+                    return;
+                };
+                match acc.push(event) {
+                    Ok(chunks) => self.pending.extend(chunks.into_iter().map(Ok)),
+                    Err(err) => {
+                        self.pending.push_back(Err(err));
+                        self.acc = None;
+                    }
+                }
+            }
+        }
+    }
+
+    let state = State {
+        body: body.map(|res| res.map_err(Into::into)).boxed(),
+        decoder,
+        acc: Some(acc),
+        pending: VecDeque::new(),
+    };
+
+    futures::stream::unfold(state, |mut st| async move {
+        loop {
+            if let Some(item) = st.pending.pop_front() {
+                return Some((item, st));
+            }
+            st.acc.as_ref()?;
+
+            match st.body.next().await {
+                Some(Ok(bytes)) => {
+                    let events = st.decoder.decode(&bytes);
+                    st.push(events);
+                }
+                Some(Err(err)) => {
+                    st.pending.push_back(Err(err));
+                    st.acc = None;
+                }
+                None => {
+                    if let Some(event) = st.decoder.finish() {
+                        st.push(vec![event]);
+                    }
+                    if let Some(acc) = st.acc.take() {
+                        st.pending
+                            .push_back(acc.finish().map(CompletionChunk::Output));
+                    }
+                }
+            }
+        }
+    })
+    .boxed()
+}
+
+/// A `chat.completion.chunk` object of the OpenAI compatible stream format.
+#[derive(Debug, Default, Deserialize)]
+struct ChatCompletionChunk {
+    #[serde(default)]
+    id: String,
+    #[serde(default)]
+    created: u64,
+    #[serde(default)]
+    model: String,
+    #[serde(default)]
+    choices: Vec<ChunkChoice>,
+    usage: Option<Json>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ChunkChoice {
+    #[serde(default)]
+    delta: ChunkDelta,
+    finish_reason: Option<String>,
+    // Kimi reports usage on the last choice
+    usage: Option<Json>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ChunkDelta {
+    role: Option<String>,
+    content: Option<String>,
+    reasoning_content: Option<String>,
+    refusal: Option<String>,
+    tool_calls: Option<Vec<ToolCallDelta>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ToolCallDelta {
+    #[serde(default)]
+    index: usize,
+    id: Option<String>,
+    function: Option<FunctionDelta>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FunctionDelta {
+    name: Option<String>,
+    arguments: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct ToolCallState {
+    id: String,
+    name: String,
+    arguments: String,
+}
+
+/// Accumulator for the OpenAI compatible `/chat/completions` stream format.
+///
+/// When the stream ends, the accumulated message is rebuilt as a non-streaming
+/// `chat.completion` response and handed to `finish`, so providers can reuse
+/// their response parsing.
+pub struct ChatCompletionAccumulator<F> {
+    id: String,
+    created: u64,
+    model: String,
+    role: Option<String>,
+    content: Option<String>,
+    reasoning: Option<String>,
+    refusal: Option<String>,
+    tool_calls: BTreeMap<usize, ToolCallState>,
+    finish_reason: Option<String>,
+    usage: Option<Json>,
+    finish: F,
+}
+
+impl<F> ChatCompletionAccumulator<F>
+where
+    F: FnOnce(Json) -> Result<AgentOutput, BoxError> + Send + 'static,
+{
+    pub fn new(finish: F) -> Self {
+        Self {
+            id: String::new(),
+            created: 0,
+            model: String::new(),
+            role: None,
+            content: None,
+            reasoning: None,
+            refusal: None,
+            tool_calls: BTreeMap::new(),
+            finish_reason: None,
+            usage: None,
+            finish,
+        }
+    }
+
+    /// Returns the accumulated response in the non-streaming format.
+    fn to_response(&self) -> Json {
+        let mut message = json!({
+            "role": self.role.as_deref().unwrap_or("assistant"),
+            "content": self.content,
+        });
+        let msg = message.as_object_mut().unwrap();
+        if let Some(reasoning) = &self.reasoning {
+            msg.insert("reasoning_content".to_string(), reasoning.clone().into());
+        }
+        if let Some(refusal) = &self.refusal {
+            msg.insert("refusal".to_string(), refusal.clone().into());
+        }
+        if !self.tool_calls.is_empty() {
+            msg.insert(
+                "tool_calls".to_string(),
+                self.tool_calls
+                    .values()
+                    .map(|tc| {
+                        json!({
+                            "id": tc.id,
+                            "type": "function",
+                            "function": {
+                                "name": tc.name,
+                                "arguments": if tc.arguments.is_empty() { "{}" } else { tc.arguments.as_str() },
+                            },
+                        })
+                    })
+                    .collect::<Vec<_>>()
+                    .into(),
+            );
+        }
+
+        json!({
+            "id": self.id,
+            "object": "chat.completion",
+            "created": self.created,
+            "model": self.model,
+            "choices": [{
+                "index": 0,
+                "message": message,
+                // a stream that ends without finish_reason was cut off
+                "finish_reason": self.finish_reason.as_deref().unwrap_or("incomplete"),
+            }],
+            "usage": self.usage,
+        })
+    }
+}
+
+impl<F> StreamAccumulator for ChatCompletionAccumulator<F>
+where
+    F: FnOnce(Json) -> Result<AgentOutput, BoxError> + Send + 'static,
+{
+    fn push(&mut self, event: SseEvent) -> Result<Vec<CompletionChunk>, BoxError> {
+        if event.data == "[DONE]" {
+            return Ok(Vec::new());
+        }
+
+        let val: Json = serde_json::from_str(&event.data)
+            .map_err(|err| format!("invalid stream chunk: {}, data: {}", err, event.data))?;
+        if let Some(err) = val.get("error") {
+            return Err(format!("stream error: {}", err).into());
+        }
+        let chunk: ChatCompletionChunk = serde_json::from_value(val)
+            .map_err(|err| format!("invalid stream chunk: {}, data: {}", err, event.data))?;
+
+        if self.id.is_empty() {
+            self.id = chunk.id;
+            self.created = chunk.created;
+            self.model = chunk.model;
+        }
+        if chunk.usage.is_some() {
+            self.usage = chunk.usage;
+        }
+
+        let mut chunks = Vec::new();
+        // Only the first choice is used, as in the non-streaming responses.
+        if let Some(choice) = chunk.choices.into_iter().next() {
+            let delta = choice.delta;
+            if delta.role.is_some() {
+                self.role = delta.role;
+            }
+            if let Some(text) = delta.reasoning_content
+                && !text.is_empty()
+            {
+                self.reasoning.get_or_insert_default().push_str(&text);
+                chunks.push(CompletionChunk::Reasoning { text });
+            }
+            if let Some(text) = delta.content
+                && !text.is_empty()
+            {
+                self.content.get_or_insert_default().push_str(&text);
+                chunks.push(CompletionChunk::Text { text });
+            }
+            if let Some(refusal) = delta.refusal {
+                self.refusal.get_or_insert_default().push_str(&refusal);
+            }
+            for tc in delta.tool_calls.unwrap_or_default() {
+                let state = self.tool_calls.entry(tc.index).or_default();
+                let (name, args) = match tc.function {
+                    Some(f) => (f.name, f.arguments.unwrap_or_default()),
+                    None => (None, String::new()),
+                };
+                if let Some(id) = &tc.id {
+                    state.id.push_str(id);
+                }
+                if let Some(name) = &name {
+                    state.name.push_str(name);
+                }
+                state.arguments.push_str(&args);
+                chunks.push(CompletionChunk::ToolCall {
+                    index: tc.index,
+                    call_id: tc.id,
+                    name,
+                    args,
+                });
+            }
+            if choice.finish_reason.is_some() {
+                self.finish_reason = choice.finish_reason;
+            }
+            if choice.usage.is_some() {
+                self.usage = choice.usage;
+            }
+        }
+
+        Ok(chunks)
+    }
+
+    fn finish(self) -> Result<AgentOutput, BoxError> {
+        let res = self.to_response();
+        (self.finish)(res)
+    }
+}
+
+/// Collects a completion stream, returning the deltas and the final output.
+#[cfg(test)]
+pub(crate) async fn collect_chunks(
+    mut stream: BoxStream<'static, Result<CompletionChunk, BoxError>>,
+) -> Result<(Vec<CompletionChunk>, AgentOutput), BoxError> {
+    let mut deltas = Vec::new();
+    while let Some(chunk) = stream.next().await {
+        match chunk? {
+            CompletionChunk::Output(output) => return Ok((deltas, output)),
+            delta => deltas.push(delta),
+        }
+    }
+    Err("completion stream ended without output".into())
+}
+
+#[cfg(test)]
+pub(crate) fn mock_body(
+    chunks: &[&str],
+) -> impl Stream<Item = Result<Bytes, BoxError>> + Send + 'static {
+    let chunks: Vec<Result<Bytes, BoxError>> = chunks
+        .iter()
+        .map(|s| Ok(Bytes::from(s.to_string())))
+        .collect();
+    futures::stream::iter(chunks)
+}
+
+#[cfg(test)]
+pub(crate) fn test_stream<A: StreamAccumulator>(
+    chunks: &[&str],
+    acc: A,
+) -> BoxStream<'static, Result<CompletionChunk, BoxError>> {
+    decode_stream(mock_body(chunks), SseDecoder::default(), acc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sse_decoder() {
+        let mut decoder = SseDecoder::default();
+        let events = decoder.decode(b": ping\n\nevent: delta\ndata: {\"a\"");
+        assert!(events.is_empty());
+        let events = decoder.decode(b":1}\r\n\r\ndata: line1\ndata: line2\n\ndata: [DONE]");
+        assert_eq!(
+            events,
+            vec![
+                SseEvent {
+                    event: Some("delta".to_string()),
+                    data: r#"{"a":1}"#.to_string(),
+                },
+                SseEvent {
+                    event: None,
+                    data: "line1\nline2".to_string(),
+                },
+            ]
+        );
+        assert_eq!(
+            decoder.finish(),
+            Some(SseEvent {
+                event: None,
+                data: "[DONE]".to_string(),
+            })
+        );
+        assert_eq!(decoder.finish(), None);
+
+        let mut decoder = SseDecoder::json_lines();
+        let events = decoder.decode(b"{\"a\":1}\n\n{\"b\"");
+        assert_eq!(
+            events,
+            vec![SseEvent {
+                event: None,
+                data: r#"{"a":1}"#.to_string(),
+            }]
+        );
+        assert_eq!(decoder.decode(b":2}\r\n")[0].data, r#"{"b":2}"#);
+        assert_eq!(decoder.finish(), None);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_chat_completion_stream() {
+        let acc = ChatCompletionAccumulator::new(|res: Json| {
+            Ok(AgentOutput {
+                content: res["choices"][0]["message"]["content"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string(),
+                failed_reason: res["choices"][0]["finish_reason"]
+                    .as_str()
+                    .filter(|v| *v != "stop" && *v != "tool_calls")
+                    .map(|v| v.to_string()),
+                raw_history: vec![res],
+                ..Default::default()
+            })
+        });
+        let stream = test_stream(
+            &[
+                "data: {\"id\":\"c1\",\"created\":1,\"model\":\"m\",\"choices\":[{\"index\":0,\"delta\":{\"role\":\"assistant\",\"reasoning_content\":\"hmm\"}}]}\n\n",
+                "data: {\"id\":\"c1\",\"choices\":[{\"index\":0,\"delta\":{\"content\":\"Hel\"}}]}\n\ndata: {\"id\":\"c1\",\"choices\":[{\"index\":0,\"delta\":{\"content\":\"lo\"}}]}\n\n",
+                "data: {\"id\":\"c1\",\"choices\":[{\"index\":0,\"delta\":{\"tool_calls\":[{\"index\":0,\"id\":\"call_1\",\"type\":\"function\",\"function\":{\"name\":\"sum\",\"arguments\":\"\"}}]}}]}\n\n",
+                "data: {\"id\":\"c1\",\"choices\":[{\"index\":0,\"delta\":{\"tool_calls\":[{\"index\":0,\"function\":{\"arguments\":\"{\\\"x\\\":\"}}]}}]}\n\n",
+                "data: {\"id\":\"c1\",\"choices\":[{\"index\":0,\"delta\":{\"tool_calls\":[{\"index\":0,\"function\":{\"arguments\":\"1}\"}}]},\"finish_reason\":\"tool_calls\"}]}\n\n",
+                "data: {\"id\":\"c1\",\"choices\":[],\"usage\":{\"prompt_tokens\":3,\"completion_tokens\":5,\"total_tokens\":8}}\n\n",
+                "data: [DONE]\n\n",
+            ],
+            acc,
+        );
+
+        let (deltas, output) = collect_chunks(stream).await.unwrap();
+        assert_eq!(deltas.len(), 6);
+        assert!(matches!(&deltas[0], CompletionChunk::Reasoning { text } if text == "hmm"));
+        assert!(matches!(&deltas[1], CompletionChunk::Text { text } if text == "Hel"));
+        assert!(matches!(
+            &deltas[3],
+            CompletionChunk::ToolCall { index: 0, call_id: Some(id), name: Some(name), .. }
+                if id == "call_1" && name == "sum"
+        ));
+
+        assert_eq!(output.content, "Hello");
+        assert!(output.failed_reason.is_none());
+        let res = &output.raw_history[0];
+        assert_eq!(res["model"], "m");
+        assert_eq!(res["usage"]["total_tokens"], 8);
+        assert_eq!(res["choices"][0]["message"]["reasoning_content"], "hmm");
+        assert_eq!(
+            res["choices"][0]["message"]["tool_calls"][0]["function"]["arguments"],
+            r#"{"x":1}"#
+        );
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_chat_completion_stream_errors() {
+        let acc = ChatCompletionAccumulator::new(|_res: Json| Ok(AgentOutput::default()));
+        let stream = test_stream(
+            &[
+                "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"Hi\"}}]}\n\n",
+                "data: {\"error\":{\"message\":\"overloaded\"}}\n\n",
+            ],
+            acc,
+        );
+        let err = collect_chunks(stream).await.unwrap_err();
+        assert!(err.to_string().contains("overloaded"));
+
+        let acc = ChatCompletionAccumulator::new(|res: Json| {
+            Ok(AgentOutput {
+                failed_reason: res["choices"][0]["finish_reason"]
+                    .as_str()
+                    .map(|v| v.to_string()),
+                ..Default::default()
+            })
+        });
+        let stream = test_stream(
+            &["data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"Hi\"}}]}\n\n"],
+            acc,
+        );
+        let (_, output) = collect_chunks(stream).await.unwrap();
+        assert_eq!(output.failed_reason.as_deref(), Some("incomplete"));
+    }
+}
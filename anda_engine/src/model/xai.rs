@@ -2,18 +2,26 @@
 //!
 //! This module provides integration with Grok's API, including:
 //! - Client configuration and management
-//! - Completion model handling
+//! - Completion model handling, including streaming completions
 //! - Response parsing and conversion to Anda's internal formats
 
 use anda_core::{
-    AgentOutput, BoxError, BoxPinFut, CompletionRequest, ContentPart, FunctionDefinition, Json,
-    Message, Usage as ModelUsage,
+    AgentOutput, BoxError, BoxPinFut, CompletionChunk, CompletionRequest, ContentPart,
+    FunctionDefinition, Json, Message, Usage as ModelUsage,
+};
+use futures::{
+    StreamExt, TryStreamExt,
+    stream::{self, BoxStream},
 };
 use log::{Level::Debug, log_enabled};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
-use super::{CompletionFeaturesDyn, request_client_builder};
+use super::{
+    CompletionFeaturesDyn, request_client_builder,
+    retry::{RetryPolicy, stream_with_requests, with_requests},
+    sse::{ChatCompletionAccumulator, completion_stream},
+};
 use crate::{rfc3339_datetime, unix_ms};
 
 // ================================================================
@@ -28,6 +36,7 @@ pub struct Client {
     endpoint: String,
     api_key: String,
     http: reqwest::Client,
+    retry: RetryPolicy,
 }
 
 impl Client {
@@ -51,6 +60,7 @@ impl Client {
             http: request_client_builder()
                 .build()
                 .expect("Grok reqwest client should build"),
+            retry: RetryPolicy::default(),
         }
     }
 
@@ -60,9 +70,15 @@ impl Client {
             endpoint: self.endpoint,
             api_key: self.api_key,
             http,
+            retry: self.retry,
         }
     }
 
+    /// Sets the retry policy for the API requests
+    pub fn with_retry(self, retry: RetryPolicy) -> Self {
+        Self { retry, ..self }
+    }
+
     /// Creates a POST request builder for the specified API path
     fn post(&self, path: &str) -> reqwest::RequestBuilder {
         let url = format!("{}{}", self.endpoint, path);
@@ -327,117 +343,134 @@ impl CompletionModel {
     }
 }
 
-impl CompletionFeaturesDyn for CompletionModel {
-    fn completion(&self, mut req: CompletionRequest) -> BoxPinFut<Result<AgentOutput, BoxError>> {
-        let model = self.model.clone();
-        let client = self.client.clone();
-
-        Box::pin(async move {
-            let timestamp = unix_ms();
-            let mut raw_history: Vec<Json> = Vec::new();
-            let mut chat_history: Vec<Message> = Vec::new();
-
-            if !req.instructions.is_empty() {
-                raw_history.push(json!(MessageInput {
-                    role: "system".into(),
-                    content: req.instructions.clone().into(),
-                    tool_call_id: None,
-                }));
-            };
+impl CompletionModel {
+    /// Builds the request body, returns it with the new raw history and chat history.
+    fn build_request(
+        &self,
+        mut req: CompletionRequest,
+    ) -> Result<(Json, Vec<Json>, Vec<Message>), BoxError> {
+        let timestamp = unix_ms();
+        let mut raw_history: Vec<Json> = Vec::new();
+        let mut chat_history: Vec<Message> = Vec::new();
+
+        if !req.instructions.is_empty() {
+            raw_history.push(json!(MessageInput {
+                role: "system".into(),
+                content: req.instructions.clone().into(),
+                tool_call_id: None,
+            }));
+        };
 
-            raw_history.append(&mut req.raw_history);
-            let skip_raw = raw_history.len();
+        raw_history.append(&mut req.raw_history);
+        let skip_raw = raw_history.len();
 
-            for msg in req.chat_history {
-                let val = to_message_input(&msg);
-                for v in val {
-                    raw_history.push(serde_json::to_value(&v)?);
-                }
+        for msg in req.chat_history {
+            let val = to_message_input(&msg);
+            for v in val {
+                raw_history.push(serde_json::to_value(&v)?);
             }
+        }
 
-            if let Some(mut msg) = req
-                .documents
-                .to_message(&rfc3339_datetime(timestamp).unwrap())
-            {
-                msg.timestamp = Some(timestamp);
-                let val = to_message_input(&msg);
-                for v in val {
-                    raw_history.push(serde_json::to_value(&v)?);
-                }
-                chat_history.push(msg);
+        if let Some(mut msg) = req
+            .documents
+            .to_message(&rfc3339_datetime(timestamp).unwrap())
+        {
+            msg.timestamp = Some(timestamp);
+            let val = to_message_input(&msg);
+            for v in val {
+                raw_history.push(serde_json::to_value(&v)?);
             }
+            chat_history.push(msg);
+        }
 
-            let mut content = req.content;
-            if !req.prompt.is_empty() {
-                content.push(req.prompt.into());
-            }
-            if !content.is_empty() {
-                let msg = Message {
-                    role: req.role.unwrap_or_else(|| "user".to_string()),
-                    content,
-                    timestamp: Some(timestamp),
-                    ..Default::default()
-                };
-
-                let val = to_message_input(&msg);
-                for v in val {
-                    raw_history.push(serde_json::to_value(&v)?);
-                }
-                chat_history.push(msg);
+        let mut content = req.content;
+        if !req.prompt.is_empty() {
+            content.push(req.prompt.into());
+        }
+        if !content.is_empty() {
+            let msg = Message {
+                role: req.role.unwrap_or_else(|| "user".to_string()),
+                content,
+                timestamp: Some(timestamp),
+                ..Default::default()
+            };
+
+            let val = to_message_input(&msg);
+            for v in val {
+                raw_history.push(serde_json::to_value(&v)?);
             }
+            chat_history.push(msg);
+        }
 
-            let mut body = json!({
-                "model": model,
-                "messages": &raw_history,
-            });
+        let mut body = json!({
+            "model": self.model,
+            "messages": &raw_history,
+        });
 
-            let body = body.as_object_mut().unwrap();
-            if let Some(temperature) = req.temperature {
-                body.insert("temperature".to_string(), Json::from(temperature));
-            }
+        let obj = body.as_object_mut().unwrap();
+        if let Some(temperature) = req.temperature {
+            obj.insert("temperature".to_string(), Json::from(temperature));
+        }
 
-            if let Some(max_tokens) = req.max_output_tokens {
-                body.insert("max_completion_tokens".to_string(), Json::from(max_tokens));
-            }
+        if let Some(max_tokens) = req.max_output_tokens {
+            obj.insert("max_completion_tokens".to_string(), Json::from(max_tokens));
+        }
 
-            if let Some(output_schema) = req.output_schema {
-                body.insert(
-                    "response_format".to_string(),
-                    json!({ "type": "json_schema", "json_schema": output_schema }),
-                );
-            }
+        if let Some(output_schema) = req.output_schema {
+            obj.insert(
+                "response_format".to_string(),
+                json!({ "type": "json_schema", "json_schema": output_schema }),
+            );
+        }
 
-            if let Some(stop) = req.stop {
-                body.insert("stop".to_string(), Json::from(stop));
-            }
+        if let Some(stop) = req.stop {
+            obj.insert("stop".to_string(), Json::from(stop));
+        }
 
-            if !req.tools.is_empty() {
-                body.insert(
-                    "tools".to_string(),
-                    json!(
-                        req.tools
-                            .into_iter()
-                            .map(ToolDefinition::from)
-                            .collect::<Vec<_>>()
-                    ),
-                );
-                body.insert(
-                    "tool_choice".to_string(),
-                    if req.tool_choice_required {
-                        Json::from("required")
-                    } else {
-                        Json::from("auto")
-                    },
-                );
-            };
+        if !req.tools.is_empty() {
+            obj.insert(
+                "tools".to_string(),
+                json!(
+                    req.tools
+                        .into_iter()
+                        .map(ToolDefinition::from)
+                        .collect::<Vec<_>>()
+                ),
+            );
+            obj.insert(
+                "tool_choice".to_string(),
+                if req.tool_choice_required {
+                    Json::from("required")
+                } else {
+                    Json::from("auto")
+                },
+            );
+        };
+
+        if skip_raw > 0 {
+            raw_history.drain(0..skip_raw);
+        }
+        Ok((body, raw_history, chat_history))
+    }
+}
 
+impl CompletionFeaturesDyn for CompletionModel {
+    fn completion(&self, req: CompletionRequest) -> BoxPinFut<Result<AgentOutput, BoxError>> {
+        let client = self.client.clone();
+        let prepared = self.build_request(req);
+
+        Box::pin(async move {
+            let (body, raw_history, chat_history) = prepared?;
             if log_enabled!(Debug)
                 && let Ok(val) = serde_json::to_string(&body)
             {
                 log::debug!(request = val; "Grok completions request");
             }
 
-            let response = client.post("/chat/completions").json(body).send().await?;
+            let (response, attempts) = client
+                .retry
+                .send(client.post("/chat/completions").json(&body))
+                .await?;
             if response.status().is_success() {
                 let text = response.text().await?;
                 match serde_json::from_str::<CompletionResponse>(&text) {
@@ -453,10 +486,8 @@ impl CompletionFeaturesDyn for CompletionModel {
                                 response:serde = res;
                                 "completions maybe failed");
                         }
-                        if skip_raw > 0 {
-                            raw_history.drain(0..skip_raw);
-                        }
                         res.try_into(raw_history, chat_history)
+                            .map(|output| with_requests(output, attempts))
                     }
                     Err(err) => {
                         Err(format!("Grok completions error: {}, body: {}", err, text).into())
@@ -473,4 +504,62 @@ impl CompletionFeaturesDyn for CompletionModel {
             }
         })
     }
+
+    fn completion_stream(
+        &self,
+        req: CompletionRequest,
+    ) -> BoxStream<'static, Result<CompletionChunk, BoxError>> {
+        let client = self.client.clone();
+        let prepared = self.build_request(req);
+
+        stream::once(async move {
+            let (mut body, raw_history, chat_history) = prepared?;
+            body["stream"] = Json::from(true);
+            body["stream_options"] = json!({"include_usage": true});
+            if log_enabled!(Debug)
+                && let Ok(val) = serde_json::to_string(&body)
+            {
+                log::debug!(request = val; "Grok completions stream request");
+            }
+
+            let (response, attempts) = client
+                .retry
+                .send(client.post("/chat/completions").json(&body))
+                .await?;
+            if response.status().is_success() {
+                Ok::<_, BoxError>(stream_with_requests(
+                    completion_stream(
+                        response,
+                        ChatCompletionAccumulator::new(move |res| {
+                            let res: CompletionResponse = serde_json::from_value(res)
+                                .map_err(|err| format!("Grok completions error: {}", err))?;
+                            if log_enabled!(Debug) {
+                                log::debug!(
+                                request:serde = body,
+                                response:serde = res;
+                                "Grok completions stream response");
+                            } else if res.maybe_failed() {
+                                log::warn!(
+                                request:serde = body,
+                                response:serde = res;
+                                "completions maybe failed");
+                            }
+                            res.try_into(raw_history, chat_history)
+                        }),
+                    ),
+                    attempts,
+                ))
+            } else {
+                let status = response.status();
+                let msg = response.text().await?;
+                log::error!(
+                    request:serde = body;
+                    "completions request failed: {status}, body: {msg}",
+                );
+                Err(format!("Grok completions error: {}", msg).into())
+            }
+        })
+        .try_flatten()
+        .boxed()
+    }
 }
@@ -8,11 +8,12 @@
 //! - **Store**: Main storage interface that handles object storage operations
 //! - **VectorStore**: Wrapper for vector search functionality
 //! - **VectorSearchFeaturesDyn**: Trait defining vector search capabilities
+//! - **AndaVectorStore**: Vector search backed by AndaDB with hybrid BM25 + HNSW indexes
 //!
 //! ## Features
 //!
 //! - Object storage operations (get, put, list, delete, rename)
-//! - Vector search operations (top_n, top_n_ids)
+//! - Vector search operations (top_n, top_n_ids, search, upsert, delete)
 //! - Namespace isolation for multi-tenant support
 //! - Mock and placeholder implementations for testing
 //!
@@ -30,12 +31,18 @@
 //! let (content, meta) = store.store_get(&namespace, &path).await?;
 //! ```
 
-use anda_core::{BoxError, BoxPinFut, ObjectMeta, Path, PutMode, PutResult, path_lowercase};
+use anda_core::{
+    BoxError, BoxPinFut, ObjectMeta, Path, PutMode, PutResult, VectorDocument, VectorQuery,
+    path_lowercase,
+};
 use futures::TryStreamExt;
 use object_store::PutOptions;
 use std::sync::Arc;
 
+mod vector;
+
 pub use object_store::{ObjectStore, local::LocalFileSystem, memory::InMemory};
+pub use vector::*;
 
 pub const MAX_STORE_OBJECT_SIZE: usize = 1024 * 1024 * 2; // 2 MB
 
@@ -58,6 +65,22 @@ pub trait VectorSearchFeaturesDyn: Send + Sync + 'static {
         query: String,
         n: usize,
     ) -> BoxPinFut<Result<Vec<String>, BoxError>>;
+
+    /// Hybrid search with metadata filters
+    /// Returns the matched documents, the most relevant first
+    fn search(
+        &self,
+        namespace: Path,
+        query: VectorQuery,
+    ) -> BoxPinFut<Result<Vec<VectorDocument>, BoxError>>;
+
+    /// Embeds and indexes the documents, replacing the documents with the same IDs
+    fn upsert(&self, namespace: Path, docs: Vec<VectorDocument>)
+    -> BoxPinFut<Result<(), BoxError>>;
+
+    /// Deletes the documents by IDs
+    /// Returns the number of deleted documents
+    fn delete(&self, namespace: Path, ids: Vec<String>) -> BoxPinFut<Result<usize, BoxError>>;
 }
 
 /// Wrapper for vector search functionality
@@ -96,6 +119,26 @@ impl VectorSearchFeaturesDyn for VectorStore {
     ) -> BoxPinFut<Result<Vec<String>, BoxError>> {
         self.inner.top_n_ids(namespace, query, n)
     }
+
+    fn search(
+        &self,
+        namespace: Path,
+        query: VectorQuery,
+    ) -> BoxPinFut<Result<Vec<VectorDocument>, BoxError>> {
+        self.inner.search(namespace, query)
+    }
+
+    fn upsert(
+        &self,
+        namespace: Path,
+        docs: Vec<VectorDocument>,
+    ) -> BoxPinFut<Result<(), BoxError>> {
+        self.inner.upsert(namespace, docs)
+    }
+
+    fn delete(&self, namespace: Path, ids: Vec<String>) -> BoxPinFut<Result<usize, BoxError>> {
+        self.inner.delete(namespace, ids)
+    }
 }
 
 /// A placeholder for not implemented features.
@@ -120,6 +163,26 @@ impl VectorSearchFeaturesDyn for NotImplemented {
     ) -> BoxPinFut<Result<Vec<String>, BoxError>> {
         Box::pin(futures::future::ready(Err("not implemented".into())))
     }
+
+    fn search(
+        &self,
+        _namespace: Path,
+        _query: VectorQuery,
+    ) -> BoxPinFut<Result<Vec<VectorDocument>, BoxError>> {
+        Box::pin(futures::future::ready(Err("not implemented".into())))
+    }
+
+    fn upsert(
+        &self,
+        _namespace: Path,
+        _docs: Vec<VectorDocument>,
+    ) -> BoxPinFut<Result<(), BoxError>> {
+        Box::pin(futures::future::ready(Err("not implemented".into())))
+    }
+
+    fn delete(&self, _namespace: Path, _ids: Vec<String>) -> BoxPinFut<Result<usize, BoxError>> {
+        Box::pin(futures::future::ready(Err("not implemented".into())))
+    }
 }
 
 /// Mock implementation of vector search that returns empty results
@@ -144,6 +207,26 @@ impl VectorSearchFeaturesDyn for MockImplemented {
     ) -> BoxPinFut<Result<Vec<String>, BoxError>> {
         Box::pin(futures::future::ready(Ok(vec![])))
     }
+
+    fn search(
+        &self,
+        _namespace: Path,
+        _query: VectorQuery,
+    ) -> BoxPinFut<Result<Vec<VectorDocument>, BoxError>> {
+        Box::pin(futures::future::ready(Ok(vec![])))
+    }
+
+    fn upsert(
+        &self,
+        _namespace: Path,
+        _docs: Vec<VectorDocument>,
+    ) -> BoxPinFut<Result<(), BoxError>> {
+        Box::pin(futures::future::ready(Ok(())))
+    }
+
+    fn delete(&self, _namespace: Path, _ids: Vec<String>) -> BoxPinFut<Result<usize, BoxError>> {
+        Box::pin(futures::future::ready(Ok(0)))
+    }
 }
 
 /// Main storage interface combining object storage and vector search capabilities
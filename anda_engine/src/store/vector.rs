@@ -0,0 +1,520 @@
+use anda_core::{BoxError, BoxPinFut, Path, VectorDocument, VectorQuery};
+use anda_db::{
+    collection::{Collection, CollectionConfig},
+    database::AndaDB,
+    error::DBError,
+    index::HnswConfig,
+    query::{Filter, Query, RangeQuery, Search},
+};
+use anda_db_hnsw::DistanceMetric;
+use anda_db_schema::{
+    AndaDBSchema, Document, FieldEntry, FieldKey, FieldType, Fv, Json, Schema, SchemaError, Vector,
+    vector_from_f32,
+};
+use anda_db_tfs::jieba_tokenizer;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, sync::Arc};
+use structured_logger::unix_ms;
+
+use super::VectorSearchFeaturesDyn;
+use crate::{KeyLocks, model::EmbeddingFeaturesDyn};
+
+/// The number of locks that serialize the writes of documents with the same key.
+const KEY_LOCKS: usize = 64;
+
+/// A document in the AndaDB collection "vectors".
+#[derive(Debug, Clone, Deserialize, Serialize, AndaDBSchema)]
+struct VectorRecord {
+    /// The unique identifier for this resource in the Anda DB collection "vectors".
+    _id: u64,
+
+    /// The namespace and the ID of the document, `{namespace}/{id}`.
+    key: String,
+
+    namespace: String,
+
+    id: String,
+
+    text: String,
+
+    metadata: BTreeMap<String, Json>,
+
+    /// The `{key}={value}` labels of the scalar metadata, indexed for filters.
+    labels: Vec<String>,
+
+    embedding: Vector,
+
+    /// The timestamp when the document was indexed, in milliseconds.
+    updated_at: u64,
+}
+
+impl From<VectorRecord> for VectorDocument {
+    fn from(record: VectorRecord) -> Self {
+        Self {
+            id: record.id,
+            text: record.text,
+            metadata: record.metadata,
+        }
+    }
+}
+
+/// Vector search backed by AndaDB.
+///
+/// Documents of all namespaces are stored in the collection "vectors", with a BM25 index on the text,
+/// a HNSW index on the embedding and BTree indexes for namespaces and metadata filters.
+/// Searches combine the BM25 and vector results with Reciprocal Rank Fusion.
+/// The namespace is the path of the agent or tool context, so each agent has its own documents.
+#[derive(Clone)]
+pub struct AndaVectorStore {
+    docs: Arc<Collection>,
+    embedder: Arc<dyn EmbeddingFeaturesDyn>,
+    locks: Arc<KeyLocks>,
+}
+
+impl AndaVectorStore {
+    /// Opens or creates the collection "vectors" in the database.
+    /// The dimension of the HNSW index is the dimension of the embedder.
+    pub async fn connect(
+        db: Arc<AndaDB>,
+        embedder: Arc<dyn EmbeddingFeaturesDyn>,
+    ) -> Result<Self, BoxError> {
+        let schema = VectorRecord::schema()?;
+        let dimension = embedder.ndims();
+        let docs = db
+            .open_or_create_collection(
+                schema,
+                CollectionConfig {
+                    name: "vectors".to_string(),
+                    description: "vector search documents".to_string(),
+                },
+                async |collection| {
+                    // set tokenizer
+                    collection.set_tokenizer(jieba_tokenizer());
+                    // create BTree indexes if not exists
+                    collection.create_btree_index_nx(&["key"]).await?;
+                    collection.create_btree_index_nx(&["namespace"]).await?;
+                    collection.create_btree_index_nx(&["labels"]).await?;
+                    // create BM25 & HNSW indexes if not exists
+                    collection.create_bm25_index_nx(&["text"]).await?;
+                    collection
+                        .create_hnsw_index_nx(
+                            "embedding",
+                            HnswConfig {
+                                dimension,
+                                distance_metric: DistanceMetric::Cosine,
+                                ..Default::default()
+                            },
+                        )
+                        .await?;
+
+                    Ok::<(), DBError>(())
+                },
+            )
+            .await?;
+
+        Ok(Self {
+            docs,
+            embedder,
+            locks: Arc::new(KeyLocks::new(KEY_LOCKS)),
+        })
+    }
+
+    /// Hybrid search in the namespace, see [`VectorSearchFeaturesDyn::search`].
+    pub async fn search(
+        &self,
+        namespace: &Path,
+        query: VectorQuery,
+    ) -> Result<Vec<VectorDocument>, BoxError> {
+        if query.n == 0 || query.query.trim().is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut filters = vec![Box::new(Filter::Field((
+            "namespace".to_string(),
+            RangeQuery::Eq(Fv::Text(namespace.to_string())),
+        )))];
+        for (key, value) in &query.filter {
+            let label = label(key, value)
+                .ok_or_else(|| format!("invalid filter {}: expected a scalar value", key))?;
+            filters.push(Box::new(Filter::Field((
+                "labels".to_string(),
+                RangeQuery::Eq(Fv::Text(label)),
+            ))));
+        }
+
+        let (embedding, _) = self.embedder.embed_query(query.query.clone()).await?;
+        let records: Vec<VectorRecord> = self
+            .docs
+            .search_as(Query {
+                search: Some(Search {
+                    text: Some(query.query),
+                    vector: Some(embedding.vec),
+                    ..Default::default()
+                }),
+                filter: Some(Filter::And(filters)),
+                limit: Some(query.n),
+            })
+            .await?;
+        Ok(records.into_iter().map(VectorDocument::from).collect())
+    }
+
+    /// Embeds and indexes the documents in the namespace, see [`VectorSearchFeaturesDyn::upsert`].
+    pub async fn upsert(
+        &self,
+        namespace: &Path,
+        docs: Vec<VectorDocument>,
+    ) -> Result<(), BoxError> {
+        if docs.is_empty() {
+            return Ok(());
+        }
+
+        let (embeddings, _) = self
+            .embedder
+            .embed(docs.iter().map(|doc| doc.text.clone()).collect())
+            .await?;
+        if embeddings.len() != docs.len() {
+            return Err(format!(
+                "embedding error: expected {} embeddings, got {}",
+                docs.len(),
+                embeddings.len()
+            )
+            .into());
+        }
+
+        let now_ms = unix_ms();
+        for (doc, embedding) in docs.into_iter().zip(embeddings) {
+            let labels = doc
+                .metadata
+                .iter()
+                .filter_map(|(key, value)| label(key, value))
+                .collect();
+            let record = VectorRecord {
+                _id: 0,
+                key: format!("{}/{}", namespace, doc.id),
+                namespace: namespace.to_string(),
+                id: doc.id,
+                text: doc.text,
+                metadata: doc.metadata,
+                labels,
+                embedding: vector_from_f32(embedding.vec),
+                updated_at: now_ms,
+            };
+            self.replace(record).await?;
+        }
+        self.docs.flush(now_ms).await?;
+        Ok(())
+    }
+
+    /// Deletes the documents in the namespace, see [`VectorSearchFeaturesDyn::delete`].
+    pub async fn delete(&self, namespace: &Path, ids: Vec<String>) -> Result<usize, BoxError> {
+        let mut deleted = 0;
+        for id in ids {
+            let key = format!("{}/{}", namespace, id);
+            let _guard = self.locks.lock(&key).await;
+            deleted += self.remove(self.query_ids(&key).await?).await?;
+        }
+        if deleted > 0 {
+            self.docs.flush(unix_ms()).await?;
+        }
+        Ok(deleted)
+    }
+
+    /// Adds the record, or updates the existing record with the same key in place,
+    /// so the document is never missing and not duplicated by concurrent upserts.
+    async fn replace(&self, record: VectorRecord) -> Result<(), BoxError> {
+        let _guard = self.locks.lock(&record.key).await;
+        let mut ids = self.query_ids(&record.key).await?;
+        if ids.is_empty() {
+            self.docs.add_from(&record).await?;
+            return Ok(());
+        }
+
+        let id = ids.remove(0);
+        let doc = Document::try_from(self.docs.schema(), &record)?;
+        let changes = ["text", "metadata", "labels", "embedding", "updated_at"]
+            .into_iter()
+            .filter_map(|name| Some((name.to_string(), doc.get_field(name)?.clone())))
+            .collect();
+        self.docs.update(id, changes).await?;
+        // removes the duplicates left by earlier versions
+        self.remove(ids).await?;
+        Ok(())
+    }
+
+    async fn query_ids(&self, key: &str) -> Result<Vec<u64>, BoxError> {
+        let ids = self
+            .docs
+            .query_ids(
+                Filter::Field(("key".to_string(), RangeQuery::Eq(Fv::Text(key.to_string())))),
+                None,
+            )
+            .await?;
+        Ok(ids)
+    }
+
+    async fn remove(&self, ids: Vec<u64>) -> Result<usize, BoxError> {
+        let mut removed = 0;
+        for id in ids {
+            if self.docs.remove(id).await?.is_some() {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+/// Returns the `{key}={value}` label of a scalar metadata value.
+fn label(key: &str, value: &Json) -> Option<String> {
+    match value {
+        Json::String(_) | Json::Number(_) | Json::Bool(_) => Some(format!("{}={}", key, value)),
+        _ => None,
+    }
+}
+
+impl VectorSearchFeaturesDyn for AndaVectorStore {
+    fn top_n(
+        &self,
+        namespace: Path,
+        query: String,
+        n: usize,
+    ) -> BoxPinFut<Result<Vec<String>, BoxError>> {
+        let this = self.clone();
+        Box::pin(async move {
+            let docs = this
+                .search(
+                    &namespace,
+                    VectorQuery {
+                        query,
+                        n,
+                        ..Default::default()
+                    },
+                )
+                .await?;
+            docs.iter()
+                .map(|doc| serde_json::to_string(doc).map_err(|err| err.into()))
+                .collect()
+        })
+    }
+
+    fn top_n_ids(
+        &self,
+        namespace: Path,
+        query: String,
+        n: usize,
+    ) -> BoxPinFut<Result<Vec<String>, BoxError>> {
+        let this = self.clone();
+        Box::pin(async move {
+            let docs = this
+                .search(
+                    &namespace,
+                    VectorQuery {
+                        query,
+                        n,
+                        ..Default::default()
+                    },
+                )
+                .await?;
+            Ok(docs.into_iter().map(|doc| doc.id).collect())
+        })
+    }
+
+    fn search(
+        &self,
+        namespace: Path,
+        query: VectorQuery,
+    ) -> BoxPinFut<Result<Vec<VectorDocument>, BoxError>> {
+        let this = self.clone();
+        Box::pin(async move { this.search(&namespace, query).await })
+    }
+
+    fn upsert(
+        &self,
+        namespace: Path,
+        docs: Vec<VectorDocument>,
+    ) -> BoxPinFut<Result<(), BoxError>> {
+        let this = self.clone();
+        Box::pin(async move { this.upsert(&namespace, docs).await })
+    }
+
+    fn delete(&self, namespace: Path, ids: Vec<String>) -> BoxPinFut<Result<usize, BoxError>> {
+        let this = self.clone();
+        Box::pin(async move { this.delete(&namespace, ids).await })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anda_core::{Embedding, Usage};
+    use anda_db::database::DBConfig;
+    use object_store_shim::memory::InMemory;
+    use serde_json::json;
+
+    static TOPICS: [&str; 3] = ["rust", "python", "database"];
+
+    /// Embeds texts by the topics they mention.
+    struct TopicEmbedder;
+
+    fn topic_vec(text: &str) -> Vec<f32> {
+        let text = text.to_lowercase();
+        TOPICS
+            .iter()
+            .map(|topic| if text.contains(topic) { 1.0 } else { 0.1 })
+            .collect()
+    }
+
+    impl EmbeddingFeaturesDyn for TopicEmbedder {
+        fn ndims(&self) -> usize {
+            TOPICS.len()
+        }
+
+        fn embed(
+            &self,
+            texts: Vec<String>,
+        ) -> BoxPinFut<Result<(Vec<Embedding>, Usage), BoxError>> {
+            let res = texts
+                .into_iter()
+                .map(|text| Embedding {
+                    vec: topic_vec(&text),
+                    text,
+                })
+                .collect();
+            Box::pin(futures::future::ready(Ok((res, Usage::default()))))
+        }
+
+        fn embed_query(&self, text: String) -> BoxPinFut<Result<(Embedding, Usage), BoxError>> {
+            let res = Embedding {
+                vec: topic_vec(&text),
+                text,
+            };
+            Box::pin(futures::future::ready(Ok((res, Usage::default()))))
+        }
+    }
+
+    fn doc(id: &str, text: &str, lang: &str) -> VectorDocument {
+        VectorDocument {
+            id: id.to_string(),
+            text: text.to_string(),
+            metadata: BTreeMap::from([("lang".to_string(), json!(lang))]),
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_anda_vector_store() {
+        let db = AndaDB::connect(Arc::new(InMemory::new()), DBConfig::default())
+            .await
+            .unwrap();
+        let store = AndaVectorStore::connect(Arc::new(db), Arc::new(TopicEmbedder))
+            .await
+            .unwrap();
+        let agent = Path::from("A:assistant");
+        let other = Path::from("A:other");
+
+        store
+            .upsert(
+                &agent,
+                vec![
+                    doc("1", "Rust is a systems programming language", "en"),
+                    doc("2", "Python is a scripting language", "en"),
+                    doc("3", "Rust 是一门系统级编程语言", "zh"),
+                    doc("4", "AndaDB is a database for agents", "en"),
+                ],
+            )
+            .await
+            .unwrap();
+        store
+            .upsert(&other, vec![doc("1", "Rust in other namespace", "en")])
+            .await
+            .unwrap();
+
+        let query = |query: &str, n: usize, filter: BTreeMap<String, Json>| VectorQuery {
+            query: query.to_string(),
+            n,
+            filter,
+        };
+        let res = store
+            .search(&agent, query("rust language", 2, BTreeMap::new()))
+            .await
+            .unwrap();
+        assert_eq!(res.len(), 2);
+        assert!(res.iter().all(|doc| doc.text.contains("Rust")));
+
+        // metadata filters
+        let filter = BTreeMap::from([("lang".to_string(), json!("zh"))]);
+        let res = store
+            .search(&agent, query("rust", 10, filter))
+            .await
+            .unwrap();
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].id, "3");
+        let filter = BTreeMap::from([("lang".to_string(), json!(["zh"]))]);
+        assert!(
+            store
+                .search(&agent, query("rust", 10, filter))
+                .await
+                .is_err()
+        );
+
+        // namespaces
+        let res = store
+            .search(&other, query("rust", 10, BTreeMap::new()))
+            .await
+            .unwrap();
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].text, "Rust in other namespace");
+
+        // upsert replaces the document with the same ID
+        store
+            .upsert(&agent, vec![doc("2", "Python and Rust bindings", "en")])
+            .await
+            .unwrap();
+        let res = store
+            .top_n_ids(agent.clone(), "python".to_string(), 10)
+            .await
+            .unwrap();
+        assert_eq!(res.first().unwrap(), "2");
+        let res = store
+            .search(&agent, query("python", 10, BTreeMap::new()))
+            .await
+            .unwrap();
+        assert_eq!(res[0].text, "Python and Rust bindings");
+        assert_eq!(res.iter().filter(|doc| doc.id == "2").count(), 1);
+
+        // concurrent upserts of the same document keep one copy
+        let (r1, r2) = tokio::join!(
+            store.upsert(&agent, vec![doc("2", "Python bindings", "zh")]),
+            store.upsert(&agent, vec![doc("2", "Python bindings", "zh")])
+        );
+        assert!(r1.is_ok() && r2.is_ok());
+        let filter = BTreeMap::from([("lang".to_string(), json!("zh"))]);
+        let res = store
+            .search(&agent, query("python", 10, filter))
+            .await
+            .unwrap();
+        assert_eq!(res.iter().filter(|doc| doc.id == "2").count(), 1);
+        assert_eq!(store.docs.ids().len(), 5);
+
+        // delete
+        let deleted = store
+            .delete(
+                &agent,
+                vec!["1".to_string(), "2".to_string(), "5".to_string()],
+            )
+            .await
+            .unwrap();
+        assert_eq!(deleted, 2);
+        let res = store
+            .search(&agent, query("rust", 10, BTreeMap::new()))
+            .await
+            .unwrap();
+        assert!(res.iter().all(|doc| doc.id != "1" && doc.id != "2"));
+        assert_eq!(
+            store
+                .search(&other, query("rust", 10, BTreeMap::new()))
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+}
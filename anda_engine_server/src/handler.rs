@@ -1,4 +1,4 @@
-use anda_core::{AgentInput, Json, ToolInput};
+use anda_core::{AgentInput, AndaError, ApprovalInput, Json, RPCRequest, RPCResponse, ToolInput};
 use anda_engine::engine::Engine;
 use axum::{
     extract::{Path, State},
@@ -12,11 +12,9 @@ use ic_auth_verifier::{
     unix_timestamp,
 };
 use ic_cose_types::to_cbor_bytes;
-use ic_tee_agent::{
-    RPCRequest, RPCResponse,
-    http::{Content, ContentWithSHA3},
-};
-use std::collections::BTreeMap;
+use ic_tee_agent::http::{Content, ContentWithSHA3};
+use serde::de::DeserializeOwned;
+use std::collections::{BTreeMap, BTreeSet};
 use std::sync::Arc;
 
 use crate::types::*;
@@ -75,14 +73,25 @@ pub async fn get_engine_information(
             .into_response();
     };
 
+    let caller = if let Some(se) = SignedEnvelope::from_authorization(&headers)
+        .or_else(|| SignedEnvelope::from_headers(&headers))
+    {
+        match se.verify(unix_timestamp().as_millis() as u64, None, None) {
+            Ok(_) => se.sender(),
+            Err(_) => ANONYMOUS_PRINCIPAL,
+        }
+    } else {
+        ANONYMOUS_PRINCIPAL
+    };
+
     match app.engines.get(&id) {
-        Some(engine) => {
-            let info = engine.information();
-            match Content::from(&headers) {
+        Some(engine) => match engine.information_for(&caller).await {
+            Ok(info) => match Content::from(&headers) {
                 Content::CBOR(_, _) => Content::CBOR(info, None).into_response(),
                 _ => Content::JSON(info, None).into_response(),
-            }
-        }
+            },
+            Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+        },
         None => (
             StatusCode::NOT_FOUND,
             format!("engine {} not found", id.to_text()),
@@ -152,34 +161,181 @@ async fn engine_run(
     let engine = app
         .engines
         .get(&id)
-        .ok_or_else(|| format!("engine {} not found", id.to_text()))?;
+        .ok_or_else(|| AndaError::not_found(format!("engine {} not found", id.to_text())))?;
 
     match req.method.as_str() {
         "agent_run" => {
-            let args: (AgentInput,) = from_reader(req.params.as_slice())
-                .map_err(|err| format!("failed to decode params: {err:?}"))?;
+            let args: (AgentInput,) = from_reader(req.params.as_slice()).map_err(|err| {
+                AndaError::invalid_argument(format!("failed to decode params: {err:?}"))
+            })?;
             let res = engine
                 .agent_run(caller, args.0)
                 .await
-                .map_err(|err| format!("failed to run agent: {err:?}"))?;
+                .map_err(|err| err.context("failed to run agent"))?;
             Ok(to_cbor_bytes(&res).into())
         }
         "tool_call" => {
-            let args: (ToolInput<Json>,) = from_reader(req.params.as_slice())
-                .map_err(|err| format!("failed to decode params: {err:?}"))?;
+            let args: (ToolInput<Json>,) = from_reader(req.params.as_slice()).map_err(|err| {
+                AndaError::invalid_argument(format!("failed to decode params: {err:?}"))
+            })?;
             let res = engine
                 .tool_call(caller, args.0)
                 .await
-                .map_err(|err| format!("failed to call tool: {err:?}"))?;
+                .map_err(|err| err.context("failed to call tool"))?;
+            Ok(to_cbor_bytes(&res).into())
+        }
+        "approve_run" => {
+            let args: (ApprovalInput,) = decode_params(req)?;
+            let res = engine
+                .approve_run(caller, args.0)
+                .await
+                .map_err(|err| err.context("failed to resume agent run"))?;
             Ok(to_cbor_bytes(&res).into())
         }
         "information" => {
-            let res = engine.information();
+            let res = engine.information_for(&caller).await?;
+            Ok(to_cbor_bytes(&res).into())
+        }
+        // Admin methods, only the controller and managers can call them.
+        "get_user" => {
+            let args: (Principal,) = decode_params(req)?;
+            let res = engine.get_user(caller, args.0).await?;
+            Ok(to_cbor_bytes(&res).into())
+        }
+        "list_users" => {
+            let args: (usize, usize) = decode_params(req)?;
+            let res = engine.list_users(caller, args.0, args.1).await?;
+            Ok(to_cbor_bytes(&res).into())
+        }
+        "topup_credit" => {
+            let args: (Principal, u64, u64) = decode_params(req)?;
+            let res = engine
+                .topup_credit(caller, args.0, args.1, args.2)
+                .await
+                .map_err(|err| err.context("failed to top up credit"))?;
+            Ok(to_cbor_bytes(&res).into())
+        }
+        "update_subscription" => {
+            let args: (Principal, u8, u64) = decode_params(req)?;
+            let res = engine
+                .update_subscription(caller, args.0, args.1, args.2)
+                .await
+                .map_err(|err| err.context("failed to update subscription"))?;
+            Ok(to_cbor_bytes(&res).into())
+        }
+        "update_features" => {
+            let args: (Principal, BTreeSet<String>) = decode_params(req)?;
+            let res = engine
+                .update_features(caller, args.0, args.1)
+                .await
+                .map_err(|err| err.context("failed to update features"))?;
+            Ok(to_cbor_bytes(&res).into())
+        }
+        "update_status" => {
+            let args: (Principal, i8) = decode_params(req)?;
+            let res = engine
+                .update_status(caller, args.0, args.1)
+                .await
+                .map_err(|err| err.context("failed to update status"))?;
             Ok(to_cbor_bytes(&res).into())
         }
-        method => Err(format!(
+        method => Err(AndaError::unimplemented(format!(
             "{method} on engine {} not implemented",
             id.to_text()
-        )),
+        ))),
+    }
+}
+
+fn decode_params<T: DeserializeOwned>(req: &RPCRequest) -> Result<T, AndaError> {
+    from_reader(req.params.as_slice())
+        .map_err(|err| AndaError::invalid_argument(format!("failed to decode params: {err:?}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anda_core::ErrorCode;
+    use anda_engine::{
+        context::EngineCard,
+        management::{BaseManagement, Visibility},
+    };
+    use serde::Serialize;
+
+    fn test_app(manager: Principal) -> AppState {
+        let engine = Engine::builder()
+            .with_management(Arc::new(BaseManagement {
+                controller: ANONYMOUS_PRINCIPAL,
+                managers: BTreeSet::from([manager]),
+                visibility: Visibility::Public,
+            }))
+            .empty();
+        let id = engine.id();
+        AppState {
+            engines: Arc::new(BTreeMap::from([(id, engine)])),
+            default_engine: id,
+            start_time_ms: 0,
+        }
+    }
+
+    fn request(method: &str, params: &impl Serialize) -> RPCRequest {
+        RPCRequest {
+            method: method.to_string(),
+            params: to_cbor_bytes(params).into(),
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_engine_run() {
+        let manager = Principal::from_slice(&[9]);
+        let alice = Principal::from_slice(&[1]);
+        let app = test_app(manager);
+        let id = app.default_engine;
+
+        let res = engine_run(&request("information", &()), &app, alice, id)
+            .await
+            .unwrap();
+        let info: EngineCard = from_reader(res.as_slice()).unwrap();
+        assert_eq!(info.id, id);
+
+        // admin methods reject callers other than the controller and managers
+        for req in [
+            request("get_user", &(alice,)),
+            request("list_users", &(0usize, 10usize)),
+            request("topup_credit", &(alice, 10u64, 1000u64)),
+            request("update_subscription", &(alice, 1u8, 1000u64)),
+            request("update_features", &(alice, BTreeSet::<String>::new())),
+            request("update_status", &(alice, -1i8)),
+        ] {
+            let err = engine_run(&req, &app, alice, id).await.unwrap_err();
+            assert_eq!(err.code, ErrorCode::PermissionDenied, "{}", req.method);
+        }
+
+        let err = engine_run(&request("get_user", &(alice,)), &app, manager, id)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, ErrorCode::NotFound);
+        let err = engine_run(&request("get_user", &("alice",)), &app, manager, id)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidArgument);
+        // the base management does not store users
+        let err = engine_run(
+            &request("topup_credit", &(alice, 10u64, 1000u64)),
+            &app,
+            manager,
+            id,
+        )
+        .await
+        .unwrap_err();
+        assert!(err.message.contains("not implemented"), "{}", err.message);
+
+        let err = engine_run(&request("unknown", &()), &app, manager, id)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, ErrorCode::Unimplemented);
+        let err = engine_run(&request("information", &()), &app, manager, alice)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, ErrorCode::NotFound);
     }
 }
@@ -0,0 +1,96 @@
+//! # Anda Macros
+//!
+//! Procedural macros for Anda, re-exported by `anda_core`:
+//! - [`macro@tool`]: generates a tool from an async function;
+//! - [`AndaTool`]: derives the `Tool` trait for a struct with a handler method.
+//!
+//! The generated `Tool` implementation has:
+//! - a validated name, the function name or the snake case struct name without the `Tool` suffix;
+//! - the description from the doc comments;
+//! - the strict parameters schema generated from the `Args` type by `schemars`.
+//!
+//! The generated code refers to `anda_core` items with absolute paths, so `anda_core`
+//! must be a dependency of the crate using the macros.
+
+use proc_macro::TokenStream;
+
+mod tool;
+
+/// Generates a tool from an async function.
+///
+/// The function takes the context, the arguments and optionally the resources,
+/// and returns `Result<ToolOutput<T>, BoxError>`. A unit struct named in Pascal case
+/// with the `Tool` suffix is generated to implement the `Tool` trait, the function is kept as is.
+///
+/// # Attributes
+/// - `name = "..."`: the tool name, defaults to the function name;
+/// - `description = "..."`: the tool description, defaults to the doc comments;
+/// - `resource_tags = ["..."]`: the supported resource tags;
+/// - `requires_approval`: calls to the tool must be approved by a human;
+/// - `tool = Ident`: the name of the generated struct.
+///
+/// # Example
+/// ```rust,ignore
+/// use anda_core::{BoxError, ToolOutput, tool};
+/// use anda_engine::context::BaseCtx;
+///
+/// /// Returns the weather of the city.
+/// #[tool]
+/// async fn get_weather(ctx: BaseCtx, city: String) -> Result<ToolOutput<String>, BoxError> {
+///     Ok(ToolOutput::new(format!("{} is sunny", city)))
+/// }
+///
+/// engine_builder.register_tool(GetWeatherTool)?;
+/// ```
+#[proc_macro_attribute]
+pub fn tool(attr: TokenStream, item: TokenStream) -> TokenStream {
+    tool::expand_fn(attr.into(), item.into())
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// Derives the `Tool` trait for a struct, the calls are handled by a method of the struct.
+///
+/// The handler is an async method `(&self, ctx, args, resources)` that returns
+/// `Result<ToolOutput<Output>, BoxError>`.
+///
+/// # Attributes
+/// Configured with `#[anda_tool(...)]`:
+/// - `args = Type`: the arguments type, required;
+/// - `output = Type`: the output type, required;
+/// - `context = Type`: the context type, defaults to `BaseCtx`;
+/// - `handler = ident`: the handler method, defaults to `handle`;
+/// - `name = "..."`: the tool name, defaults to the snake case struct name without the `Tool` suffix;
+/// - `description = "..."`: the tool description, defaults to the doc comments;
+/// - `resource_tags = ["..."]`: the supported resource tags;
+/// - `requires_approval`: calls to the tool must be approved by a human.
+///
+/// # Example
+/// ```rust,ignore
+/// use anda_core::{AndaTool, BoxError, Resource, ToolOutput};
+/// use anda_engine::context::BaseCtx;
+///
+/// /// Transfers tokens to the account.
+/// #[derive(AndaTool)]
+/// #[anda_tool(args = TransferArgs, output = String, requires_approval)]
+/// struct TransferTool {
+///     ledger: Principal,
+/// }
+///
+/// impl TransferTool {
+///     async fn handle(
+///         &self,
+///         ctx: BaseCtx,
+///         args: TransferArgs,
+///         _resources: Vec<Resource>,
+///     ) -> Result<ToolOutput<String>, BoxError> {
+///         // ...
+///     }
+/// }
+/// ```
+#[proc_macro_derive(AndaTool, attributes(anda_tool))]
+pub fn derive_anda_tool(input: TokenStream) -> TokenStream {
+    tool::expand_derive(input.into())
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
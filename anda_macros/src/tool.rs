@@ -0,0 +1,511 @@
+use proc_macro2::{Span, TokenStream};
+use quote::{format_ident, quote};
+use syn::{
+    Attribute, DeriveInput, Expr, FnArg, GenericArgument, Ident, ItemFn, Lit, LitStr, Meta,
+    PathArguments, ReturnType, Token, Type, meta::ParseNestedMeta, parse::Parser,
+    punctuated::Punctuated,
+};
+
+/// Options of the `#[tool]` and `#[anda_tool]` attributes.
+#[derive(Default)]
+struct ToolOptions {
+    name: Option<LitStr>,
+    description: Option<LitStr>,
+    resource_tags: Vec<LitStr>,
+    requires_approval: bool,
+    args: Option<Type>,
+    output: Option<Type>,
+    context: Option<Type>,
+    handler: Option<Ident>,
+    tool: Option<Ident>,
+}
+
+impl ToolOptions {
+    fn parse_meta(&mut self, meta: ParseNestedMeta, derive: bool) -> syn::Result<()> {
+        if meta.path.is_ident("name") {
+            self.name = Some(meta.value()?.parse()?);
+        } else if meta.path.is_ident("description") {
+            self.description = Some(meta.value()?.parse()?);
+        } else if meta.path.is_ident("resource_tags") {
+            let value = meta.value()?;
+            let content;
+            syn::bracketed!(content in value);
+            self.resource_tags = Punctuated::<LitStr, Token![,]>::parse_terminated(&content)?
+                .into_iter()
+                .collect();
+        } else if meta.path.is_ident("requires_approval") {
+            self.requires_approval = if meta.input.peek(Token![=]) {
+                meta.value()?.parse::<syn::LitBool>()?.value
+            } else {
+                true
+            };
+        } else if derive && meta.path.is_ident("args") {
+            self.args = Some(meta.value()?.parse()?);
+        } else if derive && meta.path.is_ident("output") {
+            self.output = Some(meta.value()?.parse()?);
+        } else if derive && meta.path.is_ident("context") {
+            self.context = Some(meta.value()?.parse()?);
+        } else if derive && meta.path.is_ident("handler") {
+            self.handler = Some(meta.value()?.parse()?);
+        } else if !derive && meta.path.is_ident("tool") {
+            self.tool = Some(meta.value()?.parse()?);
+        } else {
+            return Err(meta.error("unsupported tool attribute"));
+        }
+        Ok(())
+    }
+
+    /// Returns the validated tool name.
+    fn name(&self, default: String, span: Span) -> syn::Result<LitStr> {
+        let name = match &self.name {
+            Some(name) => name.clone(),
+            None => LitStr::new(&default, span),
+        };
+        validate_name(&name.value()).map_err(|err| {
+            syn::Error::new(
+                name.span(),
+                format!("invalid tool name {:?}: {}", name.value(), err),
+            )
+        })?;
+        Ok(name)
+    }
+
+    /// Returns the description, defaults to the doc comments.
+    fn description(&self, attrs: &[Attribute], span: Span) -> syn::Result<LitStr> {
+        if let Some(description) = &self.description {
+            return Ok(description.clone());
+        }
+        let doc = doc_comments(attrs);
+        if doc.is_empty() {
+            return Err(syn::Error::new(
+                span,
+                "tool description is required, add doc comments or `description = \"...\"`",
+            ));
+        }
+        Ok(LitStr::new(&doc, span))
+    }
+}
+
+/// Expands `#[tool]` on an async function.
+pub fn expand_fn(attr: TokenStream, item: TokenStream) -> syn::Result<TokenStream> {
+    let mut opts = ToolOptions::default();
+    let parser = syn::meta::parser(|meta| opts.parse_meta(meta, false));
+    parser.parse2(attr)?;
+
+    let func: ItemFn = syn::parse2(item)?;
+    let sig = &func.sig;
+    if sig.asyncness.is_none() {
+        return Err(syn::Error::new_spanned(
+            sig.fn_token,
+            "the tool function must be async",
+        ));
+    }
+    if !sig.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(
+            &sig.generics,
+            "the tool function can not be generic",
+        ));
+    }
+
+    let mut inputs = Vec::new();
+    for arg in &sig.inputs {
+        match arg {
+            FnArg::Typed(arg) => inputs.push(arg.ty.as_ref().clone()),
+            FnArg::Receiver(arg) => {
+                return Err(syn::Error::new_spanned(
+                    arg,
+                    "the tool function can not have a self parameter",
+                ));
+            }
+        }
+    }
+    if inputs.len() != 2 && inputs.len() != 3 {
+        return Err(syn::Error::new_spanned(
+            &sig.inputs,
+            "the tool function takes (ctx, args) or (ctx, args, resources)",
+        ));
+    }
+
+    let fn_name = &sig.ident;
+    let span = fn_name.span();
+    let name = opts.name(
+        fn_name.to_string().trim_start_matches("r#").to_string(),
+        span,
+    )?;
+    let description = opts.description(&func.attrs, span)?;
+    let output = tool_output_type(&sig.output)?;
+    let ident = match &opts.tool {
+        Some(tool) => tool.clone(),
+        None => format_ident!("{}Tool", pascal_case(&fn_name.to_string())),
+    };
+    let vis = &func.vis;
+    let context = &inputs[0];
+    let args = &inputs[1];
+    let call = if inputs.len() == 3 {
+        quote! { #fn_name(ctx, args, resources) }
+    } else {
+        quote! {
+            {
+                let _ = resources;
+                #fn_name(ctx, args)
+            }
+        }
+    };
+    let struct_doc = format!(
+        "The `{}` tool generated from [`{}`].",
+        name.value(),
+        fn_name
+    );
+    let methods = tool_methods(&opts, &name, &description, args, true);
+
+    Ok(quote! {
+        #func
+
+        #[doc = #struct_doc]
+        #[derive(Debug, Clone, Copy, Default)]
+        #vis struct #ident;
+
+        impl ::anda_core::Tool<#context> for #ident {
+            type Args = #args;
+            type Output = #output;
+
+            #methods
+
+            fn call(
+                &self,
+                ctx: #context,
+                args: Self::Args,
+                resources: ::std::vec::Vec<::anda_core::Resource>,
+            ) -> impl ::std::future::Future<
+                Output = ::std::result::Result<
+                    ::anda_core::ToolOutput<Self::Output>,
+                    ::anda_core::BoxError,
+                >,
+            > + Send {
+                #call
+            }
+        }
+    })
+}
+
+/// Expands `#[derive(AndaTool)]` on a struct.
+pub fn expand_derive(input: TokenStream) -> syn::Result<TokenStream> {
+    let input: DeriveInput = syn::parse2(input)?;
+    let mut opts = ToolOptions::default();
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|a| a.path().is_ident("anda_tool"))
+    {
+        attr.parse_nested_meta(|meta| opts.parse_meta(meta, true))?;
+    }
+
+    let ident = &input.ident;
+    let span = ident.span();
+    let default_name = ident.to_string();
+    let default_name = default_name
+        .strip_suffix("Tool")
+        .filter(|s| !s.is_empty())
+        .unwrap_or(&default_name);
+    let name = opts.name(snake_case(default_name), span)?;
+    let description = opts.description(&input.attrs, span)?;
+    let args = opts
+        .args
+        .clone()
+        .ok_or_else(|| syn::Error::new(span, "missing `#[anda_tool(args = Type)]`"))?;
+    let output = opts
+        .output
+        .clone()
+        .ok_or_else(|| syn::Error::new(span, "missing `#[anda_tool(output = Type)]`"))?;
+    let context = match &opts.context {
+        Some(context) => quote! { #context },
+        None => quote! { BaseCtx },
+    };
+    let handler = match &opts.handler {
+        Some(handler) => handler.clone(),
+        None => Ident::new("handle", Span::call_site()),
+    };
+
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    // 泛型类型无法使用静态缓存
+    let cache = input.generics.params.is_empty();
+    let methods = tool_methods(&opts, &name, &description, &args, cache);
+
+    Ok(quote! {
+        impl #impl_generics ::anda_core::Tool<#context> for #ident #ty_generics #where_clause {
+            type Args = #args;
+            type Output = #output;
+
+            #methods
+
+            fn call(
+                &self,
+                ctx: #context,
+                args: Self::Args,
+                resources: ::std::vec::Vec<::anda_core::Resource>,
+            ) -> impl ::std::future::Future<
+                Output = ::std::result::Result<
+                    ::anda_core::ToolOutput<Self::Output>,
+                    ::anda_core::BoxError,
+                >,
+            > + Send {
+                self.#handler(ctx, args, resources)
+            }
+        }
+    })
+}
+
+/// Generates the `Tool` methods except `call`.
+fn tool_methods(
+    opts: &ToolOptions,
+    name: &LitStr,
+    description: &LitStr,
+    args: &Type,
+    cache: bool,
+) -> TokenStream {
+    let parameters = if cache {
+        quote! {
+            {
+                static PARAMETERS: ::std::sync::OnceLock<::anda_core::Json> =
+                    ::std::sync::OnceLock::new();
+                PARAMETERS
+                    .get_or_init(::anda_core::gen_schema_for::<#args>)
+                    .clone()
+            }
+        }
+    } else {
+        quote! { ::anda_core::gen_schema_for::<#args>() }
+    };
+
+    let mut methods = quote! {
+        fn name(&self) -> ::std::string::String {
+            #name.to_string()
+        }
+
+        fn description(&self) -> ::std::string::String {
+            #description.to_string()
+        }
+
+        fn definition(&self) -> ::anda_core::FunctionDefinition {
+            ::anda_core::FunctionDefinition {
+                name: #name.to_string(),
+                description: #description.to_string(),
+                parameters: #parameters,
+                strict: Some(true),
+            }
+        }
+    };
+
+    if !opts.resource_tags.is_empty() {
+        let tags = &opts.resource_tags;
+        methods.extend(quote! {
+            fn supported_resource_tags(&self) -> ::std::vec::Vec<::std::string::String> {
+                vec![#(#tags.to_string()),*]
+            }
+        });
+    }
+
+    if opts.requires_approval {
+        methods.extend(quote! {
+            fn requires_approval(&self) -> bool {
+                true
+            }
+        });
+    }
+
+    methods
+}
+
+/// Extracts `T` from the `Result<ToolOutput<T>, BoxError>` return type.
+fn tool_output_type(output: &ReturnType) -> syn::Result<Type> {
+    let err = || {
+        syn::Error::new_spanned(
+            output,
+            "the tool function must return `Result<ToolOutput<T>, BoxError>`",
+        )
+    };
+
+    let ReturnType::Type(_, ty) = output else {
+        return Err(err());
+    };
+    let result = generic_arg(ty, "Result").ok_or_else(err)?;
+    generic_arg(result, "ToolOutput").cloned().ok_or_else(err)
+}
+
+/// Returns the first generic type argument of the type with the given name.
+fn generic_arg<'a>(ty: &'a Type, name: &str) -> Option<&'a Type> {
+    let Type::Path(path) = ty else {
+        return None;
+    };
+    let segment = path.path.segments.last()?;
+    if segment.ident != name {
+        return None;
+    }
+    let PathArguments::AngleBracketed(args) = &segment.arguments else {
+        return None;
+    };
+    args.args.iter().find_map(|arg| match arg {
+        GenericArgument::Type(ty) => Some(ty),
+        _ => None,
+    })
+}
+
+/// Joins the doc comments of the item.
+fn doc_comments(attrs: &[Attribute]) -> String {
+    let lines: Vec<String> = attrs
+        .iter()
+        .filter_map(|attr| match &attr.meta {
+            Meta::NameValue(nv) if nv.path.is_ident("doc") => match &nv.value {
+                Expr::Lit(expr) => match &expr.lit {
+                    Lit::Str(s) => Some(s.value().trim().to_string()),
+                    _ => None,
+                },
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect();
+    lines.join("\n").trim().to_string()
+}
+
+/// Validates the tool name with the same rules as `anda_core::validate_function_name`.
+fn validate_name(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err("empty string".to_string());
+    }
+    if name.len() > 64 {
+        return Err("string length exceeds the limit 64".to_string());
+    }
+    let mut iter = name.chars();
+    if !matches!(iter.next(), Some('a'..='z')) {
+        return Err("name must start with a lowercase letter".to_string());
+    }
+    for c in iter {
+        if !matches!(c, 'a'..='z' | '0'..='9' | '_') {
+            return Err(format!("invalid character: {}", c));
+        }
+    }
+    Ok(())
+}
+
+/// Converts a Pascal case name to snake case, e.g. `FetchWebResources` to `fetch_web_resources`.
+fn snake_case(name: &str) -> String {
+    let chars: Vec<char> = name.chars().collect();
+    let mut s = String::with_capacity(name.len() + 4);
+    for (i, &c) in chars.iter().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            let prev = chars[i - 1];
+            let next_lower = chars.get(i + 1).is_some_and(|c| c.is_lowercase());
+            if prev.is_lowercase() || prev.is_ascii_digit() || (prev.is_uppercase() && next_lower) {
+                s.push('_');
+            }
+        }
+        s.extend(c.to_lowercase());
+    }
+    s
+}
+
+/// Converts a snake case name to Pascal case, e.g. `get_weather` to `GetWeather`.
+fn pascal_case(name: &str) -> String {
+    name.trim_start_matches("r#")
+        .split('_')
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let mut chars = part.chars();
+            match chars.next() {
+                Some(c) => c.to_uppercase().chain(chars).collect::<String>(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_names() {
+        assert_eq!(snake_case("FetchWebResources"), "fetch_web_resources");
+        assert_eq!(snake_case("BalanceOf"), "balance_of");
+        assert_eq!(snake_case("ICPLedger2Transfer"), "icp_ledger2_transfer");
+        assert_eq!(pascal_case("get_weather"), "GetWeather");
+        assert_eq!(pascal_case("r#type"), "Type");
+        assert!(validate_name("get_weather").is_ok());
+        assert!(validate_name("GetWeather").is_err());
+        assert!(validate_name("get-weather").is_err());
+    }
+
+    #[test]
+    fn test_expand_fn() {
+        let attr = quote! { name = "weather", resource_tags = ["text"], requires_approval };
+        let item = quote! {
+            /// Returns the weather
+            /// of the city.
+            async fn get_weather(ctx: BaseCtx, city: String) -> Result<ToolOutput<String>, BoxError> {
+                Ok(ToolOutput::new(city))
+            }
+        };
+        let code = expand_fn(attr, item).unwrap().to_string();
+        assert!(code.contains("struct GetWeatherTool"));
+        assert!(code.contains("impl :: anda_core :: Tool < BaseCtx > for GetWeatherTool"));
+        assert!(code.contains("type Args = String"));
+        assert!(code.contains("type Output = String"));
+        assert!(code.contains("\"weather\""));
+        assert!(code.contains("\"Returns the weather\\nof the city.\""));
+        assert!(code.contains("fn supported_resource_tags"));
+        assert!(code.contains("fn requires_approval"));
+
+        let err = expand_fn(
+            quote! {},
+            quote! {
+                /// Not async.
+                fn get_weather(ctx: BaseCtx, city: String) -> Result<ToolOutput<String>, BoxError> {}
+            },
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("must be async"));
+
+        let err = expand_fn(
+            quote! {},
+            quote! {
+                async fn get_weather(ctx: BaseCtx, city: String) -> Result<ToolOutput<String>, BoxError> {}
+            },
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("description is required"));
+
+        let err = expand_fn(
+            quote! { name = "GetWeather" },
+            quote! {
+                /// Returns the weather.
+                async fn get_weather(ctx: BaseCtx, city: String) -> String {}
+            },
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("invalid tool name"));
+    }
+
+    #[test]
+    fn test_expand_derive() {
+        let input = quote! {
+            /// Fetches web resources.
+            #[derive(AndaTool)]
+            #[anda_tool(args = FetchArgs, output = String, handler = fetch)]
+            struct FetchWebResourcesTool;
+        };
+        let code = expand_derive(input).unwrap().to_string();
+        assert!(code.contains("impl :: anda_core :: Tool < BaseCtx > for FetchWebResourcesTool"));
+        assert!(code.contains("\"fetch_web_resources\""));
+        assert!(code.contains("\"Fetches web resources.\""));
+        assert!(code.contains("self . fetch (ctx , args , resources)"));
+
+        let err = expand_derive(quote! {
+            /// Fetches web resources.
+            #[anda_tool(output = String)]
+            struct FetchTool;
+        })
+        .unwrap_err();
+        assert!(err.to_string().contains("args = Type"));
+    }
+}
@@ -1,4 +1,4 @@
-use anda_core::{BoxError, BoxPinFut, HttpFeatures, RPCRequestRef, cbor_rpc};
+use anda_core::{AndaError, BoxError, BoxPinFut, HttpFeatures, RPCRequestRef, cbor_rpc};
 use anda_engine::context::Web3ClientFeatures;
 use candid::{
     CandidType, Decode, Principal,
@@ -526,7 +526,10 @@ impl Web3ClientFeatures for Client {
 
         let outer_http = self.outer_http.clone();
         Box::pin(async move {
-            let res = cbor_rpc(&outer_http, &endpoint, &method, Some(headers), body).await?;
+            // decodes the remote error as AndaError, so the callers can get the error code
+            let res = cbor_rpc(&outer_http, &endpoint, &method, Some(headers), body)
+                .await
+                .map_err(AndaError::from)?;
             Ok(res.into_vec())
         })
     }
@@ -622,7 +625,9 @@ impl HttpFeatures for Client {
         let se = SignedEnvelope::sign_digest(&self.identity, digest.into())?;
         let mut headers = http::HeaderMap::new();
         se.to_authorization(&mut headers)?;
-        let res = cbor_rpc(&self.outer_http, endpoint, &method, Some(headers), body).await?;
+        let res = cbor_rpc(&self.outer_http, endpoint, &method, Some(headers), body)
+            .await
+            .map_err(AndaError::from)?;
         let res = from_reader(&res[..])?;
         Ok(res)
     }
@@ -81,6 +81,11 @@ impl Tool<BaseCtx> for TransferTool {
         }
     }
 
+    /// Each transfer must be approved by the user before it is sent to the chain.
+    fn requires_approval(&self) -> bool {
+        true
+    }
+
     async fn call(
         &self,
         ctx: BaseCtx,
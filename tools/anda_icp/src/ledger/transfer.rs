@@ -86,6 +86,11 @@ impl Tool<BaseCtx> for TransferTool {
         }
     }
 
+    /// Transfers move funds, so each call must be approved by the user.
+    fn requires_approval(&self) -> bool {
+        true
+    }
+
     async fn call(
         &self,
         ctx: BaseCtx,